@@ -8,10 +8,68 @@ pub enum Command {
     MibTest,
     /// Performs both config-test and mib-test
     PreflightCheck,
+    /// Performs a preflight-check, then requests sysUpTime.0 from every device
+    ConnectivityCheck,
+    /// Walks an oid on a device with its configured credentials and prints every value, like
+    /// snmpwalk
+    Walk {
+        /// Name of the device in the configuration
+        device: String,
+        /// MODULE::field, or a numeric oid like 1.3.6.1.2.1.2.2
+        oid: String,
+    },
+    /// Runs one cycle of a device, printing the type and oids of every request it sends
+    AuditRequests {
+        /// Name of the device in the configuration
+        device: String,
+    },
+    /// Prints the instance columns of a device with the value columns walked against them, and
+    /// its scalars, by name and numeric OID. Nothing is sent to the device.
+    ShowPlan {
+        /// Name of the device in the configuration
+        device: String,
+    },
+    /// Prints the loaded and merged configuration as a single YAML document
+    DumpConfig {
+        /// Include passwords instead of redacting them
+        #[clap(long)]
+        show_secrets: bool,
+    },
+    /// Serializes the loaded configuration, loads the result again and fails if anything
+    /// changed, e.g. a field lost to a serde alias
+    ConfigRoundtrip,
     /// Display output keys
-    ShowOutputKeys,
+    ShowOutputKeys {
+        /// Also show the keys of disabled devices
+        #[clap(long)]
+        include_disabled: bool,
+    },
+    /// Loads the MIBs and prints the numeric OID of MODULE::field, with the imports followed
+    ResolveOid {
+        /// e.g. IF-MIB::ifHCInOctets
+        oid: String,
+    },
+    /// Lists the names of the threads and tasks, names exceeding the OS limit are shortened
+    ListThreads,
+    /// Times the MIB resolution and estimates the requests of every device's cycle, without
+    /// touching the network
+    Benchmark {
+        /// Rows assumed per table
+        #[clap(long, value_name = "N", default_value_t = 100)]
+        rows: u64,
+    },
+    /// Runs like run, also receiving SNMPv3 traps and informs of the configured devices
+    Listen {
+        /// UDP address to receive notifications on
+        #[clap(long, value_name = "ADDRESS", default_value = "0.0.0.0:162")]
+        bind: String,
+    },
     /// Do the thing!
-    Run,
+    Run {
+        /// Stop every device after this many collection cycles and print a summary
+        #[clap(long, value_name = "N")]
+        cycles: Option<u64>,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -29,6 +87,20 @@ pub struct Opts {
     /// Provide a path to a configuration directory. Note: -c and -foo are mutually exclusive
     #[clap(short = 'd', long, value_name = "DIRECTORY", conflicts_with = "config")]
     pub config_dir: Option<String>,
+
+    /// Merge the overlay of this environment over the configuration, e.g. config.prod.yaml for
+    /// -c config.yaml or conf.d.prod.yaml for -d conf.d. Its values override the configured ones.
+    #[clap(long, value_name = "NAME")]
+    pub env: Option<String>,
+
+    /// Cache resolved MIB oids in this file, it is reused as long as the MIB files and the
+    /// configured oids are unchanged
+    #[clap(long, value_name = "FILE")]
+    pub mib_cache: Option<String>,
+
+    /// Ignore an existing MIB cache and rebuild it
+    #[clap(long, requires = "mib_cache")]
+    pub rebuild_mib_cache: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -0,0 +1,615 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use log::{debug, trace};
+use msnmp::session::Step;
+use rand::Rng;
+use snmp_mp::{PduType, ScopedPdu, SnmpMsg, VarBind};
+use snmp_usm::{AuthKey, Digest, PrivKey, SecurityParams};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+
+/// Timeout of a request in seconds if none is configured
+const TIMEOUT: u64 = 3;
+
+/// Client sending SNMPv3 requests to one agent over UDP. Other than msnmp's client it keeps
+/// reading until the response to the sent request arrives, so a late response to an earlier
+/// request does not cost the current one.
+pub struct Client {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    timeout: Duration,
+    response_len: usize,
+}
+
+impl Client {
+    /// Client connected to `remote_addr`, sending from `bind_address` if given and otherwise
+    /// from the address the system picks. Only addresses of the family of `bind_address` are
+    /// used. `timeout` is in seconds.
+    pub async fn new<A: ToSocketAddrs>(
+        remote_addr: A,
+        bind_address: Option<IpAddr>,
+        timeout: Option<u64>,
+    ) -> Result<Client> {
+        let remote_addr = lookup_host(remote_addr)
+            .await?
+            .find(|remote| bind_address.is_none_or(|local| local.is_ipv4() == remote.is_ipv4()))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    "no address of the family of the bind address",
+                )
+            })?;
+        let local_addr = bind_address.unwrap_or(match remote_addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let socket = UdpSocket::bind((local_addr, 0)).await?;
+        socket.connect(remote_addr).await?;
+        Ok(Client {
+            socket,
+            buf: vec![0; SnmpMsg::MAX_UDP_PACKET_SIZE],
+            timeout: Duration::from_secs(timeout.unwrap_or(TIMEOUT)),
+            response_len: 0,
+        })
+    }
+
+    /// Size in bytes of the last response as it was received
+    pub fn response_len(&self) -> usize {
+        self.response_len
+    }
+
+    /// Send `msg` and return its response. Messages with another message or request id, e.g.
+    /// late responses to earlier requests, are discarded and reading continues until the
+    /// timeout. Reports are matched by the message id only, an agent which failed to decrypt
+    /// a request can not tell its request id.
+    pub async fn send_request<D, P, S>(
+        &mut self,
+        msg: &mut SnmpMsg,
+        session: &mut Session<'_, D, P, S>,
+    ) -> Result<SnmpMsg>
+    where
+        D: Digest,
+        P: PrivKey<Salt = S>,
+        S: Step + Copy,
+    {
+        // read before sending, the scoped pdu is encrypted in place for privacy
+        let sent_request_id = request_id(msg)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "request is already encrypted"))?;
+        self.send_msg(msg, session).await?;
+
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut self.buf)).await
+            {
+                Ok(received) => received?,
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("no response within {:?}", self.timeout),
+                    ))
+                }
+            };
+            let response = match self.read_msg(len, msg.id(), session)? {
+                Some(response) => response,
+                None => continue,
+            };
+            let response_request_id = request_id(&response).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "response without a readable request id",
+                )
+            })?;
+            let is_report = response
+                .scoped_pdu_data
+                .plaintext()
+                .is_some_and(|scoped_pdu| scoped_pdu.pdu_type() == PduType::Report);
+            if response_request_id == sent_request_id || is_report {
+                self.response_len = len;
+                return Ok(response);
+            }
+            debug!(
+                "client: discarding response with request id {}, expected {}",
+                response_request_id, sent_request_id
+            );
+        }
+    }
+
+    async fn send_msg<D, P, S>(
+        &self,
+        msg: &mut SnmpMsg,
+        session: &mut Session<'_, D, P, S>,
+    ) -> Result<()>
+    where
+        D: Digest,
+        P: PrivKey<Salt = S>,
+        S: Step + Copy,
+    {
+        let mut security_params = SecurityParams::new();
+        security_params
+            .set_auth_params_placeholder()
+            .set_username(session.username())
+            .set_engine_id(session.engine_id())
+            .set_engine_boots(session.engine_boots())
+            .set_engine_time(session.engine_time());
+
+        if let Some((priv_key, salt)) = session.priv_key_and_salt() {
+            msg.encrypt_scoped_pdu(|encoded_scoped_pdu| {
+                let (encrypted_scoped_pdu, priv_params) =
+                    priv_key.encrypt(encoded_scoped_pdu, &security_params, salt);
+                security_params.set_priv_params(&priv_params);
+                encrypted_scoped_pdu
+            });
+        }
+        msg.set_security_params(&security_params.encode());
+        if session.auth_key().is_some() {
+            msg.set_auth_flag();
+        }
+
+        let mut encoded_msg = msg.encode();
+        if let Some(auth_key) = session.auth_key() {
+            auth_key.auth_out_msg(&mut encoded_msg)?;
+        }
+        self.socket.send(&encoded_msg).await?;
+        Ok(())
+    }
+
+    /// Authenticate, decode and decrypt the received message, None if it answers another
+    /// message
+    fn read_msg<D, P, S>(
+        &mut self,
+        len: usize,
+        msg_id: u32,
+        session: &mut Session<'_, D, P, S>,
+    ) -> Result<Option<SnmpMsg>>
+    where
+        D: Digest,
+        P: PrivKey,
+    {
+        let encoded_msg = &mut self.buf[..len];
+        if let Some(auth_key) = session.auth_key() {
+            auth_key.auth_in_msg(
+                encoded_msg,
+                session.engine_id(),
+                session.engine_boots(),
+                session.engine_time(),
+            )?;
+        }
+        let mut msg = SnmpMsg::decode(encoded_msg)?;
+        if msg.id() != msg_id {
+            trace!(
+                "client: discarding message {}, expected {}",
+                msg.id(),
+                msg_id
+            );
+            return Ok(None);
+        }
+
+        let security_params = SecurityParams::decode(msg.security_params())?;
+        if let Some(priv_key) = session.priv_key() {
+            msg.decrypt_scoped_pdu(|encrypted_scoped_pdu| {
+                priv_key
+                    .decrypt(encrypted_scoped_pdu, &security_params)
+                    .ok()
+            })?;
+        }
+        session
+            .set_engine_boots(security_params.engine_boots())
+            .set_engine_time(security_params.engine_time());
+
+        Ok(Some(msg))
+    }
+}
+
+fn request_id(msg: &SnmpMsg) -> Option<i32> {
+    Some(msg.scoped_pdu_data.plaintext()?.request_id())
+}
+
+/// Engine, keys and message counters of a session with an agent, set up by the discovery in
+/// `Session::new`
+#[derive(Debug, Clone)]
+pub struct Session<'a, D, P, S> {
+    username: Vec<u8>,
+    engine_id: Vec<u8>,
+    engine_boots: u32,
+    engine_time: u32,
+    sync_time: Instant,
+    msg_id: u32,
+    request_id: i32,
+    auth_key: Option<AuthKey<'a, D>>,
+    priv_key: Option<(P, S)>,
+}
+
+impl<'a, D, P, S> Session<'a, D, P, S> {
+    pub fn username(&self) -> &[u8] {
+        &self.username
+    }
+
+    pub fn engine_id(&self) -> &[u8] {
+        &self.engine_id
+    }
+
+    pub fn engine_boots(&self) -> u32 {
+        self.engine_boots
+    }
+
+    pub fn set_engine_boots(&mut self, engine_boots: u32) -> &mut Self {
+        self.engine_boots = engine_boots;
+        self
+    }
+
+    /// Engine time of the agent, advanced by the time since it was last synchronized
+    pub fn engine_time(&self) -> u32 {
+        self.engine_time
+            .wrapping_add(self.sync_time.elapsed().as_secs() as u32)
+    }
+
+    pub fn set_engine_time(&mut self, engine_time: u32) -> &mut Self {
+        self.engine_time = engine_time;
+        self.sync_time = Instant::now();
+        self
+    }
+
+    /// Take the next message id
+    pub fn msg_id(&mut self) -> u32 {
+        let msg_id = self.msg_id;
+        self.msg_id = if msg_id >= SnmpMsg::MSG_ID_MAX {
+            SnmpMsg::MSG_ID_MIN
+        } else {
+            msg_id + 1
+        };
+        msg_id
+    }
+
+    /// Take the next request id
+    pub fn request_id(&mut self) -> i32 {
+        let request_id = self.request_id;
+        self.request_id = if request_id >= ScopedPdu::REQUEST_ID_MAX {
+            ScopedPdu::REQUEST_ID_MIN
+        } else {
+            request_id + 1
+        };
+        request_id
+    }
+
+    pub fn auth_key(&self) -> &Option<AuthKey<'a, D>> {
+        &self.auth_key
+    }
+
+    pub fn set_auth_key(&mut self, auth_key: AuthKey<'a, D>) -> &mut Self {
+        self.auth_key = Some(auth_key);
+        self
+    }
+
+    pub fn priv_key(&self) -> Option<&P> {
+        self.priv_key.as_ref().map(|(priv_key, _)| priv_key)
+    }
+}
+
+impl<'a, D, P, S> Session<'a, D, P, S>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    /// Discover the engine of the agent of `client`
+    pub async fn new(client: &mut Client, username: &[u8]) -> Result<Session<'a, D, P, S>> {
+        // the rng is not Send, it must not live across the discovery
+        let (msg_id, request_id) = {
+            let mut rng = rand::thread_rng();
+            (
+                rng.gen_range(SnmpMsg::MSG_ID_MIN..SnmpMsg::MSG_ID_MAX),
+                rng.gen_range(ScopedPdu::REQUEST_ID_MIN..ScopedPdu::REQUEST_ID_MAX),
+            )
+        };
+        let mut session = Session {
+            username: vec![],
+            engine_id: vec![],
+            engine_boots: 0,
+            engine_time: 0,
+            sync_time: Instant::now(),
+            msg_id,
+            request_id,
+            auth_key: None,
+            priv_key: None,
+        };
+
+        let mut discovery = create_reportable_msg(&mut session);
+        let response = client.send_request(&mut discovery, &mut session).await?;
+        let security_params = SecurityParams::decode(response.security_params())?;
+        session.username = username.to_vec();
+        session.engine_id = security_params.engine_id().to_vec();
+        session
+            .set_engine_boots(security_params.engine_boots())
+            .set_engine_time(security_params.engine_time());
+
+        Ok(session)
+    }
+
+    /// Take the privacy key and the salt of the next message
+    pub fn priv_key_and_salt(&mut self) -> Option<(&P, S)> {
+        let (priv_key, salt) = self.priv_key.as_mut()?;
+        let current = *salt;
+        *salt = current.next();
+        Some((priv_key, current))
+    }
+
+    pub fn set_priv_key_and_salt(&mut self, priv_key: P, salt: S) -> &mut Self {
+        self.priv_key = Some((priv_key, salt));
+        self
+    }
+}
+
+/// Empty reportable message of the session, as sent for the engine discovery
+pub fn create_reportable_msg<D, P, S>(session: &mut Session<D, P, S>) -> SnmpMsg {
+    let mut msg = SnmpMsg::new(session.msg_id());
+    msg.set_reportable_flag();
+    let request_id = session.request_id();
+    if let Some(scoped_pdu) = msg.scoped_pdu_data.plaintext_mut() {
+        scoped_pdu
+            .set_request_id(request_id)
+            .set_engine_id(session.engine_id());
+    }
+    msg
+}
+
+pub fn create_request_msg<D, P, S>(
+    pdu_type: PduType,
+    var_binds: impl IntoIterator<Item = VarBind>,
+    session: &mut Session<D, P, S>,
+) -> SnmpMsg {
+    let mut msg = create_reportable_msg(session);
+    if let Some(scoped_pdu) = msg.scoped_pdu_data.plaintext_mut() {
+        scoped_pdu.set_pdu_type(pdu_type).set_var_binds(var_binds);
+    }
+    msg
+}
+
+pub fn create_bulk_request_msg<D, P, S>(
+    var_binds: impl IntoIterator<Item = VarBind>,
+    session: &mut Session<D, P, S>,
+) -> SnmpMsg {
+    create_request_msg(PduType::GetBulkRequest, var_binds, session)
+}
+
+/// An agent on localhost which answers without authentication, for tests of the requests sent
+/// to it
+#[cfg(test)]
+pub(crate) mod fake_agent {
+    use super::*;
+    use snmp_usm::{DesPrivKey, LocalizedKey, Md5, WithLocalizedKey};
+
+    pub type TestSession = Session<'static, Md5, DesPrivKey<'static, Md5>, u32>;
+
+    pub const ENGINE_ID: &[u8] = b"\x80\x00\x1f\x88\x04test";
+
+    /// A response and the engine boots and time it is sent with
+    pub struct Answer {
+        pub scoped_pdu: ScopedPdu,
+        pub engine_boots: u32,
+        pub engine_time: u32,
+    }
+
+    impl From<ScopedPdu> for Answer {
+        fn from(scoped_pdu: ScopedPdu) -> Self {
+            Answer {
+                scoped_pdu,
+                engine_boots: 1,
+                engine_time: 1000,
+            }
+        }
+    }
+
+    pub async fn bind() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    pub async fn receive(agent: &UdpSocket) -> (SnmpMsg, SocketAddr) {
+        let mut buf = vec![0; SnmpMsg::MAX_UDP_PACKET_SIZE];
+        let (len, from) = agent.recv_from(&mut buf).await.unwrap();
+        (SnmpMsg::decode(&buf[..len]).unwrap(), from)
+    }
+
+    /// Response to `request` with `var_binds`
+    pub fn response_pdu(request: &ScopedPdu, var_binds: Vec<VarBind>) -> ScopedPdu {
+        let mut scoped_pdu = ScopedPdu::new(request.request_id());
+        scoped_pdu
+            .set_engine_id(ENGINE_ID)
+            .set_pdu_type(PduType::Response)
+            .set_var_binds(var_binds);
+        scoped_pdu
+    }
+
+    pub async fn send_answer(agent: &UdpSocket, request: &SnmpMsg, to: SocketAddr, answer: Answer) {
+        let mut response = SnmpMsg::with_scoped_pdu(request.id(), answer.scoped_pdu);
+        let mut security_params = SecurityParams::new();
+        security_params
+            .set_engine_id(ENGINE_ID)
+            .set_engine_boots(answer.engine_boots)
+            .set_engine_time(answer.engine_time);
+        response.set_security_params(&security_params.encode());
+        agent.send_to(&response.encode(), to).await.unwrap();
+    }
+
+    /// Answer every request with what `answer` returns for its pdu, requests are dropped where
+    /// it returns None. Runs until it is cancelled.
+    pub async fn serve(agent: &UdpSocket, mut answer: impl FnMut(&ScopedPdu) -> Option<Answer>) {
+        loop {
+            let (request, from) = receive(agent).await;
+            if let Some(answer) = answer(request.scoped_pdu_data.plaintext().unwrap()) {
+                send_answer(agent, &request, from, answer).await;
+            }
+        }
+    }
+
+    /// Keys of an agent answering the authPriv requests of sessions with MD5 and DES
+    pub struct AgentKeys {
+        auth_key: AuthKey<'static, Md5>,
+        priv_key: DesPrivKey<'static, Md5>,
+    }
+
+    impl AgentKeys {
+        pub fn new(authpassword: &str, privpassword: &str) -> Self {
+            AgentKeys {
+                auth_key: AuthKey::new(LocalizedKey::new(authpassword.as_bytes(), ENGINE_ID)),
+                priv_key: DesPrivKey::with_localized_key(LocalizedKey::new(
+                    privpassword.as_bytes(),
+                    ENGINE_ID,
+                )),
+            }
+        }
+    }
+
+    /// Like `serve` for sessions with keys, the requests are decrypted and the answers encrypted
+    /// and authenticated. The engine discovery is answered in plaintext.
+    pub async fn serve_with_keys(
+        agent: &UdpSocket,
+        keys: &AgentKeys,
+        mut answer: impl FnMut(&ScopedPdu) -> Option<Answer>,
+    ) {
+        let mut salt: u32 = 0;
+        loop {
+            let (mut request, from) = receive(agent).await;
+            if !request.is_auth() {
+                if let Some(answer) = answer(request.scoped_pdu_data.plaintext().unwrap()) {
+                    send_answer(agent, &request, from, answer).await;
+                }
+                continue;
+            }
+            let request_params = SecurityParams::decode(request.security_params()).unwrap();
+            let request_pdu = request
+                .decrypt_scoped_pdu(|encrypted_scoped_pdu| {
+                    keys.priv_key
+                        .decrypt(encrypted_scoped_pdu, &request_params)
+                        .ok()
+                })
+                .unwrap();
+            let answer = match answer(request_pdu) {
+                Some(answer) => answer,
+                None => continue,
+            };
+
+            let mut response = SnmpMsg::with_scoped_pdu(request.id(), answer.scoped_pdu);
+            let mut security_params = SecurityParams::new();
+            security_params
+                .set_auth_params_placeholder()
+                .set_username(request_params.username())
+                .set_engine_id(ENGINE_ID)
+                .set_engine_boots(answer.engine_boots)
+                .set_engine_time(answer.engine_time);
+            salt += 1;
+            response.encrypt_scoped_pdu(|encoded_scoped_pdu| {
+                let (encrypted_scoped_pdu, priv_params) =
+                    keys.priv_key
+                        .encrypt(encoded_scoped_pdu, &security_params, salt);
+                security_params.set_priv_params(&priv_params);
+                encrypted_scoped_pdu
+            });
+            response.set_security_params(&security_params.encode());
+            response.set_auth_flag();
+            let mut encoded_msg = response.encode();
+            keys.auth_key.auth_out_msg(&mut encoded_msg).unwrap();
+            agent.send_to(&encoded_msg, from).await.unwrap();
+        }
+    }
+
+    /// Client and session with `agent`, after the engine discovery
+    pub async fn connect(agent: &UdpSocket) -> (Client, TestSession) {
+        let mut client = Client::new(agent.local_addr().unwrap(), None, Some(1))
+            .await
+            .unwrap();
+        let discovery = async {
+            let (request, from) = receive(agent).await;
+            let request_pdu = request.scoped_pdu_data.plaintext().unwrap();
+            send_answer(
+                agent,
+                &request,
+                from,
+                response_pdu(request_pdu, vec![]).into(),
+            )
+            .await;
+        };
+        let (session, _) = tokio::join!(Session::new(&mut client, b"user"), discovery);
+        let session = session.unwrap();
+        assert_eq!(session.engine_id(), ENGINE_ID);
+        (client, session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake_agent::*;
+    use super::*;
+    use snmp_usm::{DesPrivKey, Md5};
+
+    /// Answer the next request of the client with a response for each of `request_ids`, the
+    /// request id of the request where None
+    async fn respond(agent: &UdpSocket, request_ids: &[Option<i32>]) -> SocketAddr {
+        let (request, from) = receive(agent).await;
+        let request_pdu = request.scoped_pdu_data.plaintext().unwrap();
+        for request_id in request_ids {
+            let mut scoped_pdu = response_pdu(request_pdu, request_pdu.var_binds().to_vec());
+            scoped_pdu.set_request_id(request_id.unwrap_or(request_pdu.request_id()));
+            send_answer(agent, &request, from, scoped_pdu.into()).await;
+        }
+        from
+    }
+
+    #[tokio::test]
+    async fn reads_again_on_a_foreign_request_id() {
+        let agent = bind().await;
+        let (mut client, mut session) = connect(&agent).await;
+
+        let mut request = create_request_msg(PduType::GetRequest, vec![], &mut session);
+        let request_id = request.scoped_pdu_data.plaintext().unwrap().request_id();
+        let responses = [Some(request_id.wrapping_add(7)), None];
+        let (response, _) = tokio::join!(
+            client.send_request(&mut request, &mut session),
+            respond(&agent, &responses),
+        );
+        assert_eq!(
+            response
+                .unwrap()
+                .scoped_pdu_data
+                .plaintext()
+                .unwrap()
+                .request_id(),
+            request_id
+        );
+
+        // the request was sent once, the agent has nothing else to read
+        let mut buf = [0; 16];
+        let again = tokio::time::timeout(Duration::from_millis(100), agent.recv(&mut buf)).await;
+        assert!(again.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_matching_response() {
+        let agent = bind().await;
+        let (mut client, mut session) = connect(&agent).await;
+
+        let mut request = create_request_msg(PduType::GetRequest, vec![], &mut session);
+        let request_id = request.scoped_pdu_data.plaintext().unwrap().request_id();
+        let responses = [Some(request_id.wrapping_add(7))];
+        let (response, _) = tokio::join!(
+            client.send_request(&mut request, &mut session),
+            respond(&agent, &responses),
+        );
+        assert_eq!(response.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn sends_from_the_bind_address() {
+        let agent = bind().await;
+        let bind_address = Ipv4Addr::new(127, 0, 0, 2).into();
+        let mut client = Client::new(agent.local_addr().unwrap(), Some(bind_address), Some(1))
+            .await
+            .unwrap();
+
+        let (_, from) = tokio::join!(
+            Session::<Md5, DesPrivKey<Md5>, u32>::new(&mut client, b"user"),
+            respond(&agent, &[None]),
+        );
+        assert_eq!(from.ip(), bind_address);
+    }
+}
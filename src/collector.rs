@@ -1,366 +1,2600 @@
-use flume::Sender;
+use flume::{Receiver, Sender};
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::iter::Iterator;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{debug, error, info, trace, warn};
+use log::{debug, info, trace, warn};
 
-use anyhow::Error;
+use anyhow::{bail, Context, Error};
 use rand::Rng;
+use regex::Regex;
 
-use msnmp::session::{Session, Step};
-use msnmp::Client;
+use msnmp::session::Step;
 use snmp_mp::{VarBind, VarValue};
 use snmp_usm::{
     Aes128PrivKey, AuthKey, DesPrivKey, Digest, LocalizedKey, Md5, PrivKey, Sha1, WithLocalizedKey,
 };
 
-use crate::config::Config;
-use crate::config::{SnmpAuthProtocol, SnmpPrivProtocol};
+use crate::client::{Client, Session};
+use crate::config::{Config, OnEmpty, TableIndex, WalkMode};
+use crate::config::{
+    DeviceEntry, DeviceSnmpSettings, IpVersion, SnmpAuthProtocol, SnmpPrivProtocol,
+};
+use crate::mib_index;
+use crate::output::{device_self_metric, set_device_metadata, stable_hash, CarbonMetricValue};
 use crate::snmp::{
-    snmp_bulkwalk as snmp_fetch_table, snmp_get as snmp_fetch_var_binds, vec_to_var_binds,
+    self, classify_error, snmp_get as snmp_fetch_var_binds, snmp_walk as snmp_fetch_table,
+    vec_to_var_binds, BulkRepetitions, SnmpErrorClass, SYS_UPTIME_OID,
 };
 use crate::stat_result::SnmpStatResult;
 
-macro_rules! collect_device {
-    ($digest:ty, $device_name:expr, $config:expr, $oid_var_bind_map:expr, $channel:expr, $backoff:expr) => {{
-        let device = $config.devices.get($device_name).unwrap();
-        if SnmpPrivProtocol::Aes == device.snmp.privprotocol {
+// calls `$function::<Digest, PrivKey, Salt>(args.., salt)` with the types matching the auth and
+// priv protocols configured for `$device`
+macro_rules! with_usm_types {
+    (@priv $digest:ty, $device:expr, $function:ident($($arg:expr),*)) => {{
+        if SnmpPrivProtocol::Aes == $device.snmp.privprotocol {
             let salt = rand::random();
-            collect_device_::<
+            $function::<
                 $digest,
                 Aes128PrivKey<$digest>,
                 <Aes128PrivKey<$digest> as PrivKey>::Salt,
-            >(
-                $device_name,
-                $config,
-                $oid_var_bind_map,
-                $channel,
-                salt,
-                $backoff,
-            )
+            >($($arg,)* salt)
             .await
         } else {
             let salt = rand::random();
-            collect_device_::<$digest, DesPrivKey<$digest>, <DesPrivKey<$digest> as PrivKey>::Salt>(
-                $device_name,
-                $config,
-                $oid_var_bind_map,
-                $channel,
-                salt,
-                $backoff,
+            $function::<$digest, DesPrivKey<$digest>, <DesPrivKey<$digest> as PrivKey>::Salt>(
+                $($arg,)* salt
             )
             .await
         }
     }};
+    ($device:expr, $function:ident($($arg:expr),*)) => {{
+        match $device.snmp.authprotocol {
+            SnmpAuthProtocol::Sha => with_usm_types!(@priv Sha1, $device, $function($($arg),*)),
+            SnmpAuthProtocol::Md5 => with_usm_types!(@priv Md5, $device, $function($($arg),*)),
+        }
+    }};
+}
+pub(crate) use with_usm_types;
+
+/// What the task of a device shares with the rest of the collector
+#[derive(Clone)]
+pub struct DeviceContext {
+    pub config: Arc<Config>,
+    pub oid_var_bind_map: HashMap<String, VarBind>,
+    pub channel: Sender<SnmpStatResult>,
+    pub metric_channel: Sender<CarbonMetricValue>,
+    /// stop after this many cycles, successful or not
+    pub max_cycles: Option<u64>,
+    /// cuts the wait for the next cycle short
+    pub trigger: Receiver<()>,
+    pub progress: Progress,
 }
 
 pub async fn collect_device(
-    device_name: String,
-    config: Arc<Config>,
-    oid_var_bind_map: HashMap<String, VarBind>,
-    channel: Sender<SnmpStatResult>,
-    backoff: &mut f64,
+    device_name: &str,
+    context: &DeviceContext,
+    backoff: &mut Backoff,
+    summary: &mut CycleSummary,
+    spread: &mut Option<Duration>,
 ) -> Result<(), Error> {
-    let device = config.devices.get(&device_name).unwrap();
-    match &device.snmp.authprotocol {
-        SnmpAuthProtocol::Sha => {
-            collect_device!(
-                Sha1,
-                &device_name,
-                config,
-                oid_var_bind_map,
-                channel,
-                backoff
-            )
+    let device = context.config.devices.get(device_name).unwrap();
+    with_usm_types!(
+        device,
+        collect_device_(device_name, context, backoff, summary, spread)
+    )
+}
+
+/// Cycles completed by any device, readiness is notified after the first one
+pub static CYCLES_COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+/// When a device task last made progress, shared with the watchdog
+#[derive(Debug, Clone, Default)]
+pub struct Progress(Arc<AtomicU64>);
+
+impl Progress {
+    pub fn touch(&self) {
+        self.touch_after(Duration::ZERO);
+    }
+
+    /// A planned pause of `duration` counts as progress up to its end
+    pub fn touch_after(&self, duration: Duration) {
+        let at = SystemTime::now() + duration;
+        let millis = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.0.store(millis as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the last progress, none before the task started collecting
+    pub fn stalled_for(&self) -> Option<Duration> {
+        let millis = self.0.load(Ordering::Relaxed);
+        if millis == 0 {
+            return None;
         }
-        SnmpAuthProtocol::Md5 => {
-            collect_device!(
-                Md5,
-                &device_name,
-                config,
-                oid_var_bind_map,
-                channel,
-                backoff
-            )
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_millis(millis))
+            .ok()
+    }
+}
+
+/// Collection statistics of a device, reported by `Run --cycles`
+#[derive(Debug, Default)]
+pub struct CycleSummary {
+    /// cycles which completed without an error
+    pub cycles: u64,
+    pub errors: u64,
+    pub var_binds: u64,
+    /// time spent requesting data in completed cycles
+    pub duration: Duration,
+}
+
+impl CycleSummary {
+    /// Failed cycles count towards the limit as well, so unreachable devices do not keep a run
+    /// going forever
+    fn done(&self, max_cycles: Option<u64>) -> bool {
+        max_cycles.is_some_and(|max_cycles| self.cycles + self.errors >= max_cycles)
+    }
+
+    pub fn average_duration(&self) -> Option<Duration> {
+        u32::try_from(self.cycles)
+            .ok()
+            .filter(|cycles| *cycles > 0)
+            .map(|cycles| self.duration / cycles)
+    }
+}
+
+/// Rows walked of a column at most unless `max_rows` is configured, far more than the largest
+/// tables seen in practice
+const DEFAULT_MAX_ROWS: usize = 100_000;
+
+/// The value columns fetched for an instance column
+#[derive(Debug)]
+struct CollectInstance {
+    start_index: Vec<u64>,
+    /// rows walked of each column at most
+    max_rows: usize,
+    /// counter discontinuity column of the table
+    discontinuity: Option<VarBind>,
+    /// discovery columns restricting the rows of some values
+    selectors: Vec<Selector>,
+    values: HashMap<VarBind, ValuePlan>,
+}
+
+/// Scalars of a `table: false` collector, keyed by the value of the `instance` scalar
+#[derive(Debug)]
+struct ScalarPlan {
+    instance: VarBind,
+    /// each value with its fallbacks, the first one the device has is emitted as the first
+    values: Vec<Vec<VarBind>>,
+}
+
+#[derive(Debug)]
+struct ValuePlan {
+    exclude_indices: HashSet<Vec<u64>>,
+    /// positions in `CollectInstance::selectors`, rows matching any of them are collected. All
+    /// rows are collected if unset.
+    selectors: Option<Vec<usize>>,
+    /// columns walked in order when the value column returns no rows, their values are emitted
+    /// as the value column
+    fallbacks: Vec<VarBind>,
+    on_empty: OnEmpty,
+    /// configured name of the value column without the module
+    name: String,
+    /// column of the value table holding the index of the instance row, rows are correlated by
+    /// index if unset
+    join_by: Option<VarBind>,
+}
+
+/// Rows are selected if the value of `column` at their index matches `regex`
+#[derive(Debug)]
+struct Selector {
+    column: VarBind,
+    regex: Regex,
+}
+
+/// Retry state of a device, reset by `collect_device_` once data has been fetched successfully
+pub struct Backoff {
+    interval: Duration,
+    current: f64,
+    auth_failure_logged: bool,
+    consecutive_failures: u32,
+    circuit_open: bool,
+    /// kept across sessions, so a reduction is not lost when a timeout ends the session
+    repetitions: BulkRepetitions,
+}
+
+impl Backoff {
+    pub fn new(interval: Duration, max_pdu_size: Option<u32>) -> Self {
+        Backoff {
+            interval,
+            current: calc_initial_backoff(interval),
+            auth_failure_logged: false,
+            consecutive_failures: 0,
+            circuit_open: false,
+            repetitions: BulkRepetitions::new(max_pdu_size),
         }
     }
+
+    fn reset(&mut self) {
+        self.current = calc_initial_backoff(self.interval);
+        self.auth_failure_logged = false;
+        self.consecutive_failures = 0;
+        self.circuit_open = false;
+    }
+}
+
+/// Place of a device in the startup ramp of `main.startup_rate`
+#[derive(Debug, Clone, Copy)]
+pub struct StartupSlot {
+    pub position: usize,
+    pub total: usize,
+    pub rate: u32,
 }
 
 pub async fn collect_device_safe(
     device_name: String,
-    config: Arc<Config>,
-    oid_var_bind_map: HashMap<String, VarBind>,
-    channel: Sender<SnmpStatResult>,
-) {
+    context: DeviceContext,
+    startup_slot: Option<StartupSlot>,
+) -> CycleSummary {
+    let DeviceContext {
+        config,
+        metric_channel,
+        max_cycles,
+        trigger,
+        progress,
+        ..
+    } = &context;
+    let max_cycles = *max_cycles;
     let device = config.devices.get(&device_name).unwrap();
 
     let interval = Duration::from_secs(device.interval.into());
 
-    let mut backoff: f64 = calc_initial_backoff(interval);
-    let max_backoff: f64 = interval.as_secs_f64() * 5.0;
+    let mut backoff = Backoff::new(interval, device.snmp.max_pdu_size);
+    // retrying later than the next cycle would be due gains nothing
+    let max_backoff: f64 = interval.as_secs_f64();
     let backoff_multiplier: f64 = 2.0;
+    let mut error_counts: HashMap<SnmpErrorClass, u64> = HashMap::new();
+    let mut summary = CycleSummary::default();
 
-    let max_startup_delay: u64 = (interval.as_millis() / 3).try_into().unwrap();
-    let startup_delay = Duration::from_millis(rand::thread_rng().gen_range(0..max_startup_delay));
+    // with initial_burst the delay is added after the first cycle instead, so the devices are
+    // spread over their interval from the second cycle on
+    let mut spread = None;
+    let startup_delay = match (device.offset_secs, startup_slot) {
+        (Some(offset_secs), _) if !config.main.initial_burst => {
+            until_aligned(interval, Duration::from_secs(offset_secs))
+        }
+        (_, Some(slot)) => Duration::from_secs_f64(slot.position as f64 / f64::from(slot.rate)),
+        // aligned cycles find their offset again after the first one
+        (Some(_), None) => Duration::ZERO,
+        (None, None) => {
+            let max_startup_delay: u64 = (interval.as_millis() / 3).try_into().unwrap();
+            let delay = Duration::from_millis(rand::thread_rng().gen_range(0..max_startup_delay));
+            if config.main.initial_burst {
+                spread = Some(delay);
+                Duration::ZERO
+            } else {
+                delay
+            }
+        }
+    };
     debug!(
         "collect_device_safe({}): startup delay -> sleeping for {:?}",
         device_name, startup_delay
     );
     tokio::time::sleep(startup_delay).await;
+    progress.touch();
+    if let Some(slot) = startup_slot {
+        // once per second of the ramp, and for the last device
+        if (slot.position + 1) % slot.rate as usize == 0 || slot.position + 1 == slot.total {
+            info!(
+                "collect_device_safe({}): startup ramp, {} of {} devices started",
+                device_name,
+                slot.position + 1,
+                slot.total
+            );
+        }
+    }
 
     loop {
         let collect = collect_device(
-            device_name.clone(),
-            config.clone(),
-            oid_var_bind_map.clone(),
-            channel.clone(),
+            &device_name,
+            &context,
             &mut backoff,
+            &mut summary,
+            &mut spread,
         );
         if let Err(error) = &collect.await {
-            // condense error
-            let error_debug_str = format!("{:#?}", error)
-                .split('\n')
-                .map(|s| s.trim_matches(' '))
-                .collect::<Vec<&str>>()
-                .join(" ");
+            summary.errors += 1;
+            backoff.consecutive_failures += 1;
+            let error_class = classify_error(error);
+            let error_count = error_counts.entry(error_class).or_insert(0);
+            *error_count += 1;
+            metric_channel
+                .send(device_self_metric(
+                    &device_name,
+                    &format!("errors.{}", error_class),
+                    *error_count,
+                ))
+                .unwrap();
 
-            warn!(
-                "collect_device_safe({}): error: {}; backing off for {:?}",
-                device_name, error_debug_str, backoff
-            );
+            let error_debug_str = format_error_chain(error);
 
-            tokio::time::sleep(Duration::from_secs_f64(backoff)).await;
+            let circuit_breaker = device
+                .circuit_breaker
+                .as_ref()
+                .filter(|circuit_breaker| backoff.consecutive_failures >= circuit_breaker.failures);
 
-            info!(
-                "collect_device_safe({}): backoff {:?} done, retrying...",
-                device_name, backoff
-            );
+            // wrong credentials will not fix themselves, retry rarely and log only once
+            let sleep = if let Some(circuit_breaker) = circuit_breaker {
+                let sleep = circuit_breaker.cooldown_secs as f64;
+                if backoff.circuit_open {
+                    debug!(
+                        "collect_device_safe({}): probe failed: {}; circuit stays open for {}s",
+                        device_name, error_debug_str, sleep
+                    );
+                } else {
+                    warn!(
+                        "collect_device_safe({}): {} consecutive failures, last {} error: {}; circuit open, probing every {}s",
+                        device_name, backoff.consecutive_failures, error_class, error_debug_str, sleep
+                    );
+                    backoff.circuit_open = true;
+                    metric_channel
+                        .send(device_self_metric(&device_name, "circuit_open", 1))
+                        .unwrap();
+                }
+                sleep
+            } else if error_class == SnmpErrorClass::Auth {
+                let sleep = calc_auth_failure_backoff(interval);
+                if backoff.auth_failure_logged {
+                    debug!(
+                        "collect_device_safe({}): authentication error: {}; backing off for {:?}",
+                        device_name, error_debug_str, sleep
+                    );
+                } else {
+                    warn!(
+                        "collect_device_safe({}): authentication error: {}; check credentials, retrying every {:?} until then",
+                        device_name, error_debug_str, sleep
+                    );
+                    backoff.auth_failure_logged = true;
+                }
+                sleep
+            } else {
+                // devices failing together, e.g. behind the same link, spread their retries
+                let sleep = jittered_backoff(backoff.current);
+                warn!(
+                    "collect_device_safe({}): {} error: {}; backing off for {:.1}s",
+                    device_name, error_class, error_debug_str, sleep
+                );
+                metric_channel
+                    .send(device_self_metric(
+                        &device_name,
+                        "backoff_ms",
+                        (sleep * 1000.0) as u64,
+                    ))
+                    .unwrap();
+                backoff.current *= backoff_multiplier;
+                if backoff.current > max_backoff {
+                    backoff.current = max_backoff;
+                }
+                sleep
+            };
 
-            backoff *= backoff_multiplier;
-            if backoff > max_backoff {
-                backoff = max_backoff;
+            if summary.done(max_cycles) {
+                break;
+            }
+
+            progress.touch_after(Duration::from_secs_f64(sleep));
+            sleep_or_triggered(Duration::from_secs_f64(sleep), trigger, &device_name).await;
+
+            if backoff.circuit_open {
+                debug!(
+                    "collect_device_safe({}): circuit half open, probing",
+                    device_name
+                );
+            } else {
+                info!(
+                    "collect_device_safe({}): backoff {:?} done, retrying...",
+                    device_name, sleep
+                );
             }
         }
+        if summary.done(max_cycles) {
+            break;
+        }
     }
+
+    info!(
+        "collect_device_safe({}): stopping after {} cycles",
+        device_name,
+        summary.cycles + summary.errors
+    );
+    summary
 }
 
-async fn collect_device_<'a, D: 'a, P, S>(
+async fn collect_device_<'a, D, P, S>(
     device_name: &str,
-    config: Arc<Config>,
-    oid_var_bind_map: HashMap<String, VarBind>,
-    channel: Sender<SnmpStatResult>,
+    context: &DeviceContext,
+    backoff: &mut Backoff,
+    summary: &mut CycleSummary,
+    spread: &mut Option<Duration>,
     salt: P::Salt,
-    backoff: &mut f64,
 ) -> Result<(), Error>
 where
-    D: Digest,
+    D: 'a + Digest,
     P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
     S: Step + Copy,
 {
+    let DeviceContext {
+        config,
+        oid_var_bind_map,
+        metric_channel,
+        max_cycles,
+        trigger,
+        progress,
+        ..
+    } = context;
+    let max_cycles = *max_cycles;
     debug!("collect_device({}): start", device_name);
     let device = config.devices.get(device_name).unwrap();
     let interval = Duration::from_secs(device.interval.into());
 
-    // condense mibs to connect
-    let mut collect_map: HashMap<VarBind, HashSet<VarBind>> = HashMap::new();
-    for collect in &device.collect {
-        let config_data_entry = config.data.get(collect).unwrap();
-        let instance_oid = oid_var_bind_map.get(&config_data_entry.instance).unwrap();
-
-        let entry = collect_map.entry(instance_oid.clone()).or_default();
-
-        for value in &config_data_entry.values {
-            entry.insert(oid_var_bind_map.get(value).unwrap().clone());
-        }
-    }
-    let collect_map = collect_map;
+    let plan = DevicePlan {
+        collect_map: plan_collection(device, config, oid_var_bind_map)?,
+        scalars: plan_scalars(device, config, oid_var_bind_map),
+        metadata: plan_metadata(config, oid_var_bind_map),
+    };
+    // right in the first cycle, then once per ttl_secs
+    let mut metadata_due = Instant::now();
 
     debug!(
         "collect_device({}): collect_map = {:?}",
-        device_name, collect_map
+        device_name, plan.collect_map
     );
 
-    let timeout = device.snmp.timeout.0;
-
-    // snmp
-    let host = if device.snmp.host.find(':').is_none() {
-        format!("{}:{}", device.snmp.host, msnmp::SNMP_PORT_NUM)
-    } else {
-        device.snmp.host.clone()
-    };
-
-    let mut client = Client::new(host, Some(timeout)).await?;
-    let mut session: Session<D, P, S> =
-        Session::new(&mut client, device.snmp.secname.as_bytes()).await?;
+    let cycle_deadline = device.snmp.cycle_deadline.map(Duration::from_secs);
 
-    let localized_key =
-        LocalizedKey::<D>::new(device.snmp.authpassword.as_bytes(), session.engine_id());
-    let auth_key = AuthKey::new(localized_key);
-    session.set_auth_key(auth_key);
+    // snmp, a session per endpoint
+    let mut endpoints: Vec<EndpointSession<D, P, S>> = vec![];
+    for target in targets(&device.snmp) {
+        let (client, session) = connect(
+            &format!("collect_device({})", device_name),
+            &target.address,
+            &device.snmp,
+            salt,
+        )
+        .await
+        .with_context(|| target.describe("session setup"))?;
+        endpoints.push(EndpointSession {
+            target,
+            client,
+            session,
+            discontinuity_times: HashMap::new(),
+            missing_columns: HashSet::new(),
+        });
+    }
 
-    let localized_key =
-        LocalizedKey::<D>::new(device.snmp.privpassword.as_bytes(), session.engine_id());
-    let priv_key = P::with_localized_key(localized_key);
-    session.set_priv_key_and_salt(priv_key, salt);
+    // set after the engine has been rediscovered, until a cycle succeeds
+    let mut rediscovered = false;
 
     // fetch metrics in this loop
     loop {
+        progress.touch();
         let start_time = Instant::now();
 
-        for (collect_key, collect_values) in &collect_map {
-            let mut hpe_comware_workaround_var_binds: Vec<VarBind> = vec![];
-            debug!(
-                "collect_device({}) fetch_table({:?}) start",
+        let mut var_binds_collected: u64 = 0;
+
+        // the endpoint the cycle is at, for rediscovering its engine after a failure
+        let mut current_endpoint = 0;
+        // everything requested within a cycle, bounded by the cycle deadline
+        let cycle = async {
+            for (position, endpoint) in endpoints.iter_mut().enumerate() {
+                current_endpoint = position;
+                let context_name = endpoint.target.context_name.clone();
+                // a device has the same metadata at all of its endpoints
+                let metadata_due = (position == 0).then_some(&mut metadata_due);
+                snmp::with_context_name(
+                    context_name,
+                    collect_endpoint(
+                        device_name,
+                        context,
+                        &plan,
+                        endpoint,
+                        metadata_due,
+                        backoff,
+                        &mut var_binds_collected,
+                    ),
+                )
+                .await
+                .map_err(|error| match &endpoint.target.name {
+                    Some(name) => error.context(format!("endpoint {}", name)),
+                    None => error,
+                })?;
+            }
+            Ok::<(), Error>(())
+        };
+        let cycle = snmp::with_max_pps(
+            device.snmp.max_pps,
+            snmp::with_cycle_timestamp(config.main.cycle_timestamps, cycle),
+        );
+        let (cycle_result, rate_limited) = match cycle_deadline {
+            Some(cycle_deadline) => match tokio::time::timeout(cycle_deadline, cycle).await {
+                Ok(result) => result,
+                Err(elapsed) => {
+                    return Err(Error::new(elapsed)
+                        .context(format!("cycle deadline of {:?} exceeded", cycle_deadline)))
+                }
+            },
+            None => cycle.await,
+        };
+        if let Err(error) = cycle_result {
+            // the engine time drifted away from the one discovered when the session was opened,
+            // e.g. after a long run, rediscover it and start over once. A cycle which sent values
+            // already is not repeated, they would be sent twice. It fails and the sessions are
+            // set up again after the backoff instead.
+            if snmp::is_time_window_error(&error) && !rediscovered && var_binds_collected == 0 {
+                info!(
+                    "collect_device({}): {}, rediscovering the engine time",
+                    device_name, error
+                );
+                let endpoint = &mut endpoints[current_endpoint];
+                endpoint.session = open_session(&mut endpoint.client, &device.snmp, salt)
+                    .await
+                    .with_context(|| endpoint.target.describe("session setup"))?;
+                rediscovered = true;
+                continue;
+            }
+            return Err(error);
+        }
+        rediscovered = false;
+        let snmp_duration = start_time.elapsed();
+        backoff.repetitions.cycle_succeeded();
+        summary.cycles += 1;
+        CYCLES_COMPLETED.fetch_add(1, Ordering::Relaxed);
+        summary.var_binds += var_binds_collected;
+        summary.duration += snmp_duration;
+
+        // throughput and latency per device, for sizing the output pipeline
+        metric_channel
+            .send(device_self_metric(
                 device_name,
-                collect_key.name().components()
-            );
+                "varbinds_collected",
+                var_binds_collected,
+            ))
+            .unwrap();
+        metric_channel
+            .send(device_self_metric(
+                device_name,
+                "snmp_duration_ms",
+                snmp_duration.as_millis(),
+            ))
+            .unwrap();
+        if device.snmp.max_pps.is_some() {
+            // 0 unless max_pps slowed down the cycle
+            metric_channel
+                .send(device_self_metric(
+                    device_name,
+                    "rate_limited_ms",
+                    rate_limited.as_millis(),
+                ))
+                .unwrap();
+        }
 
-            // request snmp data
-            let table_names =
-                snmp_fetch_table(vec![collect_key.clone()], &mut client, &mut session).await?;
+        if summary.done(max_cycles) {
+            return Ok(());
+        }
 
-            // reset backoff after successful fetch of table_names
-            *backoff = calc_initial_backoff(interval);
+        if let Some(offset_secs) = device.offset_secs {
+            // aligned cycles always wait for the next boundary, a cycle running past one skips it
+            if snmp_duration >= interval {
+                warn!(
+                    "collect_device({}): snmp took {:?}, which is longer than set interval {:?}",
+                    device_name, snmp_duration, interval
+                );
+            }
+            let wait = until_aligned(interval, Duration::from_secs(offset_secs));
+            debug!(
+                "collect_device({}): snmp took {:?}, waiting for {:?} until next aligned interval",
+                device_name, snmp_duration, wait
+            );
+            sleep_or_triggered(wait, trigger, device_name).await;
+            continue;
+        }
 
+        let cycle_interval =
+            jittered_interval(interval, device.jitter) + spread.take().unwrap_or_default();
+        if snmp_duration < cycle_interval {
+            let wait = cycle_interval - snmp_duration;
             debug!(
-                "collect_device({}) fetch_table({:?}) done",
+                "collect_device({}): snmp took {:?}, waiting for {:?} until next interval",
+                device_name, snmp_duration, wait
+            );
+
+            sleep_or_triggered(wait, trigger, device_name).await;
+        } else {
+            warn!(
+                "collect_device({}): snmp took {:?}, which is longer than set interval {:?}",
+                device_name, snmp_duration, interval
+            );
+        }
+    }
+}
+
+/// What is requested from a device in every cycle
+struct DevicePlan {
+    collect_map: HashMap<VarBind, CollectInstance>,
+    scalars: Vec<ScalarPlan>,
+    metadata: Vec<(String, VarBind)>,
+}
+
+/// Request everything of `plan` from one endpoint of a device and send the results, counting
+/// them in `var_binds_collected` as they are sent. The metadata is fetched if `metadata_due` is
+/// given and has passed, a device has the same metadata at all of its endpoints.
+async fn collect_endpoint<'p, D, P, S>(
+    device_name: &str,
+    context: &DeviceContext,
+    plan: &'p DevicePlan,
+    endpoint: &mut EndpointSession<'_, 'p, D, P, S>,
+    metadata_due: Option<&mut Instant>,
+    backoff: &mut Backoff,
+    var_binds_collected: &mut u64,
+) -> Result<(), Error>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    let DeviceContext {
+        config,
+        channel,
+        metric_channel,
+        ..
+    } = context;
+    let device = config.devices.get(device_name).unwrap();
+    let EndpointSession {
+        target,
+        client,
+        session,
+        discontinuity_times,
+        missing_columns,
+    } = endpoint;
+    // columns walked in this cycle, for columns used by several instances or as a
+    // selector or discontinuity column as well
+    let mut walked = HashMap::new();
+    if let Some(metadata_due) = metadata_due {
+        if !plan.metadata.is_empty() && Instant::now() >= *metadata_due {
+            fetch_metadata(device_name, &plan.metadata, metric_channel, client, session)
+                .await
+                .context("get of device metadata")?;
+            let ttl_secs = config.main.device_metadata.as_ref().unwrap().ttl_secs;
+            *metadata_due = Instant::now() + Duration::from_secs(ttl_secs);
+        }
+    }
+    if !plan.scalars.is_empty() {
+        *var_binds_collected += collect_scalars(
+            device_name,
+            &target.name,
+            &plan.scalars,
+            channel,
+            missing_columns,
+            client,
+            session,
+        )
+        .await
+        .context("get of scalars")?;
+        data_received(device_name, backoff, metric_channel);
+    }
+    for (collect_key, collect_instance) in &plan.collect_map {
+        let start_index = collect_instance.start_index.as_slice();
+        let mut hpe_comware_workaround_var_binds: Vec<VarBind> = vec![];
+        // value oids sent for this instance column in this cycle
+        let mut emitted: HashSet<Vec<u64>> = HashSet::new();
+        debug!(
+            "collect_device({}) fetch_table({:?}) start",
+            device_name,
+            collect_key.name().components()
+        );
+
+        // request snmp data
+        let table_names = walk_column(
+            &mut walked,
+            collect_key,
+            start_index,
+            collect_instance.max_rows,
+            &device.snmp.walk_mode,
+            &mut backoff.repetitions,
+            client,
+            session,
+        )
+        .await
+        .with_context(|| format!("walk of {}", collect_key.name()))?;
+        let collect_key_len = collect_key.name().components().len();
+
+        // reset backoff after successful fetch of table_names
+        data_received(device_name, backoff, metric_channel);
+
+        debug!(
+            "collect_device({}) fetch_table({:?}) done",
+            device_name,
+            collect_key.name().components()
+        );
+
+        // rows whose counters have been reset since the last cycle
+        let mut discontinued: HashSet<Vec<u64>> = HashSet::new();
+        if let Some(discontinuity) = &collect_instance.discontinuity {
+            let discontinuity_len = discontinuity.name().components().len();
+            let current: HashMap<Vec<u64>, VarValue> = walk_column(
+                &mut walked,
+                discontinuity,
+                start_index,
+                collect_instance.max_rows,
+                &device.snmp.walk_mode,
+                &mut backoff.repetitions,
+                client,
+                session,
+            )
+            .await
+            .with_context(|| format!("walk of {}", discontinuity.name()))?
+            .into_iter()
+            .map(|(_, bind)| {
+                (
+                    bind.name().components()[discontinuity_len..].to_vec(),
+                    bind.value().clone(),
+                )
+            })
+            .collect();
+
+            if let Some(previous) = discontinuity_times.get(collect_key) {
+                for (index, value) in &current {
+                    if previous
+                        .get(index)
+                        .is_some_and(|previous| previous != value)
+                    {
+                        debug!(
+                            "collect_device({}): counter discontinuity of row {:?}, skipping it this cycle",
+                            device_name, index
+                        );
+                        discontinued.insert(index.clone());
+                    }
+                }
+            }
+            discontinuity_times.insert(collect_key, current);
+        }
+
+        // indices matched by each selector
+        let mut selected: Vec<HashSet<Vec<u64>>> = vec![];
+        for selector in &collect_instance.selectors {
+            let selector_len = selector.column.name().components().len();
+            let matching: HashSet<Vec<u64>> = walk_column(
+                &mut walked,
+                &selector.column,
+                start_index,
+                collect_instance.max_rows,
+                &device.snmp.walk_mode,
+                &mut backoff.repetitions,
+                client,
+                session,
+            )
+            .await
+            .with_context(|| format!("walk of {}", selector.column.name()))?
+            .into_iter()
+            .filter(|(_, bind)| {
+                snmp::var_numeric_value_to_string(bind.value())
+                    .is_some_and(|value| selector.regex.is_match(&value))
+            })
+            .map(|(_, bind)| bind.name().components()[selector_len..].to_vec())
+            .collect();
+            trace!(
+                "collect_device({}): select_by {} matched {} rows",
                 device_name,
-                collect_key.name().components()
+                selector.regex,
+                matching.len()
             );
+            selected.push(matching);
+        }
 
-            for collect_value in collect_values {
-                debug!(
-                    "collect_device({}) fetch_table({:?}) start",
+        for (collect_value, value_plan) in &collect_instance.values {
+            let exclude_indices = &value_plan.exclude_indices;
+            // the rows of a joined table do not follow the index of the instance column
+            let value_start_index = if value_plan.join_by.is_some() {
+                &[][..]
+            } else {
+                start_index
+            };
+            debug!(
+                "collect_device({}) fetch_table({:?}) start",
+                device_name,
+                collect_value.name().components()
+            );
+            // the first column which returns any value is used, fallbacks are only
+            // walked if the ones before it are empty
+            let mut value_column = collect_value;
+            let mut table_values = vec![];
+            for column in std::iter::once(collect_value).chain(&value_plan.fallbacks) {
+                value_column = column;
+                table_values = walk_column(
+                    &mut walked,
+                    column,
+                    value_start_index,
+                    collect_instance.max_rows,
+                    &device.snmp.walk_mode,
+                    &mut backoff.repetitions,
+                    client,
+                    session,
+                )
+                .await
+                .with_context(|| format!("walk of {}", column.name()))?;
+                if !table_values.is_empty() {
+                    break;
+                }
+                trace!(
+                    "collect_device({}): {} returned no values",
                     device_name,
-                    collect_value.name().components()
+                    column.name()
                 );
-                let table_values =
-                    snmp_fetch_table(vec![collect_value.clone()], &mut client, &mut session)
-                        .await?;
+            }
+            let collect_value_len = value_column.name().components().len();
 
-                debug!(
-                    "collect_device({}) fetch_table({:?}) done",
-                    device_name,
-                    collect_value.name().components()
-                );
+            // rows of the instance column which are not collected for this value
+            let is_filtered = |name_index: &[u64]| {
+                if exclude_indices.contains(name_index) {
+                    trace!(
+                        "collect_device({}): index {:?} is excluded, skipping",
+                        device_name,
+                        name_index
+                    );
+                    return true;
+                }
+                match &value_plan.selectors {
+                    Some(selectors) => !selectors
+                        .iter()
+                        .any(|selector| selected[*selector].contains(name_index)),
+                    None => false,
+                }
+            };
+
+            debug!(
+                "collect_device({}) fetch_table({:?}) done",
+                device_name,
+                collect_value.name().components()
+            );
+            if value_plan.on_empty == OnEmpty::Metric {
+                metric_channel
+                    .send(device_self_metric(
+                        device_name,
+                        &format!("table_empty.{}", value_plan.name),
+                        u8::from(table_values.is_empty()),
+                    ))
+                    .unwrap();
+            }
 
-                // zip key value tuples from the name and value tables
-                for (_, name_bind) in &table_names {
-                    let name_string: String = match name_bind.value() {
-                        VarValue::String(s) => String::from_utf8_lossy(s).to_string(),
-                        _ => {
-                            error!("collect_device({}): table_name oid does not return STRING, unsupported type", device_name);
-                            break;
+            // each value row is keyed by the instance row the join column points to, an
+            // empty column is handled by on_empty like any other
+            if let Some(join_by) = value_plan
+                .join_by
+                .as_ref()
+                .filter(|_| !table_values.is_empty() || value_plan.on_empty != OnEmpty::Zero)
+            {
+                let join_by_len = join_by.name().components().len();
+                let instance_rows: HashMap<Vec<u64>, Vec<u64>> = walk_column(
+                    &mut walked,
+                    join_by,
+                    &[],
+                    collect_instance.max_rows,
+                    &device.snmp.walk_mode,
+                    &mut backoff.repetitions,
+                    client,
+                    session,
+                )
+                .await
+                .with_context(|| format!("walk of {}", join_by.name()))?
+                .into_iter()
+                .filter_map(|(_, bind)| {
+                    let row = bind.name().components().get(join_by_len..)?.to_vec();
+                    let instance_index = u64::try_from(snmp::var_bind_to_i128(bind)?).ok()?;
+                    Some((row, vec![instance_index]))
+                })
+                .collect();
+                let name_binds: HashMap<&[u64], &VarBind> = table_names
+                    .iter()
+                    .map(|(_, name_bind)| {
+                        (&name_bind.name().components()[collect_key_len..], name_bind)
+                    })
+                    .collect();
+
+                for (table_instant, table_bind) in &table_values {
+                    let row = &table_bind.name().components()[collect_value_len..];
+                    let name_bind = match instance_rows
+                        .get(row)
+                        .and_then(|instance_index| name_binds.get(instance_index.as_slice()))
+                    {
+                        Some(name_bind) => *name_bind,
+                        None => {
+                            trace!(
+                                "collect_device({}): {} of row {:?} does not point to a row of {}, skipping",
+                                device_name,
+                                join_by.name(),
+                                row,
+                                collect_key.name()
+                            );
+                            continue;
                         }
                     };
+                    let name_index = &name_bind.name().components()[collect_key_len..];
+                    if is_filtered(name_index)
+                        || is_discontinued(&discontinued, name_index, table_bind.value())
+                    {
+                        continue;
+                    }
 
-                    let name_index = name_bind.name().components().last().unwrap();
-                    let table_value = table_values.iter().find(|(_, val_bind)| {
-                        val_bind.name().components().last().unwrap() == name_index
-                    });
+                    // emitted at the index of the instance row, like a value of its table
+                    let mut oid = collect_value.name().components().to_vec();
+                    oid.extend_from_slice(name_index);
+                    let mut joined_bind = vec_to_var_binds(oid);
+                    joined_bind.set_value(table_bind.value().clone());
+                    channel
+                        .send_async(SnmpStatResult {
+                            device: device_name.to_string(),
+                            endpoint: target.name.clone(),
+                            timestamp: *table_instant,
+                            key: name_bind.clone(),
+                            value: joined_bind,
+                            index: name_index.to_vec(),
+                        })
+                        .await
+                        .unwrap();
+                    *var_binds_collected += 1;
+                }
+                continue;
+            }
 
-                    if let Some(table_value) = table_value {
-                        // we found a value_bind for the corresponding name_bind
-                        let (table_instant, table_bind) = table_value.clone();
+            // zip key value tuples from the name and value tables
+            for (_, name_bind) in &table_names {
+                // match rows by their full index suffix, composite indices span several
+                // sub-identifiers
+                let name_index = &name_bind.name().components()[collect_key_len..];
 
-                        channel
-                            .send_async(SnmpStatResult {
-                                device: device_name.to_string(),
-                                timestamp: table_instant,
-                                key: name_bind.clone(),
-                                value: table_bind,
-                            })
-                            .await
-                            .unwrap();
-                    } else {
-                        // we did not, try requesting it through a simple get_request
-                        trace!("collect_device({}): hpe_comware_workaround: {} = {} not found in value table, triggering workaround", device_name, name_bind.name(), name_string);
-                        hpe_comware_workaround_var_binds.push(name_bind.clone());
+                // only used for logging, instance columns which are not a STRING are keyed
+                // by their index
+                let name_string: String = match name_bind.value() {
+                    VarValue::String(s) => String::from_utf8_lossy(s).to_string(),
+                    _ => mib_index::format_index(name_index, None),
+                };
+                if is_filtered(name_index) {
+                    continue;
+                }
+
+                let table_value = table_values.iter().find(|(_, val_bind)| {
+                    val_bind.name().components().get(collect_value_len..) == Some(name_index)
+                });
+
+                if let Some(table_value) = table_value {
+                    // we found a value_bind for the corresponding name_bind
+                    let (table_instant, table_bind) = table_value.clone();
+                    if is_discontinued(&discontinued, name_index, table_bind.value()) {
+                        continue;
                     }
+                    let value = as_column(table_bind, collect_value, name_index);
+                    if !first_emission(&mut emitted, device_name, &value) {
+                        continue;
+                    }
+
+                    channel
+                        .send_async(SnmpStatResult {
+                            device: device_name.to_string(),
+                            endpoint: target.name.clone(),
+                            timestamp: table_instant,
+                            key: name_bind.clone(),
+                            value,
+                            index: name_index.to_vec(),
+                        })
+                        .await
+                        .unwrap();
+                    *var_binds_collected += 1;
+                } else if table_values.is_empty() && value_plan.on_empty == OnEmpty::Zero {
+                    // the column has no rows at all, not just this one
+                    let mut oid = collect_value.name().components().to_vec();
+                    oid.extend_from_slice(name_index);
+                    let mut zero_bind = vec_to_var_binds(oid);
+                    zero_bind.set_value(VarValue::Int(0));
+                    if !first_emission(&mut emitted, device_name, &zero_bind) {
+                        continue;
+                    }
+                    channel
+                        .send_async(SnmpStatResult {
+                            device: device_name.to_string(),
+                            endpoint: target.name.clone(),
+                            timestamp: snmp::received_at(),
+                            key: name_bind.clone(),
+                            value: zero_bind,
+                            index: name_index.to_vec(),
+                        })
+                        .await
+                        .unwrap();
+                    *var_binds_collected += 1;
+                } else {
+                    // we did not, try requesting it through a simple get_request
+                    trace!("collect_device({}): hpe_comware_workaround: {} = {} not found in value table, triggering workaround", device_name, name_bind.name(), name_string);
+                    hpe_comware_workaround_var_binds.push(name_bind.clone());
                 }
+            }
 
-                // HPE comware workaround -> request missing oids with a GetRequest
-                if !hpe_comware_workaround_var_binds.is_empty() {
-                    trace!("collect_device({}): hpe_comware_workaround: {} oids not found, requesting via snmpget", device_name, hpe_comware_workaround_var_binds.len());
+            // HPE comware workaround -> request missing oids with a GetRequest
+            if !hpe_comware_workaround_var_binds.is_empty() {
+                trace!("collect_device({}): hpe_comware_workaround: {} oids not found, requesting via snmpget", device_name, hpe_comware_workaround_var_binds.len());
 
-                    // build request var_binds
-                    let mut hpe_comware_workaround_value_var_binds: Vec<VarBind> = vec![];
+                // build request var_binds, remember which name_bind each requested oid
+                // belongs to so the response can be matched by oid instead of by position
+                let mut hpe_comware_workaround_value_var_binds: Vec<VarBind> = vec![];
+                let mut hpe_comware_workaround_requested: HashMap<Vec<u64>, &VarBind> =
+                    HashMap::new();
+                // this may fail if the requested table is empty, this case is logged
+                if table_values.is_empty() {
+                    debug!("collect_device({}): hpe_comware_workaround: table_values is empty, possibly an empty table was received from the device", device_name);
+                } else {
                     for name_bind in &hpe_comware_workaround_var_binds {
-                        // get base oid from another (not missing) table value
-                        // this may fail if the requested table is empty, this case is logged
-                        if let Some(first_table_value) = table_values.first() {
-                            let mut request_oid = first_table_value.1.name().components().to_vec();
-
-                            // replace last element in oid with the one that's been indicated as
-                            // missing in hpe_comware_workaround_var_binds
-                            *request_oid.last_mut().unwrap() =
-                                *name_bind.name().components().last().unwrap(); // oid of missing element
-
-                            hpe_comware_workaround_value_var_binds
-                                .push(vec_to_var_binds(request_oid));
-                        } else {
-                            debug!("collect_device({}): hpe_comware_workaround: table_value.first() is None, possibly an empty table was received from the device", device_name);
-                        }
+                        // value column oid followed by the index of the missing element
+                        let mut request_oid = value_column.name().components().to_vec();
+                        request_oid
+                            .extend_from_slice(&name_bind.name().components()[collect_key_len..]);
+
+                        hpe_comware_workaround_requested.insert(request_oid.clone(), name_bind);
+                        hpe_comware_workaround_value_var_binds.push(vec_to_var_binds(request_oid));
                     }
+                }
 
-                    // only execute if a non empty table with missing values has been detected
-                    // while building the request var_binds
-                    if !hpe_comware_workaround_value_var_binds.is_empty() {
-                        // request binds
-                        let hpe_comware_snmp_data = snmp_fetch_var_binds(
-                            hpe_comware_workaround_value_var_binds,
-                            &mut client,
-                            &mut session,
-                        )
-                        .await?;
-                        for (name_bind, (table_instant, table_bind)) in
-                            hpe_comware_workaround_var_binds
-                                .iter()
-                                .zip(hpe_comware_snmp_data.iter())
+                // only execute if a non empty table with missing values has been detected
+                // while building the request var_binds
+                if !hpe_comware_workaround_value_var_binds.is_empty() {
+                    // request binds
+                    let hpe_comware_snmp_data = snmp_fetch_var_binds(
+                        hpe_comware_workaround_value_var_binds,
+                        client,
+                        session,
+                    )
+                    .await
+                    .context("get request")?;
+                    let received = hpe_comware_snmp_data.values.into_iter().chain(
+                        hpe_comware_snmp_data
+                            .missing
+                            .into_iter()
+                            .map(|bind| (snmp::received_at(), bind)),
+                    );
+                    for (table_instant, mut table_bind) in received {
+                        // a partial or reordered response must not shift values onto the
+                        // wrong index, so match each returned oid to the one we requested
+                        let name_bind = match hpe_comware_workaround_requested
+                            .remove(table_bind.name().components())
                         {
-                            let mut table_bind = table_bind.clone();
-                            if table_bind.value() == &VarValue::NoSuchInstance {
-                                trace!("collect_device({}): hpe_comware_workaround: {} = {} ->  NoSuchInstance for value, assuming 0_64", device_name, name_bind.name(), msnmp::format_var_bind::format_var_value(name_bind.value()));
-                                table_bind.set_value(VarValue::BigCounter(0));
+                            Some(name_bind) => name_bind,
+                            None => {
+                                debug!("collect_device({}): hpe_comware_workaround: received unrequested oid {}, ignoring", device_name, table_bind.name());
+                                continue;
+                            }
+                        };
+
+                        if table_bind.value() == &VarValue::NoSuchInstance {
+                            trace!("collect_device({}): hpe_comware_workaround: {} = {} ->  NoSuchInstance for value, assuming 0_64", device_name, name_bind.name(), msnmp::format_var_bind::format_var_value(name_bind.value()));
+                            table_bind.set_value(VarValue::BigCounter(0));
+                        } else if snmp::is_exception(table_bind.value()) {
+                            // the device does not have the column, this does not change
+                            // between cycles
+                            if missing_columns.insert(value_column.clone()) {
+                                info!(
+                                    "collect_device({}): {} = {}, not logging this again",
+                                    device_name,
+                                    table_bind.name(),
+                                    msnmp::format_var_bind::format_var_value(table_bind.value())
+                                );
                             }
-                            channel
-                                .send_async(SnmpStatResult {
-                                    device: device_name.to_string(),
-                                    timestamp: *table_instant,
-                                    key: name_bind.clone(),
-                                    value: table_bind.clone(),
-                                })
-                                .await
-                                .unwrap();
+                            continue;
+                        }
+                        let name_index = &name_bind.name().components()[collect_key_len..];
+                        if is_discontinued(&discontinued, name_index, table_bind.value()) {
+                            continue;
                         }
+                        let value = as_column(table_bind, collect_value, name_index);
+                        if !first_emission(&mut emitted, device_name, &value) {
+                            continue;
+                        }
+                        channel
+                            .send_async(SnmpStatResult {
+                                device: device_name.to_string(),
+                                endpoint: target.name.clone(),
+                                timestamp: table_instant,
+                                key: name_bind.clone(),
+                                value,
+                                index: name_bind.name().components()[collect_key_len..].to_vec(),
+                            })
+                            .await
+                            .unwrap();
+                        *var_binds_collected += 1;
                     }
 
-                    // clear, will be filled with new missing var binds in the next iteration
-                    hpe_comware_workaround_var_binds.clear();
+                    // whatever is left in the map did not get an answer from the device
+                    if !hpe_comware_workaround_requested.is_empty() {
+                        let mut missing_indices: Vec<&[u64]> = hpe_comware_workaround_requested
+                            .keys()
+                            .map(|oid| &oid[collect_value_len..])
+                            .collect();
+                        missing_indices.sort_unstable();
+                        warn!("collect_device({}): hpe_comware_workaround: no response for {} requested indices: {:?}", device_name, missing_indices.len(), missing_indices);
+                    }
                 }
+
+                // clear, will be filled with new missing var binds in the next iteration
+                hpe_comware_workaround_var_binds.clear();
             }
         }
-        let snmp_duration = start_time.elapsed();
-        if snmp_duration < interval {
-            let wait = interval - snmp_duration;
-            debug!(
-                "collect_device({}): snmp took {:?}, waiting for {:?} until next interval",
-                device_name, snmp_duration, wait
-            );
+    }
+    Ok(())
+}
 
-            tokio::time::sleep(wait).await;
-        } else {
-            warn!(
-                "collect_device({}): snmp took {:?}, which is longer than set interval {:?}",
-                device_name, snmp_duration, interval
-            );
-        }
+/// Whether `value` is sent for the first time for its instance column in this cycle, a row the
+/// walk returned is not sent again by the comware workaround or a later value column
+fn first_emission(emitted: &mut HashSet<Vec<u64>>, device_name: &str, value: &VarBind) -> bool {
+    if emitted.insert(value.name().components().to_vec()) {
+        return true;
     }
+    debug!(
+        "collect_device({}): {} was already sent in this cycle, suppressing the duplicate",
+        device_name,
+        value.name()
+    );
+    false
 }
 
-fn calc_initial_backoff(interval: Duration) -> f64 {
-    interval.as_secs_f64() / 3.0
+/// Whether `value` of the row at `index` is skipped in this cycle because the counters of the
+/// row have been reset. Only counters are skipped, their difference to the last cycle is not a
+/// rate. Gauges, e.g. ifHighSpeed or a Gauge32 queue length, are current values and sent.
+fn is_discontinued(discontinued: &HashSet<Vec<u64>>, index: &[u64], value: &VarValue) -> bool {
+    discontinued.contains(index)
+        && snmp::ValueType::of(value).is_some_and(|value_type| value_type.is_counter())
+}
+
+/// The device answered, the backoff starts over and an open circuit is closed
+fn data_received(
+    device_name: &str,
+    backoff: &mut Backoff,
+    metric_channel: &Sender<CarbonMetricValue>,
+) {
+    if backoff.circuit_open {
+        info!(
+            "collect_device({}): probe succeeded, circuit closed",
+            device_name
+        );
+        metric_channel
+            .send(device_self_metric(device_name, "circuit_open", 0))
+            .unwrap();
+    }
+    backoff.reset();
+}
+
+/// The scalars of every `table: false` collector of `device`
+fn plan_scalars(
+    device: &DeviceEntry,
+    config: &Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+) -> Vec<ScalarPlan> {
+    device
+        .collect
+        .iter()
+        .map(|collect| config.data.get(collect).unwrap())
+        .filter(|data_entry| !data_entry.table)
+        .map(|data_entry| ScalarPlan {
+            instance: oid_var_bind_map.get(&data_entry.instance).unwrap().clone(),
+            values: data_entry
+                .values
+                .iter()
+                .map(|value| {
+                    value
+                        .oids()
+                        .iter()
+                        .map(|oid| oid_var_bind_map.get(*oid).unwrap().clone())
+                        .collect()
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Instance `.0` of a scalar object
+fn scalar_oid(object: &VarBind) -> Vec<u64> {
+    [object.name().components(), &[0]].concat()
+}
+
+/// The scalars of `main.device_metadata`, named by their field
+fn plan_metadata(
+    config: &Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+) -> Vec<(String, VarBind)> {
+    config
+        .main
+        .device_metadata
+        .iter()
+        .flat_map(|metadata| &metadata.oids)
+        .map(|oid| {
+            (
+                snmp::field_name(oid),
+                oid_var_bind_map.get(oid).unwrap().clone(),
+            )
+        })
+        .collect()
+}
+
+/// Fetch the metadata of the device for the tags and labels of the outputs, and send a hash of
+/// each value as `collector.devices.<device>.metadata.<field>` for outputs without them. The hash
+/// changes with the value and is the same for the same value on other devices.
+async fn fetch_metadata<D, P, S>(
+    device_name: &str,
+    metadata: &[(String, VarBind)],
+    metric_channel: &Sender<CarbonMetricValue>,
+    client: &mut Client,
+    session: &mut Session<'_, D, P, S>,
+) -> Result<(), Error>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    let requested = metadata
+        .iter()
+        .map(|(_, object)| vec_to_var_binds(scalar_oid(object)))
+        .collect();
+    let response = snmp_fetch_var_binds(requested, client, session).await?;
+
+    let mut fetched = vec![];
+    for (name, object) in metadata {
+        let oid = scalar_oid(object);
+        let value = response
+            .values
+            .iter()
+            .find(|(_, bind)| bind.name().components() == oid.as_slice())
+            .and_then(|(_, bind)| snmp::var_numeric_value_to_string(bind.value()))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+        let value = match value {
+            Some(value) => value,
+            None => {
+                debug!("collect_device({}): no metadata {}", device_name, name);
+                continue;
+            }
+        };
+        // free text is kept out of the key, 32 bits are exact as the float of a carbon value
+        metric_channel
+            .send(device_self_metric(
+                device_name,
+                &format!("metadata.{}", name),
+                metadata_hash(&value),
+            ))
+            .unwrap();
+        fetched.push((name.clone(), value));
+    }
+    debug!("collect_device({}): metadata {:?}", device_name, fetched);
+    set_device_metadata(device_name, fetched);
+    Ok(())
+}
+
+fn metadata_hash(value: &str) -> u64 {
+    stable_hash(value.as_bytes()) & 0xffff_ffff
+}
+
+/// Fetch the scalars of every collector with a single get, the response is matched by oid.
+/// Returns the number of values sent.
+async fn collect_scalars<D, P, S>(
+    device_name: &str,
+    endpoint: &Option<String>,
+    scalars: &[ScalarPlan],
+    channel: &Sender<SnmpStatResult>,
+    missing_columns: &mut HashSet<VarBind>,
+    client: &mut Client,
+    session: &mut Session<'_, D, P, S>,
+) -> Result<u64, Error>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    let mut requested: Vec<VarBind> = vec![];
+    for scalar in scalars {
+        for object in std::iter::once(&scalar.instance).chain(scalar.values.iter().flatten()) {
+            let bind = vec_to_var_binds(scalar_oid(object));
+            if !requested.contains(&bind) {
+                requested.push(bind);
+            }
+        }
+    }
+    trace!(
+        "collect_device({}): requesting {} scalars",
+        device_name,
+        requested.len()
+    );
+    let response = snmp_fetch_var_binds(requested, client, session).await?;
+
+    // the device does not have the object, this does not change between cycles
+    for missing in &response.missing {
+        if missing_columns.insert(vec_to_var_binds(missing.name().components().to_vec())) {
+            info!(
+                "collect_device({}): {} = {}, not logging this again",
+                device_name,
+                missing.name(),
+                msnmp::format_var_bind::format_var_value(missing.value())
+            );
+        }
+    }
+    let received: HashMap<Vec<u64>, &(SystemTime, VarBind)> = response
+        .values
+        .iter()
+        .map(|value| (value.1.name().components().to_vec(), value))
+        .collect();
+
+    let mut collected = 0;
+    for scalar in scalars {
+        let name_bind = match received.get(&scalar_oid(&scalar.instance)) {
+            Some((_, name_bind)) => name_bind,
+            None => continue,
+        };
+        for objects in &scalar.values {
+            let value = objects
+                .iter()
+                .find_map(|object| received.get(&scalar_oid(object)));
+            if let Some((timestamp, value_bind)) = value {
+                channel
+                    .send_async(SnmpStatResult {
+                        device: device_name.to_string(),
+                        endpoint: endpoint.clone(),
+                        timestamp: *timestamp,
+                        key: name_bind.clone(),
+                        value: as_column(value_bind.clone(), &objects[0], &[0]),
+                        index: vec![0],
+                    })
+                    .await
+                    .unwrap();
+                collected += 1;
+            }
+        }
+    }
+    Ok(collected)
+}
+
+/// The columns walked for `device` by instance column, collectors sharing an instance column are
+/// condensed into a single walk of each column
+fn plan_collection(
+    device: &DeviceEntry,
+    config: &Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+) -> Result<HashMap<VarBind, CollectInstance>, Error> {
+    // condense mibs to connect, each value oid carries the indices excluded from it
+    let mut collect_map: HashMap<VarBind, CollectInstance> = HashMap::new();
+    for collect in &device.collect {
+        let config_data_entry = config.data.get(collect).unwrap();
+        if !config_data_entry.table {
+            continue;
+        }
+        let instance_oid = oid_var_bind_map.get(&config_data_entry.instance).unwrap();
+        let exclude_indices: HashSet<Vec<u64>> = config_data_entry
+            .exclude_indices
+            .iter()
+            .map(TableIndex::sub_identifiers)
+            .collect();
+
+        let entry = collect_map
+            .entry(instance_oid.clone())
+            .or_insert_with(|| CollectInstance {
+                start_index: config_data_entry.start_index.clone(),
+                max_rows: 0,
+                discontinuity: None,
+                selectors: vec![],
+                values: HashMap::new(),
+            });
+        let selector = match &config_data_entry.select_by {
+            Some(select_by) => {
+                entry.selectors.push(Selector {
+                    column: oid_var_bind_map.get(&select_by.column).unwrap().clone(),
+                    regex: Regex::new(&select_by.regex)?,
+                });
+                Some(entry.selectors.len() - 1)
+            }
+            None => None,
+        };
+        if let Some(discontinuity) = &config_data_entry.discontinuity {
+            entry.discontinuity = Some(oid_var_bind_map.get(discontinuity).unwrap().clone());
+        }
+        // a table shared by several collectors is walked from the earliest start
+        if config_data_entry.start_index < entry.start_index {
+            entry.start_index = config_data_entry.start_index.clone();
+        }
+        // and as far as the collector allowing the most rows
+        let max_rows = config_data_entry
+            .max_rows
+            .or(config.main.max_rows)
+            .unwrap_or(DEFAULT_MAX_ROWS);
+        entry.max_rows = entry.max_rows.max(max_rows);
+
+        let join_by = config_data_entry
+            .join_by
+            .as_ref()
+            .map(|join_by| oid_var_bind_map.get(join_by).unwrap().clone());
+
+        for value in &config_data_entry.values {
+            let fallbacks: Vec<VarBind> = value.oids()[1..]
+                .iter()
+                .map(|oid| oid_var_bind_map.get(*oid).unwrap().clone())
+                .collect();
+            match entry
+                .values
+                .entry(oid_var_bind_map.get(value.name()).unwrap().clone())
+            {
+                // a value shared by several collectors is only excluded where all of them agree,
+                // and selected where any of them selects it
+                Entry::Occupied(mut occupied) => {
+                    let value_plan = occupied.get_mut();
+                    value_plan.exclude_indices = value_plan
+                        .exclude_indices
+                        .intersection(&exclude_indices)
+                        .cloned()
+                        .collect();
+                    value_plan.selectors = match (value_plan.selectors.take(), selector) {
+                        (Some(mut selectors), Some(selector)) => {
+                            selectors.push(selector);
+                            Some(selectors)
+                        }
+                        _ => None,
+                    };
+                    for fallback in fallbacks {
+                        if !value_plan.fallbacks.contains(&fallback) {
+                            value_plan.fallbacks.push(fallback);
+                        }
+                    }
+                    if value_plan.on_empty == OnEmpty::Ignore {
+                        value_plan.on_empty = config_data_entry.on_empty;
+                    }
+                    if value_plan.join_by != join_by {
+                        bail!(
+                            "{} is collected for instance {} with differing join_by by several collectors",
+                            value.name(),
+                            config_data_entry.instance
+                        );
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(ValuePlan {
+                        exclude_indices: exclude_indices.clone(),
+                        selectors: selector.map(|selector| vec![selector]),
+                        fallbacks,
+                        on_empty: config_data_entry.on_empty,
+                        name: value.name().split("::").nth(1).unwrap().to_string(),
+                        join_by: join_by.clone(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(collect_map)
+}
+
+/// Columns walked against an instance column, or the scalars fetched with an instance scalar,
+/// for `ShowPlan`
+#[derive(Debug)]
+pub struct PlannedInstance {
+    pub instance: VarBind,
+    pub table: bool,
+    pub start_index: Vec<u64>,
+    pub max_rows: usize,
+    /// discontinuity and select_by columns, walked along with the instance column
+    pub helpers: Vec<VarBind>,
+    pub values: Vec<PlannedValue>,
+}
+
+#[derive(Debug)]
+pub struct PlannedValue {
+    pub column: VarBind,
+    pub fallbacks: Vec<VarBind>,
+    pub join_by: Option<VarBind>,
+}
+
+/// The plan `collect_device` follows in every cycle of a device, ordered by OID
+pub fn plan_instances(
+    device_name: &str,
+    config: &Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+) -> Result<Vec<PlannedInstance>, Error> {
+    let device = config.devices.get(device_name).unwrap();
+    let mut planned: Vec<PlannedInstance> = plan_collection(device, config, oid_var_bind_map)?
+        .into_iter()
+        .map(|(instance, collect_instance)| {
+            let mut values: Vec<PlannedValue> = collect_instance
+                .values
+                .into_iter()
+                .map(|(column, value_plan)| PlannedValue {
+                    column,
+                    fallbacks: value_plan.fallbacks,
+                    join_by: value_plan.join_by,
+                })
+                .collect();
+            values.sort_by(|a, b| {
+                a.column
+                    .name()
+                    .components()
+                    .cmp(b.column.name().components())
+            });
+            PlannedInstance {
+                instance,
+                table: true,
+                start_index: collect_instance.start_index,
+                max_rows: collect_instance.max_rows,
+                helpers: collect_instance
+                    .discontinuity
+                    .into_iter()
+                    .chain(
+                        collect_instance
+                            .selectors
+                            .into_iter()
+                            .map(|selector| selector.column),
+                    )
+                    .collect(),
+                values,
+            }
+        })
+        .collect();
+    planned.sort_by(|a, b| {
+        a.instance
+            .name()
+            .components()
+            .cmp(b.instance.name().components())
+    });
+
+    // scalars are fetched in the order of the collectors
+    for scalar_plan in plan_scalars(device, config, oid_var_bind_map) {
+        planned.push(PlannedInstance {
+            instance: vec_to_var_binds(scalar_oid(&scalar_plan.instance)),
+            table: false,
+            start_index: vec![],
+            max_rows: 1,
+            helpers: vec![],
+            values: scalar_plan
+                .values
+                .into_iter()
+                .map(|oids| PlannedValue {
+                    column: vec_to_var_binds(scalar_oid(&oids[0])),
+                    fallbacks: oids[1..]
+                        .iter()
+                        .map(|oid| vec_to_var_binds(scalar_oid(oid)))
+                        .collect(),
+                    join_by: None,
+                })
+                .collect(),
+        });
+    }
+    Ok(planned)
+}
+
+/// Requests a device's cycle takes without touching the network, for `Benchmark`
+#[derive(Debug)]
+pub struct CyclePlan {
+    pub instances: usize,
+    /// distinct columns walked per cycle
+    pub walks: usize,
+    /// fallback columns walked in addition if every value column is empty
+    pub fallback_walks: usize,
+    /// a single get fetches the scalars of every collector
+    pub scalar_gets: usize,
+    pub repetitions: u32,
+}
+
+impl CyclePlan {
+    /// Requests per cycle if every table has `rows` rows, a walk ends with the request going
+    /// past the end of the column
+    pub fn estimated_requests(&self, rows: u64, walk_mode: &WalkMode) -> u64 {
+        let per_walk = match walk_mode {
+            WalkMode::Bulk => (rows + 1).div_ceil(u64::from(self.repetitions)),
+            WalkMode::GetNext => rows + 1,
+        };
+        self.walks as u64 * per_walk + self.scalar_gets as u64
+    }
+}
+
+pub fn plan_cycle(
+    device_name: &str,
+    config: &Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+) -> Result<CyclePlan, Error> {
+    let device = config.devices.get(device_name).unwrap();
+    let collect_map = plan_collection(device, config, oid_var_bind_map)?;
+
+    // walks are cached per column and start index within a cycle
+    let mut walks: HashSet<(&VarBind, &[u64])> = HashSet::new();
+    let mut fallback_walks: HashSet<(&VarBind, &[u64])> = HashSet::new();
+    for (instance_oid, collect_instance) in &collect_map {
+        let start_index = collect_instance.start_index.as_slice();
+        walks.insert((instance_oid, start_index));
+        for column in collect_instance.discontinuity.iter().chain(
+            collect_instance
+                .selectors
+                .iter()
+                .map(|selector| &selector.column),
+        ) {
+            walks.insert((column, start_index));
+        }
+        for (value_column, value_plan) in &collect_instance.values {
+            // joined tables are walked from their start
+            let start_index = match &value_plan.join_by {
+                Some(join_by) => {
+                    walks.insert((join_by, &[][..]));
+                    &[][..]
+                }
+                None => start_index,
+            };
+            walks.insert((value_column, start_index));
+            for fallback in &value_plan.fallbacks {
+                fallback_walks.insert((fallback, start_index));
+            }
+        }
+    }
+
+    Ok(CyclePlan {
+        instances: collect_map.len(),
+        fallback_walks: fallback_walks.difference(&walks).count(),
+        walks: walks.len(),
+        scalar_gets: usize::from(!plan_scalars(device, config, oid_var_bind_map).is_empty()),
+        repetitions: BulkRepetitions::new(device.snmp.max_pdu_size).initial(),
+    })
+}
+
+/// Rows of the columns walked in a cycle, by column and start index
+type WalkedColumns = HashMap<(VarBind, Vec<u64>), Vec<(SystemTime, VarBind)>>;
+
+/// Walk `column`, or return the result of the walk earlier in the cycle
+#[allow(clippy::too_many_arguments)]
+async fn walk_column<D, P, S>(
+    walked: &mut WalkedColumns,
+    column: &VarBind,
+    start_index: &[u64],
+    max_rows: usize,
+    walk_mode: &WalkMode,
+    repetitions: &mut BulkRepetitions,
+    client: &mut Client,
+    session: &mut Session<'_, D, P, S>,
+) -> Result<Vec<(SystemTime, VarBind)>, Error>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    let key = (column.clone(), start_index.to_vec());
+    if let Some(result) = walked.get(&key) {
+        trace!(
+            "collect_device: {} has been walked in this cycle already",
+            column.name()
+        );
+        return Ok(result.clone());
+    }
+
+    let result = snmp_fetch_table(
+        vec![column.clone()],
+        start_index,
+        max_rows,
+        walk_mode,
+        repetitions,
+        client,
+        session,
+    )
+    .await?;
+    walked.insert(key, result.clone());
+    Ok(result)
+}
+
+/// `bind` renamed to the row `index` of `column`, so values of a fallback column are emitted as
+/// the configured value column
+fn as_column(bind: VarBind, column: &VarBind, index: &[u64]) -> VarBind {
+    if bind
+        .name()
+        .components()
+        .starts_with(column.name().components())
+    {
+        return bind;
+    }
+    let mut oid = column.name().components().to_vec();
+    oid.extend_from_slice(index);
+    let mut renamed = vec_to_var_binds(oid);
+    renamed.set_value(bind.value().clone());
+    renamed
+}
+
+/// The error followed by each of its causes, on a single log line
+fn format_error_chain(error: &Error) -> String {
+    error
+        .chain()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<String>>()
+        .join("; caused by: ")
+}
+
+/// `interval` shifted by a random offset of up to +-`jitter` * `interval`, the offset is
+/// uniformly distributed so the average stays at `interval`
+fn jittered_interval(interval: Duration, jitter: Option<f64>) -> Duration {
+    match jitter {
+        Some(jitter) if jitter > 0.0 => {
+            let max_offset = jitter.min(1.0) * interval.as_secs_f64();
+            let offset = rand::thread_rng().gen_range(-max_offset..=max_offset);
+            Duration::from_secs_f64((interval.as_secs_f64() + offset).max(0.0))
+        }
+        _ => interval,
+    }
+}
+
+/// Sleep for `duration`, or until a cycle is requested through the control socket
+async fn sleep_or_triggered(duration: Duration, trigger: &Receiver<()>, device_name: &str) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        Ok(()) = trigger.recv_async() => {
+            info!("collect_device({}): cycle requested, collecting now", device_name);
+        }
+    }
+}
+
+/// Time until the next multiple of `interval` since the epoch plus `offset`
+fn until_aligned(interval: Duration, offset: Duration) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let interval_nanos = interval.as_nanos().max(1);
+    let offset_nanos = offset.as_nanos() % interval_nanos;
+    let since_boundary = (now.as_nanos() + interval_nanos - offset_nanos) % interval_nanos;
+    Duration::from_nanos((interval_nanos - since_boundary) as u64)
+}
+
+/// `msnmp::SNMP_PORT_NUM` as the type of the ports in the configuration
+const SNMP_PORT: u16 = 161;
+
+/// Device address including the port, the default SNMP port is used if none is configured
+pub fn snmp_host(snmp: &DeviceSnmpSettings) -> String {
+    let (host, port) = split_host_port(&snmp.host);
+    join_host_port(host, port.unwrap_or(SNMP_PORT))
+}
+
+/// `host`, `host:port`, an IPv6 address, or one in brackets followed by a port like
+/// `[2001:db8::1]:1161`
+fn split_host_port(host: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        if let Some((address, rest)) = bracketed.split_once(']') {
+            return (
+                address,
+                rest.strip_prefix(':').and_then(|port| port.parse().ok()),
+            );
+        }
+    }
+    match host.split_once(':') {
+        // more than one colon is an IPv6 address without a port
+        Some((name, port)) if !port.contains(':') => (name, port.parse().ok()),
+        _ => (host, None),
+    }
+}
+
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Delay before the other address family is tried as well, as recommended by RFC 8305
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `address` to the addresses of `ip_version`, in the order of the resolver
+async fn resolve_address(address: &str, ip_version: IpVersion) -> Result<Vec<SocketAddr>, Error> {
+    let resolved: Vec<SocketAddr> = tokio::net::lookup_host(address)
+        .await
+        .with_context(|| format!("resolving {}", address))?
+        .collect();
+    let selected = select_addresses(resolved, ip_version);
+    if selected.is_empty() {
+        bail!("{} has no address for ip_version {:?}", address, ip_version);
+    }
+    Ok(selected)
+}
+
+/// The addresses of a family for `v4` and `v6`. With `auto` the first address of each family,
+/// the one the resolver returned first leading.
+fn select_addresses(resolved: Vec<SocketAddr>, ip_version: IpVersion) -> Vec<SocketAddr> {
+    match ip_version {
+        IpVersion::V4 => resolved.into_iter().filter(SocketAddr::is_ipv4).collect(),
+        IpVersion::V6 => resolved.into_iter().filter(SocketAddr::is_ipv6).collect(),
+        IpVersion::Auto => {
+            let mut selected: Vec<SocketAddr> = vec![];
+            for address in resolved {
+                if !selected
+                    .iter()
+                    .any(|other| other.is_ipv4() == address.is_ipv4())
+                {
+                    selected.push(address);
+                }
+            }
+            selected
+        }
+    }
+}
+
+/// Open a client and a session with the engine at `address`. With `v4` or `v6` its addresses of
+/// that family are tried in turn. With `auto` the other family is raced against the preferred
+/// one if the engine did not answer within `HAPPY_EYEBALLS_DELAY`, the first session wins.
+/// With `bind_address` only its family is tried.
+async fn connect<'a, 's, D, P, S>(
+    context: &str,
+    address: &str,
+    snmp: &'s DeviceSnmpSettings,
+    salt: P::Salt,
+) -> Result<(Client, Session<'s, D, P, S>), Error>
+where
+    D: 'a + 's + Digest,
+    P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
+    S: Step + Copy,
+{
+    let attempt = move |candidate: SocketAddr| async move {
+        let mut client =
+            Client::new(candidate, snmp.bind_address, Some(snmp.request_timeout.0)).await?;
+        let session = open_session(&mut client, snmp, salt).await?;
+        Ok::<_, Error>((client, session))
+    };
+    // only logged if there was a choice between the families
+    let chosen = |candidate: SocketAddr| {
+        info!("{}: using {} for {}", context, candidate, address);
+    };
+
+    let ip_version = match snmp.bind_address {
+        Some(IpAddr::V4(_)) => IpVersion::V4,
+        Some(IpAddr::V6(_)) => IpVersion::V6,
+        None => snmp.ip_version,
+    };
+    let candidates = resolve_address(address, ip_version).await?;
+    if ip_version != IpVersion::Auto || candidates.len() < 2 {
+        let mut last_error = None;
+        for candidate in candidates {
+            match attempt(candidate).await {
+                Ok(connected) => {
+                    debug!("{}: using {} for {}", context, candidate, address);
+                    return Ok(connected);
+                }
+                Err(error) => {
+                    debug!("{}: {} did not answer: {:#}", context, candidate, error);
+                    last_error = Some(error);
+                }
+            }
+        }
+        return Err(last_error.unwrap());
+    }
+
+    let (preferred, other) = (candidates[0], candidates[1]);
+    let first = attempt(preferred);
+    tokio::pin!(first);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(connected) => {
+                chosen(preferred);
+                return Ok(connected);
+            }
+            Err(error) => {
+                info!("{}: {} did not answer: {:#}, trying {}", context, preferred, error, other);
+                let connected = attempt(other).await?;
+                chosen(other);
+                return Ok(connected);
+            }
+        },
+        _ = tokio::time::sleep(HAPPY_EYEBALLS_DELAY) => {}
+    }
+
+    debug!(
+        "{}: {} did not answer within {:?}, racing {}",
+        context, preferred, HAPPY_EYEBALLS_DELAY, other
+    );
+    let second = attempt(other);
+    tokio::pin!(second);
+    let (winner, result, loser, remaining) = tokio::select! {
+        result = &mut first => (preferred, result, other, &mut second),
+        result = &mut second => (other, result, preferred, &mut first),
+    };
+    match result {
+        Ok(connected) => {
+            chosen(winner);
+            Ok(connected)
+        }
+        Err(error) => {
+            debug!("{}: {} did not answer: {:#}", context, winner, error);
+            let connected = remaining.await?;
+            chosen(loser);
+            Ok(connected)
+        }
+    }
+}
+
+/// An SNMP engine of a device, the device itself unless it has `snmp.endpoints`
+struct Target {
+    /// none for the device itself
+    name: Option<String>,
+    address: String,
+    context_name: Vec<u8>,
+}
+
+impl Target {
+    /// `what` followed by the endpoint, for error contexts
+    fn describe(&self, what: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{} of endpoint {}", what, name),
+            None => what.to_string(),
+        }
+    }
+}
+
+fn targets(snmp: &DeviceSnmpSettings) -> Vec<Target> {
+    if snmp.endpoints.is_empty() {
+        return vec![Target {
+            name: None,
+            address: snmp_host(snmp),
+            context_name: vec![],
+        }];
+    }
+
+    // the port of the device's host is kept unless the endpoint has its own
+    let (device_host, device_port) = split_host_port(&snmp.host);
+    let device_port = device_port.unwrap_or(SNMP_PORT);
+    snmp.endpoints
+        .iter()
+        .map(|endpoint| {
+            let host = endpoint.host.as_deref().unwrap_or(device_host);
+            let port = endpoint.port.unwrap_or(device_port);
+            Target {
+                name: Some(endpoint.name.clone()),
+                address: join_host_port(host, port),
+                context_name: endpoint
+                    .context
+                    .as_deref()
+                    .unwrap_or_default()
+                    .as_bytes()
+                    .to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// The session with an endpoint and what is kept about its tables across cycles
+struct EndpointSession<'s, 'p, D, P, S> {
+    target: Target,
+    client: Client,
+    session: Session<'s, D, P, S>,
+    /// last known value of the discontinuity column per instance oid and row index
+    discontinuity_times: HashMap<&'p VarBind, HashMap<Vec<u64>, VarValue>>,
+    /// value columns the engine answered a get with an exception for, logged only once
+    missing_columns: HashSet<VarBind>,
+}
+
+/// Discover the engine and set up authentication and privacy keys
+async fn open_session<'a, 's, D, P, S>(
+    client: &mut Client,
+    snmp: &'s DeviceSnmpSettings,
+    salt: P::Salt,
+) -> Result<Session<'s, D, P, S>, Error>
+where
+    D: 'a + 's + Digest,
+    P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
+    S: Step + Copy,
+{
+    let mut session: Session<D, P, S> = Session::new(client, snmp.secname.as_bytes()).await?;
+
+    let (auth_key, priv_key) = localized_keys::<D, P>(snmp, session.engine_id());
+    session.set_auth_key(auth_key);
+    session.set_priv_key_and_salt(priv_key, salt);
+
+    Ok(session)
+}
+
+/// Authentication and privacy keys of `snmp`, localized to the authoritative `engine_id`
+pub fn localized_keys<'a, 'k, D, P>(
+    snmp: &DeviceSnmpSettings,
+    engine_id: &[u8],
+) -> (AuthKey<'k, D>, P)
+where
+    D: 'a + 'k + Digest,
+    P: WithLocalizedKey<'a, D>,
+{
+    let localized_key = LocalizedKey::<D>::new(snmp.authpassword.as_bytes(), engine_id);
+    let auth_key = AuthKey::new(localized_key);
+
+    let localized_key = LocalizedKey::<D>::new(snmp.privpassword.as_bytes(), engine_id);
+    let priv_key = P::with_localized_key(localized_key);
+
+    (auth_key, priv_key)
+}
+
+/// Open a session and request sysUpTime.0 once, returns the formatted uptime
+pub async fn check_device(device_name: String, config: Arc<Config>) -> Result<String, Error> {
+    let device = config.devices.get(&device_name).unwrap();
+    let timeout = Duration::from_secs(device.snmp.request_timeout.0);
+
+    // guard against hangs the client timeout does not cover (e.g. engine discovery retries)
+    tokio::time::timeout(timeout * 3, async {
+        with_usm_types!(device, check_device_(&device_name, config.clone()))
+    })
+    .await?
+}
+
+async fn check_device_<'a, D, P, S>(
+    device_name: &str,
+    config: Arc<Config>,
+    salt: P::Salt,
+) -> Result<String, Error>
+where
+    D: 'a + Digest,
+    P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
+    S: Step + Copy,
+{
+    debug!("check_device({}): start", device_name);
+    let device = config.devices.get(device_name).unwrap();
+
+    let (mut client, mut session) = connect::<D, P, S>(
+        &format!("check_device({})", device_name),
+        &snmp_host(&device.snmp),
+        &device.snmp,
+        salt,
+    )
+    .await?;
+
+    let response = snmp_fetch_var_binds(
+        vec![vec_to_var_binds(SYS_UPTIME_OID.to_vec())],
+        &mut client,
+        &mut session,
+    )
+    .await?;
+
+    if let Some(missing) = response.missing.first() {
+        bail!(
+            "{} does not exist: {}",
+            missing.name(),
+            msnmp::format_var_bind::format_var_value(missing.value())
+        );
+    }
+    match response.values.first() {
+        Some((_, var_bind)) if var_bind.name().components() == SYS_UPTIME_OID => {
+            Ok(msnmp::format_var_bind::format_var_value(var_bind.value()).to_string())
+        }
+        Some((_, var_bind)) => bail!("unexpected response {}", var_bind.name()),
+        None => bail!("empty response"),
+    }
+}
+
+/// Walk the subtree of `oid` on a device with its configured credentials and walk mode, for
+/// `Walk`
+pub async fn walk_device(
+    device_name: String,
+    config: Arc<Config>,
+    oid: Vec<u64>,
+) -> Result<Vec<(SystemTime, VarBind)>, Error> {
+    let device = config.devices.get(&device_name).unwrap();
+    with_usm_types!(device, walk_device_(&device_name, config.clone(), oid))
+}
+
+async fn walk_device_<'a, D, P, S>(
+    device_name: &str,
+    config: Arc<Config>,
+    oid: Vec<u64>,
+    salt: P::Salt,
+) -> Result<Vec<(SystemTime, VarBind)>, Error>
+where
+    D: 'a + Digest,
+    P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
+    S: Step + Copy,
+{
+    debug!("walk_device({}): walking {:?}", device_name, oid);
+    let device = config.devices.get(device_name).unwrap();
+
+    let (mut client, mut session) = connect::<D, P, S>(
+        &format!("walk_device({})", device_name),
+        &snmp_host(&device.snmp),
+        &device.snmp,
+        salt,
+    )
+    .await?;
+
+    // walked on request, as far as the agent goes
+    snmp_fetch_table(
+        vec![vec_to_var_binds(oid)],
+        &[],
+        usize::MAX,
+        &device.snmp.walk_mode,
+        &mut BulkRepetitions::new(device.snmp.max_pdu_size),
+        &mut client,
+        &mut session,
+    )
+    .await
+}
+
+fn calc_initial_backoff(interval: Duration) -> f64 {
+    interval.as_secs_f64() / 3.0
+}
+
+/// Between half of `backoff` and all of it
+fn jittered_backoff(backoff: f64) -> f64 {
+    backoff * rand::thread_rng().gen_range(0.5..=1.0)
+}
+
+fn calc_auth_failure_backoff(interval: Duration) -> f64 {
+    (interval.as_secs_f64() * 30.0).max(600.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::fake_agent::{self, response_pdu, AgentKeys, Answer};
+    use crate::config::{self, SnmpAuthProtocol, SnmpPrivProtocol, Timeout};
+    use snmp_mp::{ObjectIdent, ScopedPdu};
+    use tokio::net::UdpSocket;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/example/simple-example.yaml");
+
+    const IF_NAME: &[u64] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 1];
+    const IF_HC_IN_OCTETS: &[u64] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 6];
+    const IF_HC_OUT_OCTETS: &[u64] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 10];
+    const IF_HIGH_SPEED: &[u64] = &[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 15];
+
+    fn column(oid: &[u64]) -> VarBind {
+        VarBind::new(ObjectIdent::from_slice(oid))
+    }
+
+    /// The reverse map of the IF-MIB columns used by the tests
+    fn oid_var_bind_map() -> HashMap<String, VarBind> {
+        [
+            ("IF-MIB::ifName", IF_NAME),
+            ("IF-MIB::ifHCInOctets", IF_HC_IN_OCTETS),
+            ("IF-MIB::ifHCOutOctets", IF_HC_OUT_OCTETS),
+            ("IF-MIB::ifHighSpeed", IF_HIGH_SPEED),
+        ]
+        .iter()
+        .map(|(name, oid)| (name.to_string(), column(oid)))
+        .collect()
+    }
+
+    /// The example configuration with `data` instead of its collectors, `device` of it collects
+    /// all of them
+    fn test_config(data: &str) -> (Config, DeviceEntry) {
+        let mut config = config::from_file(EXAMPLE, None).unwrap();
+        config.data = serde_yaml::from_str(data).unwrap();
+        let mut device = config.devices["example-device001"].clone();
+        device.collect = config.data.keys().cloned().collect();
+        device.collect.sort();
+        (config, device)
+    }
+
+    #[test]
+    fn collectors_sharing_an_instance_keep_their_values_apart() {
+        let (config, device) = test_config(
+            "if_in: {table: true, instance: IF-MIB::ifName, \
+             values: [IF-MIB::ifHCInOctets, IF-MIB::ifHighSpeed]}\n\
+             if_out: {table: true, instance: IF-MIB::ifName, \
+             values: [IF-MIB::ifHCOutOctets, IF-MIB::ifHighSpeed]}\n",
+        );
+        let collect_map = plan_collection(&device, &config, &oid_var_bind_map()).unwrap();
+
+        // ifName is walked once for both collectors
+        assert_eq!(collect_map.len(), 1);
+        let values = &collect_map[&column(IF_NAME)].values;
+        let mut names: Vec<(&[u64], &str)> = values
+            .iter()
+            .map(|(value, plan)| (value.name().components(), plan.name.as_str()))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                (IF_HC_IN_OCTETS, "ifHCInOctets"),
+                (IF_HC_OUT_OCTETS, "ifHCOutOctets"),
+                (IF_HIGH_SPEED, "ifHighSpeed"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_column_is_walked_once_per_cycle() {
+        let agent = fake_agent::bind().await;
+        let (mut client, mut session) = fake_agent::connect(&agent).await;
+        let mut requests = 0;
+        let serve = fake_agent::serve(&agent, |request| {
+            requests += 1;
+            // two interfaces, then the end of the column
+            let oid = request.var_binds()[0].name().components();
+            let row = if oid.starts_with(IF_NAME) {
+                oid[IF_NAME.len()..].first()
+            } else {
+                None
+            };
+            let next = match row {
+                None => [IF_NAME, &[1]].concat(),
+                Some(1) => [IF_NAME, &[2]].concat(),
+                Some(_) => IF_HC_IN_OCTETS.to_vec(),
+            };
+            let value = VarValue::String(b"eth".to_vec());
+            let var_binds = vec![VarBind::with_value(ObjectIdent::new(next), value)];
+            Some(response_pdu(request, var_binds).into())
+        });
+
+        let mut walked = WalkedColumns::new();
+        let mut repetitions = BulkRepetitions::new(None);
+        let walks = async {
+            let mut results = vec![];
+            for _ in 0..2 {
+                results.push(
+                    walk_column(
+                        &mut walked,
+                        &column(IF_NAME),
+                        &[],
+                        100,
+                        &WalkMode::GetNext,
+                        &mut repetitions,
+                        &mut client,
+                        &mut session,
+                    )
+                    .await
+                    .unwrap(),
+                );
+            }
+            results
+        };
+        let results = tokio::select! {
+            results = walks => results,
+            _ = serve => unreachable!(),
+        };
+
+        assert_eq!(results[0].len(), 2);
+        assert_eq!(results[0], results[1]);
+        assert_eq!(requests, 3);
+    }
+
+    #[test]
+    fn only_counters_of_discontinued_rows_are_skipped() {
+        let discontinued = HashSet::from([vec![2]]);
+
+        assert!(is_discontinued(&discontinued, &[2], &VarValue::Counter(1)));
+        assert!(is_discontinued(
+            &discontinued,
+            &[2],
+            &VarValue::BigCounter(1)
+        ));
+        // Gauge32, e.g. ifHighSpeed
+        assert!(!is_discontinued(
+            &discontinued,
+            &[2],
+            &VarValue::UnsignedInt(1)
+        ));
+        assert!(!is_discontinued(&discontinued, &[2], &VarValue::Int(1)));
+        assert!(!is_discontinued(&discontinued, &[1], &VarValue::Counter(1)));
+    }
+
+    /// One interface, the rows of the fake agent in the order of their oids
+    fn interface_rows() -> Vec<VarBind> {
+        vec![
+            VarBind::with_value(
+                ObjectIdent::new([IF_NAME, &[1]].concat()),
+                VarValue::String(b"eth0".to_vec()),
+            ),
+            VarBind::with_value(
+                ObjectIdent::new([IF_HC_IN_OCTETS, &[1]].concat()),
+                VarValue::BigCounter(5),
+            ),
+            VarBind::with_value(
+                ObjectIdent::new([IF_HC_OUT_OCTETS, &[1]].concat()),
+                VarValue::BigCounter(7),
+            ),
+        ]
+    }
+
+    /// Response of the fake agent to a GETNEXT
+    fn get_next(request: &ScopedPdu) -> ScopedPdu {
+        let rows = interface_rows();
+        let var_binds = request
+            .var_binds()
+            .iter()
+            .map(|requested| {
+                rows.iter()
+                    .find(|row| row.name() > requested.name())
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        VarBind::with_value(requested.name().clone(), VarValue::EndOfMibView)
+                    })
+            })
+            .collect();
+        response_pdu(request, var_binds)
+    }
+
+    /// The walk of a column starts at the column itself
+    fn starts_walk(request: &ScopedPdu) -> Option<&[u64]> {
+        let requested = request.var_binds().first()?.name().components();
+        [IF_NAME, IF_HC_IN_OCTETS, IF_HC_OUT_OCTETS]
+            .iter()
+            .find(|column| **column == requested)
+            .copied()
+    }
+
+    /// Run up to `max_cycles` of a device collecting `data` from the fake agent serving `answer`,
+    /// with the result of the last cycle and the values sent
+    async fn collect_from(
+        data: &str,
+        max_cycles: u64,
+        answer: impl FnMut(&ScopedPdu) -> Option<Answer>,
+    ) -> (Result<(), Error>, CycleSummary, Vec<SnmpStatResult>) {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut config, mut device) = test_config(data);
+        config.main.device_metadata = None;
+        device.interval = 1;
+        device.jitter = None;
+        device.offset_secs = None;
+        device.snmp.host = agent.local_addr().unwrap().to_string();
+        device.snmp.authprotocol = SnmpAuthProtocol::Md5;
+        device.snmp.privprotocol = SnmpPrivProtocol::Des;
+        device.snmp.walk_mode = WalkMode::GetNext;
+        device.snmp.request_timeout = Timeout(1);
+        device.snmp.cycle_deadline = None;
+        let keys = AgentKeys::new(&device.snmp.authpassword, &device.snmp.privpassword);
+        config.devices = HashMap::from([("sw1".to_string(), device)]);
+
+        let (channel, results) = flume::unbounded();
+        let (metric_channel, _metrics) = flume::unbounded();
+        let (_trigger_sender, trigger) = flume::bounded(1);
+        let context = DeviceContext {
+            config: Arc::new(config),
+            oid_var_bind_map: oid_var_bind_map(),
+            channel,
+            metric_channel,
+            max_cycles: Some(max_cycles),
+            trigger,
+            progress: Progress::default(),
+        };
+        let mut backoff = Backoff::new(Duration::from_secs(1), None);
+        let mut summary = CycleSummary::default();
+        let mut spread = None;
+        let collect = collect_device("sw1", &context, &mut backoff, &mut summary, &mut spread);
+        let result = tokio::select! {
+            result = collect => result,
+            _ = fake_agent::serve_with_keys(&agent, &keys, answer) => unreachable!(),
+        };
+        (result, summary, results.try_iter().collect())
+    }
+
+    const IF_IN_OCTETS: &str =
+        "if_in: {table: true, instance: IF-MIB::ifName, values: [IF-MIB::ifHCInOctets]}\n";
+
+    #[tokio::test]
+    async fn a_rebooted_agent_is_rediscovered_once_and_the_cycle_started_over() {
+        let mut discoveries = 0;
+        let mut instance_walks = 0;
+        let (result, summary, results) = collect_from(IF_IN_OCTETS, 2, |request| {
+            if starts_walk(request) == Some(IF_NAME) {
+                instance_walks += 1;
+            }
+            // the agent restarts before the second cycle, its engine time starts over
+            let (engine_boots, engine_time) = if instance_walks < 2 {
+                (1, 1000)
+            } else {
+                (2, 10)
+            };
+            let scoped_pdu = if request.var_binds().is_empty() {
+                discoveries += 1;
+                response_pdu(request, vec![])
+            } else {
+                get_next(request)
+            };
+            Some(Answer {
+                scoped_pdu,
+                engine_boots,
+                engine_time,
+            })
+        })
+        .await;
+
+        result.unwrap();
+        assert_eq!(summary.cycles, 2);
+        assert_eq!(discoveries, 2);
+        // the failed request did not send a value, the cycle sent it once when it started over
+        assert_eq!(results.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn an_engine_time_outside_the_window_is_rediscovered_only_once() {
+        let mut discoveries = 0;
+        let mut answers = 0;
+        let (result, summary, results) = collect_from(IF_IN_OCTETS, 1, |request| {
+            // the engine time runs away from the session, also after a rediscovery
+            answers += 1;
+            let scoped_pdu = if request.var_binds().is_empty() {
+                discoveries += 1;
+                response_pdu(request, vec![])
+            } else {
+                get_next(request)
+            };
+            Some(Answer {
+                scoped_pdu,
+                engine_boots: 1,
+                engine_time: 1000 * answers,
+            })
+        })
+        .await;
+
+        assert!(snmp::is_time_window_error(&result.unwrap_err()));
+        assert_eq!(discoveries, 2);
+        assert_eq!(summary.cycles, 0);
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_cycle_which_sent_values_is_not_started_over() {
+        let mut discoveries = 0;
+        let mut walks = 0;
+        let data = "if_octets: {table: true, instance: IF-MIB::ifName, \
+             values: [IF-MIB::ifHCInOctets, IF-MIB::ifHCOutOctets]}\n";
+        let (result, summary, results) = collect_from(data, 1, |request| {
+            if starts_walk(request).is_some() {
+                walks += 1;
+            }
+            // the agent restarts after the ifName walk and the first value column
+            let (engine_boots, engine_time) = if walks < 3 { (1, 1000) } else { (2, 10) };
+            let scoped_pdu = if request.var_binds().is_empty() {
+                discoveries += 1;
+                response_pdu(request, vec![])
+            } else {
+                get_next(request)
+            };
+            Some(Answer {
+                scoped_pdu,
+                engine_boots,
+                engine_time,
+            })
+        })
+        .await;
+
+        assert!(snmp::is_time_window_error(&result.unwrap_err()));
+        assert_eq!(discoveries, 1);
+        assert_eq!(summary.cycles, 0);
+        assert_eq!(results.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn metadata_is_sent_as_a_hash_of_its_value() {
+        const SYS_NAME: &[u64] = &[1, 3, 6, 1, 2, 1, 1, 5];
+        let sys_name = "core1.rack 3/a";
+        let agent = fake_agent::bind().await;
+        let (mut client, mut session) = fake_agent::connect(&agent).await;
+        let metadata = vec![("sysName".to_string(), column(SYS_NAME))];
+        let (metric_channel, metrics) = flume::unbounded();
+
+        let fetch = fetch_metadata(
+            "metadata-test",
+            &metadata,
+            &metric_channel,
+            &mut client,
+            &mut session,
+        );
+        let serve = fake_agent::serve(&agent, |request| {
+            let var_binds = request
+                .var_binds()
+                .iter()
+                .map(|bind| {
+                    let value = VarValue::String(sys_name.as_bytes().to_vec());
+                    VarBind::with_value(bind.name().clone(), value)
+                })
+                .collect();
+            Some(response_pdu(request, var_binds).into())
+        });
+        tokio::select! {
+            result = fetch => result.unwrap(),
+            _ = serve => unreachable!(),
+        }
+
+        // the value is only a tag, its key has no free text in it
+        let sent: Vec<(String, String)> = metrics
+            .drain()
+            .map(|metricval| (metricval.metric, metricval.value))
+            .collect();
+        let key = device_self_metric("metadata-test", "metadata.sysName", 0).metric;
+        assert_eq!(sent, vec![(key, metadata_hash(sys_name).to_string())]);
+        assert_ne!(metadata_hash(sys_name), metadata_hash("core2.rack 3/a"));
+        assert_eq!(
+            *crate::output::device_metadata("metadata-test"),
+            vec![("sysName".to_string(), sys_name.to_string())]
+        );
+    }
+
+    fn v4(last: u8) -> SocketAddr {
+        SocketAddr::from((std::net::Ipv4Addr::new(192, 0, 2, last), SNMP_PORT))
+    }
+
+    fn v6(last: u16) -> SocketAddr {
+        let ip = std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last);
+        SocketAddr::from((ip, SNMP_PORT))
+    }
+
+    #[test]
+    fn addresses_of_a_v4_only_host_are_selected_by_ip_version() {
+        let resolved = vec![v4(1), v4(2)];
+        assert_eq!(
+            select_addresses(resolved.clone(), IpVersion::V4),
+            vec![v4(1), v4(2)]
+        );
+        assert_eq!(select_addresses(resolved.clone(), IpVersion::V6), vec![]);
+        assert_eq!(select_addresses(resolved, IpVersion::Auto), vec![v4(1)]);
+    }
+
+    #[test]
+    fn addresses_of_a_v6_only_host_are_selected_by_ip_version() {
+        let resolved = vec![v6(1), v6(2)];
+        assert_eq!(select_addresses(resolved.clone(), IpVersion::V4), vec![]);
+        assert_eq!(
+            select_addresses(resolved.clone(), IpVersion::V6),
+            vec![v6(1), v6(2)]
+        );
+        assert_eq!(select_addresses(resolved, IpVersion::Auto), vec![v6(1)]);
+    }
+
+    #[test]
+    fn addresses_of_a_dual_stack_host_are_selected_by_ip_version() {
+        let resolved = vec![v6(1), v4(1), v6(2), v4(2)];
+        assert_eq!(
+            select_addresses(resolved.clone(), IpVersion::V4),
+            vec![v4(1), v4(2)]
+        );
+        assert_eq!(
+            select_addresses(resolved.clone(), IpVersion::V6),
+            vec![v6(1), v6(2)]
+        );
+        // the family the resolver returned first leads
+        assert_eq!(
+            select_addresses(resolved, IpVersion::Auto),
+            vec![v6(1), v4(1)]
+        );
+        assert_eq!(
+            select_addresses(vec![v4(1), v6(1), v4(2)], IpVersion::Auto),
+            vec![v4(1), v6(1)]
+        );
+    }
+
+    #[test]
+    fn hosts_are_split_from_their_port() {
+        assert_eq!(split_host_port("192.0.2.1"), ("192.0.2.1", None));
+        assert_eq!(split_host_port("192.0.2.1:1161"), ("192.0.2.1", Some(1161)));
+        assert_eq!(split_host_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(split_host_port("[2001:db8::1]"), ("2001:db8::1", None));
+        assert_eq!(
+            split_host_port("[2001:db8::1]:1161"),
+            ("2001:db8::1", Some(1161))
+        );
+        assert_eq!(
+            split_host_port("sw1.example.com"),
+            ("sw1.example.com", None)
+        );
+        assert_eq!(
+            split_host_port("sw1.example.com:1161"),
+            ("sw1.example.com", Some(1161))
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_are_bracketed_when_joined_with_a_port() {
+        assert_eq!(join_host_port("192.0.2.1", 161), "192.0.2.1:161");
+        assert_eq!(join_host_port("2001:db8::1", 161), "[2001:db8::1]:161");
+        assert_eq!(
+            join_host_port("sw1.example.com", 161),
+            "sw1.example.com:161"
+        );
+        for host in [
+            "192.0.2.1:1161",
+            "[2001:db8::1]:1161",
+            "sw1.example.com:1161",
+        ] {
+            let (address, port) = split_host_port(host);
+            assert_eq!(join_host_port(address, port.unwrap()), host);
+        }
+    }
 }
@@ -3,9 +3,16 @@ use anyhow::Error;
 use config_file::FromConfigFile;
 use log::{debug, trace};
 use scan_dir::ScanDir;
-use serde::{Deserialize, Serialize};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::inventory;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct TokioConsole {
@@ -50,25 +57,625 @@ pub struct Tokio {
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
 pub struct Main {
     pub tokio: Tokio,
+    /// Prefix of the runtime threads and tasks, defaults to `snmpc-rs`
+    #[serde(default)]
+    pub thread_prefix: Option<String>,
+    /// Longest metric key passed to the outputs, unlimited by default
+    #[serde(default)]
+    pub max_metric_len: Option<usize>,
+    /// What happens to keys longer than `max_metric_len`
+    #[serde(default)]
+    pub metric_len_policy: MetricLenPolicy,
+    /// What happens to values of columns the loaded MIBs have no name for
+    #[serde(default)]
+    pub unresolved_columns: UnresolvedColumnPolicy,
+    /// Round the timestamp of every device metric to the nearest multiple of the device's
+    /// interval, so each cycle lands on its own evenly spaced point
+    #[serde(default)]
+    pub align_timestamps: bool,
+    /// Send every metric of a cycle with the same timestamp, the midpoint of the first request
+    /// of the cycle and its response, instead of the time each response was received
+    #[serde(default)]
+    pub cycle_timestamps: bool,
+    /// Unix socket accepting `collect <device>` to run a cycle immediately, disabled by default
+    #[serde(default)]
+    pub control_socket: Option<String>,
+    /// Start at most this many devices per second, in the order of their names, instead of
+    /// at a random point of their first interval. Devices with offset_secs keep their offset.
+    #[serde(default)]
+    pub startup_rate: Option<u32>,
+    /// Run the first cycle of every device right away, or at its place in the startup ramp,
+    /// instead of after a random delay. Later cycles are spread over the interval as usual.
+    #[serde(default)]
+    pub initial_burst: bool,
+    /// Restart the task of a device which made no progress for this many of its intervals,
+    /// disabled by default
+    #[serde(default)]
+    pub watchdog_intervals: Option<f64>,
+    /// Identifies this collector in self metrics and log lines, defaults to the short hostname
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    /// Stop walking a column after this many rows, for collectors without their own
+    /// `max_rows`. Defaults to 100000.
+    #[serde(default)]
+    pub max_rows: Option<usize>,
+    /// Names used in metric keys instead of the fields of value columns, the first matching
+    /// alias applies
+    #[serde(default)]
+    pub metric_aliases: Vec<MetricAlias>,
+    /// Scalars describing every device, attached to its metrics. Disabled by default.
+    #[serde(default)]
+    pub device_metadata: Option<DeviceMetadataSettings>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DeviceMetadataSettings {
+    /// e.g. SNMPv2-MIB::sysName and SNMPv2-MIB::sysLocation, labels and tags are named by the
+    /// field without its MIB
+    pub oids: Vec<String>,
+    /// Fetched again after this many seconds, 3600 by default
+    #[serde(default = "default_device_metadata_ttl")]
+    pub ttl_secs: u64,
+}
+
+fn default_device_metadata_ttl() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum MetricLenPolicy {
+    /// shorten the key and append a hash of the full key, so it stays the same across cycles
+    #[serde(rename = "truncate")]
+    #[default]
+    Truncate,
+    /// do not send the metric, with a warning
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum UnresolvedColumnPolicy {
+    /// do not send the value, counted in values.unresolved.skipped
+    #[serde(rename = "skip")]
+    #[default]
+    Skip,
+    /// name the value by the dotted oid of its column
+    #[serde(rename = "oid")]
+    Oid,
+}
+
+/// Prefix of the carbon output, or a list of them
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CarbonPrefix {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CarbonPrefix {
+    pub fn all(&self) -> &[String] {
+        match self {
+            CarbonPrefix::One(prefix) => std::slice::from_ref(prefix),
+            CarbonPrefix::Many(prefixes) => prefixes,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CarbonOutputSettings {
+    /// A list sends every metric once per prefix over the same connection, e.g. under the old
+    /// and the new naming scheme during a migration
+    pub prefix: CarbonPrefix,
+    // change to carbon with 'graphite' alias after issue rust#54726 has been fixeed
+    pub graphite_server: String,
+    pub graphite_port: u16,
+    /// Wrap the connection in TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the CA certificates to trust, defaults to the system trust store
+    pub tls_ca: Option<String>,
+    /// PEM files with a client certificate and its key, for relays requiring client auth
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Name to verify the certificate against (SNI), defaults to `graphite_server`
+    pub tls_server_name: Option<String>,
+    /// Accept any certificate, only meant for relays with self-signed certificates
+    #[serde(default)]
+    pub tls_insecure_skip_verify: bool,
+    /// Only send the last value per (metric, timestamp) received within this many
+    /// milliseconds, disabled by default
+    pub coalesce_window_ms: Option<u64>,
+    /// Disable Nagle's algorithm, so each line is sent without waiting for more to batch
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Buffer lines and write them at least every this many milliseconds, instead of writing
+    /// each line on its own. Unset by default.
+    pub flush_interval_ms: Option<u64>,
+    /// Probe an idle connection, so a relay which went away without closing it is detected.
+    /// Enabled by default, null disables it.
+    #[serde(default = "default_carbon_keepalive")]
+    pub keepalive: Option<TcpKeepaliveSettings>,
+    /// Reconnect if a write blocks for this many seconds, 30 by default, null disables it
+    #[serde(default = "default_carbon_write_timeout")]
+    pub write_timeout_secs: Option<u64>,
+    /// Write at most this many metrics per second, so a backlog after an outage is drained at
+    /// an even pace. Buffered lines are flushed whenever the limit is reached. Unlimited by
+    /// default. A metric counts once whatever the number of prefixes.
+    pub max_rate: Option<u64>,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct TcpKeepaliveSettings {
+    /// idle time before the first probe
+    pub idle_secs: u64,
+    /// time between unanswered probes
+    pub interval_secs: u64,
+    /// unanswered probes after which the connection is closed
+    pub count: u32,
+}
+
+impl Default for TcpKeepaliveSettings {
+    fn default() -> Self {
+        TcpKeepaliveSettings {
+            idle_secs: 60,
+            interval_secs: 10,
+            count: 6,
+        }
+    }
+}
+
+fn default_carbon_keepalive() -> Option<TcpKeepaliveSettings> {
+    Some(TcpKeepaliveSettings::default())
+}
+
+fn default_carbon_write_timeout() -> Option<u64> {
+    Some(30)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum KafkaFormat {
+    /// a JSON object with device, instance, name, metric, value and timestamp
+    #[serde(rename = "json")]
+    #[default]
+    Json,
+    /// the carbon plaintext line
+    #[serde(rename = "carbon")]
+    Carbon,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct KafkaOutputSettings {
+    pub prefix: String,
+    /// comma separated list of host:port
+    pub brokers: String,
+    pub topic: String,
+    #[serde(default)]
+    pub format: KafkaFormat,
+    /// Required acknowledgements per message, defaults to all in-sync replicas
+    pub acks: Option<String>,
+    /// Additional librdkafka producer properties
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// Add the SNMP type of each value (integer, counter32, counter64, gauge32, timeticks or
+    /// opaque) as "type" of the JSON records
+    #[serde(default)]
+    pub include_type: bool,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct MqttOutputSettings {
+    /// host:port, the port defaults to 1883
+    pub broker: String,
+    /// metrics are published to <topic_prefix>/<device>/<metric>
+    pub topic_prefix: String,
+    /// 0 (at most once), 1 (at least once) or 2 (exactly once)
+    #[serde(default)]
+    pub qos: u8,
+    pub client_id: Option<String>,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RemoteWriteOutputSettings {
+    /// e.g. http://victoriametrics:8428/api/v1/write
+    pub url: String,
+    /// Prepended to every metric name, e.g. snmp_
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_remote_write_flush_interval")]
+    pub flush_interval_secs: u64,
+    /// Samples per request, larger batches are split
+    #[serde(default = "default_remote_write_max_samples")]
+    pub max_samples_per_request: usize,
+    /// Compression of the request body, snappy by default as required by the protocol
+    #[serde(default)]
+    pub compress: Compression,
+    /// Send a staleness marker for a series not updated for this many intervals of its device,
+    /// so it ends instead of showing its last value. 2.5 by default, null disables it.
+    #[serde(default = "default_remote_write_stale_after")]
+    pub stale_after_intervals: Option<f64>,
+    /// Add the SNMP type of each value (integer, counter32, counter64, gauge32, timeticks or
+    /// opaque) as the label snmp_type
+    #[serde(default)]
+    pub include_type: bool,
+    #[serde(default)]
+    pub pool: HttpPoolSettings,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[serde(rename = "snappy")]
+    #[default]
+    Snappy,
+    /// smaller bodies than snappy for the repetitive labels of metric batches, at several times
+    /// the CPU time. Not every receiver accepts it.
+    #[serde(rename = "gzip")]
+    Gzip,
+    /// for receivers on the same host, where bandwidth does not matter
+    #[serde(rename = "none")]
+    None,
+}
+
+/// Metrics POSTed as a JSON array to the HTTP API of a hosted Graphite, e.g.
+/// `[{"name": "<prefix>.<key>", "value": 1, "time": 1700000000, "interval": 60}]`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct GraphiteHttpOutputSettings {
+    /// e.g. https://graphite-prod-01.example.net/graphite/metrics
+    pub url: String,
+    /// Sent as `Authorization: Bearer <api_key>`, may be `${VAR}` or `file:<path>` like the
+    /// device passwords
+    pub api_key: Option<String>,
+    pub prefix: String,
+    #[serde(default = "default_remote_write_flush_interval")]
+    pub flush_interval_secs: u64,
+    /// Metrics per request, larger batches are split
+    #[serde(default = "default_remote_write_max_samples")]
+    pub max_metrics_per_request: usize,
+    #[serde(default)]
+    pub pool: HttpPoolSettings,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+fn default_remote_write_flush_interval() -> u64 {
+    10
+}
+
+fn default_remote_write_max_samples() -> usize {
+    5000
+}
+
+fn default_remote_write_stale_after() -> Option<f64> {
+    Some(2.5)
+}
+
+/// Connections kept open between the requests of an HTTP output
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HttpPoolSettings {
+    /// idle connections kept per host, 0 opens a new connection for every request
+    pub max_idle: usize,
+    /// an idle connection is closed after this many seconds
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for HttpPoolSettings {
+    fn default() -> Self {
+        HttpPoolSettings {
+            max_idle: 4,
+            idle_timeout_secs: 90,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FileOutputSettings {
+    pub prefix: String,
+    pub path: String,
+    /// Rotate to <path>.<unix timestamp> once the file has reached this size
+    pub rotate_bytes: Option<u64>,
+    /// Rotate to <path>.<unix timestamp> once the file is this old
+    pub rotate_secs: Option<u64>,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DogStatsDOutputSettings {
+    pub host: String,
+    #[serde(default = "default_dogstatsd_port")]
+    pub port: u16,
+    /// Prepended to every metric name, e.g. snmp.
+    #[serde(default)]
+    pub prefix: String,
+    /// Add the SNMP type of each value (integer, counter32, counter64, gauge32, timeticks or
+    /// opaque) as the tag snmp_type
+    #[serde(default)]
+    pub include_type: bool,
+    #[serde(flatten)]
+    pub filter: MetricFilter,
+}
+
+fn default_dogstatsd_port() -> u16 {
+    8125
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum Output {
     #[serde(rename = "carbon")]
     #[serde(alias = "graphite")]
-    CarbonOutput {
-        prefix: String,
-        // change to carbon with 'graphite' alias after issue rust#54726 has been fixeed
-        graphite_server: String,
-        graphite_port: u16,
-    },
+    Carbon(CarbonOutputSettings),
+    #[serde(rename = "kafka")]
+    Kafka(KafkaOutputSettings),
+    #[serde(rename = "mqtt")]
+    Mqtt(MqttOutputSettings),
+    #[serde(rename = "remote_write")]
+    RemoteWrite(RemoteWriteOutputSettings),
+    #[serde(rename = "file")]
+    File(FileOutputSettings),
+    #[serde(rename = "dogstatsd")]
+    DogStatsD(DogStatsDOutputSettings),
+    #[serde(rename = "graphite_http")]
+    GraphiteHttp(GraphiteHttpOutputSettings),
+}
+
+/// Metrics sent to an output, matched by key against regular expressions. A metric is sent if
+/// `allow` is empty or one of its patterns matches, unless one of `deny` matches as well. The
+/// keys are not called include and exclude, `include` merges in another file.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct MetricFilter {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl Output {
+    pub fn filter(&self) -> &MetricFilter {
+        match self {
+            Output::Carbon(settings) => &settings.filter,
+            Output::Kafka(settings) => &settings.filter,
+            Output::Mqtt(settings) => &settings.filter,
+            Output::RemoteWrite(settings) => &settings.filter,
+            Output::File(settings) => &settings.filter,
+            Output::DogStatsD(settings) => &settings.filter,
+            Output::GraphiteHttp(settings) => &settings.filter,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::Carbon(_) => "carbon",
+            Output::Kafka(_) => "kafka",
+            Output::Mqtt(_) => "mqtt",
+            Output::RemoteWrite(_) => "remote_write",
+            Output::File(_) => "file",
+            Output::DogStatsD(_) => "dogstatsd",
+            Output::GraphiteHttp(_) => "graphite_http",
+        }
+    }
+
+    /// Cargo feature the output is built with and whether it is enabled, None for the outputs
+    /// which are always built
+    pub fn feature(&self) -> Option<(&'static str, bool)> {
+        match self {
+            Output::Kafka(_) => Some(("kafka", cfg!(feature = "kafka"))),
+            Output::Mqtt(_) => Some(("mqtt", cfg!(feature = "mqtt"))),
+            Output::RemoteWrite(_) => Some(("remote-write", cfg!(feature = "remote-write"))),
+            Output::GraphiteHttp(_) => Some(("graphite-http", cfg!(feature = "graphite-http"))),
+            Output::Carbon(_) | Output::File(_) | Output::DogStatsD(_) => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct DataEntry {
+    /// Walk the columns of a table, or get the `.0` instance of scalars. The scalars of every
+    /// collector of a device are fetched with a single get, each keyed by the value of its
+    /// `instance` scalar, e.g. SNMPv2-MIB::sysName.
     pub table: bool,
     pub instance: String,
-    pub values: Vec<String>,
+    pub values: Vec<ValueEntry>,
+    /// Table indices which are never emitted, e.g. `[1, [1, 5]]` for the rows indexed 1 and 1.5.
+    /// The exclusion is applied last, so it takes precedence over any other selection of indices.
+    #[serde(default)]
+    pub exclude_indices: Vec<TableIndex>,
+    /// Only walk the rows following this index (e.g. [100]), for large tables where only the
+    /// end is of interest
+    #[serde(default)]
+    pub start_index: Vec<u64>,
+    /// Column which changes when the counters of a row have been reset, e.g.
+    /// IF-MIB::ifCounterDiscontinuityTime. The counter values of a row are skipped for the cycle
+    /// in which it changed, so derivatives computed downstream show a gap instead of a spike.
+    /// Gauges of the row are still sent.
+    pub discontinuity: Option<String>,
+    /// Only collect the rows whose value in another column matches a regex
+    pub select_by: Option<SelectBy>,
+    /// Build the key from the index suffix in this format instead of the instance value, e.g.
+    /// mac for bridge tables indexed by MAC address
+    pub index_format: Option<IndexFormat>,
+    /// What to send for a value column without any rows
+    #[serde(default)]
+    pub on_empty: OnEmpty,
+    /// Column of the value table holding the index of the `instance` row each value row belongs
+    /// to, e.g. IP-MIB::ipAdEntIfIndex for addresses named by IF-MIB::ifName. Without it the rows
+    /// of the value and instance columns are correlated by their index.
+    pub join_by: Option<String>,
+    /// Stop walking a column of the table after this many rows, `main.max_rows` by default
+    pub max_rows: Option<usize>,
+    /// Key the rows by `<instance>_<index>`, e.g. `Ethernet1_5` for an ifName repeated on
+    /// several line cards. Rows keyed by their index anyway are not changed.
+    #[serde(default)]
+    pub key_with_index: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum OnEmpty {
+    /// send nothing
+    #[serde(rename = "ignore")]
+    #[default]
+    Ignore,
+    /// send 0 for every row of the instance column
+    #[serde(rename = "zero")]
+    Zero,
+    /// send the self metric table_empty.<column> of the device, 1 while the column is empty and
+    /// 0 otherwise
+    #[serde(rename = "metric")]
+    Metric,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum IndexFormat {
+    /// 00:11:22:33:44:55
+    #[serde(rename = "mac")]
+    Mac,
+    /// 10.0.0.1, or an IPv6 address for 16 sub-identifiers
+    #[serde(rename = "ip")]
+    Ip,
+    /// 001122334455
+    #[serde(rename = "hex")]
+    Hex,
+    /// 0.17.34.51.68.85
+    #[serde(rename = "decimal")]
+    Decimal,
+}
+
+/// A value column, or a list of columns tried in order until one returns rows, for metrics
+/// which live at different OIDs depending on the vendor. Values are emitted under the name of
+/// the first column.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ValueEntry {
+    Oid(String),
+    Fallback(Vec<String>),
+    Detailed(ValueSettings),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ValueSettings {
+    pub oid: String,
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Arithmetic applied to the value `x` before it is sent, e.g. `x * 8` or `100 - x`
+    pub expr: Option<String>,
+    /// Plausible range of the value, checked after `expr`
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// What happens to values outside of `min` and `max`
+    #[serde(default)]
+    pub out_of_range: OutOfRange,
+    /// Send OCTET STRING values which hold a number, e.g. "42.5", instead of dropping them.
+    /// Off by default, it hides agents returning the wrong type.
+    #[serde(default)]
+    pub parse_string_as_number: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum OutOfRange {
+    /// do not send the value
+    #[serde(rename = "drop")]
+    #[default]
+    Drop,
+    /// send the bound it exceeds instead
+    #[serde(rename = "clamp")]
+    Clamp,
+}
+
+/// `min`, `max` and `out_of_range` of a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueBounds {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub out_of_range: OutOfRange,
+}
+
+impl ValueEntry {
+    /// Column the values are emitted as
+    pub fn name(&self) -> &str {
+        self.oids().first().map_or("", |oid| oid.as_str())
+    }
+
+    pub fn oids(&self) -> Vec<&String> {
+        match self {
+            ValueEntry::Oid(oid) => vec![oid],
+            ValueEntry::Fallback(oids) => oids.iter().collect(),
+            ValueEntry::Detailed(settings) => std::iter::once(&settings.oid)
+                .chain(settings.fallbacks.iter())
+                .collect(),
+        }
+    }
+
+    pub fn expr(&self) -> Option<&str> {
+        match self {
+            ValueEntry::Detailed(settings) => settings.expr.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn parse_string_as_number(&self) -> bool {
+        match self {
+            ValueEntry::Detailed(settings) => settings.parse_string_as_number,
+            _ => false,
+        }
+    }
+
+    /// None if neither `min` nor `max` is set
+    pub fn bounds(&self) -> Option<ValueBounds> {
+        match self {
+            ValueEntry::Detailed(settings) if settings.min.is_some() || settings.max.is_some() => {
+                Some(ValueBounds {
+                    min: settings.min,
+                    max: settings.max,
+                    out_of_range: settings.out_of_range,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Renames a value column in metric keys and in the names and labels of the other outputs,
+/// without changing the collectors
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct MetricAlias {
+    /// Value column as configured, e.g. IF-MIB::ifHCInOctets, or only its field
+    pub name: Option<String>,
+    /// Or a regular expression matched against the field, e.g. ^ifHC(In|Out)Octets$
+    pub regex: Option<String>,
+    /// The new name, `$1` refers to groups of `regex`
+    pub alias: String,
+}
+
+/// The index of a table row, a single sub-identifier like `1` or all of them like `[1, 5]`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum TableIndex {
+    Single(u64),
+    SubIdentifiers(Vec<u64>),
+}
+
+impl TableIndex {
+    pub fn sub_identifiers(&self) -> Vec<u64> {
+        match self {
+            TableIndex::Single(index) => vec![*index],
+            TableIndex::SubIdentifiers(index) => index.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SelectBy {
+    /// Column of the same table, e.g. IF-MIB::ifAlias
+    pub column: String,
+    pub regex: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -87,12 +694,53 @@ pub enum SnmpPrivProtocol {
     Des,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Clone, PartialEq)]
 pub enum SnmpVersion {
     #[serde(rename = "3")]
     Three,
 }
 
+// `version: 3` is an integer to serde_yaml 0.9, config_file reads it as the variant name
+impl<'de> Deserialize<'de> for SnmpVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Number(version) if version.as_u64() == Some(3) => {
+                Ok(SnmpVersion::Three)
+            }
+            serde_yaml::Value::String(version) if version == "3" => Ok(SnmpVersion::Three),
+            version => Err(D::Error::custom(format!(
+                "unsupported SNMP version {:?}, only 3 is supported",
+                version
+            ))),
+        }
+    }
+}
+
+/// Request type used to walk tables
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum WalkMode {
+    #[serde(rename = "bulk")]
+    #[default]
+    Bulk,
+    /// for old or broken agents which do not handle GETBULK
+    #[serde(rename = "getnext")]
+    GetNext,
+}
+
+/// Address family used for a host name resolving to IPv4 and IPv6 addresses
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum IpVersion {
+    /// the family the resolver prefers, the other one is tried as well if the engine does not
+    /// answer quickly
+    #[serde(rename = "auto")]
+    #[default]
+    Auto,
+    #[serde(rename = "v4")]
+    V4,
+    #[serde(rename = "v6")]
+    V6,
+}
+
 /// Timeout in seconds.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Timeout(pub u64);
@@ -108,32 +756,257 @@ pub struct DeviceSnmpSettings {
     pub version: SnmpVersion,
     pub secname: String,
     pub authprotocol: SnmpAuthProtocol,
+    /// Passwords may reference an environment variable (`${VAR}`) or a file (`file:<path>`)
     pub authpassword: String,
     pub privprotocol: SnmpPrivProtocol,
     pub privpassword: String,
+    /// Timeout of a single request, `timeout` is accepted as well
+    #[serde(default, alias = "timeout")]
+    pub request_timeout: Timeout,
+    /// Seconds after which a collection cycle is aborted, unlimited by default
+    pub cycle_deadline: Option<u64>,
     #[serde(default)]
-    pub timeout: Timeout,
+    pub walk_mode: WalkMode,
+    /// Local address requests are sent from, e.g. one of a management VRF. By default the
+    /// system picks the address of the interface routing to the device.
+    pub bind_address: Option<IpAddr>,
+    /// Largest response in bytes expected to reach the collector, limits the rows requested per
+    /// GETBULK for paths with a small MTU
+    pub max_pdu_size: Option<u32>,
+    #[serde(default)]
+    pub ip_version: IpVersion,
+    /// Requests per second sent to the device at most, spaced evenly within a cycle. Unlimited
+    /// by default.
+    pub max_pps: Option<u32>,
+    /// SNMP engines collected for the device in every cycle instead of `host`, e.g. the blades
+    /// of a chassis behind a single address
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// An SNMP engine of a device, its metrics are keyed by the device followed by `name`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Endpoint {
+    pub name: String,
+    /// defaults to the `host` of the device
+    pub host: Option<String>,
+    /// defaults to the port of `host`, or 161
+    pub port: Option<u16>,
+    /// SNMPv3 context name, empty by default
+    pub context: Option<String>,
+}
+
+/// SNMP settings as written for a device, template or `defaults`. Missing fields of a device
+/// are taken from its template, then from `defaults`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct OptionalDeviceSnmpSettings {
+    pub host: Option<String>,
+    pub version: Option<SnmpVersion>,
+    pub secname: Option<String>,
+    pub authprotocol: Option<SnmpAuthProtocol>,
+    pub authpassword: Option<String>,
+    pub privprotocol: Option<SnmpPrivProtocol>,
+    pub privpassword: Option<String>,
+    #[serde(alias = "timeout")]
+    pub request_timeout: Option<Timeout>,
+    pub cycle_deadline: Option<u64>,
+    pub walk_mode: Option<WalkMode>,
+    pub bind_address: Option<IpAddr>,
+    pub max_pdu_size: Option<u32>,
+    pub ip_version: Option<IpVersion>,
+    pub max_pps: Option<u32>,
+    pub endpoints: Option<Vec<Endpoint>>,
+}
+
+impl OptionalDeviceSnmpSettings {
+    /// Fill the fields missing in `self` from `defaults`
+    fn merge(self, defaults: &OptionalDeviceSnmpSettings) -> Self {
+        let defaults = defaults.clone();
+        OptionalDeviceSnmpSettings {
+            host: self.host.or(defaults.host),
+            version: self.version.or(defaults.version),
+            secname: self.secname.or(defaults.secname),
+            authprotocol: self.authprotocol.or(defaults.authprotocol),
+            authpassword: self.authpassword.or(defaults.authpassword),
+            privprotocol: self.privprotocol.or(defaults.privprotocol),
+            privpassword: self.privpassword.or(defaults.privpassword),
+            request_timeout: self.request_timeout.or(defaults.request_timeout),
+            cycle_deadline: self.cycle_deadline.or(defaults.cycle_deadline),
+            walk_mode: self.walk_mode.or(defaults.walk_mode),
+            bind_address: self.bind_address.or(defaults.bind_address),
+            max_pdu_size: self.max_pdu_size.or(defaults.max_pdu_size),
+            ip_version: self.ip_version.or(defaults.ip_version),
+            max_pps: self.max_pps.or(defaults.max_pps),
+            endpoints: self.endpoints.or(defaults.endpoints),
+        }
+    }
+}
+
+impl TryFrom<OptionalDeviceSnmpSettings> for DeviceSnmpSettings {
+    type Error = Error;
+
+    fn try_from(snmp: OptionalDeviceSnmpSettings) -> Result<Self, Error> {
+        fn required<T>(value: Option<T>, field: &str) -> Result<T, Error> {
+            match value {
+                Some(value) => Ok(value),
+                None => bail!(
+                    "Missing snmp.{}, neither set for the device, its template nor in defaults",
+                    field
+                ),
+            }
+        }
+
+        if snmp.max_pps == Some(0) {
+            bail!("snmp.max_pps must be at least 1 request per second");
+        }
+        let ip_version = snmp.ip_version.unwrap_or_default();
+        match (snmp.bind_address, ip_version) {
+            (Some(IpAddr::V4(_)), IpVersion::V6) | (Some(IpAddr::V6(_)), IpVersion::V4) => bail!(
+                "snmp.bind_address {} is not of ip_version {:?}",
+                snmp.bind_address.unwrap(),
+                ip_version
+            ),
+            _ => {}
+        }
+        let endpoints = snmp.endpoints.unwrap_or_default();
+        for (position, endpoint) in endpoints.iter().enumerate() {
+            if endpoint.name.is_empty() {
+                bail!("Missing snmp.endpoints name");
+            }
+            if endpoints[..position]
+                .iter()
+                .any(|other| other.name == endpoint.name)
+            {
+                bail!("Duplicate snmp.endpoints name '{}'", endpoint.name);
+            }
+        }
+
+        Ok(DeviceSnmpSettings {
+            host: required(snmp.host, "host")?,
+            version: required(snmp.version, "version")?,
+            secname: required(snmp.secname, "secname")?,
+            authprotocol: required(snmp.authprotocol, "authprotocol")?,
+            authpassword: required(snmp.authpassword, "authpassword")?,
+            privprotocol: required(snmp.privprotocol, "privprotocol")?,
+            privpassword: required(snmp.privpassword, "privpassword")?,
+            request_timeout: snmp.request_timeout.unwrap_or_default(),
+            cycle_deadline: snmp.cycle_deadline,
+            walk_mode: snmp.walk_mode.unwrap_or_default(),
+            bind_address: snmp.bind_address,
+            max_pdu_size: snmp.max_pdu_size,
+            ip_version,
+            max_pps: snmp.max_pps,
+            endpoints,
+        })
+    }
 }
 
+/// `S` is `OptionalDeviceSnmpSettings` while loading, the loaded `Config` only contains fully
+/// populated settings
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
-pub struct DeviceEntry {
-    pub snmp: DeviceSnmpSettings,
+pub struct DeviceEntry<S = DeviceSnmpSettings> {
+    /// Name of an entry in `templates` providing SNMP settings and collectors
+    #[serde(default)]
+    pub template: Option<String>,
+    pub snmp: S,
+    /// Entries of `data` or `collector_groups`, added to the collectors of the template. A name
+    /// prefixed with `-` removes the collector (or every collector of the group) instead.
+    /// Expanded to the entries of `data` when the configuration is loaded.
+    #[serde(default)]
     pub collect: Vec<String>,
     pub interval: u16,
+    /// Vary each cycle by up to this fraction of `interval` (e.g. 0.1 for +-10%), so devices
+    /// started at the same time do not stay in phase
+    pub jitter: Option<f64>,
+    /// Start each cycle this many seconds after a multiple of `interval` since the epoch, for
+    /// staggering devices deterministically. `jitter` is ignored if set.
+    pub offset_secs: Option<u64>,
+    /// Disabled devices are kept in the configuration but not polled
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Pause polling after consecutive failures instead of retrying every backoff
+    pub circuit_breaker: Option<CircuitBreaker>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CircuitBreaker {
+    /// consecutive failed cycles which open the breaker
+    pub failures: u32,
+    /// pause before a single probe cycle is attempted, the breaker stays open if it fails
+    pub cooldown_secs: u64,
+}
+
+impl DeviceEntry<OptionalDeviceSnmpSettings> {
+    fn with_snmp(self, snmp: DeviceSnmpSettings) -> DeviceEntry {
+        DeviceEntry {
+            template: self.template,
+            snmp,
+            collect: self.collect,
+            interval: self.interval,
+            jitter: self.jitter,
+            offset_secs: self.offset_secs,
+            enabled: self.enabled,
+            circuit_breaker: self.circuit_breaker,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct Defaults {
+    #[serde(default)]
+    pub snmp: OptionalDeviceSnmpSettings,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Template {
+    #[serde(default)]
+    pub snmp: OptionalDeviceSnmpSettings,
+    /// Collectors of every device using the template, like `collect` of a device
+    #[serde(default)]
+    pub collect: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct OptionalConfig {
     pub main: Option<Main>,
+    /// a single output, kept for compatibility with configurations predating `outputs`
     pub output: Option<Output>,
+    pub outputs: Option<Vec<Output>>,
+    pub mib_dirs: Option<Vec<String>>,
+    pub extra_mibs: Option<Vec<String>>,
+    pub defaults: Option<Defaults>,
+    pub templates: Option<HashMap<String, Template>>,
+    /// Named lists of entries of `data` or other groups, usable in `collect`
+    pub collector_groups: Option<HashMap<String, Vec<String>>>,
     pub data: Option<HashMap<String, DataEntry>>,
-    pub devices: Option<HashMap<String, DeviceEntry>>,
+    pub devices: Option<HashMap<String, DeviceEntry<OptionalDeviceSnmpSettings>>>,
+    pub devices_source: Option<DevicesSource>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Devices read from an inventory at load time, in addition to `devices`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DevicesSource {
+    /// CSV file with one device per row, see `inventory::parse_csv`
+    pub csv: Option<String>,
+    /// URL returning a YAML or JSON mapping of device names to entries like in `devices`
+    pub url: Option<String>,
+    /// File keeping the devices last read, used if the source can not be read
+    pub cache: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Config {
     pub main: Main,
-    pub output: Output,
+    /// every metric is sent to all outputs
+    pub outputs: Vec<Output>,
+    /// directories searched for MIB files, replaces `MIBDIRS`
+    pub mib_dirs: Option<Vec<String>>,
+    /// MIBs loaded in addition to `MIBS`
+    pub extra_mibs: Vec<String>,
     pub data: HashMap<String, DataEntry>,
     pub devices: HashMap<String, DeviceEntry>,
 }
@@ -142,7 +1015,19 @@ impl TryFrom<OptionalConfig> for Config {
     type Error = Error;
 
     fn try_from(config: OptionalConfig) -> Result<Self, Error> {
-        Ok(Config {
+        let collector_groups = config.collector_groups.unwrap_or_default();
+        if let Some(data) = &config.data {
+            for group_name in collector_groups.keys() {
+                if data.contains_key(group_name) {
+                    bail!(
+                        "'{}' is defined both in collector_groups and in data",
+                        group_name
+                    );
+                }
+            }
+        }
+
+        let mut config = Config {
             main: match config.main {
                 Some(main) => main,
                 None => {
@@ -150,33 +1035,440 @@ impl TryFrom<OptionalConfig> for Config {
                     Main::default()
                 }
             },
-            output: match config.output {
-                Some(output) => output,
-                None => bail!("Missing 'output' section in configuration"),
+            outputs: {
+                let mut outputs: Vec<Output> = config.output.into_iter().collect();
+                outputs.extend(config.outputs.unwrap_or_default());
+                if outputs.is_empty() {
+                    bail!("Missing 'output' or 'outputs' section in configuration");
+                }
+                outputs
             },
+            mib_dirs: config.mib_dirs,
+            extra_mibs: config.extra_mibs.unwrap_or_default(),
             data: match config.data {
                 Some(data) => data,
                 None => bail!("Missing 'data' section in configuration"),
             },
-            devices: match config.devices {
-                Some(devices) => devices,
-                None => bail!("Missing 'devices' section in configuration"),
+            devices: {
+                let mut devices = match (config.devices, &config.devices_source) {
+                    (Some(devices), _) => devices,
+                    (None, Some(_)) => HashMap::new(),
+                    (None, None) => bail!("Missing 'devices' section in configuration"),
+                };
+                if let Some(devices_source) = &config.devices_source {
+                    // devices defined in the configuration take precedence
+                    for (device_name, device) in inventory::load(devices_source)? {
+                        match devices.entry(device_name) {
+                            Entry::Occupied(occupied) => debug!(
+                                "config(device={}): using the configured device instead of the inventory",
+                                occupied.key()
+                            ),
+                            Entry::Vacant(vacant) => {
+                                vacant.insert(device);
+                            }
+                        }
+                    }
+                }
+                resolve_devices(
+                    devices,
+                    &config.defaults.unwrap_or_default(),
+                    &config.templates.unwrap_or_default(),
+                    &collector_groups,
+                )?
             },
-        })
+        };
+        config.resolve_secrets()?;
+        Ok(config)
     }
 }
 
-pub fn from_file(path: &str) -> Result<Config, Error> {
+impl Config {
+    /// Serialize the effective configuration, keys are sorted so the output is stable
+    pub fn to_yaml(&self, show_secrets: bool) -> Result<String, Error> {
+        let mut config = self.clone();
+        if !show_secrets {
+            for device in config.devices.values_mut() {
+                device.snmp.authpassword = REDACTED.to_string();
+                device.snmp.privpassword = REDACTED.to_string();
+            }
+            for output in config.outputs.iter_mut() {
+                if let Output::GraphiteHttp(settings) = output {
+                    if settings.api_key.is_some() {
+                        settings.api_key = Some(REDACTED.to_string());
+                    }
+                }
+            }
+        }
+
+        // serde_json::Value uses sorted maps
+        Ok(serde_yaml::to_string(&serde_json::to_value(&config)?)?)
+    }
+
+    /// Serialize the configuration and load the result like a configuration file, listing the
+    /// fields which do not survive, e.g. because of a serde alias or a default which differs from
+    /// what is serialized
+    pub fn roundtrip_differences(&self) -> Result<Vec<String>, Error> {
+        // loaded like a configuration file, from the YAML dump-config prints
+        let yaml = self.to_yaml(true)?;
+        let reloaded: OptionalConfig = match serde_yaml::from_str(&yaml).and_then(from_yaml_value) {
+            Ok(reloaded) => reloaded,
+            Err(error) => bail!(
+                "config: the serialized configuration can not be loaded: {}",
+                error
+            ),
+        };
+        let reloaded = Config::try_from(reloaded)?;
+        if &reloaded == self {
+            return Ok(vec![]);
+        }
+        let differences = diff_fields(self, &reloaded)?;
+        if differences.is_empty() {
+            // equal once serialized, so the difference is in a field serde skips
+            return Ok(vec!["<fields which are not serialized>".to_string()]);
+        }
+        Ok(differences)
+    }
+
+    /// Replace `${VAR}` and `file:<path>` references in the device passwords and API keys by
+    /// their values
+    fn resolve_secrets(&mut self) -> Result<(), Error> {
+        for (device_name, device) in self.devices.iter_mut() {
+            for (field, value) in [
+                ("authpassword", &mut device.snmp.authpassword),
+                ("privpassword", &mut device.snmp.privpassword),
+            ] {
+                match resolve_secret(value) {
+                    Ok(Some(resolved)) => {
+                        debug!("config(device={}): resolved {}", device_name, field);
+                        *value = resolved;
+                    }
+                    Ok(None) => {}
+                    Err(error) => bail!(
+                        "config(device={}): could not resolve snmp.{}: {}",
+                        device_name,
+                        field,
+                        error
+                    ),
+                }
+            }
+        }
+        for (output_id, output) in self.outputs.iter_mut().enumerate() {
+            if let Output::GraphiteHttp(GraphiteHttpOutputSettings {
+                api_key: Some(api_key),
+                ..
+            }) = output
+            {
+                match resolve_secret(api_key) {
+                    Ok(Some(resolved)) => *api_key = resolved,
+                    Ok(None) => {}
+                    Err(error) => bail!(
+                        "config(output={}): could not resolve api_key: {}",
+                        output_id,
+                        error
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Merge the SNMP settings of every device with its template and the defaults, and expand its
+/// collectors
+fn resolve_devices(
+    devices: HashMap<String, DeviceEntry<OptionalDeviceSnmpSettings>>,
+    defaults: &Defaults,
+    templates: &HashMap<String, Template>,
+    collector_groups: &HashMap<String, Vec<String>>,
+) -> Result<HashMap<String, DeviceEntry>, Error> {
+    let mut resolved = HashMap::new();
+    for (device_name, mut device) in devices {
+        let mut snmp = device.snmp.clone();
+        let mut collect = vec![];
+        if let Some(template_name) = &device.template {
+            match templates.get(template_name) {
+                Some(template) => {
+                    snmp = snmp.merge(&template.snmp);
+                    collect = template.collect.clone();
+                }
+                None => bail!(
+                    "config(device={}): undefined template '{}'",
+                    device_name,
+                    template_name
+                ),
+            }
+        }
+        collect.extend(device.collect.iter().cloned());
+        device.collect = match expand_collect(&collect, collector_groups) {
+            Ok(collect) => collect,
+            Err(error) => bail!("config(device={}): {}", device_name, error),
+        };
+        let snmp = match DeviceSnmpSettings::try_from(snmp.merge(&defaults.snmp)) {
+            Ok(snmp) => snmp,
+            Err(error) => bail!("config(device={}): {}", device_name, error),
+        };
+
+        trace!("config(device={}): snmp settings {:?}", device_name, snmp);
+        resolved.insert(device_name, device.with_snmp(snmp));
+    }
+    Ok(resolved)
+}
+
+/// Replace groups in `collect` by their collectors and apply removals (`-<name>`) in order,
+/// every collector is kept once at its first position. Unknown names are kept as they are.
+fn expand_collect(
+    collect: &[String],
+    collector_groups: &HashMap<String, Vec<String>>,
+) -> Result<Vec<String>, Error> {
+    let mut expanded: Vec<String> = vec![];
+    for entry in collect {
+        match entry.strip_prefix('-') {
+            Some(removed) => {
+                let removed = expand_group(removed, collector_groups, &mut vec![])?;
+                expanded.retain(|collector| !removed.contains(collector));
+            }
+            None => {
+                for collector in expand_group(entry, collector_groups, &mut vec![])? {
+                    if !expanded.contains(&collector) {
+                        expanded.push(collector);
+                    }
+                }
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// The collectors of the group `name`, or `name` itself if it is no group. `stack` holds the
+/// groups being expanded to detect cycles.
+fn expand_group(
+    name: &str,
+    collector_groups: &HashMap<String, Vec<String>>,
+    stack: &mut Vec<String>,
+) -> Result<Vec<String>, Error> {
+    let members = match collector_groups.get(name) {
+        Some(members) => members,
+        None => return Ok(vec![name.to_string()]),
+    };
+    if stack.iter().any(|group| group == name) {
+        bail!(
+            "collector group '{}' includes itself via {}",
+            name,
+            stack.join(" -> ")
+        );
+    }
+
+    stack.push(name.to_string());
+    let mut collectors = vec![];
+    for member in members {
+        collectors.extend(expand_group(member, collector_groups, stack)?);
+    }
+    stack.pop();
+    Ok(collectors)
+}
+
+const REDACTED: &str = "<redacted>";
+
+fn resolve_secret(value: &str) -> Result<Option<String>, Error> {
+    if let Some(var) = value
+        .strip_prefix("${")
+        .and_then(|value| value.strip_suffix('}'))
+    {
+        return match std::env::var(var) {
+            Ok(resolved) => Ok(Some(resolved)),
+            Err(error) => bail!("environment variable {}: {}", var, error),
+        };
+    }
+
+    if let Some(path) = value.strip_prefix("file:") {
+        return match std::fs::read_to_string(path) {
+            // secret files are usually written with a trailing newline
+            Ok(resolved) => Ok(Some(
+                resolved.trim_end_matches(&['\r', '\n'][..]).to_string(),
+            )),
+            Err(error) => bail!("file {}: {}", path, error),
+        };
+    }
+
+    Ok(None)
+}
+
+/// Load the configuration file at `path`, with the overlay of `env` merged over it
+pub fn from_file(path: &str, env: Option<&str>) -> Result<Config, Error> {
     let config = from_file_optional(path)?;
-    Config::try_from(config)
+    Config::try_from(apply_env_overlay(config, path, env)?)
+}
+
+/// The overlay of `env` for the configuration file or directory at `path`: `config.yaml` is
+/// overlaid by `config.<env>.yaml` next to it, the directory `conf.d` by `conf.d.<env>.yaml`
+fn overlay_path(path: &str, env: &str) -> PathBuf {
+    let path = path.trim_end_matches('/');
+    let base = path
+        .strip_suffix(".yaml")
+        .or_else(|| path.strip_suffix(".yml"))
+        .unwrap_or(path);
+    PathBuf::from(format!("{}.{}.yaml", base, env))
+}
+
+/// Deep-merge the overlay of `env` over `config`. Unlike the files of a directory, an overlay
+/// is meant to override: its mappings are merged into the ones of the configuration, anything
+/// else replaces the value configured.
+fn apply_env_overlay(
+    config: OptionalConfig,
+    path: &str,
+    env: Option<&str>,
+) -> Result<OptionalConfig, Error> {
+    let env = match env {
+        Some(env) => env,
+        None => return Ok(config),
+    };
+    let overlay_path = overlay_path(path, env);
+    debug!(
+        "config(file={}): merging the overlay of environment {}",
+        overlay_path.display(),
+        env
+    );
+    let overlay = load_yaml(&overlay_path, &mut vec![])?;
+    if !matches!(overlay, serde_yaml::Value::Mapping(_)) {
+        bail!(
+            "config(file={}): the overlay does not contain a mapping",
+            overlay_path.display()
+        );
+    }
+    // through serde_json, enums are serialized as mappings like in configuration files instead
+    // of tagged values
+    let mut value = serde_yaml::to_value(&serde_json::to_value(&config)?)?;
+    merge_overlay(&mut value, overlay);
+    match from_yaml_value(value) {
+        Ok(config) => Ok(config),
+        Err(error) => bail!("config(file={}): {}", overlay_path.display(), error),
+    }
+}
+
+fn merge_overlay(value: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (value, overlay) {
+        (serde_yaml::Value::Mapping(mapping), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, overlay_value) in overlay {
+                match mapping.get_mut(&key) {
+                    Some(value) => merge_overlay(value, overlay_value),
+                    None => {
+                        mapping.insert(key, overlay_value);
+                    }
+                }
+            }
+        }
+        (value, overlay) => *value = overlay,
+    }
 }
 
 fn from_file_optional(path: &str) -> Result<OptionalConfig, Error> {
     debug!("config(file={}): loading from file", path);
-    Ok(OptionalConfig::from_config_file(path)?)
+    if !(path.ends_with(".yaml") || path.ends_with(".yml")) {
+        return Ok(OptionalConfig::from_config_file(path)?);
+    }
+
+    let value = load_yaml(Path::new(path), &mut vec![])?;
+    match from_yaml_value(value) {
+        Ok(config) => Ok(config),
+        Err(error) => bail!("config(file={}): {}", path, error),
+    }
+}
+
+/// Deserialize a parsed document with enums written as single key mappings, e.g.
+/// `output: {carbon: ...}`, like files loaded by config_file. serde_yaml 0.9 on its own only
+/// takes YAML tags like `output: !carbon ...`.
+fn from_yaml_value<T: DeserializeOwned>(value: serde_yaml::Value) -> Result<T, serde_yaml::Error> {
+    serde_yaml::with::singleton_map_recursive::deserialize(value)
 }
 
-pub fn from_directory(path: &str) -> Result<Config, Error> {
+/// A mapping with this key is merged over the mapping in the referenced YAML file, e.g.
+/// `snmp: {include: credentials.yaml, host: 192.0.2.1}`. Only the top level keys are merged,
+/// keys next to `include` replace the included ones.
+const INCLUDE_KEY: &str = "include";
+
+/// Parse a YAML file and resolve its includes, `stack` holds the files currently being included
+fn load_yaml(path: &Path, stack: &mut Vec<PathBuf>) -> Result<serde_yaml::Value, Error> {
+    let canonical_path = match path.canonicalize() {
+        Ok(canonical_path) => canonical_path,
+        Err(error) => bail!("config(file={}): {}", path.display(), error),
+    };
+    if stack.contains(&canonical_path) {
+        let cycle: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical_path))
+            .map(|path| path.display().to_string())
+            .collect();
+        bail!(
+            "config(file={}): include cycle: {}",
+            path.display(),
+            cycle.join(" -> ")
+        );
+    }
+
+    let mut value: serde_yaml::Value = match fs::read_to_string(path) {
+        Ok(source) => match serde_yaml::from_str(&source) {
+            Ok(value) => value,
+            Err(error) => bail!("config(file={}): {}", path.display(), error),
+        },
+        Err(error) => bail!("config(file={}): {}", path.display(), error),
+    };
+    stack.push(canonical_path);
+    resolve_includes(&mut value, path, stack)?;
+    stack.pop();
+    Ok(value)
+}
+
+fn resolve_includes(
+    value: &mut serde_yaml::Value,
+    file: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            if let Some(include) = mapping.remove(INCLUDE_KEY) {
+                let include = match include {
+                    serde_yaml::Value::String(include) => include,
+                    _ => bail!(
+                        "config(file={}): {} must be a path",
+                        file.display(),
+                        INCLUDE_KEY
+                    ),
+                };
+                // relative to the including file
+                let include_path = file
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .join(include);
+                let mut included = match load_yaml(&include_path, stack)? {
+                    serde_yaml::Value::Mapping(included) => included,
+                    _ => bail!(
+                        "config(file={}): included file {} does not contain a mapping",
+                        file.display(),
+                        include_path.display()
+                    ),
+                };
+                for (key, value) in std::mem::take(mapping) {
+                    included.insert(key, value);
+                }
+                *mapping = included;
+            }
+            for (_, value) in mapping.iter_mut() {
+                resolve_includes(value, file, stack)?;
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for value in sequence {
+                resolve_includes(value, file, stack)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Load and combine the configuration files in `path`, with the overlay of `env` merged over
+/// the result
+pub fn from_directory(path: &str, env: Option<&str>) -> Result<Config, Error> {
     debug!(
         "config(directory={}): loading configuration files from directory",
         path
@@ -201,13 +1493,121 @@ pub fn from_directory(path: &str) -> Result<Config, Error> {
             match &config.output {
                 Some(output) => {
                     if output != &tmp_output {
-                        bail!("Previous definition of output {:?} differs from new output definition {:?} in {:?}", output, tmp_output,file);
+                        bail!(
+                            "Previous definition of output differs from new definition in {:?}: {}",
+                            file,
+                            diff_fields(output, &tmp_output)?.join(", ")
+                        );
                     }
                 }
                 None => config.output = Some(tmp_output),
             };
         }
 
+        // handle outputs, identical definitions in several files are only used once
+        if let Some(tmp_outputs) = tmp_config.outputs {
+            let outputs = config.outputs.get_or_insert_with(Vec::new);
+            for tmp_output in tmp_outputs {
+                if !outputs.contains(&tmp_output) {
+                    outputs.push(tmp_output);
+                }
+            }
+        }
+
+        // handle mib_dirs and extra_mibs, the lists of all files are combined
+        for (tmp_list, list) in [
+            (tmp_config.mib_dirs, &mut config.mib_dirs),
+            (tmp_config.extra_mibs, &mut config.extra_mibs),
+        ] {
+            if let Some(tmp_list) = tmp_list {
+                let list = list.get_or_insert_with(Vec::new);
+                for entry in tmp_list {
+                    if !list.contains(&entry) {
+                        list.push(entry);
+                    }
+                }
+            }
+        }
+
+        // handle defaults
+        if let Some(tmp_defaults) = tmp_config.defaults {
+            match &config.defaults {
+                Some(defaults) => {
+                    if defaults != &tmp_defaults {
+                        bail!(
+                            "Previous definition of defaults differs from new definition in {:?}: {}",
+                            file,
+                            diff_fields(defaults, &tmp_defaults)?.join(", ")
+                        );
+                    }
+                }
+                None => config.defaults = Some(tmp_defaults),
+            };
+        }
+
+        // handle devices_source
+        if let Some(tmp_devices_source) = tmp_config.devices_source {
+            match &config.devices_source {
+                Some(devices_source) => {
+                    if devices_source != &tmp_devices_source {
+                        bail!(
+                            "Previous definition of devices_source differs from new definition in {:?}: {}",
+                            file,
+                            diff_fields(devices_source, &tmp_devices_source)?.join(", ")
+                        );
+                    }
+                }
+                None => config.devices_source = Some(tmp_devices_source),
+            };
+        }
+
+        // handle templates
+        if let Some(tmp_templates) = tmp_config.templates {
+            match &mut config.templates {
+                Some(templates) => {
+                    for (tmp_template_name, tmp_template) in tmp_templates.iter() {
+                        if let Some(template) = templates.get(tmp_template_name) {
+                            if template != tmp_template {
+                                bail!(
+                                    "Previous definition of template {} differs from new definition in {:?}: {}",
+                                    tmp_template_name,
+                                    file,
+                                    diff_fields(template, tmp_template)?.join(", ")
+                                );
+                            }
+                        } else {
+                            templates.insert(tmp_template_name.to_string(), tmp_template.clone());
+                        }
+                    }
+                }
+                None => config.templates = Some(tmp_templates),
+            }
+        }
+
+        // handle collector_groups
+        if let Some(tmp_groups) = tmp_config.collector_groups {
+            match &mut config.collector_groups {
+                Some(groups) => {
+                    for (tmp_group_name, tmp_group) in tmp_groups.iter() {
+                        if let Some(group) = groups.get(tmp_group_name) {
+                            if group != tmp_group {
+                                bail!(
+                                    "Previous definition of collector group {} differs from new definition in {:?}: {:?} vs {:?}",
+                                    tmp_group_name,
+                                    file,
+                                    group,
+                                    tmp_group
+                                );
+                            }
+                        } else {
+                            groups.insert(tmp_group_name.to_string(), tmp_group.clone());
+                        }
+                    }
+                }
+                None => config.collector_groups = Some(tmp_groups),
+            }
+        }
+
         // handle data
         if let Some(tmp_data) = tmp_config.data {
             match &mut config.data {
@@ -216,9 +1616,10 @@ pub fn from_directory(path: &str) -> Result<Config, Error> {
                         if let Some(entry) = data.get(tmp_entry_name) {
                             if entry != tmp_entry {
                                 bail!(
-                        "Previous definition of data entry {} differs from new definition in {:?}",
+                        "Previous definition of data entry {} differs from new definition in {:?}: {}",
                         tmp_entry_name,
-                        file
+                        file,
+                        diff_fields(entry, tmp_entry)?.join(", ")
                     );
                             }
                         } else {
@@ -237,7 +1638,12 @@ pub fn from_directory(path: &str) -> Result<Config, Error> {
                     for (tmp_device_name, tmp_device) in tmp_devices.iter() {
                         if let Some(device) = devices.get(tmp_device_name) {
                             if device != tmp_device {
-                                bail!("Previous definition of device entry {} differs from new definition in {:?}", tmp_device_name,file);
+                                bail!(
+                                    "Previous definition of device entry {} differs from new definition in {:?}: {}",
+                                    tmp_device_name,
+                                    file,
+                                    diff_fields(device, tmp_device)?.join(", ")
+                                );
                             }
                         } else {
                             devices.insert(tmp_device_name.to_string(), tmp_device.clone());
@@ -249,7 +1655,323 @@ pub fn from_directory(path: &str) -> Result<Config, Error> {
         }
     }
 
+    let config = apply_env_overlay(config, path, env)?;
     trace!("OptionalConfig is: {:#?}", config);
 
     Config::try_from(config)
 }
+
+/// List the fields which differ between two definitions, e.g. `snmp.host: a vs b`
+fn diff_fields<T: Serialize>(previous: &T, new: &T) -> Result<Vec<String>, Error> {
+    let mut differences = vec![];
+    diff_values(
+        "",
+        &serde_json::to_value(previous)?,
+        &serde_json::to_value(new)?,
+        &mut differences,
+    );
+    Ok(differences)
+}
+
+fn diff_values(
+    path: &str,
+    previous: &serde_json::Value,
+    new: &serde_json::Value,
+    differences: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    if previous == new {
+        return;
+    }
+
+    if let (Value::Object(previous), Value::Object(new)) = (previous, new) {
+        let mut keys: Vec<&String> = previous.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let key_path = if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            diff_values(
+                &key_path,
+                previous.get(key).unwrap_or(&Value::Null),
+                new.get(key).unwrap_or(&Value::Null),
+                differences,
+            );
+        }
+        return;
+    }
+
+    let path = if path.is_empty() { "<value>" } else { path };
+    if path.ends_with("password") || path.ends_with("api_key") {
+        differences.push(format!("{}: {} vs {}", path, REDACTED, REDACTED));
+    } else {
+        differences.push(format!("{}: {} vs {}", path, previous, new));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/example/simple-example.yaml");
+
+    /// An empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rust-snmp-collector-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn loads_example_like_config_file() {
+        let loaded = from_file_optional(EXAMPLE).unwrap();
+        let expected = OptionalConfig::from_config_file(EXAMPLE).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn overlays_example() {
+        let dir = test_dir("overlays-example");
+        let path = dir.join("config.yaml");
+        fs::copy(EXAMPLE, &path).unwrap();
+        fs::write(
+            dir.join("config.staging.yaml"),
+            "devices:\n  example-device001:\n    snmp:\n      host: 192.0.2.99\n",
+        )
+        .unwrap();
+
+        let config = from_file(path.to_str().unwrap(), Some("staging")).unwrap();
+        let device = config.devices.get("example-device001").unwrap();
+        assert_eq!(device.snmp.host, "192.0.2.99");
+        assert_eq!(device.snmp.version, SnmpVersion::Three);
+        assert_eq!(device.snmp.secname, "snmp_user");
+        assert_eq!(config.outputs.len(), 1);
+    }
+
+    #[test]
+    fn exclude_indices_take_whole_indices() {
+        let data: DataEntry = serde_yaml::from_str(
+            "table: true\ninstance: IF-MIB::ifName\nvalues: []\nexclude_indices: [1, [1, 5]]\n",
+        )
+        .unwrap();
+        let indices: Vec<Vec<u64>> = data
+            .exclude_indices
+            .iter()
+            .map(TableIndex::sub_identifiers)
+            .collect();
+        assert_eq!(indices, vec![vec![1], vec![1, 5]]);
+    }
+
+    fn yaml(source: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn overlay_merges_nested_mappings() {
+        let mut value = yaml("main: {instance_id: a, tokio: {console: {enabled: false}}}\n");
+        merge_overlay(
+            &mut value,
+            yaml("main: {tokio: {console: {enabled: true}}, max_rows: 10}\n"),
+        );
+        assert_eq!(
+            value,
+            yaml("main: {instance_id: a, tokio: {console: {enabled: true}}, max_rows: 10}\n")
+        );
+    }
+
+    #[test]
+    fn overlay_replaces_lists_and_scalars() {
+        let mut value = yaml("mib_dirs: [/a, /b]\ndevices: {d: {interval: 60}}\n");
+        merge_overlay(
+            &mut value,
+            yaml("mib_dirs: [/c]\ndevices: {d: {interval: 30}}\n"),
+        );
+        assert_eq!(
+            value,
+            yaml("mib_dirs: [/c]\ndevices: {d: {interval: 30}}\n")
+        );
+
+        // a mapping may also replace a scalar and the other way around
+        let mut value = yaml("a: 1\nb: {c: 2}\n");
+        merge_overlay(&mut value, yaml("a: {x: 1}\nb: 3\n"));
+        assert_eq!(value, yaml("a: {x: 1}\nb: 3\n"));
+    }
+
+    #[test]
+    fn merge_only_fills_missing_settings() {
+        let defaults: OptionalDeviceSnmpSettings = serde_yaml::from_str(
+            "host: 192.0.2.1\nversion: 3\nsecname: user\nrequest_timeout: 5\n",
+        )
+        .unwrap();
+        let device: OptionalDeviceSnmpSettings = serde_yaml::from_str("host: 192.0.2.2\n").unwrap();
+
+        let merged = device.merge(&defaults);
+        assert_eq!(merged.host.as_deref(), Some("192.0.2.2"));
+        assert_eq!(merged.version, Some(SnmpVersion::Three));
+        assert_eq!(merged.secname.as_deref(), Some("user"));
+        assert_eq!(merged.request_timeout, Some(Timeout(5)));
+        assert_eq!(merged.walk_mode, None);
+    }
+
+    #[test]
+    fn device_settings_take_precedence_over_template_and_defaults() {
+        let defaults: Defaults = serde_yaml::from_str(
+            "snmp: {version: 3, secname: default, authprotocol: SHA, authpassword: a, \
+             privprotocol: AES, privpassword: p, request_timeout: 5, cycle_deadline: 50}\n",
+        )
+        .unwrap();
+        let templates: HashMap<String, Template> = serde_yaml::from_str(
+            "switch: {snmp: {secname: template, request_timeout: 3}, collect: [ifname]}\n",
+        )
+        .unwrap();
+        let devices: HashMap<String, DeviceEntry<OptionalDeviceSnmpSettings>> =
+            serde_yaml::from_str(
+                "sw1: {template: switch, snmp: {host: 192.0.2.1, request_timeout: 1}, \
+                 interval: 60}\n\
+                 sw2: {template: switch, snmp: {host: 192.0.2.2}, interval: 60}\n\
+                 sw3: {snmp: {host: 192.0.2.3}, interval: 60}\n",
+            )
+            .unwrap();
+
+        let devices = resolve_devices(devices, &defaults, &templates, &HashMap::new()).unwrap();
+        let sw1 = &devices["sw1"].snmp;
+        assert_eq!(
+            (
+                sw1.host.as_str(),
+                sw1.secname.as_str(),
+                sw1.request_timeout.0
+            ),
+            ("192.0.2.1", "template", 1)
+        );
+        let sw2 = &devices["sw2"].snmp;
+        assert_eq!(
+            (
+                sw2.host.as_str(),
+                sw2.secname.as_str(),
+                sw2.request_timeout.0
+            ),
+            ("192.0.2.2", "template", 3)
+        );
+        assert_eq!(devices["sw2"].collect, vec!["ifname".to_string()]);
+        let sw3 = &devices["sw3"].snmp;
+        assert_eq!(
+            (
+                sw3.host.as_str(),
+                sw3.secname.as_str(),
+                sw3.request_timeout.0
+            ),
+            ("192.0.2.3", "default", 5)
+        );
+        assert!(devices["sw3"].collect.is_empty());
+        // a field nobody overrides comes from the defaults
+        assert!(devices
+            .values()
+            .all(|device| device.snmp.cycle_deadline == Some(50)));
+    }
+
+    #[test]
+    fn overlay_paths() {
+        assert_eq!(
+            overlay_path("/etc/snmp/config.yaml", "prod"),
+            PathBuf::from("/etc/snmp/config.prod.yaml")
+        );
+        assert_eq!(
+            overlay_path("config.yml", "prod"),
+            PathBuf::from("config.prod.yaml")
+        );
+        assert_eq!(
+            overlay_path("/etc/snmp/conf.d/", "prod"),
+            PathBuf::from("/etc/snmp/conf.d.prod.yaml")
+        );
+    }
+
+    #[test]
+    fn missing_overlay_is_an_error() {
+        let dir = test_dir("missing-overlay");
+        let path = dir.join("config.yaml");
+        fs::copy(EXAMPLE, &path).unwrap();
+        let error = from_file(path.to_str().unwrap(), Some("prod")).unwrap_err();
+        assert!(error.to_string().contains("config.prod.yaml"), "{}", error);
+    }
+
+    #[test]
+    fn includes_are_merged_under_their_keys() {
+        let dir = test_dir("includes");
+        fs::create_dir(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/credentials.yml"),
+            "secname: snmp_user\nauthpassword: secret\ninclude: priv.yml\n",
+        )
+        .unwrap();
+        fs::write(dir.join("shared/priv.yml"), "privpassword: private\n").unwrap();
+        fs::write(
+            dir.join("config.yaml"),
+            "snmp:\n  include: shared/credentials.yml\n  secname: other_user\n",
+        )
+        .unwrap();
+
+        let value = load_yaml(&dir.join("config.yaml"), &mut vec![]).unwrap();
+        // keys next to include win, nested includes are relative to the including file
+        assert_eq!(
+            value,
+            yaml("snmp: {secname: other_user, authpassword: secret, privpassword: private}\n")
+        );
+    }
+
+    #[test]
+    fn include_cycles_are_an_error() {
+        let dir = test_dir("include-cycle");
+        fs::write(dir.join("a.yml"), "include: b.yml\n").unwrap();
+        fs::write(dir.join("b.yml"), "x: {include: a.yml}\n").unwrap();
+
+        let error = load_yaml(&dir.join("a.yml"), &mut vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("include cycle"), "{}", error);
+
+        fs::write(dir.join("self.yml"), "include: self.yml\n").unwrap();
+        assert!(load_yaml(&dir.join("self.yml"), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn missing_and_invalid_includes_are_an_error() {
+        let dir = test_dir("include-missing");
+        fs::write(dir.join("missing.yaml"), "snmp: {include: nowhere.yml}\n").unwrap();
+        let error = load_yaml(&dir.join("missing.yaml"), &mut vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("nowhere.yml"), "{}", error);
+
+        fs::write(dir.join("list.yml"), "[1, 2]\n").unwrap();
+        fs::write(
+            dir.join("not-a-mapping.yaml"),
+            "snmp: {include: list.yml}\n",
+        )
+        .unwrap();
+        assert!(load_yaml(&dir.join("not-a-mapping.yaml"), &mut vec![]).is_err());
+
+        fs::write(dir.join("not-a-path.yaml"), "snmp: {include: [a.yml]}\n").unwrap();
+        assert!(load_yaml(&dir.join("not-a-path.yaml"), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn example_roundtrips() {
+        let config = from_file(EXAMPLE, None).unwrap();
+        assert_eq!(
+            config.roundtrip_differences().unwrap(),
+            Vec::<String>::new()
+        );
+    }
+}
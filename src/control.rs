@@ -0,0 +1,55 @@
+use flume::Sender;
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::Error;
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Accept commands on the Unix socket at `path`, one per line:
+///
+/// - `collect <device>`: run a cycle of the device now instead of after its interval
+///
+/// Every command is answered with a line starting with `ok` or `error`.
+pub async fn listen(path: String, triggers: HashMap<String, Sender<()>>) -> Result<(), Error> {
+    // a socket left behind by an earlier run would fail the bind
+    if fs::metadata(&path).is_ok() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    info!("control({}): listening", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let triggers = triggers.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle(stream, &triggers).await {
+                warn!("control: connection failed: {}", error);
+            }
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, triggers: &HashMap<String, Sender<()>>) -> Result<(), Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        debug!("control: received '{}'", line);
+        let reply = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["collect", device_name] => match triggers.get(*device_name) {
+                // a full channel means a cycle has been requested already
+                Some(trigger) => {
+                    let _ = trigger.try_send(());
+                    info!("control: cycle of {} requested", device_name);
+                    "ok".to_string()
+                }
+                None => format!("error: unknown or disabled device {}", device_name),
+            },
+            [] => continue,
+            _ => format!("error: unknown command '{}'", line),
+        };
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
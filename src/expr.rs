@@ -0,0 +1,255 @@
+use anyhow::{bail, Error};
+
+/// An arithmetic expression over the value `x`, with `+`, `-`, `*`, `/`, parentheses and
+/// decimal numbers
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    X,
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, Error> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let expr = parser.sum()?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.peek() {
+            bail!("unexpected '{}' at position {}", c, parser.pos + 1);
+        }
+        Ok(expr)
+    }
+
+    /// `value` with the expression applied as sent to the outputs. Integers are calculated
+    /// exactly as long as the result is an integer, so 64 bit counters keep their precision.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        if let Ok(x) = value.parse::<i128>() {
+            if let Some(result) = self.eval_integer(x) {
+                return Some(format!("{}", result));
+            }
+        }
+        format_result(self.eval(value.parse().ok()?))
+    }
+
+    /// Exact result for an integer `x`, none if a constant is not an integer, a division has a
+    /// remainder or the result overflows
+    fn eval_integer(&self, x: i128) -> Option<i128> {
+        match self {
+            Expr::Number(number) => {
+                if number.fract() == 0.0 && number.abs() < MAX_EXACT_FLOAT {
+                    Some(*number as i128)
+                } else {
+                    None
+                }
+            }
+            Expr::X => Some(x),
+            Expr::Neg(expr) => expr.eval_integer(x)?.checked_neg(),
+            Expr::Add(a, b) => a.eval_integer(x)?.checked_add(b.eval_integer(x)?),
+            Expr::Sub(a, b) => a.eval_integer(x)?.checked_sub(b.eval_integer(x)?),
+            Expr::Mul(a, b) => a.eval_integer(x)?.checked_mul(b.eval_integer(x)?),
+            Expr::Div(a, b) => {
+                let (a, b) = (a.eval_integer(x)?, b.eval_integer(x)?);
+                if b != 0 && a % b == 0 {
+                    Some(a / b)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        match self {
+            Expr::Number(number) => *number,
+            Expr::X => x,
+            Expr::Neg(expr) => -expr.eval(x),
+            Expr::Add(a, b) => a.eval(x) + b.eval(x),
+            Expr::Sub(a, b) => a.eval(x) - b.eval(x),
+            Expr::Mul(a, b) => a.eval(x) * b.eval(x),
+            Expr::Div(a, b) => a.eval(x) / b.eval(x),
+        }
+    }
+}
+
+/// Recursive descent over `sum := product (('+' | '-') product)*`,
+/// `product := factor (('*' | '/') factor)*` and `factor := '-' factor | '(' sum ')' | number | x`
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `c` if it is the next character
+    fn accept(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.product()?;
+        loop {
+            if self.accept('+') {
+                expr = Expr::Add(Box::new(expr), Box::new(self.product()?));
+            } else if self.accept('-') {
+                expr = Expr::Sub(Box::new(expr), Box::new(self.product()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.factor()?;
+        loop {
+            if self.accept('*') {
+                expr = Expr::Mul(Box::new(expr), Box::new(self.factor()?));
+            } else if self.accept('/') {
+                expr = Expr::Div(Box::new(expr), Box::new(self.factor()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, Error> {
+        if self.accept('-') {
+            return Ok(Expr::Neg(Box::new(self.factor()?)));
+        }
+        if self.accept('(') {
+            let expr = self.sum()?;
+            if !self.accept(')') {
+                bail!("missing ')' at position {}", self.pos + 1);
+            }
+            return Ok(expr);
+        }
+        if self.accept('x') {
+            return Ok(Expr::X);
+        }
+
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            match self.peek() {
+                Some(c) => bail!("unexpected '{}' at position {}", c, self.pos + 1),
+                None => bail!("unexpected end of expression"),
+            }
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        match number.parse() {
+            Ok(number) => Ok(Expr::Number(number)),
+            Err(_) => bail!("invalid number '{}' at position {}", number, start + 1),
+        }
+    }
+}
+
+/// Integers are exact in a f64 up to 2^53
+const MAX_EXACT_FLOAT: f64 = 9007199254740992.0;
+
+/// The result of a floating point calculation as sent to the outputs, integral results
+/// without a fraction
+pub fn format_result(value: f64) -> Option<String> {
+    if !value.is_finite() {
+        return None;
+    }
+    if value.fract() == 0.0 && value.abs() < MAX_EXACT_FLOAT {
+        Some(format!("{}", value as i64))
+    } else {
+        Some(format!("{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(source: &str, value: &str) -> Option<String> {
+        Expr::parse(source).unwrap().apply(value)
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(apply("1 + 2 * x", "3"), Some("7".to_string()));
+        assert_eq!(apply("(1 + 2) * x", "3"), Some("9".to_string()));
+        assert_eq!(apply("100 - x - 10", "20"), Some("70".to_string()));
+        assert_eq!(apply("x / 2 / 5", "100"), Some("10".to_string()));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(apply("-x", "5"), Some("-5".to_string()));
+        assert_eq!(apply("--x", "5"), Some("5".to_string()));
+        assert_eq!(apply("2 * -x", "5"), Some("-10".to_string()));
+        assert_eq!(apply("-(x - 10)", "4"), Some("6".to_string()));
+    }
+
+    #[test]
+    fn division_by_zero_has_no_result() {
+        assert_eq!(apply("x / 0", "5"), None);
+        assert_eq!(apply("x / (x - 5)", "5"), None);
+        assert_eq!(apply("x / 0", "0.5"), None);
+    }
+
+    #[test]
+    fn fractions_fall_back_to_floating_point() {
+        assert_eq!(apply("x / 8", "12"), Some("1.5".to_string()));
+        assert_eq!(apply("x * 0.5", "3"), Some("1.5".to_string()));
+        assert_eq!(apply("x * 1.5", "2"), Some("3".to_string()));
+        assert_eq!(apply("x", "not a number"), None);
+    }
+
+    #[test]
+    fn counters_near_u64_max_stay_exact() {
+        let max = u64::MAX.to_string();
+        assert_eq!(apply("x", &max), Some(max.clone()));
+        assert_eq!(
+            apply("x * 8", &max),
+            Some((u64::MAX as i128 * 8).to_string())
+        );
+        assert_eq!(apply("x - 1", &max), Some((u64::MAX - 1).to_string()));
+        assert_eq!(apply("x / 5", &max), Some((u64::MAX / 5).to_string()));
+        // f64 loses the last digits, the integer path does not
+        assert_ne!(format_result(u64::MAX as f64), Some(max));
+    }
+
+    #[test]
+    fn unknown_identifiers_are_rejected() {
+        assert!(Expr::parse("y + 1").is_err());
+        assert!(Expr::parse("x + value").is_err());
+        assert!(Expr::parse("max(x, 1)").is_err());
+        assert!(Expr::parse("X").is_err());
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        for source in [
+            "", " ", "x +", "* x", "(x", "x)", "()", "x x", "1..2", ".", "x ** 2", "x % 2",
+        ] {
+            assert!(Expr::parse(source).is_err(), "{:?} was accepted", source);
+        }
+    }
+}
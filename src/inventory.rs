@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::Arc;
+#[cfg(feature = "http-inventory")]
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Error};
+#[cfg(feature = "http-inventory")]
+use log::debug;
+use log::{info, warn};
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{Config, DeviceEntry, DevicesSource, OptionalDeviceSnmpSettings};
+
+pub type Devices = HashMap<String, DeviceEntry<OptionalDeviceSnmpSettings>>;
+
+#[cfg(feature = "http-inventory")]
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// CSV columns of the device entry, every other column is an snmp setting
+const DEVICE_COLUMNS: [&str; 6] = [
+    "template",
+    "collect",
+    "interval",
+    "jitter",
+    "offset_secs",
+    "enabled",
+];
+
+/// CSV columns holding numbers or booleans, all others are taken as strings
+const TYPED_COLUMNS: [&str; 9] = [
+    "interval",
+    "jitter",
+    "offset_secs",
+    "enabled",
+    "request_timeout",
+    "timeout",
+    "cycle_deadline",
+    "max_pdu_size",
+    "max_pps",
+];
+
+/// Read the devices of `source`. The devices last read are kept in its cache file and used
+/// instead if the source can not be read or parsed.
+pub fn load(source: &DevicesSource) -> Result<Devices, Error> {
+    let name = source_name(source)?;
+    let error = match fetch(source) {
+        Ok(devices) => {
+            info!("inventory({}): loaded {} devices", name, devices.len());
+            if let Some(cache) = &source.cache {
+                if let Err(error) = write_cache(cache, &devices) {
+                    warn!("inventory: could not write cache {}: {}", cache, error);
+                }
+            }
+            return Ok(devices);
+        }
+        Err(error) => error,
+    };
+
+    match &source.cache {
+        Some(cache) if fs::metadata(cache).is_ok() => {
+            warn!(
+                "inventory({}): {}; using the devices cached in {}",
+                name, error, cache
+            );
+            let devices = fs::read_to_string(cache)?;
+            serde_yaml::from_str(&devices)
+                .map_err(|error| format_err!("inventory(cache={}): {}", cache, error))
+        }
+        _ => bail!("inventory({}): {}", name, error),
+    }
+}
+
+/// The cache holds the credentials of the devices, it is only readable by the collector
+fn write_cache(cache: &str, devices: &Devices) -> Result<(), Error> {
+    let yaml = serde_yaml::to_string(devices)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(cache)?;
+    // the mode only applies to a new file
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(yaml.as_bytes())?;
+    Ok(())
+}
+
+/// Load the configuration with `load` whenever the collector receives SIGHUP, which fetches
+/// the inventory again and refreshes its cache. The running devices are not replaced, the
+/// devices added, removed or changed since the start are logged and applied by a restart.
+pub async fn refetch_on_hangup<F>(running: Arc<Config>, load: F) -> Result<(), Error>
+where
+    F: Fn() -> Result<Config, Error> + Send + Sync + 'static,
+{
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let load = Arc::new(load);
+    while hangups.recv().await.is_some() {
+        info!("inventory: SIGHUP received, loading the configuration");
+        // the inventory is read with blocking requests
+        let reloaded = match tokio::task::spawn_blocking({
+            let load = load.clone();
+            move || load()
+        })
+        .await?
+        {
+            Ok(reloaded) => reloaded,
+            Err(error) => {
+                warn!(
+                    "inventory: keeping the running devices, reload failed: {}",
+                    error
+                );
+                continue;
+            }
+        };
+        let changes = device_changes(&running.devices, &reloaded.devices);
+        if changes.is_empty() {
+            info!("inventory: the devices did not change");
+        } else {
+            warn!(
+                "inventory: devices added: [{}], removed: [{}], changed: [{}]; restart the collector to apply them",
+                changes.added.join(", "),
+                changes.removed.join(", "),
+                changes.changed.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Names of the devices which differ between two configurations, sorted
+#[derive(Debug, Default, PartialEq)]
+pub struct DeviceChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl DeviceChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn device_changes(
+    running: &HashMap<String, DeviceEntry>,
+    reloaded: &HashMap<String, DeviceEntry>,
+) -> DeviceChanges {
+    let mut changes = DeviceChanges::default();
+    for (device_name, device) in reloaded {
+        match running.get(device_name) {
+            None => changes.added.push(device_name.clone()),
+            Some(running_device) if running_device != device => {
+                changes.changed.push(device_name.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    changes.removed = running
+        .keys()
+        .filter(|device_name| !reloaded.contains_key(*device_name))
+        .cloned()
+        .collect();
+    changes.added.sort();
+    changes.removed.sort();
+    changes.changed.sort();
+    changes
+}
+
+fn source_name(source: &DevicesSource) -> Result<&str, Error> {
+    match (&source.csv, &source.url) {
+        (Some(csv), None) => Ok(csv),
+        (None, Some(url)) => Ok(url),
+        _ => bail!("devices_source needs exactly one of 'csv' or 'url'"),
+    }
+}
+
+fn fetch(source: &DevicesSource) -> Result<Devices, Error> {
+    if let Some(csv) = &source.csv {
+        return parse_csv(&fs::read_to_string(csv)?);
+    }
+
+    fetch_url(source_name(source)?)
+}
+
+#[cfg(feature = "http-inventory")]
+fn fetch_url(url: &str) -> Result<Devices, Error> {
+    debug!("inventory({}): requesting devices", url);
+    let response = reqwest::blocking::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()?
+        .error_for_status()?
+        .text()?;
+    // JSON is valid YAML as well
+    Ok(serde_yaml::from_str(&response)?)
+}
+
+#[cfg(not(feature = "http-inventory"))]
+fn fetch_url(_url: &str) -> Result<Devices, Error> {
+    bail!("devices_source url needs the http-inventory feature, which this build does not include")
+}
+
+/// One device per row with a header row naming the columns: `name`, the device entry keys
+/// (`collect` separated by spaces) and snmp settings, e.g.
+/// `name,host,template,collect,interval`. Empty cells are left unset.
+fn parse_csv(content: &str) -> Result<Devices, Error> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+
+    let mut devices = HashMap::new();
+    for (row, record) in reader.records().enumerate() {
+        // the header is line 1
+        let line = row + 2;
+        let record = record?;
+
+        let mut name = None;
+        let mut device = Mapping::new();
+        let mut snmp = Mapping::new();
+        for (column, cell) in headers.iter().zip(record.iter()) {
+            let cell = cell.trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let value = if column == "collect" {
+                Value::Sequence(
+                    cell.split_whitespace()
+                        .map(|collect| Value::String(collect.to_string()))
+                        .collect(),
+                )
+            } else if TYPED_COLUMNS.contains(&column) {
+                serde_yaml::from_str(cell)
+                    .map_err(|error| format_err!("line {}, column {}: {}", line, column, error))?
+            } else {
+                Value::String(cell.to_string())
+            };
+
+            if column == "name" {
+                name = Some(cell.to_string());
+            } else if DEVICE_COLUMNS.contains(&column) {
+                device.insert(Value::String(column.to_string()), value);
+            } else {
+                snmp.insert(Value::String(column.to_string()), value);
+            }
+        }
+        device.insert(Value::String("snmp".to_string()), Value::Mapping(snmp));
+
+        let name = match name {
+            Some(name) => name,
+            None => bail!("line {}: missing name", line),
+        };
+        let device = serde_yaml::from_value(Value::Mapping(device))
+            .map_err(|error| format_err!("line {} ({}): {}", line, name, error))?;
+        if devices.insert(name.clone(), device).is_some() {
+            bail!("line {}: duplicate device {}", line, name);
+        }
+    }
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Timeout;
+
+    fn error(content: &str) -> String {
+        parse_csv(content).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_devices() {
+        let devices = parse_csv(
+            "name,host,template,collect,interval,enabled,request_timeout\n\
+             switch1,192.0.2.1,switches,ifmib_if_octets64 ifmib_if_errors32,60,,\n\
+             switch2, 192.0.2.2 ,switches,,30,false,5\n",
+        )
+        .unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let switch1 = devices.get("switch1").unwrap();
+        assert_eq!(switch1.template.as_deref(), Some("switches"));
+        assert_eq!(
+            switch1.collect,
+            vec!["ifmib_if_octets64", "ifmib_if_errors32"]
+        );
+        assert_eq!(switch1.interval, 60);
+        assert!(switch1.enabled);
+        assert_eq!(switch1.snmp.host.as_deref(), Some("192.0.2.1"));
+        assert_eq!(switch1.snmp.request_timeout, None);
+
+        let switch2 = devices.get("switch2").unwrap();
+        assert!(switch2.collect.is_empty());
+        assert!(!switch2.enabled);
+        assert_eq!(switch2.snmp.host.as_deref(), Some("192.0.2.2"));
+        assert_eq!(switch2.snmp.request_timeout, Some(Timeout(5)));
+    }
+
+    #[test]
+    fn rejects_rows_with_missing_or_extra_cells() {
+        let header = "name,host,interval\n";
+        assert!(parse_csv(&format!("{}switch1,192.0.2.1\n", header)).is_err());
+        assert!(parse_csv(&format!("{}switch1,192.0.2.1,60,extra\n", header)).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_columns() {
+        assert_eq!(
+            error("host,interval\n192.0.2.1,60\n"),
+            "line 2: missing name"
+        );
+        let missing_interval = error("name,host\nswitch1,192.0.2.1\n");
+        assert!(
+            missing_interval.starts_with("line 2 (switch1): missing field `interval`"),
+            "{}",
+            missing_interval
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        let invalid = error("name,interval\nswitch1,often\n");
+        assert!(
+            invalid.starts_with("line 2 (switch1): invalid type"),
+            "{}",
+            invalid
+        );
+    }
+
+    #[test]
+    fn cache_is_only_readable_by_the_owner() {
+        let dir = std::env::temp_dir().join(format!("inventory-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("devices.yaml");
+        // an existing cache keeps its mode when it is truncated
+        fs::write(&cache, "").unwrap();
+        fs::set_permissions(&cache, Permissions::from_mode(0o644)).unwrap();
+
+        let devices = parse_csv("name,host,interval\nswitch1,192.0.2.1,60\n").unwrap();
+        write_cache(cache.to_str().unwrap(), &devices).unwrap();
+        let mode = fs::metadata(&cache).unwrap().permissions().mode();
+        let cached: Devices = serde_yaml::from_str(&fs::read_to_string(&cache).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(cached.keys().collect::<Vec<_>>(), vec!["switch1"]);
+    }
+
+    #[test]
+    fn device_changes_are_listed_by_name() {
+        let config = crate::config::from_file(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/example/simple-example.yaml"),
+            None,
+        )
+        .unwrap();
+        let device = config.devices["example-device001"].clone();
+        let mut changed = device.clone();
+        changed.interval += 1;
+        let devices = |entries: &[(&str, &DeviceEntry)]| -> HashMap<String, DeviceEntry> {
+            entries
+                .iter()
+                .map(|(name, device)| (name.to_string(), (*device).clone()))
+                .collect()
+        };
+
+        let running = devices(&[
+            ("kept", &device),
+            ("changed", &device),
+            ("removed", &device),
+        ]);
+        assert!(device_changes(&running, &running).is_empty());
+        let reloaded = devices(&[("kept", &device), ("changed", &changed), ("added", &device)]);
+        assert_eq!(
+            device_changes(&running, &reloaded),
+            DeviceChanges {
+                added: vec!["added".to_string()],
+                removed: vec!["removed".to_string()],
+                changed: vec!["changed".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_devices() {
+        assert_eq!(
+            error("name,host,interval\nswitch1,192.0.2.1,60\nswitch1,192.0.2.2,60\n"),
+            "line 3: duplicate device switch1"
+        );
+    }
+}
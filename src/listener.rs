@@ -0,0 +1,785 @@
+use flume::Sender;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use anyhow::{bail, format_err, Context, Error};
+use log::{debug, info, trace, warn};
+use snmp_mp::{ObjectIdent, PduType, ScopedPdu, SnmpMsg, VarBind, VarValue};
+use snmp_usm::{
+    Aes128PrivKey, AuthKey, DesPrivKey, Digest, Md5, PrivKey, SecurityError, SecurityParams, Sha1,
+    WithLocalizedKey,
+};
+use tokio::net::UdpSocket;
+
+use crate::collector::{localized_keys, snmp_host, with_usm_types};
+use crate::config::{Config, DeviceSnmpSettings, SnmpAuthProtocol, SnmpPrivProtocol};
+use crate::mib_index;
+use crate::output::{device_self_metric, CarbonMetricValue};
+use crate::snmp::{self, SYS_UPTIME_OID};
+use crate::stat_result::SnmpStatResult;
+
+/// snmpTrapOID.0, the notification a trap or inform is about
+const SNMP_TRAP_OID: [u64; 11] = [1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+/// usmStatsUnknownEngineIDs.0, reported to a sender discovering the engine id of the collector
+const USM_STATS_UNKNOWN_ENGINE_IDS: [u64; 11] = [1, 3, 6, 1, 6, 3, 15, 1, 1, 4, 0];
+/// usmStatsNotInTimeWindows.0, reported to a sender whose notion of the collector's time is off
+const USM_STATS_NOT_IN_TIME_WINDOWS: [u64; 11] = [1, 3, 6, 1, 6, 3, 15, 1, 1, 2, 0];
+
+/// The collector is the authoritative engine of the informs it receives. Its engine id is new
+/// for every run, so the boots counter never has to be persisted.
+struct LocalEngine {
+    id: Vec<u8>,
+    started: Instant,
+    unknown_engine_ids: u32,
+    not_in_time_windows: u32,
+}
+
+impl LocalEngine {
+    const BOOTS: u32 = 1;
+
+    fn new() -> Self {
+        // RFC 3411 SnmpEngineID, enterprise 0, format 5 (octets) followed by random octets
+        let mut id = vec![0x80, 0x00, 0x00, 0x00, 0x05];
+        id.extend_from_slice(&rand::random::<[u8; 8]>());
+        LocalEngine {
+            id,
+            started: Instant::now(),
+            unknown_engine_ids: 0,
+            not_in_time_windows: 0,
+        }
+    }
+
+    fn time(&self) -> u32 {
+        self.started.elapsed().as_secs() as u32
+    }
+}
+
+/// The latest boots and time received from the engine of a trap sender (RFC 3414 2.3)
+#[derive(Default)]
+struct Timeliness {
+    boots: u32,
+    time: u32,
+    received: Option<Instant>,
+}
+
+impl Timeliness {
+    fn now(&self) -> (u32, u32) {
+        let elapsed = self
+            .received
+            .map_or(0, |received| received.elapsed().as_secs() as u32);
+        (self.boots, self.time.saturating_add(elapsed))
+    }
+
+    fn update(&mut self, security_params: &SecurityParams) {
+        let (boots, time) = (
+            security_params.engine_boots(),
+            security_params.engine_time(),
+        );
+        if boots > self.boots || (boots == self.boots && time > self.now().1) {
+            self.boots = boots;
+            self.time = time;
+            self.received = Some(Instant::now());
+        }
+    }
+}
+
+/// The outcome of a message of a device
+#[derive(Default)]
+struct Received {
+    notification: Option<ScopedPdu>,
+    reply: Option<Vec<u8>>,
+}
+
+/// Receive SNMPv3 traps and informs on `bind`, authenticated with the `snmp` settings of the
+/// device they are sent from. Numeric var binds are sent to `channel` like polled values, every
+/// notification is counted as `notifications.<name>` of its device.
+pub async fn listen(
+    bind: String,
+    config: Arc<Config>,
+    oid_var_bind_map: HashMap<String, VarBind>,
+    channel: Sender<SnmpStatResult>,
+    metric_channel: Sender<CarbonMetricValue>,
+) -> Result<(), Error> {
+    let senders = resolve_senders(&config).await;
+    let socket = UdpSocket::bind(&bind)
+        .await
+        .with_context(|| format!("binding to {}", bind))?;
+    info!(
+        "listener({}): receiving notifications from {} addresses",
+        bind,
+        senders.len()
+    );
+
+    let mut engine = LocalEngine::new();
+    let mut timeliness: HashMap<(String, Vec<u8>), Timeliness> = HashMap::new();
+    let mut notifications: HashMap<(String, String), u64> = HashMap::new();
+    let mut buf = vec![0; SnmpMsg::MAX_UDP_PACKET_SIZE];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let sender = canonical(from.ip());
+        let device_names = match senders.get(&sender) {
+            Some(device_names) => device_names,
+            None => {
+                debug!(
+                    "listener({}): ignoring message of unknown sender {}",
+                    bind, from
+                );
+                continue;
+            }
+        };
+        let mut message = buf[..len].to_vec();
+        let (device_name, received) = match receive(
+            &config,
+            device_names,
+            &mut message,
+            &mut engine,
+            &mut timeliness,
+        )
+        .await
+        {
+            Ok(received) => received,
+            Err(error) => {
+                warn!(
+                    "listener({}): dropping message of {}: {}",
+                    bind, from, error
+                );
+                continue;
+            }
+        };
+
+        if let Some(reply) = received.reply {
+            if let Err(error) = socket.send_to(&reply, from).await {
+                warn!("listener({}): replying to {} failed: {}", bind, from, error);
+            }
+        }
+        let pdu = match received.notification {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        let now = SystemTime::now();
+        let (name, results) = notification_results(&device_name, &pdu, &oid_var_bind_map, now);
+        debug!(
+            "listener({}): {} from {} ({}) with {} values",
+            bind,
+            name,
+            device_name,
+            from,
+            results.len()
+        );
+        for result in results {
+            channel.send_async(result).await.unwrap();
+        }
+        let count = notifications
+            .entry((device_name.clone(), name.clone()))
+            .or_insert(0);
+        *count += 1;
+        let field = snmp::field_name(&name);
+        metric_channel
+            .send_async(device_self_metric(
+                &device_name,
+                &format!("notifications.{}", field),
+                *count,
+            ))
+            .await
+            .unwrap();
+    }
+}
+
+/// Addresses of the enabled devices, several devices may share one with different users
+async fn resolve_senders(config: &Config) -> HashMap<IpAddr, Vec<String>> {
+    let mut senders: HashMap<IpAddr, Vec<String>> = HashMap::new();
+    for (device_name, device) in config.devices.iter() {
+        if !device.enabled {
+            continue;
+        }
+        match tokio::net::lookup_host(snmp_host(&device.snmp)).await {
+            Ok(addresses) => {
+                for address in addresses {
+                    let device_names = senders.entry(canonical(address.ip())).or_default();
+                    if !device_names.contains(device_name) {
+                        device_names.push(device_name.clone());
+                    }
+                }
+            }
+            Err(error) => warn!(
+                "listener: can not resolve {} of device {}, ignoring its notifications: {}",
+                device.snmp.host, device_name, error
+            ),
+        }
+    }
+    senders
+}
+
+/// IPv4 senders appear as mapped addresses on a socket bound to an IPv6 address
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        address => address,
+    }
+}
+
+/// Authenticate and decrypt a message with the settings of the device whose `secname` it is
+/// sent by, answering engine discovery and informs
+async fn receive(
+    config: &Config,
+    device_names: &[String],
+    message: &mut [u8],
+    engine: &mut LocalEngine,
+    timeliness: &mut HashMap<(String, Vec<u8>), Timeliness>,
+) -> Result<(String, Received), Error> {
+    let msg = SnmpMsg::decode(message)?;
+    if msg.security_model() != SnmpMsg::USER_BASE_SECURITY_MODEL {
+        bail!("unsupported security model {}", msg.security_model());
+    }
+    let security_params = SecurityParams::decode(msg.security_params())?;
+    let device_name = device_names
+        .iter()
+        .find(|device_name| {
+            config
+                .devices
+                .get(*device_name)
+                .unwrap()
+                .snmp
+                .secname
+                .as_bytes()
+                == security_params.username()
+        })
+        .ok_or_else(|| {
+            format_err!(
+                "unknown user '{}'",
+                String::from_utf8_lossy(security_params.username())
+            )
+        })?;
+    let device = config.devices.get(device_name).unwrap();
+
+    // an inform sender has to discover the engine id of the collector first
+    if security_params.engine_id().is_empty() {
+        let request_id = match msg.scoped_pdu_data.plaintext() {
+            Some(pdu) if msg.is_reportable() => pdu.request_id(),
+            _ => bail!("unexpected message without engine id"),
+        };
+        engine.unknown_engine_ids = engine.unknown_engine_ids.wrapping_add(1);
+        let report = report(
+            msg.id(),
+            request_id,
+            engine,
+            security_params.username(),
+            &USM_STATS_UNKNOWN_ENGINE_IDS,
+            engine.unknown_engine_ids,
+            false,
+        );
+        trace!("listener: engine discovery of {}", device_name);
+        let received = Received {
+            notification: None,
+            reply: Some(report.encode()),
+        };
+        return Ok((device_name.clone(), received));
+    }
+
+    // the collector only uses authPriv, so do its notifications
+    if !msg.is_auth() || !msg.is_private() {
+        bail!(
+            "message of {} is not authenticated and encrypted",
+            device_name
+        );
+    }
+
+    let timeliness = timeliness
+        .entry((device_name.clone(), security_params.engine_id().to_vec()))
+        .or_default();
+    let received = with_usm_types!(
+        device,
+        receive_authenticated(
+            &device.snmp,
+            msg,
+            &security_params,
+            message,
+            engine,
+            timeliness
+        )
+    )
+    .with_context(|| format!("from {}", device_name))?;
+    Ok((device_name.clone(), received))
+}
+
+async fn receive_authenticated<'a, D, P, S>(
+    snmp: &DeviceSnmpSettings,
+    mut msg: SnmpMsg,
+    security_params: &SecurityParams,
+    message: &mut [u8],
+    engine: &mut LocalEngine,
+    timeliness: &mut Timeliness,
+    salt: P::Salt,
+) -> Result<Received, Error>
+where
+    D: 'a + Digest,
+    P: PrivKey<Salt = S> + WithLocalizedKey<'a, D>,
+{
+    let (auth_key, priv_key) = localized_keys::<D, P>(snmp, security_params.engine_id());
+
+    // informs are sent to the collector as the authoritative engine, traps come from the device
+    let authoritative = security_params.engine_id() == engine.id.as_slice();
+    let (boots, time) = if authoritative {
+        (LocalEngine::BOOTS, engine.time())
+    } else {
+        timeliness.now()
+    };
+    match auth_key.auth_in_msg(message, &engine.id, boots, time) {
+        Ok(()) => {}
+        // the sender adjusts its notion of the collector's time from the report and resends
+        Err(SecurityError::NotInTimeWindow) if authoritative && msg.is_reportable() => {
+            let request_id = msg
+                .scoped_pdu_data
+                .plaintext()
+                .map_or(0, |pdu| pdu.request_id());
+            engine.not_in_time_windows = engine.not_in_time_windows.wrapping_add(1);
+            let report = report(
+                msg.id(),
+                request_id,
+                engine,
+                security_params.username(),
+                &USM_STATS_NOT_IN_TIME_WINDOWS,
+                engine.not_in_time_windows,
+                true,
+            );
+            let mut encoded = report.encode();
+            auth_key.auth_out_msg(&mut encoded)?;
+            return Ok(Received {
+                notification: None,
+                reply: Some(encoded),
+            });
+        }
+        Err(error) => bail!("authentication failed: {}", error),
+    }
+    if !authoritative {
+        timeliness.update(security_params);
+    }
+
+    let pdu = msg
+        .decrypt_scoped_pdu(|encrypted_scoped_pdu| {
+            priv_key.decrypt(encrypted_scoped_pdu, security_params).ok()
+        })?
+        .clone();
+
+    let reply = match pdu.pdu_type() {
+        PduType::SnmpTrap => None,
+        PduType::InformRequest => Some(inform_response(
+            &msg,
+            &pdu,
+            security_params,
+            engine,
+            &auth_key,
+            &priv_key,
+            salt,
+        )?),
+        other => bail!("unexpected {:?}", other),
+    };
+
+    Ok(Received {
+        notification: Some(pdu),
+        reply,
+    })
+}
+
+/// The acknowledgement of an inform, a Response with the var binds of the request
+fn inform_response<'a, D, P: PrivKey>(
+    msg: &SnmpMsg,
+    pdu: &ScopedPdu,
+    security_params: &SecurityParams,
+    engine: &LocalEngine,
+    auth_key: &AuthKey<'a, D>,
+    priv_key: &P,
+    salt: P::Salt,
+) -> Result<Vec<u8>, Error>
+where
+    D: 'a + Digest,
+{
+    let mut response_pdu = ScopedPdu::new(pdu.request_id());
+    response_pdu
+        .set_pdu_type(PduType::Response)
+        .set_engine_id(pdu.engine_id())
+        .set_context_name(pdu.context_name())
+        .set_var_binds(pdu.var_binds().iter().cloned());
+
+    let mut response = SnmpMsg::with_scoped_pdu(msg.id(), response_pdu);
+    response.set_auth_flag();
+    let mut response_security_params = SecurityParams::new();
+    response_security_params
+        .set_auth_params_placeholder()
+        .set_username(security_params.username())
+        .set_engine_id(&engine.id)
+        .set_engine_boots(LocalEngine::BOOTS)
+        .set_engine_time(engine.time());
+    response.encrypt_scoped_pdu(|encoded_scoped_pdu| {
+        let (encrypted_scoped_pdu, priv_params) =
+            priv_key.encrypt(encoded_scoped_pdu, &response_security_params, salt);
+        response_security_params.set_priv_params(&priv_params);
+        encrypted_scoped_pdu
+    });
+    response.set_security_params(&response_security_params.encode());
+
+    let mut encoded = response.encode();
+    auth_key.auth_out_msg(&mut encoded)?;
+    Ok(encoded)
+}
+
+/// A Report of the collector's engine with one usmStats counter, `authenticated` ones still
+/// have to be signed after encoding
+fn report(
+    msg_id: u32,
+    request_id: i32,
+    engine: &LocalEngine,
+    username: &[u8],
+    counter: &[u64],
+    value: u32,
+    authenticated: bool,
+) -> SnmpMsg {
+    let mut report_pdu = ScopedPdu::new(request_id);
+    report_pdu
+        .set_pdu_type(PduType::Report)
+        .set_engine_id(&engine.id)
+        .set_var_binds(vec![VarBind::with_value(
+            ObjectIdent::from_slice(counter),
+            VarValue::Counter(value),
+        )]);
+
+    let mut report = SnmpMsg::with_scoped_pdu(msg_id, report_pdu);
+    let mut security_params = SecurityParams::new();
+    security_params
+        .set_username(username)
+        .set_engine_id(&engine.id)
+        .set_engine_boots(LocalEngine::BOOTS)
+        .set_engine_time(engine.time());
+    if authenticated {
+        report.set_auth_flag();
+        security_params.set_auth_params_placeholder();
+    }
+    report.set_security_params(&security_params.encode());
+    report
+}
+
+/// The name of a notification and its numeric var binds as results. Var binds of a configured
+/// column are indexed like polled values of it, the instance is the notification name followed
+/// by the index, e.g. `IF-MIB::linkDown_3` for the ifOperStatus.3 of a link down trap.
+fn notification_results(
+    device_name: &str,
+    pdu: &ScopedPdu,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+    timestamp: SystemTime,
+) -> (String, Vec<SnmpStatResult>) {
+    let trap_oid = pdu.var_binds().iter().find_map(|var_bind| {
+        match (var_bind.name().components(), var_bind.value()) {
+            (name, VarValue::ObjectId(trap_oid)) if name == SNMP_TRAP_OID => Some(trap_oid),
+            _ => None,
+        }
+    });
+    let name = match trap_oid {
+        Some(trap_oid) => oid_var_bind_map
+            .iter()
+            .find(|(_, var_bind)| var_bind.name() == trap_oid)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| dotted(trap_oid.components())),
+        None => "unknown".to_string(),
+    };
+
+    let results = pdu
+        .var_binds()
+        .iter()
+        .filter(|var_bind| {
+            let oid = var_bind.name().components();
+            oid != SYS_UPTIME_OID && oid != SNMP_TRAP_OID
+        })
+        .filter(|var_bind| snmp::var_bind_to_metric_value((*var_bind).clone()).is_some())
+        .map(|var_bind| {
+            let oid = var_bind.name().components();
+            let column_len = oid_var_bind_map
+                .values()
+                .map(|column| column.name().components())
+                .filter(|column| column.len() < oid.len() && oid.starts_with(column))
+                .map(|column| column.len())
+                .max()
+                .unwrap_or(oid.len());
+            let index = oid[column_len..].to_vec();
+            let instance = if index.is_empty() {
+                name.clone()
+            } else {
+                format!("{}_{}", name, mib_index::format_index(&index, None))
+            };
+            SnmpStatResult {
+                device: device_name.to_string(),
+                endpoint: None,
+                timestamp,
+                key: VarBind::with_value(
+                    ObjectIdent::from_slice(&SNMP_TRAP_OID),
+                    VarValue::String(instance.into_bytes()),
+                ),
+                value: var_bind.clone(),
+                index,
+            }
+        })
+        .collect();
+    (name, results)
+}
+
+fn dotted(components: &[u64]) -> String {
+    components
+        .iter()
+        .map(|component| component.to_string())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IF_OPER_STATUS: [u64; 10] = [1, 3, 6, 1, 2, 1, 2, 2, 1, 8];
+    const LINK_DOWN: [u64; 10] = [1, 3, 6, 1, 6, 3, 1, 1, 5, 3];
+    const DEVICE_ENGINE_ID: &[u8] = b"\x80\x00\x1f\x88\x04device";
+
+    type Salt = <Aes128PrivKey<'static, Sha1> as PrivKey>::Salt;
+
+    fn snmp_settings() -> DeviceSnmpSettings {
+        serde_yaml::from_str(
+            r#"
+host: 192.0.2.1
+version: "3"
+secname: snmp_user
+authprotocol: SHA
+authpassword: authpassword
+privprotocol: AES
+privpassword: privpassword
+"#,
+        )
+        .unwrap()
+    }
+
+    fn link_down_var_binds() -> Vec<VarBind> {
+        vec![
+            VarBind::with_value(
+                ObjectIdent::from_slice(&SYS_UPTIME_OID),
+                VarValue::TimeTicks(4200),
+            ),
+            VarBind::with_value(
+                ObjectIdent::from_slice(&SNMP_TRAP_OID),
+                VarValue::ObjectId(ObjectIdent::from_slice(&LINK_DOWN)),
+            ),
+            VarBind::with_value(
+                ObjectIdent::from_slice(&[&IF_OPER_STATUS[..], &[3]].concat()),
+                VarValue::Int(2),
+            ),
+        ]
+    }
+
+    /// A notification of `pdu_type` encoded by a sender with the engine `engine_id` and the
+    /// boots and time it believes that engine has
+    fn notification(
+        snmp: &DeviceSnmpSettings,
+        pdu_type: PduType,
+        engine_id: &[u8],
+        boots: u32,
+        time: u32,
+    ) -> Vec<u8> {
+        let (auth_key, priv_key) = localized_keys::<Sha1, Aes128PrivKey<Sha1>>(snmp, engine_id);
+        let mut pdu = ScopedPdu::new(7);
+        pdu.set_pdu_type(pdu_type)
+            .set_engine_id(engine_id)
+            .set_var_binds(link_down_var_binds());
+        let mut msg = SnmpMsg::with_scoped_pdu(42, pdu);
+        msg.set_auth_flag();
+        if pdu_type == PduType::InformRequest {
+            msg.set_reportable_flag();
+        }
+        let mut security_params = SecurityParams::new();
+        security_params
+            .set_auth_params_placeholder()
+            .set_username(snmp.secname.as_bytes())
+            .set_engine_id(engine_id)
+            .set_engine_boots(boots)
+            .set_engine_time(time);
+        msg.encrypt_scoped_pdu(|encoded_scoped_pdu| {
+            let (encrypted_scoped_pdu, priv_params) =
+                priv_key.encrypt(encoded_scoped_pdu, &security_params, 1);
+            security_params.set_priv_params(&priv_params);
+            encrypted_scoped_pdu
+        });
+        msg.set_security_params(&security_params.encode());
+        let mut encoded = msg.encode();
+        auth_key.auth_out_msg(&mut encoded).unwrap();
+        encoded
+    }
+
+    /// An inform encoded by a sender with the time it believes the collector has
+    fn inform(snmp: &DeviceSnmpSettings, engine: &LocalEngine, boots: u32, time: u32) -> Vec<u8> {
+        notification(snmp, PduType::InformRequest, &engine.id, boots, time)
+    }
+
+    async fn receive_message(
+        snmp: &DeviceSnmpSettings,
+        engine: &mut LocalEngine,
+        timeliness: &mut Timeliness,
+        mut message: Vec<u8>,
+    ) -> Result<Received, Error> {
+        let msg = SnmpMsg::decode(&message).unwrap();
+        let security_params = SecurityParams::decode(msg.security_params()).unwrap();
+        receive_authenticated::<Sha1, Aes128PrivKey<Sha1>, Salt>(
+            snmp,
+            msg,
+            &security_params,
+            &mut message,
+            engine,
+            timeliness,
+            2,
+        )
+        .await
+    }
+
+    async fn receive_inform(
+        snmp: &DeviceSnmpSettings,
+        engine: &mut LocalEngine,
+        message: Vec<u8>,
+    ) -> Received {
+        receive_message(snmp, engine, &mut Timeliness::default(), message)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_traps_of_the_device_engine() {
+        let snmp = snmp_settings();
+        let mut engine = LocalEngine::new();
+        let mut timeliness = Timeliness::default();
+        let message = notification(&snmp, PduType::SnmpTrap, DEVICE_ENGINE_ID, 3, 5000);
+        let received = receive_message(&snmp, &mut engine, &mut timeliness, message)
+            .await
+            .unwrap();
+
+        let notification_pdu = received.notification.unwrap();
+        assert_eq!(notification_pdu.pdu_type(), PduType::SnmpTrap);
+        assert_eq!(notification_pdu.var_binds(), &link_down_var_binds()[..]);
+        // traps are not acknowledged
+        assert!(received.reply.is_none());
+        assert_eq!(timeliness.now(), (3, 5000));
+
+        // a trap from before the latest reboot of the device is replayed
+        let message = notification(&snmp, PduType::SnmpTrap, DEVICE_ENGINE_ID, 2, 6000);
+        let error = receive_message(&snmp, &mut engine, &mut timeliness, message)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("not in time window"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_notifications_with_another_key() {
+        let snmp = snmp_settings();
+        let mut engine = LocalEngine::new();
+        let mut sender = snmp_settings();
+        sender.authpassword = "otherpassword".to_string();
+
+        for message in [
+            notification(&sender, PduType::SnmpTrap, DEVICE_ENGINE_ID, 3, 5000),
+            inform(&sender, &engine, LocalEngine::BOOTS, engine.time()),
+        ] {
+            let error = receive_message(&snmp, &mut engine, &mut Timeliness::default(), message)
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                error
+                    .to_string()
+                    .contains("wrong authentication parameters"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledges_informs() {
+        let snmp = snmp_settings();
+        let mut engine = LocalEngine::new();
+        let message = inform(&snmp, &engine, LocalEngine::BOOTS, engine.time());
+        let received = receive_inform(&snmp, &mut engine, message).await;
+
+        let notification = received.notification.unwrap();
+        assert_eq!(notification.pdu_type(), PduType::InformRequest);
+        assert_eq!(notification.var_binds(), &link_down_var_binds()[..]);
+
+        // the response is authenticated and encrypted by the collector as authoritative engine
+        let (auth_key, priv_key) = localized_keys::<Sha1, Aes128PrivKey<Sha1>>(&snmp, &engine.id);
+        let mut reply = received.reply.unwrap();
+        auth_key
+            .auth_in_msg(&mut reply, &[], LocalEngine::BOOTS, 0)
+            .unwrap();
+        let mut response = SnmpMsg::decode(&reply).unwrap();
+        assert_eq!(response.id(), 42);
+        let security_params = SecurityParams::decode(response.security_params()).unwrap();
+        assert_eq!(security_params.engine_id(), engine.id.as_slice());
+        let pdu = response
+            .decrypt_scoped_pdu(|encrypted_scoped_pdu| {
+                priv_key
+                    .decrypt(encrypted_scoped_pdu, &security_params)
+                    .ok()
+            })
+            .unwrap();
+        assert_eq!(pdu.pdu_type(), PduType::Response);
+        assert_eq!(pdu.request_id(), 7);
+        assert_eq!(pdu.var_binds(), &link_down_var_binds()[..]);
+    }
+
+    #[tokio::test]
+    async fn reports_informs_outside_the_time_window() {
+        let snmp = snmp_settings();
+        let mut engine = LocalEngine::new();
+        let message = inform(&snmp, &engine, 0, 0);
+        let received = receive_inform(&snmp, &mut engine, message).await;
+        assert!(received.notification.is_none());
+
+        let report = SnmpMsg::decode(&received.reply.unwrap()).unwrap();
+        assert!(report.is_auth());
+        assert!(!report.is_private());
+        let security_params = SecurityParams::decode(report.security_params()).unwrap();
+        assert_eq!(security_params.engine_boots(), LocalEngine::BOOTS);
+        let pdu = report.scoped_pdu_data.plaintext().unwrap();
+        assert_eq!(pdu.pdu_type(), PduType::Report);
+        assert_eq!(
+            pdu.var_binds()[0].name().components(),
+            USM_STATS_NOT_IN_TIME_WINDOWS
+        );
+    }
+
+    #[test]
+    fn indexes_values_of_configured_columns() {
+        let oid_var_bind_map: HashMap<String, VarBind> = vec![
+            ("IF-MIB::ifOperStatus", &IF_OPER_STATUS[..]),
+            ("IF-MIB::linkDown", &LINK_DOWN[..]),
+        ]
+        .into_iter()
+        .map(|(name, oid)| (name.to_string(), VarBind::new(ObjectIdent::from_slice(oid))))
+        .collect();
+        let mut pdu = ScopedPdu::new(1);
+        pdu.set_pdu_type(PduType::SnmpTrap)
+            .set_var_binds(link_down_var_binds());
+
+        let (name, results) =
+            notification_results("device", &pdu, &oid_var_bind_map, SystemTime::now());
+        assert_eq!(name, "IF-MIB::linkDown");
+        // sysUpTime.0 and snmpTrapOID.0 only describe the notification
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].index, vec![3]);
+        assert_eq!(
+            results[0].key.value(),
+            &VarValue::String(b"IF-MIB::linkDown_3".to_vec())
+        );
+        assert_eq!(results[0].value.value(), &VarValue::Int(2));
+    }
+}
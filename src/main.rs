@@ -3,33 +3,70 @@
 use flume::unbounded;
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::io::Write;
 use std::iter::Iterator;
-use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::task::JoinSet;
 
 use anyhow::{bail, Error, Result};
 use clap::Parser;
 use log::{debug, info, trace, warn};
-use scan_dir::ScanDir;
+use regex::Regex;
 
-use snmp_mp::VarBind;
+use snmp_mp::{VarBind, VarValue};
 
 mod cli;
+mod client;
 mod collector;
 mod config;
+mod control;
+mod expr;
+mod inventory;
+mod listener;
+mod mib_index;
+mod mibs;
 mod output;
 mod snmp;
 mod stat_result;
+mod systemd;
+mod watchdog;
 
 use cli::{Command, Opts};
-use collector::collect_device_safe;
-use output::{carbon_send_safe, CarbonMetricValue};
-use snmp::vec_to_var_binds;
+use collector::{check_device, collect_device_safe};
+use mibs::ResolvedMibs;
+use output::{CarbonMetricValue, MetricSource};
+use snmp::SnmpErrorClass;
+use stat_result::SnmpStatResult;
+
+/// How long `Run --cycles` waits for the outputs to drain their queues before exiting
+const OUTPUT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() -> Result<(), Error> {
-    env_logger::init();
+    // lines carry the instance id once the configuration has been loaded
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| match output::instance_id() {
+            Some(instance_id) => writeln!(
+                buf,
+                "[{} {:<5} {} instance={}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                instance_id,
+                record.args()
+            ),
+            None => writeln!(
+                buf,
+                "[{} {:<5} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        })
+        .init();
 
     // handle commandline arguments
     let cli: Opts = Opts::parse();
@@ -38,23 +75,67 @@ fn main() -> Result<(), Error> {
     let cli_config_test = Command::ConfigTest == cli.command;
     let cli_mib_test = Command::MibTest == cli.command;
     let cli_preflight_check = Command::PreflightCheck == cli.command;
+    let max_cycles = match cli.command {
+        Command::Run { cycles } => cycles,
+        _ => None,
+    };
+    let listen_bind = match &cli.command {
+        Command::Listen { bind } => Some(bind.clone()),
+        _ => None,
+    };
 
     // do stuff FIXME
-    let config = if let Some(config_file_path) = cli.config {
-        // load configuration from a single file
-        config::from_file(&config_file_path)?
-    } else if let Some(config_directory_path) = cli.config_dir {
-        // combine the configuration from multiple files
-        config::from_directory(&config_directory_path)?
-    } else {
-        bail!("Bug in configuration loading logic")
+    // also used to fetch the inventory again on SIGHUP
+    let load_config = {
+        let (config_file, config_dir, env) =
+            (cli.config.clone(), cli.config_dir.clone(), cli.env.clone());
+        move || -> Result<config::Config, Error> {
+            if let Some(config_file_path) = &config_file {
+                // load configuration from a single file
+                config::from_file(config_file_path, env.as_deref())
+            } else if let Some(config_directory_path) = &config_dir {
+                // combine the configuration from multiple files
+                config::from_directory(config_directory_path, env.as_deref())
+            } else {
+                bail!("Bug in configuration loading logic")
+            }
+        }
     };
+    let config = load_config()?;
 
     let config = Arc::new(config);
 
+    output::set_instance_id(
+        config
+            .main
+            .instance_id
+            .clone()
+            .unwrap_or_else(default_instance_id),
+    );
+    debug!(
+        "main: collector instance id is {}",
+        output::instance_id().unwrap()
+    );
+
     debug!("validating config");
     // validated configuration
     for (device_name, device) in config.devices.iter() {
+        if let Some(offset_secs) = device.offset_secs {
+            if offset_secs >= device.interval.into() {
+                bail!(
+                    "offset_secs {} of device '{}' is not shorter than its interval {}",
+                    offset_secs,
+                    device_name,
+                    device.interval
+                );
+            }
+        }
+        if device.collect.is_empty() && device.enabled {
+            warn!(
+                "config(device={}): no collectors, neither in collect nor in its template",
+                device_name
+            );
+        }
         for collector in &device.collect {
             if !config.data.contains_key(collector) {
                 bail!(
@@ -64,12 +145,252 @@ fn main() -> Result<(), Error> {
                 );
             }
         }
+        if let Some(bind_address) = device.snmp.bind_address {
+            if let Err(error) = std::net::UdpSocket::bind((bind_address, 0)) {
+                bail!(
+                    "bind_address {} of device '{}' is not a local address: {}",
+                    bind_address,
+                    device_name,
+                    error
+                );
+            }
+        }
+    }
+    // the index format is looked up by instance column when building keys
+    let mut index_formats: HashMap<String, config::IndexFormat> = HashMap::new();
+    // expressions applied to values, by instance and value column
+    let mut value_exprs: HashMap<String, HashMap<String, expr::Expr>> = HashMap::new();
+    // plausible ranges of values, by instance and value column
+    let mut value_bounds: HashMap<String, HashMap<String, config::ValueBounds>> = HashMap::new();
+    // instance columns whose names are followed by the row index in the key
+    let mut keys_with_index: HashMap<String, bool> = HashMap::new();
+    // value columns whose strings are parsed as numbers, by instance
+    let mut string_numbers: HashMap<String, HashSet<String>> = HashMap::new();
+    for (data_name, data) in config.data.iter() {
+        if let Some(index_format) = data.index_format {
+            match index_formats.insert(data.instance.clone(), index_format) {
+                Some(other) if other != index_format => bail!(
+                    "Conflicting index_format for instance {} in collector '{}': {:?} vs {:?}",
+                    data.instance,
+                    data_name,
+                    other,
+                    index_format
+                ),
+                _ => {}
+            }
+        }
+        // the rows of an instance column are keyed the same way for all of its collectors
+        match keys_with_index.insert(data.instance.clone(), data.key_with_index) {
+            Some(other) if other != data.key_with_index => bail!(
+                "Conflicting key_with_index for instance {} in collector '{}'",
+                data.instance,
+                data_name
+            ),
+            _ => {}
+        }
+        if data.key_with_index && data.index_format.is_some() {
+            bail!(
+                "Collector '{}' sets both key_with_index and index_format, the key would hold the index twice",
+                data_name
+            );
+        }
+        if let Some(select_by) = &data.select_by {
+            if let Err(error) = Regex::new(&select_by.regex) {
+                bail!(
+                    "Invalid select_by regex in collector '{}': {}",
+                    data_name,
+                    error
+                );
+            }
+        }
+        if data.max_rows == Some(0) {
+            bail!("max_rows of collector '{}' must be at least 1", data_name);
+        }
+        if data.values.iter().any(|value| value.oids().is_empty()) {
+            bail!("Empty list of values in collector '{}'", data_name);
+        }
+        if !data.table
+            && (data.select_by.is_some()
+                || data.discontinuity.is_some()
+                || data.index_format.is_some()
+                || !data.exclude_indices.is_empty()
+                || !data.start_index.is_empty()
+                || data.on_empty != config::OnEmpty::Ignore
+                || data.join_by.is_some()
+                || data.max_rows.is_some()
+                || data.key_with_index)
+        {
+            bail!(
+                "Collector '{}' collects scalars (table: false), its options only apply to tables",
+                data_name
+            );
+        }
+        for value in &data.values {
+            if value.parse_string_as_number() {
+                string_numbers
+                    .entry(data.instance.clone())
+                    .or_default()
+                    .insert(value.name().to_string());
+            }
+            if let Some(source) = value.expr() {
+                let value_expr = match expr::Expr::parse(source) {
+                    Ok(value_expr) => value_expr,
+                    Err(error) => bail!(
+                        "Invalid expr '{}' of {} in collector '{}': {}",
+                        source,
+                        value.name(),
+                        data_name,
+                        error
+                    ),
+                };
+                // values are collected once per instance column, whichever collector they are in
+                match value_exprs
+                    .entry(data.instance.clone())
+                    .or_default()
+                    .insert(value.name().to_string(), value_expr.clone())
+                {
+                    Some(other) if other != value_expr => bail!(
+                        "Conflicting expr for {} of instance {} in collector '{}'",
+                        value.name(),
+                        data.instance,
+                        data_name
+                    ),
+                    _ => {}
+                }
+            }
+            if let Some(bounds) = value.bounds() {
+                if let (Some(min), Some(max)) = (bounds.min, bounds.max) {
+                    if min > max {
+                        bail!(
+                            "min {} of {} in collector '{}' is not below its max {}",
+                            min,
+                            value.name(),
+                            data_name,
+                            max
+                        );
+                    }
+                }
+                match value_bounds
+                    .entry(data.instance.clone())
+                    .or_default()
+                    .insert(value.name().to_string(), bounds)
+                {
+                    Some(other) if other != bounds => bail!(
+                        "Conflicting min, max or out_of_range for {} of instance {} in collector '{}'",
+                        value.name(),
+                        data.instance,
+                        data_name
+                    ),
+                    _ => {}
+                }
+            }
+        }
+    }
+    for (output_id, output) in config.outputs.iter().enumerate() {
+        if let Err(error) = output::KeyFilter::new(output.filter()) {
+            bail!(
+                "Invalid allow or deny pattern in output {} ({}): {}",
+                output_id,
+                output.name(),
+                error
+            );
+        }
+        if let Some((feature, false)) = output.feature() {
+            bail!(
+                "Output {} ({}) needs the {} feature, which this build does not include",
+                output_id,
+                output.name(),
+                feature
+            );
+        }
+        if let config::Output::Carbon(settings) = output {
+            if settings.prefix.all().is_empty() {
+                bail!("Empty list of prefixes in output {} (carbon)", output_id);
+            }
+        }
+        if let config::Output::GraphiteHttp(settings) = output {
+            if settings.flush_interval_secs == 0 || settings.max_metrics_per_request == 0 {
+                bail!(
+                    "flush_interval_secs and max_metrics_per_request of output {} (graphite_http) must be at least 1",
+                    output_id
+                );
+            }
+        }
+    }
+    let metric_aliases = match output::MetricAliases::new(&config.main.metric_aliases) {
+        Ok(metric_aliases) => metric_aliases,
+        Err(error) => bail!("Invalid metric_aliases: {}", error),
+    };
+    if config.main.max_rows == Some(0) {
+        bail!("max_rows must be at least 1");
+    }
+    if config.main.initial_burst && config.main.startup_rate.is_none() {
+        info!("main: initial_burst without startup_rate polls every device at once");
+    }
+    if config.main.startup_rate == Some(0) {
+        bail!("startup_rate must be at least 1 device per second");
+    }
+    if let Some(watchdog_intervals) = config.main.watchdog_intervals {
+        // a cycle may take up to an interval, and is followed by the sleep until the next one
+        if watchdog_intervals < 2.0 {
+            bail!(
+                "watchdog_intervals must be at least 2, got {}",
+                watchdog_intervals
+            );
+        }
+    }
+    if let Some(max_metric_len) = config.main.max_metric_len {
+        if max_metric_len < output::MIN_METRIC_LEN {
+            bail!(
+                "max_metric_len must be at least {}, got {}",
+                output::MIN_METRIC_LEN,
+                max_metric_len
+            );
+        }
+    }
+    for mib_dir in config.mib_dirs.iter().flatten() {
+        if !std::path::Path::new(mib_dir).is_dir() {
+            warn!("config: mib_dirs entry {} is not a directory", mib_dir);
+        }
     }
     debug!(
         "config: validation successful, loaded {} devices",
         config.devices.len()
     );
 
+    if let Command::DumpConfig { show_secrets } = cli.command {
+        print!("{}", config.to_yaml(show_secrets)?);
+        return Ok(());
+    }
+    if cli.command == Command::ConfigRoundtrip {
+        let differences = config.roundtrip_differences()?;
+        if !differences.is_empty() {
+            bail!(
+                "config: {} fields change when the configuration is serialized and loaded again: {}",
+                differences.len(),
+                differences.join(", ")
+            );
+        }
+        println!("Config roundtrip is OK");
+        return Ok(());
+    }
+
+    let thread_prefix = config
+        .main
+        .thread_prefix
+        .clone()
+        .unwrap_or_else(|| DEFAULT_THREAD_PREFIX.to_string());
+    if cli.command == Command::ListThreads {
+        for (name, target) in task_names(&thread_prefix, &config) {
+            println!("{}\t{}", name, target);
+        }
+        println!(
+            "{}\ttokio runtime threads",
+            thread_name(&thread_prefix, "#<n>")
+        );
+        return Ok(());
+    }
+
     if cli_config_test {
         debug!("Configtest succeeded");
         println!("Config is OK");
@@ -82,74 +403,142 @@ fn main() -> Result<(), Error> {
         .split(':')
         .map(|s| s.to_string())
         .collect();
+    required_mibs.extend(config.extra_mibs.iter().cloned());
 
     let mut required_oids: HashSet<String> = HashSet::new();
     for data in config.data.values() {
         required_oids.insert(data.instance.to_string());
         required_mibs.insert(data.instance.split("::").nth(0).unwrap().to_string());
-        for value in &data.values {
+        for value in data
+            .values
+            .iter()
+            .flat_map(|value| value.oids())
+            .chain(data.discontinuity.iter())
+            .chain(data.select_by.iter().map(|select_by| &select_by.column))
+            .chain(data.join_by.iter())
+        {
             required_oids.insert(value.to_string());
             required_mibs.insert(value.split("::").nth(0).unwrap().to_string());
         }
     }
+    for oid in config
+        .main
+        .device_metadata
+        .iter()
+        .flat_map(|metadata| &metadata.oids)
+    {
+        required_oids.insert(oid.to_string());
+        required_mibs.insert(oid.split("::").nth(0).unwrap().to_string());
+    }
 
     let required_mibs = required_mibs;
     debug!("config: required mibs = {:?}", required_mibs);
 
-    let mibdirs: Vec<String> = env::var("MIBDIRS")
-        .unwrap_or_else(|_| "/var/lib/snmp/mibs:/usr/share/mibs:/usr/share/snmp/mibs".to_string())
-        .split(':')
-        .map(|s| s.to_string())
-        .collect();
+    let mibdirs: Vec<String> = match &config.mib_dirs {
+        Some(mib_dirs) => mib_dirs.clone(),
+        None => env::var("MIBDIRS")
+            .unwrap_or_else(|_| {
+                "/var/lib/snmp/mibs:/usr/share/mibs:/usr/share/snmp/mibs".to_string()
+            })
+            .split(':')
+            .map(|s| s.to_string())
+            .collect(),
+    };
     debug!("mibs: MIBDIRS={:?}", mibdirs);
 
-    debug!("mibs: loading required_mibs");
-    trace!("mibs: required_mibs = {:?}", required_mibs);
-    let mut mibs: Vec<mib_parser::MibInfo> = vec![];
-    let mib_parse_options = mib_parser::ParseOptions {
-        pretty_print: false,
-    };
-    for mibdir in mibdirs {
-        if !Path::new(&mibdir).is_dir() {
-            debug!("mibs: mibdir {} does not exist, skipping", mibdir);
-            continue;
-        }
-
-        let _: Vec<_> = ScanDir::files()
-            .walk(mibdir, |iter| {
-                iter.filter(|(_, name)| required_mibs.contains(name.split('.').nth(0).unwrap()))
-                    .map(|(ref entry, _)| {
-                        // load mib
-                        debug!("mibs: parsing {:?}", entry.path());
-                        mibs.push(
-                            mib_parser::parse_file(&entry.path(), &mib_parse_options).unwrap(),
-                        );
-                    })
-                    .collect()
-            })
-            .unwrap();
+    if let Command::ResolveOid { oid } = &cli.command {
+        let module = match oid.split_once("::") {
+            Some((module, field)) if !module.is_empty() && !field.is_empty() => module,
+            _ => bail!("Expected MODULE::field, got '{}'", oid),
+        };
+        let mut required_mibs = required_mibs.clone();
+        required_mibs.insert(module.to_string());
+        let mib_files = mibs::find_mib_files(&mibdirs, &required_mibs);
+        let (full_oid, import_chain) = mibs::resolve_oid(&mib_files, &required_mibs, oid)?;
+        println!(
+            "{} = {}",
+            oid,
+            full_oid
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<String>>()
+                .join(".")
+        );
+        for (depth, name) in import_chain.iter().enumerate().skip(1) {
+            println!("{}imported as {}", "  ".repeat(depth), name);
+        }
+        return Ok(());
     }
-    let mibs = mibs;
 
-    if mibs.len() != required_mibs.len() {
-        let mut mibs_found = HashSet::new();
-        for mib in mibs {
-            mibs_found.insert(mib.modules.first().unwrap().name.clone());
+    if let Command::Walk { device, oid } = &cli.command {
+        if !config.devices.contains_key(device) {
+            bail!("Unknown device {}", device);
         }
-        trace!("mibs: mibs_found={:?}", mibs_found);
-        bail!(
-            "Could not find some required mibs: {:?}",
-            required_mibs.difference(&mibs_found)
-        );
+        let full_oid = match oid.split_once("::") {
+            Some((module, _)) => {
+                let mut required_mibs = required_mibs.clone();
+                required_mibs.insert(module.to_string());
+                let mib_files = mibs::find_mib_files(&mibdirs, &required_mibs);
+                mibs::resolve_oid(&mib_files, &required_mibs, oid)?.0
+            }
+            None => match oid
+                .trim_start_matches('.')
+                .split('.')
+                .map(|component| component.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+            {
+                Ok(full_oid) if full_oid.len() >= 2 => full_oid,
+                _ => bail!("Expected MODULE::field or a numeric oid, got '{}'", oid),
+            },
+        };
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let var_binds = rt.block_on(collector::walk_device(
+            device.clone(),
+            config.clone(),
+            full_oid,
+        ))?;
+        for (_, var_bind) in &var_binds {
+            println!(
+                "{} = {}",
+                var_bind.name(),
+                msnmp::format_var_bind::format_var_value(var_bind.value())
+            );
+        }
+        debug!("main: walk of {} returned {} values", oid, var_binds.len());
+        return Ok(());
     }
 
-    let mut oid_var_bind_map: HashMap<String, VarBind> = HashMap::new();
+    let instances: HashSet<String> = config
+        .data
+        .values()
+        .map(|data| data.instance.to_string())
+        .collect();
+    let mib_files = mibs::find_mib_files(&mibdirs, &required_mibs);
+    let mib_cache = mibs::MibCacheOptions {
+        path: cli.mib_cache.as_deref(),
+        rebuild: cli.rebuild_mib_cache,
+    };
+    let mibs_start = Instant::now();
+    let ResolvedMibs {
+        oid_var_bind_map,
+        index_parts_map,
+        display_hints,
+        index_names,
+    } = mibs::load(
+        &mib_files,
+        &required_mibs,
+        &required_oids,
+        &instances,
+        &mib_cache,
+    )?;
 
-    for oid in required_oids {
-        let full_oid = snmp::build_snmp_mib_tree(&oid, &mibs)?;
+    check_value_tables(&config, &oid_var_bind_map, &index_names)?;
 
-        debug!("mibs: resolved {} to {:?}", oid, full_oid);
-        oid_var_bind_map.insert(oid, vec_to_var_binds(full_oid));
+    if let Command::Benchmark { rows } = cli.command {
+        return benchmark(&config, &oid_var_bind_map, mibs_start.elapsed(), rows);
     }
 
     if cli_mib_test {
@@ -164,20 +553,55 @@ fn main() -> Result<(), Error> {
         return Ok(());
     }
 
+    if cli.command == Command::ConnectivityCheck {
+        return connectivity_check(config);
+    }
+
+    if let Command::AuditRequests { device } = &cli.command {
+        return audit_requests(config, oid_var_bind_map, device);
+    }
+
+    if let Command::ShowPlan { device } = &cli.command {
+        return show_plan(&config, &oid_var_bind_map, device);
+    }
+
     // TODO: generate this with the same code that is used in collector::collect_device
-    if cli.command == Command::ShowOutputKeys {
+    if let Command::ShowOutputKeys { include_disabled } = cli.command {
         for (device_name, device) in &config.devices {
+            if !device.enabled && !include_disabled {
+                continue;
+            }
             for collector in &device.collect {
                 let collector_def = config.data.get(collector).unwrap();
                 for collector_value in &collector_def.values {
                     // let collector_value.replace("::", ".");
 
-                    let key = output::format_key(
-                        device_name,
-                        &format!("<{}>", collector_def.instance),
-                        collector_value.split("::").nth(1).unwrap(),
+                    let instance = if collector_def.key_with_index {
+                        format!("<{}>_<index>", collector_def.instance)
+                    } else {
+                        format!("<{}>", collector_def.instance)
+                    };
+                    let metric_name = metric_aliases.apply(
+                        collector_value.name(),
+                        &snmp::field_name(collector_value.name()),
                     );
-                    println!("{}", key);
+                    if device.snmp.endpoints.is_empty() {
+                        println!(
+                            "{}",
+                            output::format_key(device_name, &instance, &metric_name)
+                        );
+                    }
+                    for endpoint in &device.snmp.endpoints {
+                        println!(
+                            "{}",
+                            output::format_endpoint_key(
+                                device_name,
+                                &endpoint.name,
+                                &instance,
+                                &metric_name
+                            )
+                        );
+                    }
                 }
             }
         }
@@ -219,10 +643,13 @@ fn main() -> Result<(), Error> {
     };
 
     let rt = rt
-        .thread_name_fn(|| {
-            static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
-            let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
-            format!("snmpc-rs#{}", id)
+        .thread_name_fn({
+            let thread_prefix = thread_prefix.clone();
+            move || {
+                static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
+                let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
+                thread_name(&thread_prefix, &format!("#{}", id))
+            }
         })
         .enable_all()
         .build()?;
@@ -232,74 +659,333 @@ fn main() -> Result<(), Error> {
         // set up channel where we communicate SnmpStatResults
         let (snmp_chan_sender, snmp_chan_receiver) = unbounded();
 
+        // carbon channel, also used by the collection threads for self metrics
+        let (carbon_chan_sender, carbon_chan_receiver) = unbounded();
+
+        if config
+            .devices
+            .keys()
+            .any(|device_name| thread_prefix.len() + 1 + device_name.len() > MAX_THREAD_NAME_LEN)
+        {
+            info!("main: some task names have been shortened, list-threads shows their devices");
+        }
+
         // start collection threads, one per device
-        for (device_name, _) in config.devices.iter() {
-            let device_name = device_name.clone();
+        let mut devices_started = 0;
+        let mut device_tasks = vec![];
+        let mut triggers = HashMap::new();
+        // the startup ramp takes the devices by name
+        let mut startup_order: Vec<&String> = config
+            .devices
+            .iter()
+            .filter(|(_, device)| device.enabled)
+            .map(|(device_name, _)| device_name)
+            .collect();
+        startup_order.sort();
+        // also used by the watchdog to replace the task of a stuck device
+        let spawn_device = {
             let config = config.clone();
             let oid_var_bind_map = oid_var_bind_map.clone();
             let snmp_chan_sender = snmp_chan_sender.clone();
+            let carbon_chan_sender = carbon_chan_sender.clone();
+            let thread_prefix = thread_prefix.clone();
+            move |device_name: &str,
+                  trigger: flume::Receiver<()>,
+                  startup_slot: Option<collector::StartupSlot>,
+                  progress: collector::Progress|
+                  -> Result<tokio::task::JoinHandle<collector::CycleSummary>, Error> {
+                let task = tokio::task::Builder::new()
+                    .name(&thread_name(&thread_prefix, &format!(":{}", device_name)))
+                    .spawn(collect_device_safe(
+                        device_name.to_string(),
+                        collector::DeviceContext {
+                            config: config.clone(),
+                            oid_var_bind_map: oid_var_bind_map.clone(),
+                            channel: snmp_chan_sender.clone(),
+                            metric_channel: carbon_chan_sender.clone(),
+                            max_cycles,
+                            trigger,
+                            progress,
+                        },
+                        startup_slot,
+                    ))?;
+                Ok(task)
+            }
+        };
+        for (device_name, device) in config.devices.iter() {
+            if !device.enabled {
+                info!("main: device {} is disabled, skipping", device_name);
+                continue;
+            }
+            devices_started += 1;
+            let startup_slot = config.main.startup_rate.map(|rate| collector::StartupSlot {
+                position: startup_order.binary_search(&device_name).unwrap(),
+                total: startup_order.len(),
+                rate,
+            });
+            // a single pending request is enough to cut the sleep short
+            let (trigger_sender, trigger) = flume::bounded(1);
+            triggers.insert(device_name.clone(), trigger_sender);
             // one thread per device
+            let progress = collector::Progress::default();
+            let handle =
+                spawn_device(device_name, trigger.clone(), startup_slot, progress.clone())?;
+            device_tasks.push(watchdog::DeviceTask {
+                device_name: device_name.clone(),
+                handle,
+                progress,
+                trigger,
+            });
+        }
+        let device_progress: watchdog::DeviceProgress = Arc::new(RwLock::new(
+            device_tasks
+                .iter()
+                .map(|task| (task.device_name.clone(), task.progress.clone()))
+                .collect(),
+        ));
+        tokio::task::Builder::new()
+            .name(&thread_name(&thread_prefix, ":systemd"))
+            .spawn({
+                let config = config.clone();
+                let device_progress = device_progress.clone();
+                async move {
+                    if let Err(error) = systemd::notify(config, device_progress).await {
+                        warn!("main: systemd notification failed: {}", error);
+                    }
+                }
+            })?;
+        tokio::task::Builder::new()
+            .name(&thread_name(&thread_prefix, ":inventory"))
+            .spawn({
+                let config = config.clone();
+                async move {
+                    if let Err(error) = inventory::refetch_on_hangup(config, load_config).await {
+                        warn!("main: SIGHUP handler failed: {}", error);
+                    }
+                }
+            })?;
+        // a run limited by --cycles ends on its own, the watchdog would keep it going
+        match config.main.watchdog_intervals {
+            Some(intervals) if max_cycles.is_none() => {
+                let tasks = std::mem::take(&mut device_tasks);
+                tokio::task::Builder::new()
+                    .name(&thread_name(&thread_prefix, ":watchdog"))
+                    .spawn({
+                        let config = config.clone();
+                        let carbon_chan_sender = carbon_chan_sender.clone();
+                        async move {
+                            let respawn = move |device_name: &str, trigger, progress| {
+                                spawn_device(device_name, trigger, None, progress)
+                            };
+                            if let Err(error) = watchdog::watch(
+                                config,
+                                tasks,
+                                intervals,
+                                respawn,
+                                device_progress,
+                                carbon_chan_sender,
+                            )
+                            .await
+                            {
+                                warn!("main: watchdog failed: {}", error);
+                            }
+                        }
+                    })?;
+            }
+            _ => drop(spawn_device),
+        }
+        // notifications go the way of polled values, the result channel stays connected
+        if let Some(bind) = listen_bind {
             tokio::task::Builder::new()
-                .name(format!("collect_device_safe({})", device_name).as_str())
-                .spawn(async move {
-                    collect_device_safe(device_name, config, oid_var_bind_map, snmp_chan_sender)
+                .name(&thread_name(&thread_prefix, ":listener"))
+                .spawn({
+                    let config = config.clone();
+                    let oid_var_bind_map = oid_var_bind_map.clone();
+                    let snmp_chan_sender = snmp_chan_sender.clone();
+                    let carbon_chan_sender = carbon_chan_sender.clone();
+                    async move {
+                        if let Err(error) = listener::listen(
+                            bind,
+                            config,
+                            oid_var_bind_map,
+                            snmp_chan_sender,
+                            carbon_chan_sender,
+                        )
                         .await
+                        {
+                            warn!("main: notification listener failed: {}", error);
+                        }
+                    }
+                })?;
+        }
+        // the result channel disconnects once every collection thread has stopped, which ends a
+        // run limited by --cycles. Without it the collector keeps running, also without devices.
+        let _results_connected = max_cycles.is_none().then_some(snmp_chan_sender);
+
+        // without a control socket nothing sends on the triggers
+        if let Some(control_socket) = &config.main.control_socket {
+            tokio::task::Builder::new()
+                .name(&thread_name(&thread_prefix, ":control"))
+                .spawn({
+                    let control_socket = control_socket.clone();
+                    async move {
+                        if let Err(error) = control::listen(control_socket, triggers).await {
+                            warn!("main: control socket failed: {}", error);
+                        }
+                    }
                 })?;
         }
 
         info!(
-            "main: started collection for {} devices",
-            config.devices.len()
+            "main: started collection for {} devices, {} disabled",
+            devices_started,
+            config.devices.len() - devices_started
         );
 
-        // start carbon_output thread
-        let (carbon_chan_sender, carbon_chan_receiver) = unbounded();
-        let carbon_chan_recovery_sender = carbon_chan_sender.clone(); // used to reinject carbonMetricValues on TCP errors
-
-        info!("main: starting output thread");
+        // start output threads, one per sink, each buffering and backing off on its own
+        let mut output_senders = vec![];
+        let mut output_flush_senders = vec![];
+        let mut output_queues = vec![];
+        for (output_id, output) in config.outputs.iter().enumerate() {
+            let (output_sender, output_receiver) = unbounded();
+            let output_name = format!("{}_{}", output.name(), output_id);
+            output_queues.push((output_name.clone(), output_sender.clone()));
+            output_senders.push((
+                output_sender.clone(),
+                output::KeyFilter::new(output.filter())?,
+            ));
+            output_flush_senders.push(output_sender);
+            let output = output.clone();
+            info!(
+                "main: starting output thread {} ({})",
+                output_id,
+                output.name()
+            );
+            tokio::task::Builder::new()
+                .name(&thread_name(&thread_prefix, &format!(":out{}", output_id)))
+                .spawn(output::send_safe(
+                    output,
+                    output_name,
+                    output_receiver,
+                    carbon_chan_sender.clone(),
+                ))?;
+        }
         tokio::task::Builder::new()
-            .name("carbon_output")
-            .spawn(async move {
-                carbon_send_safe(
-                    config.output.clone(),
-                    carbon_chan_recovery_sender,
-                    carbon_chan_receiver,
-                )
-                .await
-            })?;
+            .name(&thread_name(&thread_prefix, ":fanout"))
+            .spawn(output::fan_out(carbon_chan_receiver, output_senders))?;
+        tokio::task::Builder::new()
+            .name(&thread_name(&thread_prefix, ":queues"))
+            .spawn(output::report_queues(
+                output_queues,
+                carbon_chan_sender.clone(),
+            ))?;
+        tokio::task::Builder::new()
+            .name(&thread_name(&thread_prefix, ":alive"))
+            .spawn(output::heartbeat(carbon_chan_sender.clone()))?;
 
         // stats processing format SnmpStatResults and send them as carbonMetricValue
         info!("main: starting main processing loop");
+        let mut long_keys = LongKeys::new();
+        let mut out_of_range = OutOfRangeValues::new();
+        let mut unresolved_columns = UnresolvedColumns::new();
         loop {
-            let result = snmp_chan_receiver.recv_async().await.unwrap();
+            let result = match snmp_chan_receiver.recv_async().await {
+                Ok(result) => result,
+                Err(_) => break,
+            };
 
-            // convert var_bind oid to its named string
-            let result_value_name_oid = result.value.name().components().split_last().unwrap().1;
-            let full_val_name = oid_var_bind_map
-                .iter()
-                .find_map(|kv| {
-                    if kv.1.name().components() == result_value_name_oid {
-                        Some(kv.0.clone())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap();
-            let val_name = full_val_name.split("::").nth(1).unwrap().to_string();
+            // convert var_bind oid to its named string, columns which can not be resolved (e.g.
+            // after a MIB change) are skipped or named by their dotted oid
+            let full_val_name = match find_oid_name(
+                &oid_var_bind_map,
+                result.value.name().components(),
+                &result.index,
+            ) {
+                Some(full_val_name) => full_val_name.clone(),
+                None => match unresolved_columns.name(
+                    &result,
+                    config.main.unresolved_columns,
+                    &carbon_chan_sender,
+                ) {
+                    Some(column) => column,
+                    None => continue,
+                },
+            };
+            let val_name = metric_aliases.apply(&full_val_name, &snmp::field_name(&full_val_name));
 
             // example: IF-MIB::ifName -> Ethernet1/1
-            let key_value = snmp::var_numeric_value_to_string(result.key.value());
-            if key_value.is_none() {
-                warn!(
-                    "result_loop(for {}): can not handle non numeric values ({}).",
-                    result.device, val_name
-                );
-                continue;
-            }
+            // instance values which can not be used as a name (e.g. a binary MAC address, or an
+            // integer column of a table like entPhysicalTable) are replaced by the decoded index,
+            // e.g. 00:11:22:33:44:55, 10.0.0.1 or 1001. A configured index_format always
+            // renders the index, the DISPLAY-HINT of the instance column is used if there is one.
+            let instance_name = find_oid_name(
+                &oid_var_bind_map,
+                result.key.name().components(),
+                &result.index,
+            );
+            let index_format =
+                instance_name.and_then(|instance_name| index_formats.get(instance_name));
+            let hinted = match result.key.value() {
+                VarValue::String(s) => instance_name
+                    .and_then(|instance_name| display_hints.get(instance_name))
+                    .and_then(|display_hint| mib_index::format_display_hint(s, display_hint))
+                    .filter(|hinted| snmp::is_printable(hinted.as_bytes())),
+                _ => None,
+            };
+            let index_parts = instance_name
+                .and_then(|instance_name| index_parts_map.get(instance_name))
+                .map(|index_parts| index_parts.as_slice());
+            let named = match (result.key.value(), index_format, hinted) {
+                (_, Some(_), _) => None,
+                (_, None, Some(hinted)) => Some(hinted),
+                (VarValue::String(s), None, None) if snmp::is_printable(s) => {
+                    Some(String::from_utf8_lossy(s).to_string())
+                }
+                _ => None,
+            };
+            let with_index = instance_name
+                .and_then(|instance_name| keys_with_index.get(instance_name))
+                .copied()
+                .unwrap_or(false);
+            let key_value = match (named, index_format) {
+                // names repeated across rows, e.g. ifName on several line cards, get the index
+                (Some(named), _) if with_index => format!(
+                    "{}_{}",
+                    named,
+                    mib_index::format_index(&result.index, index_parts)
+                ),
+                (Some(named), _) => named,
+                (None, Some(index_format)) => {
+                    mib_index::format_index_as(&result.index, *index_format)
+                }
+                (None, None) => mib_index::format_index(&result.index, index_parts),
+            };
 
             // actual metric value
-            let value = snmp::var_bind_to_i128(result.value);
-            if value.is_none() {
+            // some agents send measurements as text, only parsed where configured
+            let parse_string = instance_name
+                .and_then(|instance_name| string_numbers.get(instance_name))
+                .is_some_and(|columns| columns.contains(&full_val_name));
+            let (value, value_type) = match result.value.value() {
+                VarValue::String(s) if parse_string => {
+                    let value = snmp::string_to_number(s);
+                    if value.is_none() {
+                        warn!(
+                            "result_loop(for {}): can not parse '{}' of {} as a number",
+                            result.device,
+                            String::from_utf8_lossy(s),
+                            val_name
+                        );
+                        continue;
+                    }
+                    (value, Some(snmp::ValueType::OctetString))
+                }
+                other => (
+                    snmp::var_bind_to_metric_value(result.value.clone()),
+                    snmp::ValueType::of(other),
+                ),
+            };
+            if value.is_none() || value_type.is_none() {
                 warn!(
                     "result_loop(for {}): can not handle snmp result for {}",
                     result.device, val_name
@@ -308,12 +994,77 @@ fn main() -> Result<(), Error> {
                 continue;
             };
 
-            let key_value = key_value.unwrap();
+            // configured arithmetic, e.g. a unit conversion
+            let value_expr = instance_name
+                .and_then(|instance_name| value_exprs.get(instance_name))
+                .and_then(|value_exprs| value_exprs.get(&full_val_name));
+            let value = match value_expr {
+                Some(value_expr) => {
+                    let evaluated = value.as_deref().and_then(|value| value_expr.apply(value));
+                    if evaluated.is_none() {
+                        warn!(
+                            "result_loop(for {}): expr of {} has no finite result for {}",
+                            result.device,
+                            val_name,
+                            value.unwrap()
+                        );
+                        continue;
+                    }
+                    evaluated
+                }
+                None => value,
+            };
+
+            // values of a misbehaving agent, e.g. a negative temperature far below freezing
+            let bounds = instance_name
+                .and_then(|instance_name| value_bounds.get(instance_name))
+                .and_then(|value_bounds| value_bounds.get(&full_val_name));
+            let value = match (bounds, value) {
+                (Some(bounds), Some(value)) => {
+                    let checked = out_of_range.check(
+                        &result.device,
+                        &val_name,
+                        value,
+                        bounds,
+                        &carbon_chan_sender,
+                    );
+                    if checked.is_none() {
+                        continue;
+                    }
+                    checked
+                }
+                (_, value) => value,
+            };
 
-            let ts = result.timestamp;
-            let key = output::format_key(&result.device, &key_value, &val_name);
+            let interval =
+                Duration::from_secs(config.devices.get(&result.device).unwrap().interval.into());
+            let ts = if config.main.align_timestamps {
+                align_timestamp(result.timestamp, interval)
+            } else {
+                result.timestamp
+            };
+            let mut key = match &result.endpoint {
+                Some(endpoint) => {
+                    output::format_endpoint_key(&result.device, endpoint, &key_value, &val_name)
+                }
+                None => output::format_key(&result.device, &key_value, &val_name),
+            };
+            if let Some(max_metric_len) = config.main.max_metric_len {
+                if key.len() > max_metric_len {
+                    match long_keys.shorten(
+                        &result.device,
+                        &key,
+                        max_metric_len,
+                        config.main.metric_len_policy,
+                        &carbon_chan_sender,
+                    ) {
+                        Some(shortened) => key = shortened,
+                        None => continue,
+                    }
+                }
+            }
 
-            let value = format!("{}", value.unwrap());
+            let value = value.unwrap();
 
             trace!(
                 "result_loop(for {}): sending to carbon '{} {} {}'",
@@ -328,8 +1079,751 @@ fn main() -> Result<(), Error> {
                     timestamp: ts,
                     metric: key.clone(),
                     value: value.clone(),
+                    source: Some(MetricSource {
+                        device: result.device.clone(),
+                        endpoint: result.endpoint,
+                        instance: key_value,
+                        name: val_name,
+                        interval,
+                        value_type: value_type.unwrap(),
+                    }),
                 })
                 .unwrap();
         }
+
+        // reached once every collection thread has stopped, with --cycles or without devices
+        let mut summaries = vec![];
+        for task in device_tasks {
+            summaries.push((task.device_name, task.handle.await?));
+        }
+        summaries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // give the outputs a chance to send what is left in their queues
+        let flush_deadline = tokio::time::Instant::now() + OUTPUT_FLUSH_TIMEOUT;
+        while (!carbon_chan_sender.is_empty()
+            || output_flush_senders.iter().any(|sender| !sender.is_empty()))
+            && tokio::time::Instant::now() < flush_deadline
+        {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        println!("device\tcycles\terrors\tvarbinds\tavg_duration");
+        for (device_name, summary) in summaries {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                device_name,
+                summary.cycles,
+                summary.errors,
+                summary.var_binds,
+                summary
+                    .average_duration()
+                    .map_or_else(|| "-".to_string(), |duration| format!("{:?}", duration))
+            );
+        }
+        Ok(())
+    })
+}
+
+/// `timestamp` rounded to the nearest multiple of `interval` since the epoch
+fn align_timestamp(timestamp: SystemTime, interval: Duration) -> SystemTime {
+    let since_epoch = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let interval = interval.as_millis().max(1);
+    let aligned = (since_epoch + interval / 2) / interval * interval;
+    SystemTime::UNIX_EPOCH + Duration::from_millis(aligned as u64)
+}
+
+/// Keys longer than `max_metric_len` seen by the result loop
+struct LongKeys {
+    truncated: u64,
+    dropped: u64,
+    /// dropped keys are only warned about once
+    dropped_keys: HashSet<String>,
+    last_report: Instant,
+}
+
+const LONG_KEYS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl LongKeys {
+    fn new() -> Self {
+        LongKeys {
+            truncated: 0,
+            dropped: 0,
+            dropped_keys: HashSet::new(),
+            last_report: Instant::now(),
+        }
+    }
+
+    /// The key to send instead of `key`, none if it is dropped
+    fn shorten(
+        &mut self,
+        device_name: &str,
+        key: &str,
+        max_len: usize,
+        policy: config::MetricLenPolicy,
+        metric_channel: &flume::Sender<CarbonMetricValue>,
+    ) -> Option<String> {
+        let shortened = match policy {
+            config::MetricLenPolicy::Truncate => {
+                trace!(
+                    "result_loop(for {}): shortening {} to {} bytes",
+                    device_name,
+                    key,
+                    max_len
+                );
+                self.truncated += 1;
+                Some(output::shorten_key(key, max_len))
+            }
+            config::MetricLenPolicy::Drop => {
+                if self.dropped_keys.insert(key.to_string()) {
+                    warn!(
+                        "result_loop(for {}): dropping {}, it is longer than max_metric_len {}",
+                        device_name, key, max_len
+                    );
+                }
+                self.dropped += 1;
+                None
+            }
+        };
+
+        if self.last_report.elapsed() >= LONG_KEYS_REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            for (metric_name, count) in [
+                ("metric_keys.truncated", self.truncated),
+                ("metric_keys.dropped", self.dropped),
+            ] {
+                metric_channel
+                    .send(output::self_metric(metric_name, count))
+                    .unwrap();
+            }
+        }
+        shortened
+    }
+}
+
+/// Values of columns missing from the reverse map seen by the result loop
+struct UnresolvedColumns {
+    skipped: u64,
+    /// columns are only warned about once
+    columns: HashSet<String>,
+    last_report: Option<Instant>,
+}
+
+const UNRESOLVED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl UnresolvedColumns {
+    fn new() -> Self {
+        UnresolvedColumns {
+            skipped: 0,
+            columns: HashSet::new(),
+            last_report: None,
+        }
+    }
+
+    /// The name of the value column of `result`, its dotted oid. None if the value is skipped.
+    fn name(
+        &mut self,
+        result: &SnmpStatResult,
+        policy: config::UnresolvedColumnPolicy,
+        metric_channel: &flume::Sender<CarbonMetricValue>,
+    ) -> Option<String> {
+        let components = result.value.name().components();
+        let column = components[..components.len().saturating_sub(result.index.len())]
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>()
+            .join(".");
+        let first = self.columns.insert(column.clone());
+        match policy {
+            config::UnresolvedColumnPolicy::Oid => {
+                if first {
+                    warn!(
+                        "result_loop(for {}): no name for column {}, using the oid, not logging this again",
+                        result.device, column
+                    );
+                }
+                Some(column)
+            }
+            config::UnresolvedColumnPolicy::Skip => {
+                if first {
+                    warn!(
+                        "result_loop(for {}): no name for column {}, skipping its values, not logging this again",
+                        result.device, column
+                    );
+                }
+                self.skipped += 1;
+                if self
+                    .last_report
+                    .is_none_or(|last_report| last_report.elapsed() >= UNRESOLVED_REPORT_INTERVAL)
+                {
+                    self.last_report = Some(Instant::now());
+                    metric_channel
+                        .send(output::self_metric(
+                            "values.unresolved.skipped",
+                            self.skipped,
+                        ))
+                        .unwrap();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Counts values outside of their bounds, warnings and self metrics are limited to one per
+/// `OUT_OF_RANGE_REPORT_INTERVAL`
+struct OutOfRangeValues {
+    dropped: u64,
+    clamped: u64,
+    /// since the last warning
+    suppressed: u64,
+    last_report: Option<Instant>,
+}
+
+const OUT_OF_RANGE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl OutOfRangeValues {
+    fn new() -> Self {
+        OutOfRangeValues {
+            dropped: 0,
+            clamped: 0,
+            suppressed: 0,
+            last_report: None,
+        }
+    }
+
+    /// The value to send instead of `value`, none if it is dropped. Values which are not a
+    /// number are passed on unchanged.
+    fn check(
+        &mut self,
+        device_name: &str,
+        val_name: &str,
+        value: String,
+        bounds: &config::ValueBounds,
+        metric_channel: &flume::Sender<CarbonMetricValue>,
+    ) -> Option<String> {
+        let number: f64 = match value.parse() {
+            Ok(number) => number,
+            Err(_) => return Some(value),
+        };
+        let bound = match (bounds.min, bounds.max) {
+            (Some(min), _) if number < min => min,
+            (_, Some(max)) if number > max => max,
+            _ => return Some(value),
+        };
+        let checked = match bounds.out_of_range {
+            config::OutOfRange::Drop => {
+                self.dropped += 1;
+                None
+            }
+            config::OutOfRange::Clamp => {
+                self.clamped += 1;
+                expr::format_result(bound)
+            }
+        };
+
+        if self
+            .last_report
+            .is_none_or(|last_report| last_report.elapsed() >= OUT_OF_RANGE_REPORT_INTERVAL)
+        {
+            self.last_report = Some(Instant::now());
+            let action = match &checked {
+                Some(clamped) => format!("sending {}", clamped),
+                None => "dropping it".to_string(),
+            };
+            warn!(
+                "result_loop(for {}): {} = {} is out of range, {} ({} more since the last warning)",
+                device_name, val_name, value, action, self.suppressed
+            );
+            self.suppressed = 0;
+            for (metric_name, count) in [
+                ("values.out_of_range.dropped", self.dropped),
+                ("values.out_of_range.clamped", self.clamped),
+            ] {
+                metric_channel
+                    .send(output::self_metric(metric_name, count))
+                    .unwrap();
+            }
+        } else {
+            self.suppressed += 1;
+        }
+        checked
+    }
+}
+
+/// The hostname up to its first dot, for `main.instance_id`
+fn default_instance_id() -> String {
+    let hostname = env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .unwrap_or_default();
+    match hostname.trim().split('.').next() {
+        Some(short) if !short.is_empty() => short.to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Longest thread name accepted by Linux, excluding the terminating NUL
+const MAX_THREAD_NAME_LEN: usize = 15;
+const DEFAULT_THREAD_PREFIX: &str = "snmpc-rs";
+
+/// `prefix` followed by `name`, names exceeding `MAX_THREAD_NAME_LEN` are truncated and end with
+/// a hash of `name`, so they stay distinct and do not change across restarts
+fn thread_name(prefix: &str, name: &str) -> String {
+    let full_name = format!("{}{}", prefix, name);
+    if full_name.len() <= MAX_THREAD_NAME_LEN {
+        return full_name;
+    }
+
+    let suffix = format!("~{:04x}", output::stable_hash(name.as_bytes()) & 0xffff);
+    let mut truncated = String::new();
+    for c in full_name.chars() {
+        if truncated.len() + c.len_utf8() + suffix.len() > MAX_THREAD_NAME_LEN {
+            break;
+        }
+        truncated.push(c);
+    }
+    truncated + &suffix
+}
+
+/// Names of the tasks started by `Run` and what they are running
+fn task_names(prefix: &str, config: &config::Config) -> Vec<(String, String)> {
+    let mut devices: Vec<&String> = config
+        .devices
+        .iter()
+        .filter(|(_, device)| device.enabled)
+        .map(|(device_name, _)| device_name)
+        .collect();
+    devices.sort();
+
+    let mut names: Vec<(String, String)> = devices
+        .into_iter()
+        .map(|device_name| {
+            (
+                thread_name(prefix, &format!(":{}", device_name)),
+                format!("device {}", device_name),
+            )
+        })
+        .collect();
+    for (output_id, output) in config.outputs.iter().enumerate() {
+        names.push((
+            thread_name(prefix, &format!(":out{}", output_id)),
+            format!("output {} ({})", output_id, output.name()),
+        ));
+    }
+    names.push((thread_name(prefix, ":fanout"), "output fan out".to_string()));
+    names.push((
+        thread_name(prefix, ":queues"),
+        "output queue metrics".to_string(),
+    ));
+    names.push((thread_name(prefix, ":alive"), "heartbeat".to_string()));
+    if config.main.control_socket.is_some() {
+        names.push((
+            thread_name(prefix, ":control"),
+            "control socket".to_string(),
+        ));
+    }
+    names
+}
+
+fn benchmark(
+    config: &config::Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+    mib_duration: Duration,
+    rows: u64,
+) -> Result<(), Error> {
+    println!(
+        "resolved {} oids in {:?}",
+        oid_var_bind_map.len(),
+        mib_duration
+    );
+
+    let mut devices: Vec<(&String, &config::DeviceEntry)> = config
+        .devices
+        .iter()
+        .filter(|(_, device)| device.enabled)
+        .collect();
+    devices.sort_by(|a, b| a.0.cmp(b.0));
+
+    println!(
+        "device\tinstances\twalks\tfallback_walks\tmax_repetitions\trequests_per_cycle\trequests_per_sec"
+    );
+    let (mut total_requests, mut total_rate) = (0, 0.0);
+    for (device_name, device) in devices {
+        let planning_start = Instant::now();
+        let plan = collector::plan_cycle(device_name, config, oid_var_bind_map)?;
+        debug!(
+            "benchmark({}): planned in {:?}",
+            device_name,
+            planning_start.elapsed()
+        );
+        let requests = plan.estimated_requests(rows, &device.snmp.walk_mode);
+        let rate = requests as f64 / f64::from(device.interval.max(1));
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+            device_name,
+            plan.instances,
+            plan.walks,
+            plan.fallback_walks,
+            plan.repetitions,
+            requests,
+            rate
+        );
+        total_requests += requests;
+        total_rate += rate;
+    }
+    println!(
+        "total\t\t\t\t\t{}\t{:.2} (assuming {} rows per table)",
+        total_requests, total_rate, rows
+    );
+    Ok(())
+}
+
+fn connectivity_check(config: Arc<config::Config>) -> Result<(), Error> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let mut failed = 0;
+    let mut checked = 0;
+    rt.block_on(async {
+        let mut checks = JoinSet::new();
+        for (device_name, device) in config.devices.iter() {
+            if !device.enabled {
+                println!("{}: SKIPPED, disabled", device_name);
+                continue;
+            }
+            let device_name = device_name.clone();
+            let config = config.clone();
+            checks.spawn(async move {
+                let result = check_device(device_name.clone(), config).await;
+                (device_name, result)
+            });
+        }
+
+        let mut results = vec![];
+        while let Some(result) = checks.join_next().await {
+            results.push(result?);
+        }
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        checked = results.len();
+        for (device_name, result) in results {
+            match result {
+                Ok(uptime) => println!("{}: OK, sysUpTime {}", device_name, uptime),
+                Err(error) => {
+                    failed += 1;
+                    let remediation = match snmp::classify_error(&error) {
+                        SnmpErrorClass::Auth => "AUTH FAILURE, check secname and passwords",
+                        SnmpErrorClass::Timeout => "TIMEOUT, check reachability and host",
+                        SnmpErrorClass::Other => "ERROR",
+                    };
+                    println!("{}: {}: {:#}", device_name, remediation, error);
+                }
+            }
+        }
+        Ok::<(), Error>(())
+    })?;
+
+    if failed > 0 {
+        bail!(
+            "Connectivity check failed for {} of {} devices",
+            failed,
+            checked
+        );
+    }
+
+    debug!("Connectivity-check succeeded");
+    println!("All devices are reachable.");
+    Ok(())
+}
+
+/// Print the instances, value columns and scalars collected from `device_name`, without
+/// sending anything to it
+fn show_plan(
+    config: &config::Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+    device_name: &str,
+) -> Result<(), Error> {
+    if !config.devices.contains_key(device_name) {
+        bail!("Unknown device {}", device_name);
+    }
+    // several names may resolve to the same oid, the first in order is shown
+    let mut names: HashMap<&[u64], &str> = HashMap::new();
+    for (name, var_bind) in oid_var_bind_map {
+        let entry = names.entry(var_bind.name().components()).or_insert(name);
+        if name.as_str() < *entry {
+            *entry = name;
+        }
+    }
+    let describe = |var_bind: &VarBind| {
+        let oid = var_bind.name().components();
+        let name = match names.get(oid) {
+            Some(name) => name.to_string(),
+            None => match oid.split_last() {
+                Some((0, object)) if names.contains_key(object) => {
+                    format!("{}.0", names.get(object).unwrap())
+                }
+                _ => "?".to_string(),
+            },
+        };
+        format!("{} = {}", name, var_bind.name())
+    };
+
+    for planned in collector::plan_instances(device_name, config, oid_var_bind_map)? {
+        if planned.table {
+            println!(
+                "table {} (start_index {:?}, max_rows {})",
+                describe(&planned.instance),
+                planned.start_index,
+                planned.max_rows
+            );
+        } else {
+            println!("scalars {}", describe(&planned.instance));
+        }
+        for helper in &planned.helpers {
+            println!("  column {}", describe(helper));
+        }
+        for value in &planned.values {
+            println!("  value {}", describe(&value.column));
+            if let Some(join_by) = &value.join_by {
+                println!("    join_by {}", describe(join_by));
+            }
+            for fallback in &value.fallbacks {
+                println!("    fallback {}", describe(fallback));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run a single cycle of `device_name`. `snmp::send_request` refuses anything but get, getnext
+/// and getbulk requests, the audit prints each request it sent.
+fn audit_requests(
+    config: Arc<config::Config>,
+    oid_var_bind_map: HashMap<String, VarBind>,
+    device_name: &str,
+) -> Result<(), Error> {
+    let device = match config.devices.get(device_name) {
+        Some(device) => device,
+        None => bail!("Unknown device {}", device_name),
+    };
+    let mut backoff = collector::Backoff::new(
+        Duration::from_secs(device.interval.into()),
+        device.snmp.max_pdu_size,
+    );
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let mut summary = collector::CycleSummary::default();
+    rt.block_on(async {
+        // nothing is sent to the outputs, the results are only counted
+        let (channel, results) = unbounded();
+        let (metric_channel, _metrics) = unbounded();
+        let (_trigger_sender, trigger) = flume::bounded(1);
+        let context = collector::DeviceContext {
+            config: config.clone(),
+            oid_var_bind_map,
+            channel,
+            metric_channel,
+            max_cycles: Some(1),
+            trigger,
+            progress: collector::Progress::default(),
+        };
+        let (collected, audited) = snmp::with_request_audit(collector::collect_device(
+            device_name,
+            &context,
+            &mut backoff,
+            &mut summary,
+            &mut None,
+        ))
+        .await;
+        // the requests sent before a failure are listed as well
+        println!("pdu_type\toids");
+        for request in &audited {
+            println!("{:?}\t{}", request.pdu_type, request.oids.join(" "));
+        }
+        collected?;
+        println!(
+            "{} requests, all of them reads, {} results",
+            audited.len(),
+            results.len()
+        );
+        Ok::<(), Error>(())
     })
 }
+
+/// Values zipped with an instance column of another table are keyed by the wrong rows. A value
+/// column has to be in the table of its instance, or in one with the same INDEX objects, e.g. a
+/// table which AUGMENTS it. Joined values have to be in the table of their join_by column.
+fn check_value_tables(
+    config: &config::Config,
+    oid_var_bind_map: &HashMap<String, VarBind>,
+    index_names: &HashMap<String, Vec<String>>,
+) -> Result<(), Error> {
+    for (data_name, data) in config.data.iter().filter(|(_, data)| data.table) {
+        // joined values share the rows of the join_by column instead
+        let (rows_name, rows) = match &data.join_by {
+            Some(join_by) => ("join_by", join_by),
+            None => ("instance", &data.instance),
+        };
+        let rows_oid = oid_var_bind_map.get(rows).unwrap().name().components();
+        for value in data.values.iter().flat_map(|value| value.oids()) {
+            let value_oid = oid_var_bind_map.get(value).unwrap().name().components();
+            // columns of a table share the oid of its entry
+            if value_oid.len() == rows_oid.len()
+                && value_oid[..value_oid.len() - 1] == rows_oid[..rows_oid.len() - 1]
+            {
+                continue;
+            }
+            match (index_names.get(rows), index_names.get(value)) {
+                (Some(rows_index), Some(value_index)) if rows_index == value_index => {}
+                (Some(rows_index), Some(value_index)) => bail!(
+                    "{} in collector '{}' is not a column of the table of {} {}, \
+                     it is indexed by {:?} instead of {:?}",
+                    value,
+                    data_name,
+                    rows_name,
+                    rows,
+                    value_index,
+                    rows_index
+                ),
+                _ => warn!(
+                    "config: can not verify that {} in collector '{}' shares the rows of {} {}, the INDEX of a table is unknown",
+                    value, data_name, rows_name, rows
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reverse lookup the configured name of a column oid, `index` is stripped from `oid` first
+fn find_oid_name<'a>(
+    oid_var_bind_map: &'a HashMap<String, VarBind>,
+    oid: &[u64],
+    index: &[u64],
+) -> Option<&'a String> {
+    let column_oid = &oid[..oid.len().checked_sub(index.len())?];
+    oid_var_bind_map.iter().find_map(|(name, var_bind)| {
+        if var_bind.name().components() == column_oid {
+            Some(name)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snmp_mp::ObjectIdent;
+
+    /// A value of row 7 of a private column, which no MIB names
+    fn unresolved_result() -> SnmpStatResult {
+        let column = [1, 3, 6, 1, 4, 1, 99999, 1, 1];
+        let value = snmp::vec_to_var_binds([&column[..], &[7]].concat());
+        let mut key = VarBind::new(ObjectIdent::from_slice(&[
+            1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 1, 7,
+        ]));
+        key.set_value(VarValue::String(b"eth0".to_vec()));
+        SnmpStatResult {
+            device: "sw1".to_string(),
+            endpoint: None,
+            timestamp: SystemTime::now(),
+            key,
+            value,
+            index: vec![7],
+        }
+    }
+
+    #[test]
+    fn unresolved_columns_are_skipped_and_counted() {
+        let result = unresolved_result();
+        let oid_var_bind_map = HashMap::from([(
+            "IF-MIB::ifName".to_string(),
+            VarBind::new(ObjectIdent::from_slice(&[1, 3, 6, 1, 2, 1, 31, 1, 1, 1, 1])),
+        )]);
+        assert_eq!(
+            find_oid_name(
+                &oid_var_bind_map,
+                result.value.name().components(),
+                &result.index
+            ),
+            None
+        );
+
+        let (metric_channel, metrics) = unbounded();
+        let mut unresolved = UnresolvedColumns::new();
+        for _ in 0..3 {
+            let name = unresolved.name(
+                &result,
+                config::UnresolvedColumnPolicy::Skip,
+                &metric_channel,
+            );
+            assert_eq!(name, None);
+        }
+        assert_eq!(unresolved.skipped, 3);
+        // reported right away, then once per interval
+        let reported: Vec<(String, String)> = metrics
+            .try_iter()
+            .map(|metricval| (metricval.metric, metricval.value))
+            .collect();
+        assert_eq!(reported.len(), 1);
+        assert!(
+            reported[0].0.ends_with(".values.unresolved.skipped"),
+            "{:?}",
+            reported
+        );
+        assert_eq!(reported[0].1, "1");
+    }
+
+    #[test]
+    fn unresolved_columns_can_be_named_by_their_oid() {
+        let (metric_channel, metrics) = unbounded();
+        let mut unresolved = UnresolvedColumns::new();
+        let name = unresolved.name(
+            &unresolved_result(),
+            config::UnresolvedColumnPolicy::Oid,
+            &metric_channel,
+        );
+        assert_eq!(name.as_deref(), Some("1.3.6.1.4.1.99999.1.1"));
+        assert_eq!(unresolved.skipped, 0);
+        assert!(metrics.is_empty());
+    }
+
+    #[test]
+    fn short_thread_names_are_kept() {
+        assert_eq!(thread_name("snmpc-rs", ":sw1"), "snmpc-rs:sw1");
+        assert_eq!(thread_name("snmpc-rs", "#123456"), "snmpc-rs#123456");
+    }
+
+    #[test]
+    fn long_thread_names_are_truncated_with_a_hash() {
+        let name = thread_name("snmpc-rs", ":core-switch-01");
+        assert_eq!(name.len(), MAX_THREAD_NAME_LEN);
+        // FNV-1a of the name, the same with every build
+        assert_eq!(name, "snmpc-rs:c~a2b3");
+        assert_ne!(name, thread_name("snmpc-rs", ":core-switch-02"));
+        assert_eq!(name, thread_name("snmpc-rs", ":core-switch-01"));
+    }
+
+    #[test]
+    fn thread_names_are_cut_at_char_boundaries() {
+        // 'ä' takes two bytes and '€' three, a cut by bytes would split them
+        for device_name in [":äääääää", ":x€€€€€", ":€ä€ä€ä"] {
+            let name = thread_name("snmpc-rs", device_name);
+            assert!(name.len() <= MAX_THREAD_NAME_LEN, "{}", name);
+            let (truncated, hash) = name.split_once('~').unwrap();
+            assert!(
+                format!("snmpc-rs{}", device_name).starts_with(truncated),
+                "{}",
+                name
+            );
+            assert_eq!(hash.len(), 4);
+        }
+    }
+}
@@ -0,0 +1,864 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::config::IndexFormat;
+
+// mib_parser only exposes the oid assignments of a module, so INDEX clauses, the SYNTAX of
+// the index objects and DISPLAY-HINTs are looked up by scanning the MIB sources directly. Names are treated as a
+// single namespace across all loaded modules, which is good enough for decoding index suffixes.
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum OctetFormat {
+    Text,
+    Ip,
+    Mac,
+    Inet,
+    Hex,
+}
+
+/// Encoding of a single INDEX component in the instance sub-identifiers, see RFC 2578 7.7
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum IndexPart {
+    Integer,
+    OctetString {
+        fixed_len: Option<usize>,
+        implied: bool,
+        format: OctetFormat,
+    },
+    ObjectIdentifier {
+        implied: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+struct ObjectDef {
+    syntax: Vec<String>,
+    index: Vec<(String, bool)>,
+    augments: Option<String>,
+    parent: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct MibIndexResolver {
+    objects: HashMap<String, ObjectDef>,
+    types: HashMap<String, Vec<String>>,
+    /// DISPLAY-HINT of the textual conventions which have one
+    display_hints: HashMap<String, String>,
+}
+
+const MAX_TYPE_DEPTH: usize = 16;
+
+impl MibIndexResolver {
+    pub fn add_source(&mut self, text: &str) {
+        let tokens = tokenize(text);
+
+        for i in 1..tokens.len() {
+            let name = &tokens[i - 1];
+            if tokens[i] == "OBJECT-TYPE"
+                && starts_with_lowercase(name)
+                && tokens.get(i + 1).map(|s| s.as_str()) != Some("MACRO")
+            {
+                let object = parse_object(&tokens[i + 1..]);
+                trace!("mib_index: object {} = {:?}", name, object);
+                self.objects.insert(name.to_string(), object);
+            } else if tokens[i] == "::="
+                && starts_with_uppercase(name)
+                && name != "IDENTIFIER"
+                && name != "MACRO"
+                && (i < 2 || tokens[i - 2] != "SYNTAX")
+            {
+                let rest = &tokens[i + 1..];
+                let syntax = if rest.first().map(|s| s.as_str()) == Some("TEXTUAL-CONVENTION") {
+                    let pos = match rest.iter().position(|t| t == "SYNTAX") {
+                        Some(pos) => pos,
+                        None => continue,
+                    };
+                    if let Some(hint) = rest[..pos]
+                        .iter()
+                        .position(|t| t == "DISPLAY-HINT")
+                        .and_then(|hint| rest.get(hint + 1))
+                    {
+                        self.display_hints
+                            .insert(name.to_string(), hint.trim_matches('"').to_string());
+                    }
+                    rest[pos + 1..].to_vec()
+                } else {
+                    rest.to_vec()
+                };
+                // only the leading tokens matter for the base type and the size constraint
+                let syntax = syntax.into_iter().take(16).collect();
+                self.types.insert(name.to_string(), syntax);
+            }
+        }
+    }
+
+    /// The entry defining the INDEX of the table containing `column`, following AUGMENTS
+    fn index_entry(&self, column: &str) -> Option<&ObjectDef> {
+        let column = column.split("::").last().unwrap();
+        let mut entry = self
+            .objects
+            .get(self.objects.get(column)?.parent.as_ref()?)?;
+
+        // follow AUGMENTS to the entry which actually defines the INDEX
+        for _ in 0..MAX_TYPE_DEPTH {
+            match &entry.augments {
+                Some(augments) => entry = self.objects.get(augments)?,
+                None => break,
+            }
+        }
+
+        if entry.index.is_empty() {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Names of the INDEX objects of the table containing `column`, tables with the same ones
+    /// share their rows
+    pub fn index_names(&self, column: &str) -> Option<Vec<String>> {
+        Some(
+            self.index_entry(column)?
+                .index
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect(),
+        )
+    }
+
+    /// Resolve the INDEX components of the table containing `column` (e.g. `IF-MIB::ifName`)
+    pub fn index_parts(&self, column: &str) -> Option<Vec<IndexPart>> {
+        self.index_entry(column)?
+            .index
+            .iter()
+            .map(|(name, implied)| {
+                let object = self.objects.get(name)?;
+                self.syntax_to_index_part(&object.syntax, *implied, None, 0)
+            })
+            .collect()
+    }
+
+    /// DISPLAY-HINT of the textual convention `column` (e.g. `BRIDGE-MIB::dot1dTpFdbAddress`) is
+    /// defined with, directly or through other textual conventions
+    pub fn display_hint(&self, column: &str) -> Option<&str> {
+        let column = column.split("::").last().unwrap();
+        let mut syntax = &self.objects.get(column)?.syntax;
+        for _ in 0..MAX_TYPE_DEPTH {
+            let base = syntax.first()?;
+            if let Some(hint) = self.display_hints.get(base) {
+                return Some(hint);
+            }
+            syntax = self.types.get(base)?;
+        }
+        None
+    }
+
+    fn syntax_to_index_part(
+        &self,
+        syntax: &[String],
+        implied: bool,
+        format: Option<OctetFormat>,
+        depth: usize,
+    ) -> Option<IndexPart> {
+        if depth > MAX_TYPE_DEPTH {
+            return None;
+        }
+
+        // skip tags like [APPLICATION 0] IMPLICIT
+        let mut syntax = syntax;
+        if syntax.first().map(|s| s.as_str()) == Some("[") {
+            let end = syntax.iter().position(|t| t == "]")?;
+            syntax = &syntax[end + 1..];
+        }
+        if syntax.first().map(|s| s.as_str()) == Some("IMPLICIT") {
+            syntax = &syntax[1..];
+        }
+
+        let base = syntax.first()?.as_str();
+        let size = fixed_size(&syntax[1..]);
+        match base {
+            "INTEGER" | "Integer32" | "Unsigned32" | "Gauge32" | "Counter32" | "TimeTicks" => {
+                Some(IndexPart::Integer)
+            }
+            "OCTET" | "BITS" | "Opaque" => Some(IndexPart::OctetString {
+                fixed_len: fixed_size(syntax.get(2..).unwrap_or(&[])),
+                implied,
+                format: format.unwrap_or(OctetFormat::Hex),
+            }),
+            "OBJECT" => Some(IndexPart::ObjectIdentifier { implied }),
+            "IpAddress" => Some(IndexPart::OctetString {
+                fixed_len: Some(4),
+                implied,
+                format: OctetFormat::Ip,
+            }),
+            "MacAddress" => Some(IndexPart::OctetString {
+                fixed_len: Some(6),
+                implied,
+                format: OctetFormat::Mac,
+            }),
+            _ => {
+                let format = format.or(match base {
+                    "DisplayString" | "SnmpAdminString" => Some(OctetFormat::Text),
+                    "PhysAddress" => Some(OctetFormat::Mac),
+                    "InetAddress" => Some(OctetFormat::Inet),
+                    _ => None,
+                });
+
+                let part = match self.types.get(base) {
+                    Some(type_syntax) => {
+                        self.syntax_to_index_part(type_syntax, implied, format, depth + 1)?
+                    }
+                    // the defining module is not loaded, fall back to well-known conventions
+                    None => match format {
+                        Some(format) => IndexPart::OctetString {
+                            fixed_len: None,
+                            implied,
+                            format,
+                        },
+                        None => return None,
+                    },
+                };
+
+                // a size constraint on the object narrows the size of the textual convention
+                match (part, size) {
+                    (
+                        IndexPart::OctetString {
+                            implied, format, ..
+                        },
+                        Some(len),
+                    ) => Some(IndexPart::OctetString {
+                        fixed_len: Some(len),
+                        implied,
+                        format,
+                    }),
+                    (part, _) => Some(part),
+                }
+            }
+        }
+    }
+}
+
+fn parse_object(tokens: &[String]) -> ObjectDef {
+    let mut object = ObjectDef::default();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].as_str() {
+            "SYNTAX" => {
+                object.syntax = tokens[i + 1..]
+                    .iter()
+                    .take_while(|t| !is_object_clause(t))
+                    .cloned()
+                    .collect();
+            }
+            "INDEX" => {
+                let mut implied = false;
+                for token in tokens[i + 1..].iter().skip(1) {
+                    match token.as_str() {
+                        "}" => break,
+                        "," => {}
+                        "IMPLIED" => implied = true,
+                        name => {
+                            object.index.push((name.to_string(), implied));
+                            implied = false;
+                        }
+                    }
+                }
+            }
+            "AUGMENTS" => object.augments = tokens.get(i + 2).cloned(),
+            "::=" => {
+                object.parent = tokens.get(i + 2).cloned();
+                break;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    object
+}
+
+fn is_object_clause(token: &str) -> bool {
+    matches!(
+        token,
+        "UNITS" | "MAX-ACCESS" | "ACCESS" | "STATUS" | "DESCRIPTION" | "::="
+    )
+}
+
+// parses `( SIZE ( n ) )`, only a single fixed size is of interest for index decoding
+fn fixed_size(tokens: &[String]) -> Option<usize> {
+    let tokens: Vec<&str> = tokens.iter().take(6).map(|s| s.as_str()).collect();
+    match tokens.as_slice() {
+        ["(", "SIZE", "(", size, ")", ..] => size.parse().ok(),
+        _ => None,
+    }
+}
+
+fn starts_with_lowercase(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+}
+
+fn starts_with_uppercase(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c.is_ascii_uppercase())
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // quoted strings (DESCRIPTION, REFERENCE, ...) may contain anything, keep a placeholder.
+            // Only a DISPLAY-HINT is kept, it is short and used for formatting.
+            '"' => {
+                flush_token(&mut current, &mut tokens);
+                let mut quoted = String::new();
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    quoted.push(c);
+                }
+                if tokens.last().map(|t| t.as_str()) == Some("DISPLAY-HINT") {
+                    tokens.push(format!("\"{}\"", quoted));
+                } else {
+                    tokens.push("\"\"".to_string());
+                }
+            }
+            // comments run until the end of the line or the next "--"
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                flush_token(&mut current, &mut tokens);
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                    if c == '-' && chars.peek() == Some(&'-') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ',' | ';' | '|' => {
+                flush_token(&mut current, &mut tokens);
+                tokens.push(c.to_string());
+            }
+            c if c.is_whitespace() => flush_token(&mut current, &mut tokens),
+            c => current.push(c),
+        }
+    }
+    flush_token(&mut current, &mut tokens);
+
+    tokens
+}
+
+fn flush_token(current: &mut String, tokens: &mut Vec<String>) {
+    if !current.is_empty() {
+        tokens.push(std::mem::take(current));
+    }
+}
+
+/// Decode an index suffix into one human-readable string per INDEX component
+pub fn decode_index(index: &[u64], parts: &[IndexPart]) -> Option<Vec<String>> {
+    let mut rest = index;
+    let mut decoded = vec![];
+
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        match part {
+            IndexPart::Integer => {
+                let (value, tail) = rest.split_first()?;
+                decoded.push(format!("{}", value));
+                rest = tail;
+            }
+            IndexPart::OctetString {
+                fixed_len,
+                implied,
+                format,
+            } => {
+                let (octets, tail) = take_sub_identifiers(rest, *fixed_len, *implied && last)?;
+                let octets = octets
+                    .iter()
+                    .map(|o| u8::try_from(*o).ok())
+                    .collect::<Option<Vec<u8>>>()?;
+                decoded.push(format_octets(&octets, *format));
+                rest = tail;
+            }
+            IndexPart::ObjectIdentifier { implied } => {
+                let (oid, tail) = take_sub_identifiers(rest, None, *implied && last)?;
+                decoded.push(join_sub_identifiers(oid));
+                rest = tail;
+            }
+        }
+    }
+
+    if rest.is_empty() {
+        Some(decoded)
+    } else {
+        None
+    }
+}
+
+fn take_sub_identifiers(
+    index: &[u64],
+    fixed_len: Option<usize>,
+    implied: bool,
+) -> Option<(&[u64], &[u64])> {
+    match fixed_len {
+        Some(len) if len <= index.len() => Some(index.split_at(len)),
+        Some(_) => None,
+        None if implied => Some((index, &[])),
+        None => {
+            let (len, tail) = index.split_first()?;
+            let len = usize::try_from(*len).ok()?;
+            if len <= tail.len() {
+                Some(tail.split_at(len))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub fn format_octets(octets: &[u8], format: OctetFormat) -> String {
+    let hex = |separator: &str| {
+        octets
+            .iter()
+            .map(|o| format!("{:02x}", o))
+            .collect::<Vec<String>>()
+            .join(separator)
+    };
+
+    match format {
+        OctetFormat::Text => String::from_utf8_lossy(octets).to_string(),
+        OctetFormat::Ip if octets.len() == 4 => octets
+            .iter()
+            .map(|o| format!("{}", o))
+            .collect::<Vec<String>>()
+            .join("."),
+        OctetFormat::Inet if octets.len() == 4 => format_octets(octets, OctetFormat::Ip),
+        OctetFormat::Inet if octets.len() == 16 => {
+            let mut address = [0u8; 16];
+            address.copy_from_slice(octets);
+            format!("{}", Ipv6Addr::from(address))
+        }
+        OctetFormat::Mac => hex(":"),
+        _ => hex(""),
+    }
+}
+
+/// One part of an octet string DISPLAY-HINT, see RFC 2579 3.1
+#[derive(Debug)]
+struct HintSpec {
+    /// the first octet holds the number of times the spec is applied
+    repeat: bool,
+    len: usize,
+    format: char,
+    separator: Option<char>,
+    terminator: Option<char>,
+}
+
+fn parse_display_hint(hint: &str) -> Option<Vec<HintSpec>> {
+    let mut chars = hint.chars().peekable();
+    let mut specs = vec![];
+    while chars.peek().is_some() {
+        let repeat = chars.next_if_eq(&'*').is_some();
+        let mut len = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            len.push(digit);
+        }
+        let len: usize = len.parse().ok()?;
+        let format = chars.next().filter(|format| "xdoat".contains(*format))?;
+        // numbers are built from at most 8 octets
+        if len == 0 || (len > 8 && "xdo".contains(format)) {
+            return None;
+        }
+        let mut delimiter = || chars.next_if(|c| !c.is_ascii_digit() && *c != '*');
+        let separator = delimiter();
+        let terminator = if repeat { delimiter() } else { None };
+        specs.push(HintSpec {
+            repeat,
+            len,
+            format,
+            separator,
+            terminator,
+        });
+    }
+    Some(specs)
+}
+
+/// Render `octets` as described by an octet string DISPLAY-HINT, e.g. `1x:` for MAC addresses
+/// or `255a` for text. None for a hint which is not one for octet strings.
+pub fn format_display_hint(octets: &[u8], hint: &str) -> Option<String> {
+    let specs = parse_display_hint(hint)?;
+    let mut rendered = String::new();
+    let mut rest = octets;
+    // the last spec is applied until the octets are used up
+    for spec in specs.iter().chain(std::iter::repeat(specs.last()?)) {
+        if rest.is_empty() {
+            break;
+        }
+        let repetitions = if spec.repeat {
+            let (count, tail) = rest.split_first()?;
+            rest = tail;
+            usize::from(*count)
+        } else {
+            1
+        };
+        for repetition in 0..repetitions {
+            if rest.is_empty() {
+                break;
+            }
+            let (value, tail) = rest.split_at(spec.len.min(rest.len()));
+            rest = tail;
+            let number = || value.iter().fold(0u64, |n, o| n << 8 | u64::from(*o));
+            match spec.format {
+                'x' => rendered.push_str(&format!("{:01$x}", number(), value.len() * 2)),
+                'd' => rendered.push_str(&format!("{}", number())),
+                'o' => rendered.push_str(&format!("{:o}", number())),
+                _ => rendered.push_str(&String::from_utf8_lossy(value)),
+            }
+            if rest.is_empty() {
+                break;
+            }
+            match (spec.terminator, spec.separator) {
+                (Some(terminator), _) if repetition + 1 == repetitions => rendered.push(terminator),
+                (_, Some(separator)) => rendered.push(separator),
+                _ => {}
+            }
+        }
+    }
+    Some(rendered)
+}
+
+fn join_sub_identifiers(index: &[u64]) -> String {
+    index
+        .iter()
+        .map(|i| format!("{}", i))
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+/// Render an index suffix in the format configured with `index_format`. Suffixes which are not
+/// a list of octets are rendered as dotted sub-identifiers.
+pub fn format_index_as(index: &[u64], format: IndexFormat) -> String {
+    let format = match format {
+        IndexFormat::Mac => OctetFormat::Mac,
+        IndexFormat::Ip if index.len() == 16 => OctetFormat::Inet,
+        IndexFormat::Hex => OctetFormat::Hex,
+        // IPv4 addresses are dotted sub-identifiers already
+        IndexFormat::Ip | IndexFormat::Decimal => return join_sub_identifiers(index),
+    };
+    match index
+        .iter()
+        .map(|i| u8::try_from(*i))
+        .collect::<Result<Vec<u8>, _>>()
+    {
+        Ok(octets) if !octets.is_empty() => format_octets(&octets, format),
+        _ => join_sub_identifiers(index),
+    }
+}
+
+/// Render an index suffix, components are separated by '_', undecodable suffixes are rendered as
+/// dotted sub-identifiers
+pub fn format_index(index: &[u64], parts: Option<&[IndexPart]>) -> String {
+    match parts.and_then(|parts| decode_index(index, parts)) {
+        Some(decoded) => decoded.join("_"),
+        None => join_sub_identifiers(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIB: &str = r#"
+TEST-MIB DEFINITIONS ::= BEGIN
+
+testEntry OBJECT-TYPE
+    SYNTAX      TestEntry
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION "A row, with an INDEX { in the description"
+    INDEX       { testIfIndex, testAddress, IMPLIED testName }
+    ::= { testTable 1 }
+
+testIfIndex OBJECT-TYPE
+    SYNTAX      Integer32 (1..2147483647)
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION "-- not a comment"
+    ::= { testEntry 1 }
+
+testAddress OBJECT-TYPE
+    SYNTAX      IpAddress
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION ""
+    ::= { testEntry 2 }
+
+testName OBJECT-TYPE
+    SYNTAX      DisplayString (SIZE (0..32)) -- variable size
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION ""
+    ::= { testEntry 3 }
+
+testValue OBJECT-TYPE
+    SYNTAX      Counter32
+    MAX-ACCESS  read-only
+    STATUS      current
+    DESCRIPTION ""
+    ::= { testEntry 4 }
+
+testExtEntry OBJECT-TYPE
+    SYNTAX      TestExtEntry
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION ""
+    AUGMENTS    { testEntry }
+    ::= { testExtTable 1 }
+
+testExtValue OBJECT-TYPE
+    SYNTAX      Counter32
+    MAX-ACCESS  read-only
+    STATUS      current
+    DESCRIPTION ""
+    ::= { testExtEntry 1 }
+
+TestCode ::= OCTET STRING (SIZE (4))
+
+codeEntry OBJECT-TYPE
+    SYNTAX      CodeEntry
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION ""
+    INDEX       { codeName, codeValue }
+    ::= { codeTable 1 }
+
+codeName OBJECT-TYPE
+    SYNTAX      DisplayString
+    MAX-ACCESS  not-accessible
+    STATUS      current
+    DESCRIPTION ""
+    ::= { codeEntry 1 }
+
+codeValue OBJECT-TYPE
+    SYNTAX      TestCode
+    MAX-ACCESS  read-only
+    STATUS      current
+    DESCRIPTION ""
+    ::= { codeEntry 2 }
+
+END
+"#;
+
+    fn resolver() -> MibIndexResolver {
+        let mut resolver = MibIndexResolver::default();
+        resolver.add_source(TEST_MIB);
+        resolver
+    }
+
+    fn text(s: &str) -> Vec<u64> {
+        s.bytes().map(u64::from).collect()
+    }
+
+    #[test]
+    fn resolves_index_clauses() {
+        let resolver = resolver();
+        assert_eq!(
+            resolver.index_names("TEST-MIB::testValue"),
+            Some(vec![
+                "testIfIndex".to_string(),
+                "testAddress".to_string(),
+                "testName".to_string()
+            ])
+        );
+        assert_eq!(
+            resolver.index_parts("TEST-MIB::testValue"),
+            Some(vec![
+                IndexPart::Integer,
+                IndexPart::OctetString {
+                    fixed_len: Some(4),
+                    implied: false,
+                    format: OctetFormat::Ip,
+                },
+                IndexPart::OctetString {
+                    fixed_len: None,
+                    implied: true,
+                    format: OctetFormat::Text,
+                },
+            ])
+        );
+        // AUGMENTS shares the INDEX of the augmented table
+        assert_eq!(
+            resolver.index_names("TEST-MIB::testExtValue"),
+            resolver.index_names("TEST-MIB::testValue")
+        );
+        assert_eq!(resolver.index_parts("TEST-MIB::unknownColumn"), None);
+    }
+
+    #[test]
+    fn decodes_multi_column_indices() {
+        let parts = resolver().index_parts("TEST-MIB::testValue").unwrap();
+        let index = [&[3, 10, 0, 0, 1][..], &text("eth0")].concat();
+        assert_eq!(
+            decode_index(&index, &parts),
+            Some(vec![
+                "3".to_string(),
+                "10.0.0.1".to_string(),
+                "eth0".to_string()
+            ])
+        );
+        assert_eq!(format_index(&index, Some(&parts)), "3_10.0.0.1_eth0");
+    }
+
+    #[test]
+    fn decodes_fixed_and_variable_strings() {
+        let parts = resolver().index_parts("TEST-MIB::codeValue").unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                IndexPart::OctetString {
+                    fixed_len: None,
+                    implied: false,
+                    format: OctetFormat::Text,
+                },
+                IndexPart::OctetString {
+                    fixed_len: Some(4),
+                    implied: false,
+                    format: OctetFormat::Hex,
+                },
+            ]
+        );
+        // the variable length string is prefixed by its length, the fixed one is not
+        let index = [&[2][..], &text("ab"), &[0, 1, 254, 255]].concat();
+        assert_eq!(
+            decode_index(&index, &parts),
+            Some(vec!["ab".to_string(), "0001feff".to_string()])
+        );
+    }
+
+    #[test]
+    fn decodes_object_identifiers() {
+        let parts = [
+            IndexPart::ObjectIdentifier { implied: false },
+            IndexPart::ObjectIdentifier { implied: true },
+        ];
+        assert_eq!(
+            decode_index(&[2, 1, 3, 1, 3, 6], &parts),
+            Some(vec!["1.3".to_string(), "1.3.6".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_over_long_suffixes() {
+        let resolver = resolver();
+        let parts = resolver.index_parts("TEST-MIB::testValue").unwrap();
+        assert_eq!(decode_index(&[], &parts), None);
+        assert_eq!(decode_index(&[3, 10, 0], &parts), None);
+        // octets are at most 255
+        assert_eq!(decode_index(&[3, 10, 0, 0, 256], &parts), None);
+
+        let parts = resolver.index_parts("TEST-MIB::codeValue").unwrap();
+        // length prefix beyond the end of the suffix
+        assert_eq!(decode_index(&[200, 97], &parts), None);
+        assert_eq!(decode_index(&[u64::MAX, 97], &parts), None);
+        // fixed size string cut short, and a sub-identifier left over
+        assert_eq!(decode_index(&[1, 97, 0, 1, 2], &parts), None);
+        assert_eq!(decode_index(&[1, 97, 0, 1, 2, 3, 4], &parts), None);
+
+        // undecodable suffixes are still rendered
+        assert_eq!(format_index(&[1, 97, 0, 1, 2], Some(&parts)), "1.97.0.1.2");
+    }
+
+    #[test]
+    fn formats_index_as_configured() {
+        let mac = [0, 17, 34, 51, 68, 85];
+        assert_eq!(format_index_as(&mac, IndexFormat::Mac), "00:11:22:33:44:55");
+        assert_eq!(format_index_as(&mac, IndexFormat::Hex), "001122334455");
+        assert_eq!(
+            format_index_as(&mac, IndexFormat::Decimal),
+            "0.17.34.51.68.85"
+        );
+        assert_eq!(format_index_as(&[10, 0, 0, 1], IndexFormat::Ip), "10.0.0.1");
+        let ipv6 = [32, 1, 13, 184, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(format_index_as(&ipv6, IndexFormat::Ip), "2001:db8::1");
+        // sub-identifiers which are not octets, or none at all, stay dotted
+        assert_eq!(format_index_as(&[1, 1000], IndexFormat::Mac), "1.1000");
+        assert_eq!(format_index_as(&[], IndexFormat::Hex), "");
+    }
+
+    #[test]
+    fn resolves_display_hints_through_textual_conventions() {
+        let mut resolver = resolver();
+        resolver.add_source(
+            r#"
+HINT-MIB DEFINITIONS ::= BEGIN
+
+MacAddress ::= TEXTUAL-CONVENTION
+    DISPLAY-HINT "1x:"
+    STATUS       current
+    DESCRIPTION  "An 802 MAC address"
+    SYNTAX       OCTET STRING (SIZE (6))
+
+BridgeAddress ::= TEXTUAL-CONVENTION
+    STATUS       current
+    DESCRIPTION  "A MacAddress without a hint of its own"
+    SYNTAX       MacAddress
+
+fdbAddress OBJECT-TYPE
+    SYNTAX      BridgeAddress
+    MAX-ACCESS  read-only
+    STATUS      current
+    DESCRIPTION ""
+    ::= { fdbEntry 1 }
+
+END
+"#,
+        );
+        assert_eq!(resolver.display_hint("HINT-MIB::fdbAddress"), Some("1x:"));
+        assert_eq!(resolver.display_hint("TEST-MIB::testValue"), None);
+    }
+
+    #[test]
+    fn formats_octets_with_display_hints() {
+        let mac = [0, 17, 34, 51, 68, 255];
+        assert_eq!(
+            format_display_hint(&mac, "1x:"),
+            Some("00:11:22:33:44:ff".to_string())
+        );
+        assert_eq!(
+            format_display_hint(b"eth0", "255a"),
+            Some("eth0".to_string())
+        );
+        assert_eq!(
+            format_display_hint(&[10, 0, 0, 1], "1d.1d.1d.1d"),
+            Some("10.0.0.1".to_string())
+        );
+        assert_eq!(
+            format_display_hint(&[1, 2, 3, 4], "2x-"),
+            Some("0102-0304".to_string())
+        );
+        // the first octet counts the repetitions of a `*` spec
+        assert_eq!(
+            format_display_hint(&[2, 1, 2, 3], "*1d.;1d"),
+            Some("1.2;3".to_string())
+        );
+        // octets running out in the middle of a spec are rendered as far as they go
+        assert_eq!(
+            format_display_hint(&[1, 2, 3], "2d."),
+            Some("258.3".to_string())
+        );
+        assert_eq!(format_display_hint(&[], "1x:"), Some("".to_string()));
+    }
+
+    #[test]
+    fn rejects_hints_which_are_not_for_octet_strings() {
+        for hint in ["", "d-2", "x", "0x", "9d", "1q", "1x:x"] {
+            assert_eq!(format_display_hint(&[1, 2], hint), None, "{:?}", hint);
+        }
+    }
+}
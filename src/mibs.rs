@@ -0,0 +1,301 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Error};
+use log::{debug, trace, warn};
+use rayon::prelude::*;
+use scan_dir::ScanDir;
+use serde::{Deserialize, Serialize};
+
+use snmp_mp::VarBind;
+
+use crate::mib_index::{IndexPart, MibIndexResolver};
+use crate::snmp::{self, vec_to_var_binds};
+
+pub struct ResolvedMibs {
+    pub oid_var_bind_map: HashMap<String, VarBind>,
+    /// index suffix decoding per instance column, for tables keyed by non printable values
+    pub index_parts_map: HashMap<String, Vec<IndexPart>>,
+    /// octet string DISPLAY-HINT per instance column, for instance values which are not text
+    pub display_hints: HashMap<String, String>,
+    /// names of the INDEX objects of the table of each resolved column
+    pub index_names: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MibCache {
+    hash: u64,
+    oids: HashMap<String, Vec<u64>>,
+    index_parts: HashMap<String, Vec<IndexPart>>,
+    display_hints: HashMap<String, String>,
+    index_names: HashMap<String, Vec<String>>,
+}
+
+pub struct MibCacheOptions<'a> {
+    pub path: Option<&'a str>,
+    pub rebuild: bool,
+}
+
+/// Enumerate the files in `mibdirs` which provide one of `required_mibs`
+pub fn find_mib_files(mibdirs: &[String], required_mibs: &HashSet<String>) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for mibdir in mibdirs {
+        if !Path::new(&mibdir).is_dir() {
+            debug!("mibs: mibdir {} does not exist, skipping", mibdir);
+            continue;
+        }
+
+        let mut mibdir_files: Vec<PathBuf> = ScanDir::files()
+            .walk(mibdir, |iter| {
+                iter.filter(|(_, name)| required_mibs.contains(name.split('.').nth(0).unwrap()))
+                    .map(|(ref entry, _)| entry.path())
+                    .collect()
+            })
+            .unwrap();
+        files.append(&mut mibdir_files);
+    }
+    files
+}
+
+/// Resolve `required_oids`, using the cache if its inputs are unchanged
+pub fn load(
+    files: &[PathBuf],
+    required_mibs: &HashSet<String>,
+    required_oids: &HashSet<String>,
+    instances: &HashSet<String>,
+    cache: &MibCacheOptions,
+) -> Result<ResolvedMibs, Error> {
+    let cache_path = match cache.path {
+        Some(cache_path) => cache_path,
+        None => return resolve(files, required_mibs, required_oids, instances),
+    };
+
+    let hash = inputs_hash(files, required_oids)?;
+    if cache.rebuild {
+        debug!("mibs: rebuilding cache {}", cache_path);
+    } else {
+        match read_cache(cache_path, hash) {
+            Ok(Some(resolved)) => {
+                debug!("mibs: loaded resolved oids from cache {}", cache_path);
+                return Ok(resolved);
+            }
+            Ok(None) => debug!("mibs: cache {} is outdated, rebuilding", cache_path),
+            Err(error) => debug!("mibs: could not read cache {}: {}", cache_path, error),
+        }
+    }
+
+    let resolved = resolve(files, required_mibs, required_oids, instances)?;
+    match write_cache(cache_path, hash, &resolved) {
+        Ok(()) => debug!("mibs: wrote cache {}", cache_path),
+        Err(error) => warn!("mibs: could not write cache {}: {}", cache_path, error),
+    }
+    Ok(resolved)
+}
+
+/// Parse the MIB files and resolve `required_oids`
+pub fn resolve(
+    files: &[PathBuf],
+    required_mibs: &HashSet<String>,
+    required_oids: &HashSet<String>,
+    instances: &HashSet<String>,
+) -> Result<ResolvedMibs, Error> {
+    let (mibs, mib_index_resolver) = parse(files, required_mibs)?;
+
+    let mut oid_var_bind_map: HashMap<String, VarBind> = HashMap::new();
+    let mut index_names: HashMap<String, Vec<String>> = HashMap::new();
+
+    for oid in required_oids {
+        let full_oid = snmp::build_snmp_mib_tree(oid, &mibs)?;
+
+        debug!("mibs: resolved {} to {:?}", oid, full_oid);
+        oid_var_bind_map.insert(oid.to_string(), vec_to_var_binds(full_oid));
+        if let Some(names) = mib_index_resolver.index_names(oid) {
+            index_names.insert(oid.to_string(), names);
+        }
+    }
+
+    let mut index_parts_map: HashMap<String, Vec<IndexPart>> = HashMap::new();
+    let mut display_hints: HashMap<String, String> = HashMap::new();
+    for instance in instances {
+        if let Some(display_hint) = mib_index_resolver.display_hint(instance) {
+            debug!("mibs: DISPLAY-HINT of {} is {}", instance, display_hint);
+            display_hints.insert(instance.to_string(), display_hint.to_string());
+        }
+        match mib_index_resolver.index_parts(instance) {
+            Some(index_parts) => {
+                debug!("mibs: INDEX of {} resolved to {:?}", instance, index_parts);
+                index_parts_map.insert(instance.to_string(), index_parts);
+            }
+            None => debug!(
+                "mibs: INDEX of {} could not be resolved, using numeric index suffixes",
+                instance
+            ),
+        }
+    }
+
+    Ok(ResolvedMibs {
+        oid_var_bind_map,
+        index_parts_map,
+        display_hints,
+        index_names,
+    })
+}
+
+/// Parse the MIB files and resolve a single oid, along with the imports followed to resolve it
+pub fn resolve_oid(
+    files: &[PathBuf],
+    required_mibs: &HashSet<String>,
+    oid: &str,
+) -> Result<(Vec<u64>, Vec<String>), Error> {
+    let (mibs, _) = parse(files, required_mibs)?;
+    let mut import_chain = vec![];
+    let full_oid = snmp::build_snmp_mib_tree_traced(oid, &mibs, &mut import_chain)?;
+    Ok((full_oid, import_chain))
+}
+
+// fails if one of `required_mibs` is missing or could not be parsed
+fn parse(
+    files: &[PathBuf],
+    required_mibs: &HashSet<String>,
+) -> Result<(Vec<mib_parser::MibInfo>, MibIndexResolver), Error> {
+    debug!("mibs: loading required_mibs");
+    trace!("mibs: required_mibs = {:?}", required_mibs);
+    let mut mibs: Vec<mib_parser::MibInfo> = vec![];
+    let mut mib_index_resolver = MibIndexResolver::default();
+    let mib_parse_options = mib_parser::ParseOptions {
+        pretty_print: false,
+    };
+
+    // parse in parallel, results are sorted by path afterwards so the outcome does not depend
+    // on the order in which the files have been parsed
+    let mut parsed: Vec<(
+        &PathBuf,
+        Result<mib_parser::MibInfo, String>,
+        Option<String>,
+    )> = files
+        .par_iter()
+        .map(|file| {
+            // load mib
+            debug!("mibs: parsing {:?}", file);
+            let mib = mib_parser::parse_file(file, &mib_parse_options)
+                .map_err(|error| format!("{:?}", error));
+            // INDEX clauses are not part of MibInfo, scan the source for them
+            let source = match fs::read(file) {
+                Ok(source) => Some(String::from_utf8_lossy(&source).to_string()),
+                Err(error) => {
+                    debug!(
+                        "mibs: could not read {:?} for INDEX clauses: {}",
+                        file, error
+                    );
+                    None
+                }
+            };
+            (file, mib, source)
+        })
+        .collect();
+    parsed.sort_by(|a, b| a.0.cmp(b.0));
+
+    // MIB directories commonly contain broken vendor files, only fail if a required one is
+    // affected, which is detected by the check for missing mibs below
+    let mut parse_errors = vec![];
+    for (file, mib, source) in parsed {
+        match mib {
+            Ok(mib) => mibs.push(mib),
+            Err(error) => {
+                warn!("mibs: could not parse {:?}, skipping: {}", file, error);
+                parse_errors.push((file, error));
+                continue;
+            }
+        }
+        if let Some(source) = source {
+            mib_index_resolver.add_source(&source);
+        }
+    }
+
+    let mibs_found: HashSet<String> = mibs
+        .iter()
+        .map(|mib| mib.modules.first().unwrap().name.clone())
+        .collect();
+    trace!("mibs: mibs_found={:?}", mibs_found);
+    let mibs_missing: HashSet<&String> = required_mibs.difference(&mibs_found).collect();
+    if !mibs_missing.is_empty() {
+        let unparseable: Vec<String> = parse_errors
+            .iter()
+            .filter(|(file, _)| {
+                let file_name = file.file_name().unwrap().to_string_lossy();
+                mibs_missing.contains(&file_name.split('.').nth(0).unwrap().to_string())
+            })
+            .map(|(file, error)| format!("{:?}: {}", file, error))
+            .collect();
+        if !unparseable.is_empty() {
+            bail!(
+                "Could not parse some required mibs:\n{}",
+                unparseable.join("\n")
+            );
+        }
+
+        bail!("Could not find some required mibs: {:?}", mibs_missing);
+    }
+
+    Ok((mibs, mib_index_resolver))
+}
+
+// DefaultHasher is not guaranteed to be stable across releases, which only causes a rebuild
+fn inputs_hash(files: &[PathBuf], required_oids: &HashSet<String>) -> Result<u64, Error> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+
+    let mut files: Vec<&PathBuf> = files.iter().collect();
+    files.sort();
+    for file in files {
+        file.hash(&mut hasher);
+        fs::read(file)?.hash(&mut hasher);
+    }
+
+    let mut required_oids: Vec<&String> = required_oids.iter().collect();
+    required_oids.sort();
+    required_oids.hash(&mut hasher);
+
+    Ok(hasher.finish())
+}
+
+fn read_cache(path: &str, hash: u64) -> Result<Option<ResolvedMibs>, Error> {
+    let cache: MibCache = serde_json::from_slice(&fs::read(path)?)?;
+    if cache.hash != hash {
+        return Ok(None);
+    }
+
+    Ok(Some(ResolvedMibs {
+        oid_var_bind_map: cache
+            .oids
+            .into_iter()
+            .map(|(name, oid)| (name, vec_to_var_binds(oid)))
+            .collect(),
+        index_parts_map: cache.index_parts,
+        display_hints: cache.display_hints,
+        index_names: cache.index_names,
+    }))
+}
+
+fn write_cache(path: &str, hash: u64, resolved: &ResolvedMibs) -> Result<(), Error> {
+    let cache = MibCache {
+        hash,
+        oids: resolved
+            .oid_var_bind_map
+            .iter()
+            .map(|(name, var_bind)| (name.clone(), var_bind.name().components().to_vec()))
+            .collect(),
+        index_parts: resolved.index_parts_map.clone(),
+        display_hints: resolved.display_hints.clone(),
+        index_names: resolved.index_names.clone(),
+    };
+
+    // write to a temporary file first so an interrupted write never leaves a broken cache
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_vec(&cache)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
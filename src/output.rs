@@ -1,102 +1,842 @@
 use flume::{Receiver, Sender};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs::File;
+#[cfg(feature = "remote-write")]
+use std::hash::Hash;
 use std::io::prelude::*;
-use std::net::TcpStream;
-use std::time::Duration;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
-use anyhow::Error;
-use log::{debug, info, trace, warn};
-use size_format::SizeFormatterSI;
+use anyhow::{bail, format_err, Error};
+use log::{debug, error, info, trace, warn};
+use regex::{Regex, RegexSet};
+use socket2::{SockRef, TcpKeepalive};
 
-use crate::config::Output;
+#[cfg(any(feature = "remote-write", feature = "graphite-http"))]
+use crate::config::HttpPoolSettings;
+use crate::config::{CarbonOutputSettings, MetricAlias, MetricFilter, Output};
+use crate::snmp::ValueType;
 
-#[derive(Debug)]
+mod dogstatsd;
+mod file;
+#[cfg(feature = "graphite-http")]
+mod graphite_http;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+mod sink;
+
+use sink::MetricSink;
+
+#[derive(Debug, Clone)]
 pub struct CarbonMetricValue {
     pub timestamp: SystemTime,
     pub metric: String,
     pub value: String,
+    /// What the metric has been collected from, unset for metrics about the collector itself
+    pub source: Option<MetricSource>,
 }
 
-pub async fn carbon_send_safe(
-    output: Output,
-    channel_sender: Sender<CarbonMetricValue>,
-    channel_receiver: Receiver<CarbonMetricValue>,
-) {
-    let backoff = Duration::from_secs(1);
+#[derive(Debug, Clone)]
+pub struct MetricSource {
+    pub device: String,
+    /// endpoint of a device with `snmp.endpoints`
+    pub endpoint: Option<String>,
+    /// instance name or decoded index of the row
+    pub instance: String,
+    /// name of the value column, without its MIB module
+    pub name: String,
+    /// collection interval of the device
+    #[cfg_attr(not(feature = "remote-write"), allow(dead_code))]
+    pub interval: Duration,
+    pub value_type: ValueType,
+}
 
-    loop {
-        let sender = carbon_send(
-            output.clone(),
-            channel_sender.clone(),
-            channel_receiver.clone(),
+/// Last value of every series, for outputs which mark a series stale once it has not been
+/// updated for `factor` times the interval of its device, e.g. because the device stopped
+/// answering
+#[cfg(feature = "remote-write")]
+pub struct Freshness<K> {
+    factor: f64,
+    series: HashMap<K, (CarbonMetricValue, Instant)>,
+}
+
+#[cfg(feature = "remote-write")]
+impl<K: Hash + Eq> Freshness<K> {
+    pub fn new(factor: f64) -> Self {
+        Freshness {
+            factor,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Metrics about the collector itself have no interval and are not tracked
+    pub fn update(&mut self, key: K, metricval: &CarbonMetricValue) {
+        if metricval.source.is_some() {
+            self.series.insert(key, (metricval.clone(), Instant::now()));
+        }
+    }
+
+    /// The last values of the series which went stale since the last call, they are forgotten
+    pub fn take_stale(&mut self) -> Vec<CarbonMetricValue> {
+        let factor = self.factor;
+        let mut stale = vec![];
+        self.series.retain(|_, (metricval, updated)| {
+            let interval = metricval.source.as_ref().unwrap().interval;
+            if updated.elapsed() < interval.mul_f64(factor) {
+                return true;
+            }
+            stale.push(metricval.clone());
+            false
+        });
+        stale
+    }
+}
+
+/// A metric value which could not be written, it is retried before newer values from the
+/// channel so the order is preserved
+pub struct RetryEntry {
+    pub metricval: CarbonMetricValue,
+    pub attempts: u32,
+}
+
+/// A metric value is dropped after failing to be written this many times
+pub const MAX_SEND_ATTEMPTS: u32 = 5;
+
+/// Take the next metric value to send, values waiting for a retry come first
+pub async fn next_metric(
+    retry_buffer: &mut VecDeque<RetryEntry>,
+    channel_receiver: &Receiver<CarbonMetricValue>,
+) -> (CarbonMetricValue, u32) {
+    match retry_buffer.pop_front() {
+        Some(entry) => (entry.metricval, entry.attempts),
+        None => (channel_receiver.recv_async().await.unwrap(), 0),
+    }
+}
+
+/// Queue a metric value which failed to be sent for a retry in front of newer values, unless
+/// it already failed `MAX_SEND_ATTEMPTS` times
+pub fn retry_first(
+    context: &str,
+    retry_buffer: &mut VecDeque<RetryEntry>,
+    metricval: CarbonMetricValue,
+    attempts: u32,
+    error: &dyn Display,
+) {
+    let attempts = attempts + 1;
+    if attempts >= MAX_SEND_ATTEMPTS {
+        warn!(
+            "{}: error {} while sending {}, dropping it after {} attempts",
+            context, error, metricval.metric, attempts
         );
-        if let Err(error) = sender.await {
-            let (carbon_server, carbon_port) = match &output {
-                Output::CarbonOutput {
-                    prefix: _,
-                    graphite_server,
-                    graphite_port,
-                } => (graphite_server, graphite_port),
+    } else {
+        debug!(
+            "{}: error {} while sending {}, retrying it first after reconnecting",
+            context, error, metricval.metric
+        );
+        retry_buffer.push_front(RetryEntry {
+            metricval,
+            attempts,
+        });
+    }
+}
+
+/// The `MetricFilter` of an output with its patterns compiled
+pub struct KeyFilter {
+    allow: Option<RegexSet>,
+    deny: Option<RegexSet>,
+}
+
+impl KeyFilter {
+    pub fn new(filter: &MetricFilter) -> Result<Self, Error> {
+        let compile = |patterns: &Vec<String>| -> Result<Option<RegexSet>, Error> {
+            if patterns.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(RegexSet::new(patterns)?))
+            }
+        };
+        Ok(KeyFilter {
+            allow: compile(&filter.allow)?,
+            deny: compile(&filter.deny)?,
+        })
+    }
+
+    /// `deny` takes precedence over `allow`
+    pub fn matches(&self, metric: &str) -> bool {
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.is_match(metric))
+            && !self.deny.as_ref().is_some_and(|deny| deny.is_match(metric))
+    }
+}
+
+/// `main.metric_aliases` with their patterns compiled
+pub struct MetricAliases(Vec<(AliasPattern, String)>);
+
+enum AliasPattern {
+    Name(String),
+    Regex(Regex),
+}
+
+impl MetricAliases {
+    pub fn new(aliases: &[MetricAlias]) -> Result<Self, Error> {
+        let mut compiled = vec![];
+        for alias in aliases {
+            let pattern = match (&alias.name, &alias.regex) {
+                (Some(name), None) => AliasPattern::Name(name.clone()),
+                (None, Some(regex)) => AliasPattern::Regex(Regex::new(regex)?),
+                _ => bail!(
+                    "metric alias {} needs either a name or a regex",
+                    alias.alias
+                ),
             };
-            let carbon_host = format!("{}:{}", carbon_server, carbon_port);
-
-            let queue_len = channel_receiver.len();
-            let memory_consumed =
-                std::mem::size_of::<CarbonMetricValue>() as u64 * queue_len as u64;
-            warn!(
-                "carbon_send_safe({}): error {:?}; buffering {} metric values, using {} memory; backing off for {:?}",
-                carbon_host, error, queue_len, SizeFormatterSI::new(memory_consumed), backoff
-            );
-            tokio::time::sleep(backoff).await;
-            info!(
-                "carbon_send_safe({}): backoff {:?} done, retrying...",
-                carbon_host, backoff
-            );
+            if sanitize_carbon(&alias.alias).is_empty() {
+                bail!(
+                    "metric alias for {:?} is empty",
+                    alias.name.as_ref().or(alias.regex.as_ref())
+                );
+            }
+            compiled.push((pattern, alias.alias.clone()));
+        }
+        Ok(MetricAliases(compiled))
+    }
+
+    /// Name of the value column `full_name` in metric keys, `field` unless an alias matches.
+    /// Aliases are sanitized like device and instance names, they can not add levels to a key.
+    pub fn apply(&self, full_name: &str, field: &str) -> String {
+        for (pattern, alias) in &self.0 {
+            match pattern {
+                AliasPattern::Name(name) if name == full_name || name == field => {
+                    return sanitize_carbon(alias);
+                }
+                AliasPattern::Regex(regex) if regex.is_match(field) => {
+                    return sanitize_carbon(&regex.replace(field, alias.as_str()));
+                }
+                _ => {}
+            }
+        }
+        field.to_string()
+    }
+}
+
+/// Client of an HTTP output. Requests reuse the connections of the pool, a connection which
+/// broke is dropped from it and the request fails, so the output can retry the batch.
+#[cfg(any(feature = "remote-write", feature = "graphite-http"))]
+pub fn http_client(pool: &HttpPoolSettings, timeout: Duration) -> Result<reqwest::Client, Error> {
+    Ok(reqwest::Client::builder()
+        .timeout(timeout)
+        .pool_max_idle_per_host(pool.max_idle)
+        .pool_idle_timeout(Duration::from_secs(pool.idle_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()?)
+}
+
+/// Outcome of a request of an HTTP output
+#[cfg(any(feature = "remote-write", feature = "graphite-http"))]
+pub enum Delivery {
+    Sent,
+    /// the receiver may accept the batch later (HTTP 5xx)
+    Retry(String),
+    /// the request did not complete, e.g. because the receiver closed a pooled connection. The
+    /// connection is not reused.
+    ConnectionFailed(String),
+    /// the receiver will never accept the batch (HTTP 4xx)
+    Rejected(String),
+}
+
+#[cfg(any(feature = "remote-write", feature = "graphite-http"))]
+impl Delivery {
+    pub async fn of(response: reqwest::Result<reqwest::Response>) -> Delivery {
+        match response {
+            Ok(response) if response.status().is_success() => Delivery::Sent,
+            Ok(response) => {
+                let status = response.status();
+                let reason = format!(
+                    "HTTP {}: {}",
+                    status,
+                    response.text().await.unwrap_or_default().trim()
+                );
+                if status.is_client_error() {
+                    Delivery::Rejected(reason)
+                } else {
+                    Delivery::Retry(reason)
+                }
+            }
+            Err(error) => Delivery::ConnectionFailed(format!("{:?}", error)),
+        }
+    }
+}
+
+/// Copy every metric value to the channel of each output whose filter matches it
+pub async fn fan_out(
+    channel_receiver: Receiver<CarbonMetricValue>,
+    output_senders: Vec<(Sender<CarbonMetricValue>, KeyFilter)>,
+) {
+    while let Ok(metricval) = channel_receiver.recv_async().await {
+        for (output_sender, filter) in &output_senders {
+            if filter.matches(&metricval.metric) {
+                output_sender.send(metricval.clone()).unwrap();
+            } else {
+                trace!("fan_out: {} is filtered", metricval.metric);
+            }
         }
     }
 }
 
-pub async fn carbon_send(
+/// Send everything received on `channel_receiver` to `output`, reconnecting on errors. Self
+/// metrics of the output are sent to `metric_channel` as `outputs.<output_name>.*`.
+pub async fn send_safe(
     output: Output,
-    channel_sender: Sender<CarbonMetricValue>,
+    output_name: String,
     channel_receiver: Receiver<CarbonMetricValue>,
-) -> Result<(), Error> {
-    // set up output
-    let (prefix, carbon_server, carbon_port) = match output {
-        Output::CarbonOutput {
-            prefix,
-            graphite_server,
-            graphite_port,
-        } => (prefix, graphite_server, graphite_port),
+    metric_channel: Sender<CarbonMetricValue>,
+) {
+    match output {
+        Output::Carbon(settings) => {
+            carbon_send_safe(settings, output_name, channel_receiver, metric_channel).await
+        }
+        #[cfg(feature = "kafka")]
+        Output::Kafka(settings) => kafka::kafka_send_safe(settings, channel_receiver).await,
+        #[cfg(feature = "mqtt")]
+        Output::Mqtt(settings) => mqtt::mqtt_send_safe(settings, channel_receiver).await,
+        #[cfg(feature = "remote-write")]
+        Output::RemoteWrite(settings) => {
+            remote_write::remote_write_send_safe(
+                settings,
+                output_name,
+                channel_receiver,
+                metric_channel,
+            )
+            .await
+        }
+        Output::File(settings) => file::file_send_safe(settings, channel_receiver).await,
+        Output::DogStatsD(settings) => {
+            dogstatsd::dogstatsd_send_safe(settings, channel_receiver).await
+        }
+        #[cfg(feature = "graphite-http")]
+        Output::GraphiteHttp(settings) => {
+            graphite_http::graphite_http_send_safe(
+                settings,
+                output_name,
+                channel_receiver,
+                metric_channel,
+            )
+            .await
+        }
+        // rejected when the configuration is loaded
+        #[allow(unreachable_patterns)]
+        output => error!(
+            "output {}: {} is not included in this build",
+            output_name,
+            output.name()
+        ),
+    }
+}
+
+pub async fn carbon_send_safe(
+    settings: CarbonOutputSettings,
+    output_name: String,
+    channel_receiver: Receiver<CarbonMetricValue>,
+    metric_channel: Sender<CarbonMetricValue>,
+) {
+    let channel_receiver = match settings.coalesce_window_ms {
+        Some(coalesce_window_ms) => {
+            let (coalesced_sender, coalesced_receiver) = flume::unbounded();
+            tokio::spawn(coalesce(
+                Duration::from_millis(coalesce_window_ms),
+                channel_receiver,
+                coalesced_sender,
+            ));
+            coalesced_receiver
+        }
+        None => channel_receiver,
+    };
+    let carbon_host = format!("{}:{}", settings.graphite_server, settings.graphite_port);
+    let drive_settings = sink::DriveSettings {
+        flush_interval: settings
+            .flush_interval_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis),
+        max_rate: settings.max_rate,
+        backoff: Duration::from_secs(1),
     };
+    let connect = || -> Result<CarbonSink, Error> {
+        Ok(CarbonSink {
+            name: carbon_host.clone(),
+            prefixes: settings.prefix.all().to_vec(),
+            stream: BufWriter::new(connect_carbon(&settings)?),
+        })
+    };
+
+    sink::drive(
+        &carbon_host,
+        &output_name,
+        drive_settings,
+        connect,
+        channel_receiver,
+        metric_channel,
+    )
+    .await
+}
 
-    let carbon_host = format!("{}:{}", carbon_server, carbon_port);
+/// Carbon plaintext lines over a TCP or TLS connection, one line per metric and prefix
+struct CarbonSink {
+    name: String,
+    prefixes: Vec<String>,
+    stream: BufWriter<Box<dyn Write + Send>>,
+}
 
-    let mut stream = TcpStream::connect(carbon_host)?;
+impl MetricSink for CarbonSink {
+    fn send_batch(&mut self, metrics: &[CarbonMetricValue]) -> Result<(), Error> {
+        for metricval in metrics {
+            for prefix in &self.prefixes {
+                let line = format_carbon(
+                    prefix,
+                    &metricval.metric,
+                    &metricval.value,
+                    &metricval.timestamp,
+                );
+                trace!("carbon_send: sending '{}'", line);
+                self.stream.write_all(line.as_bytes())?;
+                self.stream.write_all(b"\n")?;
+            }
+        }
+        self.stream.flush()?;
+        Ok(())
+    }
 
-    loop {
-        let metricval = channel_receiver.recv_async().await.unwrap();
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Length of a slot of `RateLimiter` at rates of 10 metrics per second and more
+const RATE_SLOT: Duration = Duration::from_millis(100);
+const BACKLOG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Paces writes to `rate` metrics per second, the writes of a slot are sent as one batch
+struct RateLimiter {
+    rate: u64,
+    per_slot: u64,
+    slot: Duration,
+    slot_start: Instant,
+    sent: u64,
+    last_report: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        let per_slot = (rate * RATE_SLOT.as_millis() as u64 / 1000).max(1);
+        RateLimiter {
+            rate,
+            per_slot,
+            slot: Duration::from_secs_f64(per_slot as f64 / rate as f64),
+            slot_start: Instant::now(),
+            sent: 0,
+            last_report: None,
+        }
+    }
 
-        let buf = format_carbon(
-            &prefix,
-            &metricval.metric,
-            &metricval.value,
-            &metricval.timestamp,
+    /// Writing `count` more metrics would exceed the share of the current slot, or it is over
+    fn exhausted(&self, count: usize) -> bool {
+        self.sent + count as u64 > self.per_slot || self.slot_start.elapsed() >= self.slot
+    }
+
+    /// Wait for the end of the current slot and start the next one
+    async fn next_slot(&mut self) {
+        tokio::time::sleep_until((self.slot_start + self.slot).into()).await;
+        self.slot_start = Instant::now();
+        self.sent = 0;
+    }
+
+    /// Log the progress of draining `backlog` queued metrics, at most every
+    /// `BACKLOG_REPORT_INTERVAL` and only if it takes longer than a second
+    fn report_backlog(&mut self, context: &str, backlog: usize) {
+        if backlog as u64 <= self.rate
+            || self
+                .last_report
+                .is_some_and(|last_report| last_report.elapsed() < BACKLOG_REPORT_INTERVAL)
+        {
+            return;
+        }
+        self.last_report = Some(Instant::now());
+        info!(
+            "{}: draining backlog of {} metrics at {} per second, about {:?} left",
+            context,
+            backlog,
+            self.rate,
+            Duration::from_secs(backlog as u64 / self.rate)
         );
+    }
+}
 
-        trace!("carbon_send: sending '{}'", buf);
+/// Forward metric values from `input` to `output` once per `window`, keeping only the last value
+/// per (metric, timestamp) that was received within the window
+pub async fn coalesce(
+    window: Duration,
+    input: Receiver<CarbonMetricValue>,
+    output: Sender<CarbonMetricValue>,
+) {
+    let mut pending: Vec<CarbonMetricValue> = vec![];
+    let mut positions: HashMap<(String, SystemTime), usize> = HashMap::new();
+    let mut flush = tokio::time::interval(window);
 
-        let write = stream.write(&[buf.as_bytes(), &[b'\n']].concat());
-        if let Err(error) = write {
-            debug!(
-                "carbon_send: error {:?} while sending '{}', reinjecting into channel",
-                error, buf
+    loop {
+        let mut disconnected = false;
+        tokio::select! {
+            metricval = input.recv_async() => match metricval {
+                Ok(metricval) => {
+                    match positions.entry((metricval.metric.clone(), metricval.timestamp)) {
+                        Entry::Occupied(entry) => {
+                            trace!("coalesce: replacing value of {}", metricval.metric);
+                            pending[*entry.get()] = metricval;
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(pending.len());
+                            pending.push(metricval);
+                        }
+                    }
+                    continue;
+                }
+                Err(_) => disconnected = true,
+            },
+            _ = flush.tick() => {}
+        }
+
+        positions.clear();
+        for metricval in pending.drain(..) {
+            output.send(metricval).unwrap();
+        }
+        if disconnected {
+            debug!("coalesce: input channel closed");
+            return;
+        }
+    }
+}
+
+/// Rough estimate of the memory used by `queue_len` buffered metric values, their strings
+/// are not accounted for
+pub fn estimate_buffered_bytes(queue_len: usize) -> u64 {
+    std::mem::size_of::<CarbonMetricValue>() as u64 * queue_len as u64
+}
+
+const QUEUE_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Emit the backlog of every output as self metrics on a fixed interval, so a growing queue is
+/// visible before it runs out of memory
+pub async fn report_queues(
+    outputs: Vec<(String, Sender<CarbonMetricValue>)>,
+    metric_channel: Sender<CarbonMetricValue>,
+) {
+    let mut interval = tokio::time::interval(QUEUE_METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (output_name, output_sender) in &outputs {
+            let queue_len = output_sender.len();
+            trace!(
+                "report_queues: output {} buffers {} metric values",
+                output_name,
+                queue_len
             );
-            channel_sender.send(metricval).unwrap();
+            metric_channel
+                .send(self_metric(
+                    &format!("outputs.{}.queue_length", output_name),
+                    queue_len,
+                ))
+                .unwrap();
+            metric_channel
+                .send(self_metric(
+                    &format!("outputs.{}.buffered_bytes", output_name),
+                    estimate_buffered_bytes(queue_len),
+                ))
+                .unwrap();
+        }
+    }
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Emit `collector.heartbeat` on a fixed interval, it keeps being sent while no device can be
+/// polled and only stops if the process itself is dead
+pub async fn heartbeat(metric_channel: Sender<CarbonMetricValue>) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        metric_channel.send(self_metric("heartbeat", 1)).unwrap();
+    }
+}
+
+/// Metrics about the collector itself are emitted below this reserved first key segment
+pub const SELF_METRIC_PREFIX: &str = "collector";
+
+/// Tells collectors polling the same devices apart, in self metrics and log lines
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+/// Only the first call has an effect, self metrics emitted before have no instance id
+pub fn set_instance_id(instance_id: String) {
+    let _ = INSTANCE_ID.set(instance_id);
+}
+
+pub fn instance_id() -> Option<&'static str> {
+    INSTANCE_ID.get().map(|instance_id| instance_id.as_str())
+}
+
+/// Metadata of a device by name, shared by the metrics sent for it
+type DeviceMetadata = HashMap<String, Arc<Vec<(String, String)>>>;
+
+/// Latest `main.device_metadata` of every device, e.g. `[("sysName", "core1")]`
+static DEVICE_METADATA: RwLock<Option<DeviceMetadata>> = RwLock::new(None);
+
+pub fn set_device_metadata(device_name: &str, metadata: Vec<(String, String)>) {
+    DEVICE_METADATA
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(device_name.to_string(), Arc::new(metadata));
+}
 
-            return Err(error.into());
+/// Empty until the metadata of the device has been fetched
+pub fn device_metadata(device_name: &str) -> Arc<Vec<(String, String)>> {
+    DEVICE_METADATA
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|metadata| metadata.get(device_name).cloned())
+        .unwrap_or_default()
+}
+
+/// `collector.<instance id>.<metric_name>`
+pub fn self_metric(metric_name: &str, value: impl Display) -> CarbonMetricValue {
+    let metric = match instance_id() {
+        Some(instance_id) => format!(
+            "{}.{}.{}",
+            SELF_METRIC_PREFIX,
+            sanitize_carbon(instance_id),
+            metric_name
+        ),
+        None => format!("{}.{}", SELF_METRIC_PREFIX, metric_name),
+    };
+    CarbonMetricValue {
+        timestamp: SystemTime::now(),
+        metric,
+        value: format!("{}", value),
+        source: None,
+    }
+}
+
+pub fn device_self_metric(
+    device_name: &str,
+    metric_name: &str,
+    value: impl Display,
+) -> CarbonMetricValue {
+    self_metric(
+        &format!("devices.{}.{}", sanitize_carbon(device_name), metric_name),
+        value,
+    )
+}
+
+/// Delay before the next address is tried while a connection attempt is still pending
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn connect_carbon(settings: &CarbonOutputSettings) -> Result<Box<dyn Write + Send>, Error> {
+    let carbon_server = unbracket(&settings.graphite_server);
+    let addrs = interleave_families(
+        (carbon_server, settings.graphite_port)
+            .to_socket_addrs()
+            .map_err(|error| format_err!("could not resolve {}: {}", carbon_server, error))?
+            .collect(),
+    );
+    let stream = happy_eyeballs_connect(&addrs)?;
+    stream.set_nodelay(settings.tcp_nodelay)?;
+    // without these a half-open connection only fails once the kernel's send buffer is full
+    if let Some(keepalive) = &settings.keepalive {
+        SockRef::from(&stream).set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(Duration::from_secs(keepalive.idle_secs))
+                .with_interval(Duration::from_secs(keepalive.interval_secs))
+                .with_retries(keepalive.count),
+        )?;
+    }
+    stream.set_write_timeout(
+        settings
+            .write_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    )?;
+
+    if !settings.tls {
+        return Ok(Box::new(stream));
+    }
+
+    let server_name = settings.tls_server_name.as_deref().unwrap_or(carbon_server);
+    let server_name = rustls::ServerName::try_from(server_name)
+        .map_err(|_| format_err!("invalid TLS server name {}", server_name))?;
+    let connection = rustls::ClientConnection::new(Arc::new(tls_config(settings)?), server_name)?;
+
+    debug!("carbon_send: using TLS");
+    Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+}
+
+/// IPv6 literals may be given as `[::1]`, which the resolver does not accept
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Order the resolved addresses alternating between IPv6 and IPv4, starting with the family of
+/// the first address (RFC 8305)
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().is_none_or(SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<SocketAddr>, VecDeque<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+
+    let mut interleaved = vec![];
+    while let Some(addr) = first.pop_front() {
+        interleaved.push(addr);
+        if let Some(addr) = second.pop_front() {
+            interleaved.push(addr);
+        }
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+/// Connect to the first address that answers. A new attempt is started whenever the previous
+/// one failed or has not succeeded within `HAPPY_EYEBALLS_DELAY`, attempts still running once
+/// a connection has been established are dropped when they complete.
+fn happy_eyeballs_connect(addrs: &[SocketAddr]) -> Result<TcpStream, Error> {
+    if addrs.is_empty() {
+        bail!("no addresses to connect to");
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut pending = 0;
+    let mut last_error = None;
+    let mut addrs = addrs.iter();
+    loop {
+        if let Some(addr) = addrs.next() {
+            let addr = *addr;
+            let tx = tx.clone();
+            pending += 1;
+            trace!("carbon_send: connecting to {}", addr);
+            std::thread::spawn(move || {
+                let _ = tx.send((addr, TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)));
+            });
+        } else if pending == 0 {
+            break;
+        }
+
+        let result = if !addrs.as_slice().is_empty() {
+            rx.recv_timeout(HAPPY_EYEBALLS_DELAY).ok()
+        } else {
+            rx.recv().ok()
+        };
+        match result {
+            Some((addr, Ok(stream))) => {
+                debug!("carbon_send: connected to {}", addr);
+                return Ok(stream);
+            }
+            Some((addr, Err(error))) => {
+                debug!("carbon_send: could not connect to {}: {}", addr, error);
+                pending -= 1;
+                last_error = Some(format_err!("{}: {}", addr, error));
+            }
+            // still pending, start the next attempt
+            None => {}
         }
     }
+
+    Err(last_error.unwrap())
+}
+
+fn tls_config(settings: &CarbonOutputSettings) -> Result<rustls::ClientConfig, Error> {
+    let mut root_store = rustls::RootCertStore::empty();
+    match &settings.tls_ca {
+        Some(tls_ca) => {
+            for cert in load_pem_certs(tls_ca)? {
+                root_store.add(&cert)?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // the system trust store may contain certificates rustls can not handle
+                if let Err(error) = root_store.add(&rustls::Certificate(cert.0)) {
+                    trace!("carbon_send: skipping system certificate: {}", error);
+                }
+            }
+        }
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+    let mut config = match (&settings.tls_cert, &settings.tls_key) {
+        (Some(tls_cert), Some(tls_key)) => {
+            builder.with_client_auth_cert(load_pem_certs(tls_cert)?, load_pem_key(tls_key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("tls_cert and tls_key have to be configured together"),
+    };
+
+    if settings.tls_insecure_skip_verify {
+        warn!("carbon_send: TLS certificate verification is disabled");
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    Ok(config)
+}
+
+fn load_pem_certs(path: &str) -> Result<Vec<rustls::Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path);
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_pem_key(path: &str) -> Result<rustls::PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key found in {}", path)
+}
+
+struct NoCertificateVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
 }
 
 pub fn sanitize_carbon(s: &str) -> String {
@@ -112,6 +852,54 @@ pub fn format_key(device_name: &str, variable_part: &str, metric_name: &str) ->
     )
 }
 
+/// `format_key` of a metric collected from an endpoint of the device, see `snmp.endpoints`
+pub fn format_endpoint_key(
+    device_name: &str,
+    endpoint: &str,
+    variable_part: &str,
+    metric_name: &str,
+) -> String {
+    format!(
+        "{}.{}.{}.{}",
+        sanitize_carbon(device_name),
+        sanitize_carbon(endpoint),
+        sanitize_carbon(variable_part),
+        metric_name
+    )
+}
+
+/// Shortest `max_metric_len`, a shortened key has to fit the hash
+pub const MIN_METRIC_LEN: usize = 32;
+
+/// Shorten `key` to `max_len` bytes, keeping the metric name at the end if possible. A hash of
+/// the full key replaces what is cut, so a key is always shortened the same way and distinct keys
+/// stay distinct.
+pub fn shorten_key(key: &str, max_len: usize) -> String {
+    let hash = format!("~{:016x}", stable_hash(key.as_bytes()));
+    let metric_name = key.rfind('.').map_or("", |pos| &key[pos..]);
+    let tail = if hash.len() + metric_name.len() < max_len {
+        metric_name
+    } else {
+        ""
+    };
+
+    let mut shortened = String::new();
+    for c in key.chars() {
+        if shortened.len() + c.len_utf8() + hash.len() + tail.len() > max_len {
+            break;
+        }
+        shortened.push(c);
+    }
+    shortened + &hash + tail
+}
+
+/// 64 bit FNV-1a, unlike the std hashers it does not change between Rust releases
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
 pub fn format_carbon(prefix: &str, metric: &str, value: &str, timestamp: &SystemTime) -> String {
     format!(
         "{}.{} {} {}",
@@ -124,3 +912,245 @@ pub fn format_carbon(prefix: &str, metric: &str, value: &str, timestamp: &System
             .as_secs()
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
+
+    fn v4(last: u8) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::new(192, 0, 2, last), 2003))
+    }
+
+    fn v6(last: u16) -> SocketAddr {
+        SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last), 2003))
+    }
+
+    /// A local address nothing listens on, connections to it are refused
+    fn refusing(ip: IpAddr) -> SocketAddr {
+        TcpListener::bind((ip, 0)).unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn unbracket_strips_brackets_of_ipv6_literals() {
+        assert_eq!(unbracket("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(unbracket("2001:db8::1"), "2001:db8::1");
+        assert_eq!(unbracket("192.0.2.1"), "192.0.2.1");
+        assert_eq!(unbracket("carbon.example.com"), "carbon.example.com");
+        assert_eq!(unbracket("[2001:db8::1"), "[2001:db8::1");
+    }
+
+    #[test]
+    fn interleave_families_alternates_starting_with_the_first() {
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(1)]),
+            vec![v6(1), v4(1), v6(2), v6(3)]
+        );
+        assert_eq!(
+            interleave_families(vec![v4(1), v4(2), v6(1), v6(2), v6(3)]),
+            vec![v4(1), v6(1), v4(2), v6(2), v6(3)]
+        );
+        assert_eq!(interleave_families(vec![v4(1), v4(2)]), vec![v4(1), v4(2)]);
+        assert_eq!(interleave_families(vec![v6(1), v6(2)]), vec![v6(1), v6(2)]);
+        assert_eq!(interleave_families(vec![]), vec![]);
+    }
+
+    #[test]
+    fn happy_eyeballs_connects_over_either_family() {
+        for ip in [
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            Ipv6Addr::LOCALHOST.into(),
+        ] {
+            let listener = TcpListener::bind((ip, 0)).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let stream = happy_eyeballs_connect(&[addr]).unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn happy_eyeballs_falls_back_to_the_other_family() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let v4_addr = listener.local_addr().unwrap();
+        let v6_refusing = refusing(Ipv6Addr::LOCALHOST.into());
+
+        let addrs = interleave_families(vec![v6_refusing, v4_addr]);
+        let stream = happy_eyeballs_connect(&addrs).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v4_addr);
+
+        let error = happy_eyeballs_connect(&[v6_refusing]).unwrap_err();
+        assert!(error.to_string().starts_with(&v6_refusing.to_string()));
+        assert!(happy_eyeballs_connect(&[]).is_err());
+    }
+
+    fn metric_at(name: &str, secs: u64, value: &str) -> CarbonMetricValue {
+        CarbonMetricValue {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            metric: name.to_string(),
+            value: value.to_string(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn rate_limiter_shares_the_rate_out_to_slots() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.per_slot, 100);
+        assert_eq!(limiter.slot, Duration::from_millis(100));
+        assert!(!limiter.exhausted(100));
+        assert!(limiter.exhausted(101));
+
+        // below 10 per second a slot holds a single metric and lasts longer
+        let limiter = RateLimiter::new(4);
+        assert_eq!(limiter.per_slot, 1);
+        assert_eq!(limiter.slot, Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_starts_the_next_slot_when_the_current_one_ends() {
+        let mut limiter = RateLimiter::new(1000);
+        limiter.sent = 100;
+        assert!(limiter.exhausted(1));
+
+        let start = Instant::now();
+        limiter.next_slot().await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(limiter.sent, 0);
+        assert!(!limiter.exhausted(100));
+    }
+
+    #[tokio::test]
+    async fn coalesce_keeps_the_last_value_per_metric_and_timestamp() {
+        let (sender, input) = flume::unbounded();
+        let (output, receiver) = flume::unbounded();
+        let coalescing = tokio::spawn(coalesce(Duration::from_secs(10), input, output));
+        // past the first tick of the window, which is right away
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        sender.send(metric_at("a", 60, "1")).unwrap();
+        sender.send(metric_at("b", 60, "2")).unwrap();
+        sender.send(metric_at("a", 60, "3")).unwrap();
+        sender.send(metric_at("a", 120, "4")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.is_empty());
+
+        // what is pending is sent once the input is closed
+        drop(sender);
+        coalescing.await.unwrap();
+        let values: Vec<(String, String)> = receiver
+            .drain()
+            .map(|metricval| (metricval.metric, metricval.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("a".to_string(), "3".to_string()),
+                ("b".to_string(), "2".to_string()),
+                ("a".to_string(), "4".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn shorten_key_keeps_the_metric_name_and_stays_distinct() {
+        let key = format!("sw1.{}.ifHCInOctets", "Ethernet1_1".repeat(20));
+        let shortened = shorten_key(&key, 64);
+        assert_eq!(shortened.len(), 64);
+        assert!(shortened.starts_with("sw1.Ethernet1_1"));
+        assert!(shortened.ends_with(".ifHCInOctets"));
+        assert_eq!(shorten_key(&key, 64), shortened);
+
+        let other = format!("sw1.{}2.ifHCInOctets", "Ethernet1_1".repeat(20));
+        assert_ne!(shorten_key(&other, 64), shortened);
+    }
+
+    #[test]
+    fn shorten_key_drops_a_metric_name_which_does_not_fit() {
+        let key = format!("sw1.{}.aVeryLongMetricNameOfAnMib", "x".repeat(100));
+        let shortened = shorten_key(&key, MIN_METRIC_LEN);
+        assert_eq!(shortened.len(), MIN_METRIC_LEN);
+        assert_eq!(
+            &shortened[MIN_METRIC_LEN - 17..],
+            format!("~{:016x}", stable_hash(key.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn shorten_key_does_not_split_characters() {
+        let key = format!("sw1.{}.ifAlias", "ä".repeat(100));
+        let shortened = shorten_key(&key, 64);
+        assert!(shortened.len() <= 64);
+        assert!(shortened.ends_with(".ifAlias"));
+    }
+
+    #[test]
+    fn key_filter_deny_takes_precedence_over_allow() {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        let everything = KeyFilter::new(&MetricFilter::default()).unwrap();
+        assert!(everything.matches("sw1.eth0.ifHCInOctets"));
+
+        let filter = KeyFilter::new(&MetricFilter {
+            allow: patterns(&[r"^sw1\.", r"^sw2\."]),
+            deny: patterns(&[r"\.ifHCOutOctets$"]),
+        })
+        .unwrap();
+        assert!(filter.matches("sw1.eth0.ifHCInOctets"));
+        assert!(filter.matches("sw2.eth0.ifHCInOctets"));
+        assert!(!filter.matches("sw3.eth0.ifHCInOctets"));
+        assert!(!filter.matches("sw1.eth0.ifHCOutOctets"));
+
+        let deny_only = KeyFilter::new(&MetricFilter {
+            allow: vec![],
+            deny: patterns(&["^collector\\."]),
+        })
+        .unwrap();
+        assert!(deny_only.matches("sw1.eth0.ifHCInOctets"));
+        assert!(!deny_only.matches("collector.heartbeat"));
+
+        assert!(KeyFilter::new(&MetricFilter {
+            allow: patterns(&["("]),
+            deny: vec![],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn metric_aliases_rename_by_name_or_regex() {
+        let alias = |name: Option<&str>, regex: Option<&str>, alias: &str| MetricAlias {
+            name: name.map(str::to_string),
+            regex: regex.map(str::to_string),
+            alias: alias.to_string(),
+        };
+        let aliases = MetricAliases::new(&[
+            alias(Some("IF-MIB::ifHighSpeed"), None, "speed"),
+            alias(Some("sysUpTime"), None, "uptime"),
+            alias(None, Some("^ifHC(In|Out)Octets$"), "bytes_$1"),
+            alias(Some("ifAlias"), None, "if.alias"),
+        ])
+        .unwrap();
+        assert_eq!(aliases.apply("IF-MIB::ifHighSpeed", "ifHighSpeed"), "speed");
+        assert_eq!(
+            aliases.apply("SNMPv2-MIB::sysUpTime", "sysUpTime"),
+            "uptime"
+        );
+        assert_eq!(
+            aliases.apply("IF-MIB::ifHCInOctets", "ifHCInOctets"),
+            "bytes_In"
+        );
+        assert_eq!(
+            aliases.apply("IF-MIB::ifHCOutOctets", "ifHCOutOctets"),
+            "bytes_Out"
+        );
+        // an alias can not add a level to the key
+        assert_eq!(aliases.apply("IF-MIB::ifAlias", "ifAlias"), "if__alias");
+        assert_eq!(
+            aliases.apply("IF-MIB::ifInErrors", "ifInErrors"),
+            "ifInErrors"
+        );
+
+        assert!(MetricAliases::new(&[alias(Some("ifAlias"), Some("^if"), "a")]).is_err());
+        assert!(MetricAliases::new(&[alias(None, None, "a")]).is_err());
+        assert!(MetricAliases::new(&[alias(Some("ifAlias"), None, "")]).is_err());
+        assert!(MetricAliases::new(&[alias(None, Some("("), "a")]).is_err());
+    }
+}
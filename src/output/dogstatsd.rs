@@ -0,0 +1,222 @@
+use flume::Receiver;
+use std::time::Duration;
+
+use anyhow::{format_err, Error};
+use log::{debug, info, trace, warn};
+use tokio::net::UdpSocket;
+
+use super::CarbonMetricValue;
+use crate::config::DogStatsDOutputSettings;
+
+/// Largest datagram accepted by the agent, lines are packed into datagrams up to this size
+const MAX_PACKET_SIZE: usize = 8192;
+
+/// Lines are sent at the latest after this long, or once a datagram is full
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn dogstatsd_send_safe(
+    settings: DogStatsDOutputSettings,
+    channel_receiver: Receiver<CarbonMetricValue>,
+) {
+    // the agent usually runs on the same host, errors are mostly name resolution
+    let backoff = Duration::from_secs(10);
+
+    loop {
+        if let Err(error) = dogstatsd_send(&settings, &channel_receiver).await {
+            warn!(
+                "dogstatsd_send_safe({}:{}): error {:?}; buffering {} metric values; backing off for {:?}",
+                settings.host,
+                settings.port,
+                error,
+                channel_receiver.len(),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            info!(
+                "dogstatsd_send_safe({}:{}): backoff {:?} done, retrying...",
+                settings.host, settings.port, backoff
+            );
+        }
+    }
+}
+
+async fn dogstatsd_send(
+    settings: &DogStatsDOutputSettings,
+    channel_receiver: &Receiver<CarbonMetricValue>,
+) -> Result<(), Error> {
+    let addr = tokio::net::lookup_host((settings.host.as_str(), settings.port))
+        .await?
+        .next()
+        .ok_or_else(|| format_err!("{} did not resolve to an address", settings.host))?;
+    let socket = UdpSocket::bind(if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .await?;
+    socket.connect(addr).await?;
+    debug!("dogstatsd_send: sending to {}", addr);
+
+    let mut packet = String::new();
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            metricval = channel_receiver.recv_async() => {
+                let line = match format_line(settings, &metricval.unwrap()) {
+                    Some(line) => line,
+                    None => continue,
+                };
+                if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                    send_packet(&socket, &mut packet).await;
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+            _ = flush.tick() => {
+                if !packet.is_empty() {
+                    send_packet(&socket, &mut packet).await;
+                }
+            }
+        }
+    }
+}
+
+/// Delivery is not acknowledged, so failed datagrams are dropped instead of being retried
+async fn send_packet(socket: &UdpSocket, packet: &mut String) {
+    trace!("dogstatsd_send: sending {} bytes", packet.len());
+    if let Err(error) = socket.send(packet.as_bytes()).await {
+        warn!(
+            "dogstatsd_send: could not send {} lines, dropping them: {}",
+            packet.lines().count(),
+            error
+        );
+    }
+    packet.clear();
+}
+
+/// `<prefix><name>:<value>|g|#device:<device>,instance:<instance>`, self metrics are sent by
+/// their key without tags
+fn format_line(
+    settings: &DogStatsDOutputSettings,
+    metricval: &CarbonMetricValue,
+) -> Option<String> {
+    let prefix = &settings.prefix;
+    if metricval.value.parse::<f64>().is_err() {
+        debug!(
+            "dogstatsd_send: skipping non numeric value {} of {}",
+            metricval.value, metricval.metric
+        );
+        return None;
+    }
+
+    Some(match &metricval.source {
+        Some(source) => {
+            let mut line = format!(
+                "{}:{}|g|#device:{},instance:{}",
+                sanitize_metric_name(&format!("{}{}", prefix, source.name)),
+                metricval.value,
+                sanitize_tag_value(&source.device),
+                sanitize_tag_value(&source.instance)
+            );
+            if let Some(endpoint) = &source.endpoint {
+                line.push_str(&format!(",endpoint:{}", sanitize_tag_value(endpoint)));
+            }
+            for (name, value) in super::device_metadata(&source.device).iter() {
+                line.push_str(&format!(
+                    ",{}:{}",
+                    sanitize_tag_value(name),
+                    sanitize_tag_value(value)
+                ));
+            }
+            if settings.include_type {
+                line.push_str(&format!(",snmp_type:{}", source.value_type.name()));
+            }
+            line
+        }
+        None => format!(
+            "{}:{}|g",
+            sanitize_metric_name(&format!("{}{}", prefix, metricval.metric)),
+            metricval.value
+        ),
+    })
+}
+
+/// Metric names consist of ASCII alphanumerics, '_' and '.' and start with a letter
+pub fn sanitize_metric_name(s: &str) -> String {
+    let mut sanitized: String = s
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        sanitized.insert(0, 'm');
+    }
+    sanitized
+}
+
+/// Tags consist of alphanumerics, '_', '-', ':', '.' and '/', ',' and '|' would end the tag
+pub fn sanitize_tag_value(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_-:./".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::MetricSource;
+    use crate::snmp::ValueType;
+    use std::time::SystemTime;
+
+    fn sample(name: &str, value: &str, value_type: ValueType) -> CarbonMetricValue {
+        CarbonMetricValue {
+            timestamp: SystemTime::now(),
+            metric: format!("dogstatsd-test.eth0.{}", name),
+            value: value.to_string(),
+            source: Some(MetricSource {
+                device: "dogstatsd-test".to_string(),
+                endpoint: None,
+                instance: "eth0".to_string(),
+                name: name.to_string(),
+                interval: Duration::from_secs(60),
+                value_type,
+            }),
+        }
+    }
+
+    #[test]
+    fn include_type_tags_the_snmp_type() {
+        let counter = sample("ifHCInOctets", "18446744073709551615", ValueType::Counter64);
+        let gauge = sample("ifHighSpeed", "1000", ValueType::Gauge32);
+
+        let mut settings: DogStatsDOutputSettings =
+            serde_yaml::from_str("{host: localhost, prefix: snmp.}").unwrap();
+        assert_eq!(
+            format_line(&settings, &gauge).unwrap(),
+            "snmp.ifHighSpeed:1000|g|#device:dogstatsd-test,instance:eth0"
+        );
+
+        settings.include_type = true;
+        assert_eq!(
+            format_line(&settings, &counter).unwrap(),
+            "snmp.ifHCInOctets:18446744073709551615|g|#device:dogstatsd-test,instance:eth0,snmp_type:counter64"
+        );
+        assert_eq!(
+            format_line(&settings, &gauge).unwrap(),
+            "snmp.ifHighSpeed:1000|g|#device:dogstatsd-test,instance:eth0,snmp_type:gauge32"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use flume::Receiver;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Error;
+use log::{debug, info, trace, warn};
+
+use super::{format_carbon, next_metric, retry_first, CarbonMetricValue, RetryEntry};
+use crate::config::FileOutputSettings;
+
+struct OutputFile {
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened: SystemTime,
+}
+
+pub async fn file_send_safe(
+    settings: FileOutputSettings,
+    channel_receiver: Receiver<CarbonMetricValue>,
+) {
+    // errors are most likely a full disk, which takes a while to be resolved
+    let backoff = Duration::from_secs(10);
+    let mut retry_buffer = VecDeque::new();
+
+    loop {
+        if let Err(error) = file_send(&settings, &mut retry_buffer, &channel_receiver).await {
+            warn!(
+                "file_send_safe({}): error {:?}; buffering {} metric values; backing off for {:?}",
+                settings.path,
+                error,
+                channel_receiver.len() + retry_buffer.len(),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            info!(
+                "file_send_safe({}): backoff {:?} done, retrying...",
+                settings.path, backoff
+            );
+        }
+    }
+}
+
+async fn file_send(
+    settings: &FileOutputSettings,
+    retry_buffer: &mut VecDeque<RetryEntry>,
+    channel_receiver: &Receiver<CarbonMetricValue>,
+) -> Result<(), Error> {
+    let mut file = open_file(&settings.path)?;
+
+    loop {
+        // flush once everything queued has been written, so the file is never far behind
+        if retry_buffer.is_empty() && channel_receiver.is_empty() {
+            file.writer.flush()?;
+        }
+
+        let (metricval, attempts) = next_metric(retry_buffer, channel_receiver).await;
+
+        if needs_rotation(settings, &file) {
+            file = match rotate(&settings.path, file) {
+                Ok(file) => file,
+                Err(error) => {
+                    retry_first("file_send", retry_buffer, metricval, attempts, &error);
+                    return Err(error);
+                }
+            };
+        }
+
+        let buf = format_carbon(
+            &settings.prefix,
+            &metricval.metric,
+            &metricval.value,
+            &metricval.timestamp,
+        );
+        trace!("file_send: writing '{}'", buf);
+
+        if let Err(error) = writeln!(file.writer, "{}", buf) {
+            retry_first("file_send", retry_buffer, metricval, attempts, &error);
+            return Err(error.into());
+        }
+        file.bytes += buf.len() as u64 + 1;
+    }
+}
+
+fn open_file(path: &str) -> Result<OutputFile, Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok(OutputFile {
+        writer: BufWriter::new(file),
+        bytes: metadata.len(),
+        // an existing file is rotated after a full period, its creation time is not portable
+        opened: SystemTime::now(),
+    })
+}
+
+fn needs_rotation(settings: &FileOutputSettings, file: &OutputFile) -> bool {
+    let too_large = match settings.rotate_bytes {
+        Some(rotate_bytes) => file.bytes >= rotate_bytes,
+        None => false,
+    };
+    let too_old = match settings.rotate_secs {
+        Some(rotate_secs) => match file.opened.elapsed() {
+            Ok(age) => age >= Duration::from_secs(rotate_secs),
+            Err(_) => false,
+        },
+        None => false,
+    };
+    too_large || too_old
+}
+
+/// Flush and sync the current file, move it to <path>.<unix timestamp> and open a new one
+fn rotate(path: &str, file: OutputFile) -> Result<OutputFile, Error> {
+    let file = file
+        .writer
+        .into_inner()
+        .map_err(|error| error.into_error())?;
+    file.sync_all()?;
+    drop(file);
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let rotated_path = format!("{}.{}", path, timestamp);
+    fs::rename(path, &rotated_path)?;
+    debug!("file_send: rotated {} to {}", path, rotated_path);
+
+    open_file(path)
+}
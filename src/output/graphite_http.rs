@@ -0,0 +1,140 @@
+use flume::{Receiver, Sender};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, trace, warn};
+use serde_json::json;
+
+use super::{http_client, self_metric, CarbonMetricValue, Delivery};
+use crate::config::GraphiteHttpOutputSettings;
+
+/// Interval reported for metrics about the collector itself, they have no device
+const SELF_METRIC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Batch metric values and POST them every `flush_interval_secs`. Unlike the carbon output a
+/// value reaches the receiver up to a flush interval late, in return for one request per
+/// batch instead of a line each.
+pub async fn graphite_http_send_safe(
+    settings: GraphiteHttpOutputSettings,
+    output_name: String,
+    channel_receiver: Receiver<CarbonMetricValue>,
+    metric_channel: Sender<CarbonMetricValue>,
+) {
+    let client = match http_client(&settings.pool, Duration::from_secs(30)) {
+        Ok(client) => client,
+        Err(error) => {
+            warn!(
+                "graphite_http_send_safe({}): could not create HTTP client, not sending: {:?}",
+                settings.url, error
+            );
+            return;
+        }
+    };
+
+    let mut batch: Vec<CarbonMetricValue> = vec![];
+    let mut flush = tokio::time::interval(Duration::from_secs(settings.flush_interval_secs));
+    let mut connection_errors: u64 = 0;
+
+    loop {
+        tokio::select! {
+            metricval = channel_receiver.recv_async() => {
+                batch.push(metricval.unwrap());
+                continue;
+            }
+            _ = flush.tick() => {}
+        }
+
+        // failed batches stay in front of newer values until the next flush
+        while !batch.is_empty() {
+            let len = batch.len().min(settings.max_metrics_per_request);
+            match post(&client, &settings, &batch[..len]).await {
+                Delivery::Sent => {
+                    trace!("graphite_http_send: sent {} metric values", len);
+                    batch.drain(..len);
+                }
+                Delivery::Retry(reason) => {
+                    warn!(
+                        "graphite_http_send_safe({}): error {}; buffering {} metric values, retrying at the next flush",
+                        settings.url,
+                        reason,
+                        batch.len() + channel_receiver.len()
+                    );
+                    break;
+                }
+                Delivery::ConnectionFailed(reason) => {
+                    connection_errors += 1;
+                    metric_channel
+                        .send(self_metric(
+                            &format!("outputs.{}.connection_errors", output_name),
+                            connection_errors,
+                        ))
+                        .unwrap();
+                    warn!(
+                        "graphite_http_send_safe({}): connection failed: {}; buffering {} metric values, retrying at the next flush",
+                        settings.url,
+                        reason,
+                        batch.len() + channel_receiver.len()
+                    );
+                    break;
+                }
+                Delivery::Rejected(reason) => {
+                    warn!(
+                        "graphite_http_send_safe({}): {} metric values rejected, dropping them: {}",
+                        settings.url, len, reason
+                    );
+                    batch.drain(..len);
+                }
+            }
+        }
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    settings: &GraphiteHttpOutputSettings,
+    batch: &[CarbonMetricValue],
+) -> Delivery {
+    let mut request = client.post(&settings.url);
+    if let Some(api_key) = &settings.api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let response = request
+        .header("Content-Type", "application/json")
+        .body(encode(&settings.prefix, batch))
+        .send()
+        .await;
+    Delivery::of(response).await
+}
+
+/// The JSON array of a batch, named like the lines of the carbon output
+fn encode(prefix: &str, batch: &[CarbonMetricValue]) -> String {
+    let metrics: Vec<serde_json::Value> = batch
+        .iter()
+        .filter_map(|metricval| {
+            let value: f64 = match metricval.value.parse() {
+                Ok(value) if f64::is_finite(value) => value,
+                _ => {
+                    debug!(
+                        "graphite_http_send: skipping non numeric value {} of {}",
+                        metricval.value, metricval.metric
+                    );
+                    return None;
+                }
+            };
+            let interval = metricval
+                .source
+                .as_ref()
+                .map_or(SELF_METRIC_INTERVAL, |source| source.interval);
+            Some(json!({
+                "name": format!("{}.{}", prefix, metricval.metric),
+                "value": value,
+                "time": metricval
+                    .timestamp
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                "interval": interval.as_secs(),
+            }))
+        })
+        .collect();
+    serde_json::Value::Array(metrics).to_string()
+}
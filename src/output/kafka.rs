@@ -0,0 +1,233 @@
+use flume::Receiver;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Error};
+use log::{debug, info, trace, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use serde_json::json;
+
+use super::{format_carbon, next_metric, retry_first, CarbonMetricValue, RetryEntry};
+use crate::config::{KafkaFormat, KafkaOutputSettings};
+
+/// Delivery reports are awaited before sending more once this many messages are in flight
+const MAX_IN_FLIGHT: usize = 1000;
+
+type InFlight = VecDeque<(CarbonMetricValue, u32, DeliveryFuture)>;
+
+pub async fn kafka_send_safe(
+    settings: KafkaOutputSettings,
+    channel_receiver: Receiver<CarbonMetricValue>,
+) {
+    let backoff = Duration::from_secs(1);
+    let mut retry_buffer = VecDeque::new();
+
+    loop {
+        let sender = kafka_send(&settings, &mut retry_buffer, &channel_receiver);
+        if let Err(error) = sender.await {
+            warn!(
+                "kafka_send_safe({}): error {:?}; buffering {} metric values; backing off for {:?}",
+                settings.brokers,
+                error,
+                channel_receiver.len() + retry_buffer.len(),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            info!(
+                "kafka_send_safe({}): backoff {:?} done, retrying...",
+                settings.brokers, backoff
+            );
+        }
+    }
+}
+
+async fn kafka_send(
+    settings: &KafkaOutputSettings,
+    retry_buffer: &mut VecDeque<RetryEntry>,
+    channel_receiver: &Receiver<CarbonMetricValue>,
+) -> Result<(), Error> {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", &settings.brokers)
+        .set("acks", settings.acks.as_deref().unwrap_or("all"));
+    for (key, value) in &settings.properties {
+        client_config.set(key, value);
+    }
+    let producer: FutureProducer = client_config.create()?;
+
+    let mut in_flight: InFlight = VecDeque::new();
+
+    loop {
+        // collect delivery reports when there is nothing else to send or too many are pending
+        let idle = retry_buffer.is_empty() && channel_receiver.is_empty();
+        if in_flight.len() >= MAX_IN_FLIGHT || (idle && !in_flight.is_empty()) {
+            let (metricval, attempts, delivery) = in_flight.pop_front().unwrap();
+            if let Err(error) = check_delivery(delivery).await {
+                fail_in_flight(&mut in_flight, retry_buffer).await;
+                retry_first("kafka_send", retry_buffer, metricval, attempts, &error);
+                return Err(error);
+            }
+            continue;
+        }
+
+        let (metricval, attempts) = next_metric(retry_buffer, channel_receiver).await;
+
+        // keyed by device, so all metrics of a device end up in the same partition
+        let key = match &metricval.source {
+            Some(source) => source.device.clone(),
+            None => metricval.metric.clone(),
+        };
+        let payload = format_payload(settings, &metricval);
+        let timestamp = metricval
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        trace!("kafka_send: sending '{}' with key {}", payload, key);
+
+        let record = FutureRecord::to(&settings.topic)
+            .key(&key)
+            .payload(&payload)
+            .timestamp(timestamp);
+        match producer.send_result(record) {
+            Ok(delivery) => in_flight.push_back((metricval, attempts, delivery)),
+            Err((error, _)) => {
+                retry_first("kafka_send", retry_buffer, metricval, attempts, &error);
+                fail_in_flight(&mut in_flight, retry_buffer).await;
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+async fn check_delivery(delivery: DeliveryFuture) -> Result<(), Error> {
+    match delivery.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((error, _))) => Err(error.into()),
+        Err(_) => bail!("delivery report canceled, the producer has been closed"),
+    }
+}
+
+/// Wait for the outstanding delivery reports and queue the undelivered messages for a retry,
+/// in front of the retry buffer as they have been sent first
+async fn fail_in_flight(in_flight: &mut InFlight, retry_buffer: &mut VecDeque<RetryEntry>) {
+    let mut undelivered = vec![];
+    for (metricval, attempts, delivery) in in_flight.drain(..) {
+        if let Err(error) = check_delivery(delivery).await {
+            undelivered.push((metricval, attempts, error));
+        }
+    }
+    debug!(
+        "kafka_send: {} outstanding messages were not delivered",
+        undelivered.len()
+    );
+    for (metricval, attempts, error) in undelivered.into_iter().rev() {
+        retry_first("kafka_send", retry_buffer, metricval, attempts, &error);
+    }
+}
+
+fn format_payload(settings: &KafkaOutputSettings, metricval: &CarbonMetricValue) -> String {
+    match settings.format {
+        KafkaFormat::Carbon => format_carbon(
+            &settings.prefix,
+            &metricval.metric,
+            &metricval.value,
+            &metricval.timestamp,
+        ),
+        KafkaFormat::Json => {
+            // values are integers, but may exceed what JSON consumers can represent
+            let value = match metricval.value.parse::<i64>() {
+                Ok(value) => json!(value),
+                Err(_) => match metricval.value.parse::<u64>() {
+                    Ok(value) => json!(value),
+                    Err(_) => json!(metricval.value),
+                },
+            };
+            let mut record = json!({
+                "metric": format!("{}.{}", settings.prefix, metricval.metric),
+                "value": value,
+                "timestamp": metricval
+                    .timestamp
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            });
+            if let Some(source) = &metricval.source {
+                record["device"] = json!(source.device);
+                record["instance"] = json!(source.instance);
+                record["name"] = json!(source.name);
+                if let Some(endpoint) = &source.endpoint {
+                    record["endpoint"] = json!(endpoint);
+                }
+                for (name, value) in super::device_metadata(&source.device).iter() {
+                    record[name.as_str()] = json!(value);
+                }
+                if settings.include_type {
+                    record["type"] = json!(source.value_type.name());
+                }
+            }
+            record.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::MetricSource;
+    use crate::snmp::ValueType;
+    use serde_json::Value;
+
+    fn sample(name: &str, value: &str, value_type: ValueType) -> CarbonMetricValue {
+        CarbonMetricValue {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            metric: format!("kafka-test.eth0.{}", name),
+            value: value.to_string(),
+            source: Some(MetricSource {
+                device: "kafka-test".to_string(),
+                endpoint: None,
+                instance: "eth0".to_string(),
+                name: name.to_string(),
+                interval: Duration::from_secs(60),
+                value_type,
+            }),
+        }
+    }
+
+    fn record(settings: &KafkaOutputSettings, metricval: &CarbonMetricValue) -> Value {
+        serde_json::from_str(&format_payload(settings, metricval)).unwrap()
+    }
+
+    #[test]
+    fn include_type_adds_the_snmp_type_to_json_records() {
+        let counter = sample("ifHCInOctets", "18446744073709551615", ValueType::Counter64);
+        let gauge = sample("ifHighSpeed", "1000", ValueType::Gauge32);
+
+        let mut settings: KafkaOutputSettings =
+            serde_yaml::from_str("{prefix: snmp, brokers: 'localhost:9092', topic: metrics}")
+                .unwrap();
+        assert_eq!(record(&settings, &gauge).get("type"), None);
+
+        settings.include_type = true;
+        assert_eq!(
+            record(&settings, &counter),
+            json!({
+                "metric": "snmp.kafka-test.eth0.ifHCInOctets",
+                "value": 18446744073709551615u64,
+                "timestamp": 1_700_000_000,
+                "device": "kafka-test",
+                "instance": "eth0",
+                "name": "ifHCInOctets",
+                "type": "counter64",
+            })
+        );
+        assert_eq!(record(&settings, &gauge)["type"], "gauge32");
+        assert_eq!(record(&settings, &gauge)["value"], 1000);
+
+        // carbon lines have no room for it
+        settings.format = KafkaFormat::Carbon;
+        assert!(!format_payload(&settings, &counter).contains("counter64"));
+    }
+}
@@ -0,0 +1,138 @@
+use flume::Receiver;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use log::{debug, info, trace, warn};
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+
+use super::{next_metric, retry_first, CarbonMetricValue};
+use crate::config::MqttOutputSettings;
+
+/// Publish requests queued in the client while the broker is unreachable, then the channel
+/// buffers
+const REQUEST_QUEUE_CAPACITY: usize = 1000;
+
+pub async fn mqtt_send_safe(
+    settings: MqttOutputSettings,
+    channel_receiver: Receiver<CarbonMetricValue>,
+) {
+    let (client, eventloop) = match mqtt_client(&settings) {
+        Ok(client) => client,
+        Err(error) => {
+            // the settings do not change, retrying is pointless
+            warn!(
+                "mqtt_send_safe({}): invalid settings, not sending: {:?}",
+                settings.broker, error
+            );
+            return;
+        }
+    };
+    let qos = match settings.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+
+    tokio::spawn(mqtt_connection(settings.broker.clone(), eventloop));
+
+    let mut retry_buffer = VecDeque::new();
+    loop {
+        let (metricval, attempts) = next_metric(&mut retry_buffer, &channel_receiver).await;
+        let topic = mqtt_topic(&settings.topic_prefix, &metricval);
+
+        trace!("mqtt_send: publishing '{}' to {}", metricval.value, topic);
+
+        // only fails if the connection task is gone
+        let publish = client
+            .publish(&topic, qos, false, metricval.value.clone())
+            .await;
+        if let Err(error) = publish {
+            retry_first("mqtt_send", &mut retry_buffer, metricval, attempts, &error);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+fn mqtt_client(settings: &MqttOutputSettings) -> Result<(AsyncClient, EventLoop), Error> {
+    let (host, port) = match settings.broker.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (settings.broker.as_str(), 1883),
+    };
+    if settings.qos > 2 {
+        bail!("qos has to be 0, 1 or 2, not {}", settings.qos);
+    }
+
+    let client_id = settings
+        .client_id
+        .clone()
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+
+    Ok(AsyncClient::new(options, REQUEST_QUEUE_CAPACITY))
+}
+
+/// Drive the connection, the event loop reconnects on the next poll after an error and resends
+/// unacknowledged messages
+async fn mqtt_connection(broker: String, mut eventloop: EventLoop) {
+    let backoff = Duration::from_secs(1);
+    let mut connected = false;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(event) => {
+                if !connected {
+                    info!("mqtt_send_safe({}): connected", broker);
+                    connected = true;
+                }
+                trace!("mqtt_send_safe({}): {:?}", broker, event);
+            }
+            Err(error) => {
+                connected = false;
+                warn!(
+                    "mqtt_send_safe({}): error {:?}; buffering publish requests; backing off for {:?}",
+                    broker, error, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                debug!(
+                    "mqtt_send_safe({}): backoff {:?} done, reconnecting...",
+                    broker, backoff
+                );
+            }
+        }
+    }
+}
+
+/// <topic_prefix>/<device>/<metric>, metrics about the collector itself are split after their
+/// first key segment
+fn mqtt_topic(topic_prefix: &str, metricval: &CarbonMetricValue) -> String {
+    let (device, metric) = match &metricval.source {
+        Some(source) => match &source.endpoint {
+            Some(endpoint) => (
+                source.device.clone(),
+                format!("{}.{}.{}", endpoint, source.instance, source.name),
+            ),
+            None => (
+                source.device.clone(),
+                format!("{}.{}", source.instance, source.name),
+            ),
+        },
+        None => match metricval.metric.split_once('.') {
+            Some((first, rest)) => (first.to_string(), rest.to_string()),
+            None => (metricval.metric.clone(), String::new()),
+        },
+    };
+
+    format!(
+        "{}/{}/{}",
+        topic_prefix.trim_matches('/'),
+        sanitize_mqtt(&device),
+        sanitize_mqtt(&metric)
+    )
+}
+
+/// Topic levels must not contain wildcards, separators or NUL characters
+pub fn sanitize_mqtt(s: &str) -> String {
+    s.replace(['+', '#', '/', '\0'], "_")
+}
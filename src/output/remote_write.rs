@@ -0,0 +1,394 @@
+use flume::{Receiver, Sender};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Error;
+use flate2::write::GzEncoder;
+use log::{debug, trace, warn};
+use prost::Message;
+
+use super::{http_client, self_metric, CarbonMetricValue, Delivery, Freshness};
+use crate::config::{Compression, RemoteWriteOutputSettings};
+
+// messages of the remote write protocol, see prometheus/prompb/{remote,types}.proto
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Value of the staleness marker, a NaN distinct from the NaN of a sample
+const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+/// Stands in for the staleness marker in a batch, regular values are numbers
+const STALE_VALUE: &str = "stale";
+
+pub async fn remote_write_send_safe(
+    settings: RemoteWriteOutputSettings,
+    output_name: String,
+    channel_receiver: Receiver<CarbonMetricValue>,
+    metric_channel: Sender<CarbonMetricValue>,
+) {
+    let client = match http_client(&settings.pool, Duration::from_secs(30)) {
+        Ok(client) => client,
+        Err(error) => {
+            warn!(
+                "remote_write_send_safe({}): could not create HTTP client, not sending: {:?}",
+                settings.url, error
+            );
+            return;
+        }
+    };
+
+    let mut batch: Vec<CarbonMetricValue> = vec![];
+    let mut flush = tokio::time::interval(Duration::from_secs(settings.flush_interval_secs));
+    // falls back to snappy once the receiver rejects another compression
+    let mut compression = settings.compress;
+    let mut freshness = settings.stale_after_intervals.map(Freshness::new);
+    // each failed request closed its connection, the next one opens a new connection
+    let mut connection_errors: u64 = 0;
+
+    loop {
+        tokio::select! {
+            metricval = channel_receiver.recv_async() => {
+                let metricval = metricval.unwrap();
+                if let Some(freshness) = &mut freshness {
+                    freshness.update(labels(&settings, &metricval), &metricval);
+                }
+                batch.push(metricval);
+                continue;
+            }
+            _ = flush.tick() => {}
+        }
+
+        if let Some(freshness) = &mut freshness {
+            for mut metricval in freshness.take_stale() {
+                trace!("remote_write_send: {} went stale", metricval.metric);
+                metricval.value = STALE_VALUE.to_string();
+                metricval.timestamp = SystemTime::now();
+                batch.push(metricval);
+            }
+        }
+
+        // failed batches stay in front of newer samples until the next flush
+        while !batch.is_empty() {
+            let len = batch.len().min(settings.max_samples_per_request);
+            match remote_write(&client, &settings, compression, &batch[..len]).await {
+                Delivery::Sent => {
+                    trace!("remote_write_send: sent {} samples", len);
+                    batch.drain(..len);
+                }
+                Delivery::Retry(reason) => {
+                    warn!(
+                        "remote_write_send_safe({}): error {}; buffering {} samples, retrying at the next flush",
+                        settings.url,
+                        reason,
+                        batch.len() + channel_receiver.len()
+                    );
+                    break;
+                }
+                Delivery::ConnectionFailed(reason) => {
+                    connection_errors += 1;
+                    metric_channel
+                        .send(self_metric(
+                            &format!("outputs.{}.connection_errors", output_name),
+                            connection_errors,
+                        ))
+                        .unwrap();
+                    warn!(
+                        "remote_write_send_safe({}): connection failed: {}; buffering {} samples, retrying at the next flush",
+                        settings.url,
+                        reason,
+                        batch.len() + channel_receiver.len()
+                    );
+                    break;
+                }
+                Delivery::Rejected(reason) if compression != Compression::Snappy => {
+                    warn!(
+                        "remote_write_send_safe({}): request with {:?} compression rejected, using snappy from now on: {}",
+                        settings.url, compression, reason
+                    );
+                    compression = Compression::Snappy;
+                }
+                Delivery::Rejected(reason) => {
+                    warn!(
+                        "remote_write_send_safe({}): {} samples rejected, dropping them: {}",
+                        settings.url, len, reason
+                    );
+                    batch.drain(..len);
+                }
+            }
+        }
+    }
+}
+
+async fn remote_write(
+    client: &reqwest::Client,
+    settings: &RemoteWriteOutputSettings,
+    compression: Compression,
+    batch: &[CarbonMetricValue],
+) -> Delivery {
+    let body = match compress(encode(settings, batch), compression) {
+        Ok(body) => body,
+        Err(error) => return Delivery::Rejected(format!("{:?}", error)),
+    };
+
+    let mut request = client.post(&settings.url);
+    request = match compression {
+        Compression::Snappy => request.header("Content-Encoding", "snappy"),
+        Compression::Gzip => request.header("Content-Encoding", "gzip"),
+        Compression::None => request,
+    };
+    let response = request
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .body(body)
+        .send()
+        .await;
+
+    Delivery::of(response).await
+}
+
+/// Build a WriteRequest, device and instance become labels
+fn encode(settings: &RemoteWriteOutputSettings, batch: &[CarbonMetricValue]) -> Vec<u8> {
+    let mut series: Vec<TimeSeries> = vec![];
+    let mut series_positions: HashMap<Vec<(String, String)>, usize> = HashMap::new();
+
+    for metricval in batch {
+        let value: f64 = match metricval.value.parse() {
+            Ok(value) => value,
+            Err(_) if metricval.value == STALE_VALUE => f64::from_bits(STALE_NAN),
+            Err(_) => {
+                debug!(
+                    "remote_write_send: skipping non numeric value {} of {}",
+                    metricval.value, metricval.metric
+                );
+                continue;
+            }
+        };
+        let timestamp = metricval
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let labels = labels(settings, metricval);
+
+        // samples of one series are sent in the order they have been received
+        let position = *series_positions.entry(labels.clone()).or_insert_with(|| {
+            series.push(TimeSeries {
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| Label { name, value })
+                    .collect(),
+                samples: vec![],
+            });
+            series.len() - 1
+        });
+        series[position].samples.push(Sample { value, timestamp });
+    }
+
+    WriteRequest { timeseries: series }.encode_to_vec()
+}
+
+fn compress(body: Vec<u8>, compression: Compression) -> Result<Vec<u8>, Error> {
+    match compression {
+        Compression::Snappy => Ok(snap::raw::Encoder::new().compress_vec(&body)?),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&body)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::None => Ok(body),
+    }
+}
+
+/// Labels of the series of `metricval`, sorted by name: device, instance and the optional
+/// snmp_type, or only the name for metrics about the collector itself
+fn labels(
+    settings: &RemoteWriteOutputSettings,
+    metricval: &CarbonMetricValue,
+) -> Vec<(String, String)> {
+    let mut labels = match &metricval.source {
+        Some(source) => vec![
+            (
+                "__name__".to_string(),
+                sanitize_prometheus(&format!("{}{}", settings.prefix, source.name)),
+            ),
+            ("device".to_string(), source.device.clone()),
+            ("instance".to_string(), source.instance.clone()),
+        ],
+        None => vec![(
+            "__name__".to_string(),
+            sanitize_prometheus(&format!("{}{}", settings.prefix, metricval.metric)),
+        )],
+    };
+    if let Some(endpoint) = metricval
+        .source
+        .as_ref()
+        .and_then(|source| source.endpoint.as_ref())
+    {
+        labels.push(("endpoint".to_string(), endpoint.clone()));
+    }
+    if let Some(source) = &metricval.source {
+        for (name, value) in super::device_metadata(&source.device).iter() {
+            labels.push((sanitize_prometheus(name), value.clone()));
+        }
+    }
+    if let Some(source) = metricval.source.as_ref().filter(|_| settings.include_type) {
+        labels.push((
+            "snmp_type".to_string(),
+            source.value_type.name().to_string(),
+        ));
+    }
+    labels.sort();
+    labels
+}
+
+/// Metric names must match [a-zA-Z_:][a-zA-Z0-9_:]*
+pub fn sanitize_prometheus(s: &str) -> String {
+    let mut sanitized: String = s
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::MetricSource;
+    use crate::snmp::ValueType;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn settings(yaml: &str) -> RemoteWriteOutputSettings {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// A cycle of `devices` switches with 48 interfaces each, an in and an out counter per
+    /// interface
+    fn batch(devices: usize) -> Vec<CarbonMetricValue> {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut batch = vec![];
+        for device in 0..devices {
+            for interface in 0..48 {
+                for name in ["ifHCInOctets", "ifHCOutOctets"] {
+                    batch.push(CarbonMetricValue {
+                        timestamp,
+                        metric: format!(
+                            "switch{}.GigabitEthernet1_0_{}.{}",
+                            device, interface, name
+                        ),
+                        value: (1_000_000 * interface + device).to_string(),
+                        source: Some(MetricSource {
+                            device: format!("switch{}", device),
+                            endpoint: None,
+                            instance: format!("GigabitEthernet1/0/{}", interface),
+                            name: name.to_string(),
+                            interval: Duration::from_secs(60),
+                            value_type: ValueType::Counter64,
+                        }),
+                    });
+                }
+            }
+        }
+        batch
+    }
+
+    #[test]
+    fn gzip_shrinks_a_batch_which_decodes_back() {
+        let settings = settings("{url: 'http://localhost:8428/api/v1/write', compress: gzip}");
+        let body = encode(&settings, &batch(10));
+        let compressed = compress(body.clone(), settings.compress).unwrap();
+        let snappy = compress(body.clone(), Compression::Snappy).unwrap();
+        assert!(
+            compressed.len() * 4 < body.len(),
+            "{} of {} bytes",
+            compressed.len(),
+            body.len()
+        );
+        assert!(compressed.len() < snappy.len());
+
+        let mut decompressed = vec![];
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+        let request = WriteRequest::decode(decompressed.as_slice()).unwrap();
+        assert_eq!(request.timeseries.len(), 10 * 48 * 2);
+        assert_eq!(
+            request.timeseries[0].samples,
+            vec![Sample {
+                value: 0.0,
+                timestamp: 1_700_000_000_000,
+            }]
+        );
+    }
+
+    #[test]
+    fn include_type_adds_the_snmp_type_label() {
+        let mut settings = settings("{url: 'http://localhost', prefix: snmp_}");
+        let counter = &batch(1)[0];
+        let mut gauge = counter.clone();
+        if let Some(source) = gauge.source.as_mut() {
+            source.name = "ifHighSpeed".to_string();
+            source.value_type = ValueType::Gauge32;
+        }
+        let label = |labels: Vec<(String, String)>, name: &str| {
+            labels
+                .into_iter()
+                .find(|(label, _)| label == name)
+                .map(|(_, value)| value)
+        };
+        assert_eq!(label(labels(&settings, counter), "snmp_type"), None);
+
+        settings.include_type = true;
+        assert_eq!(
+            labels(&settings, counter),
+            vec![
+                ("__name__".to_string(), "snmp_ifHCInOctets".to_string()),
+                ("device".to_string(), "switch0".to_string()),
+                ("instance".to_string(), "GigabitEthernet1/0/0".to_string()),
+                ("snmp_type".to_string(), "counter64".to_string()),
+            ]
+        );
+        assert_eq!(
+            label(labels(&settings, &gauge), "snmp_type").as_deref(),
+            Some("gauge32")
+        );
+    }
+}
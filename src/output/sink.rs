@@ -0,0 +1,450 @@
+use flume::{Receiver, Sender};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{debug, info, trace, warn};
+use size_format::SizeFormatterSI;
+
+use super::{
+    estimate_buffered_bytes, next_metric, retry_first, self_metric, CarbonMetricValue, RateLimiter,
+    RetryEntry,
+};
+
+/// Metrics are written in batches of at most this many
+const MAX_BATCH: usize = 1000;
+
+/// A connected destination of metric values. `drive` takes care of batching, pacing,
+/// reconnecting and retrying, a sink only writes.
+pub trait MetricSink {
+    /// Write all of `metrics`. On an error the sink is dropped and the whole batch is retried
+    /// with a new one, it is unknown which of them arrived.
+    fn send_batch(&mut self, metrics: &[CarbonMetricValue]) -> Result<(), Error>;
+
+    /// Where the sink writes to, for log messages
+    fn name(&self) -> &str;
+}
+
+/// How `drive` batches and paces the writes of a sink
+pub struct DriveSettings {
+    /// Collect metrics for up to this long before writing them, by default whatever is queued
+    /// is written right away
+    pub flush_interval: Option<Duration>,
+    /// Metrics per second, unlimited if unset
+    pub max_rate: Option<u64>,
+    /// Wait after a failed connection or write
+    pub backoff: Duration,
+}
+
+/// Send everything received on `channel_receiver` to a sink opened by `connect`, opening a new
+/// one after `backoff` whenever connecting or writing fails. Reconnects are sent to
+/// `metric_channel` as `outputs.<output_name>.reconnects`.
+pub async fn drive<S, F>(
+    context: &str,
+    output_name: &str,
+    settings: DriveSettings,
+    mut connect: F,
+    channel_receiver: Receiver<CarbonMetricValue>,
+    metric_channel: Sender<CarbonMetricValue>,
+) where
+    S: MetricSink,
+    F: FnMut() -> Result<S, Error>,
+{
+    let backoff = settings.backoff;
+    let mut retry_buffer = VecDeque::new();
+    let mut reconnects: u64 = 0;
+
+    loop {
+        let sender = send(
+            &settings,
+            &mut connect,
+            &mut retry_buffer,
+            &channel_receiver,
+        );
+        if let Err(error) = sender.await {
+            let queue_len = channel_receiver.len() + retry_buffer.len();
+            let memory_consumed = estimate_buffered_bytes(queue_len);
+            warn!(
+                "sink_send_safe({}): error {:?}; buffering {} metric values, using {} memory; backing off for {:?}",
+                context, error, queue_len, SizeFormatterSI::new(memory_consumed), backoff
+            );
+            tokio::time::sleep(backoff).await;
+            info!(
+                "sink_send_safe({}): backoff {:?} done, retrying...",
+                context, backoff
+            );
+            reconnects += 1;
+            metric_channel
+                .send(self_metric(
+                    &format!("outputs.{}.reconnects", output_name),
+                    reconnects,
+                ))
+                .unwrap();
+        }
+    }
+}
+
+async fn send<S, F>(
+    settings: &DriveSettings,
+    connect: &mut F,
+    retry_buffer: &mut VecDeque<RetryEntry>,
+    channel_receiver: &Receiver<CarbonMetricValue>,
+) -> Result<(), Error>
+where
+    S: MetricSink,
+    F: FnMut() -> Result<S, Error>,
+{
+    let mut sink = connect()?;
+    let context = format!("sink_send({})", sink.name());
+    debug!("{}: connected", context);
+
+    let mut limiter = settings
+        .max_rate
+        .filter(|rate| *rate > 0)
+        .map(RateLimiter::new);
+    // a batch never takes more than one slot of the limiter
+    let max_batch = limiter.as_ref().map_or(MAX_BATCH, |limiter| {
+        MAX_BATCH.min(limiter.per_slot as usize)
+    });
+    let mut metrics: Vec<CarbonMetricValue> = vec![];
+    let mut attempts: Vec<u32> = vec![];
+
+    loop {
+        let (metricval, attempt) = next_metric(retry_buffer, channel_receiver).await;
+        metrics.push(metricval);
+        attempts.push(attempt);
+        let deadline = settings
+            .flush_interval
+            .map(|flush_interval| tokio::time::Instant::now() + flush_interval);
+        while metrics.len() < max_batch {
+            let (metricval, attempt) = match retry_buffer.pop_front() {
+                Some(entry) => (entry.metricval, entry.attempts),
+                None => match deadline {
+                    Some(deadline) => {
+                        match tokio::time::timeout_at(deadline, channel_receiver.recv_async()).await
+                        {
+                            Ok(metricval) => (metricval.unwrap(), 0),
+                            Err(_) => break,
+                        }
+                    }
+                    None => match channel_receiver.try_recv() {
+                        Ok(metricval) => (metricval, 0),
+                        Err(_) => break,
+                    },
+                },
+            };
+            metrics.push(metricval);
+            attempts.push(attempt);
+        }
+
+        if let Some(limiter) = limiter.as_mut() {
+            if limiter.exhausted(metrics.len()) {
+                let backlog = channel_receiver.len() + retry_buffer.len() + metrics.len();
+                limiter.report_backlog(&context, backlog);
+                limiter.next_slot().await;
+            }
+        }
+
+        if let Err(error) = sink.send_batch(&metrics) {
+            for (metricval, attempt) in metrics.drain(..).zip(attempts.drain(..)).rev() {
+                retry_first(&context, retry_buffer, metricval, attempt, &error);
+            }
+            return Err(error);
+        }
+        trace!("{}: sent {} metric values", context, metrics.len());
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.sent += metrics.len() as u64;
+        }
+        metrics.clear();
+        attempts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Instant, SystemTime};
+
+    use crate::output::MAX_SEND_ATTEMPTS;
+
+    /// Records the metric names of every batch it is given
+    struct FakeSink {
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl MetricSink for FakeSink {
+        fn send_batch(&mut self, metrics: &[CarbonMetricValue]) -> Result<(), Error> {
+            let names = metrics.iter().map(|metricval| metricval.metric.clone());
+            self.batches.lock().unwrap().push(names.collect());
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            "fake"
+        }
+    }
+
+    /// When a batch was given to a sink, its metric names and whether it was written
+    type Batch = (Instant, Vec<String>, bool);
+
+    /// Fails the first `failures` batches over all of its connections, records every batch it
+    /// is given with when it came and whether it was written
+    #[derive(Clone, Default)]
+    struct FlakySink {
+        failures: Arc<Mutex<u32>>,
+        batches: Arc<Mutex<Vec<Batch>>>,
+        /// sent to the channel on the first failure, newer than the failed batch
+        queue_on_failure: Option<(Sender<CarbonMetricValue>, String)>,
+    }
+
+    impl MetricSink for FlakySink {
+        fn send_batch(&mut self, metrics: &[CarbonMetricValue]) -> Result<(), Error> {
+            let mut failures = self.failures.lock().unwrap();
+            let names = metrics.iter().map(|metricval| metricval.metric.clone());
+            self.batches
+                .lock()
+                .unwrap()
+                .push((Instant::now(), names.collect(), *failures == 0));
+            if *failures == 0 {
+                return Ok(());
+            }
+            *failures -= 1;
+            if let Some((sender, name)) = self.queue_on_failure.take() {
+                sender.send(metric(&name)).unwrap();
+            }
+            anyhow::bail!("write failed")
+        }
+
+        fn name(&self) -> &str {
+            "flaky"
+        }
+    }
+
+    impl FlakySink {
+        fn failing(failures: u32) -> Self {
+            FlakySink {
+                failures: Arc::new(Mutex::new(failures)),
+                ..FlakySink::default()
+            }
+        }
+
+        /// Run `drive` with connections to this sink for `duration`, returns the batches and the
+        /// reconnects it counted
+        async fn drive_for(
+            &self,
+            duration: Duration,
+            settings: DriveSettings,
+            channel_receiver: Receiver<CarbonMetricValue>,
+        ) -> (Vec<Batch>, Vec<String>) {
+            let mut sink = Some(self.clone());
+            let connect = || {
+                // the sink to queue a metric on failure is only handed out once
+                Ok(sink.take().unwrap_or_else(|| FlakySink {
+                    queue_on_failure: None,
+                    ..self.clone()
+                }))
+            };
+            let (metric_channel, metrics) = flume::unbounded();
+            let driver = drive(
+                "test",
+                "flaky",
+                settings,
+                connect,
+                channel_receiver,
+                metric_channel,
+            );
+            assert!(tokio::time::timeout(duration, driver).await.is_err());
+            let reconnects = metrics.drain().map(|metricval| metricval.value).collect();
+            let batches = self.batches.lock().unwrap().clone();
+            (batches, reconnects)
+        }
+    }
+
+    fn written(batches: &[Batch]) -> Vec<(Vec<String>, bool)> {
+        batches
+            .iter()
+            .map(|(_, names, ok)| (names.clone(), *ok))
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn metric(name: &str) -> CarbonMetricValue {
+        CarbonMetricValue {
+            timestamp: SystemTime::now(),
+            metric: name.to_string(),
+            value: "1".to_string(),
+            source: None,
+        }
+    }
+
+    fn settings(flush_interval: Option<Duration>) -> DriveSettings {
+        DriveSettings {
+            flush_interval,
+            max_rate: None,
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    /// Run `send` for `duration`, it only returns on errors
+    async fn send_for(
+        duration: Duration,
+        settings: &DriveSettings,
+        channel_receiver: &Receiver<CarbonMetricValue>,
+    ) -> Vec<Vec<String>> {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let mut connect = || {
+            Ok(FakeSink {
+                batches: batches.clone(),
+            })
+        };
+        let mut retry_buffer = VecDeque::new();
+        let sender = send(settings, &mut connect, &mut retry_buffer, channel_receiver);
+        assert!(tokio::time::timeout(duration, sender).await.is_err());
+        let batches = batches.lock().unwrap().clone();
+        batches
+    }
+
+    #[tokio::test]
+    async fn batches_are_capped_at_max_batch() {
+        let (sender, receiver) = flume::unbounded();
+        for i in 0..2 * MAX_BATCH + 10 {
+            sender.send(metric(&i.to_string())).unwrap();
+        }
+
+        let batches = send_for(Duration::from_millis(100), &settings(None), &receiver).await;
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![MAX_BATCH, MAX_BATCH, 10]);
+        assert_eq!(batches[2][9], (2 * MAX_BATCH + 9).to_string());
+    }
+
+    #[tokio::test]
+    async fn queued_metrics_are_written_right_away_without_flush_interval() {
+        let (sender, receiver) = flume::unbounded();
+        let producer = sender.clone();
+        tokio::spawn(async move {
+            producer.send(metric("a")).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            producer.send(metric("b")).unwrap();
+        });
+
+        let batches = send_for(Duration::from_millis(300), &settings(None), &receiver).await;
+        assert_eq!(batches, vec![vec!["a"], vec!["b"]]);
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn flush_interval_collects_metrics_into_one_batch() {
+        let (sender, receiver) = flume::unbounded();
+        let producer = sender.clone();
+        tokio::spawn(async move {
+            producer.send(metric("a")).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            producer.send(metric("b")).unwrap();
+            // after the flush interval of a
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            producer.send(metric("c")).unwrap();
+        });
+
+        let flush_interval = Some(Duration::from_millis(300));
+        let batches = send_for(
+            Duration::from_millis(1200),
+            &settings(flush_interval),
+            &receiver,
+        )
+        .await;
+        assert_eq!(batches, vec![vec!["a", "b"], vec!["c"]]);
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn flush_interval_does_not_delay_full_batches() {
+        let (sender, receiver) = flume::unbounded();
+        for i in 0..MAX_BATCH + 1 {
+            sender.send(metric(&i.to_string())).unwrap();
+        }
+
+        // only the partial batch waits for the flush interval
+        let flush_interval = Some(Duration::from_secs(10));
+        let batches = send_for(
+            Duration::from_millis(100),
+            &settings(flush_interval),
+            &receiver,
+        )
+        .await;
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![MAX_BATCH]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_is_retried_before_newer_metrics() {
+        let (sender, receiver) = flume::unbounded();
+        for name in ["a", "b", "c"] {
+            sender.send(metric(name)).unwrap();
+        }
+        let sink = FlakySink {
+            queue_on_failure: Some((sender.clone(), "d".to_string())),
+            ..FlakySink::failing(2)
+        };
+
+        let (batches, reconnects) = sink
+            .drive_for(Duration::from_millis(200), settings(None), receiver)
+            .await;
+        assert_eq!(
+            written(&batches),
+            vec![
+                (names(&["a", "b", "c"]), false),
+                (names(&["a", "b", "c", "d"]), false),
+                (names(&["a", "b", "c", "d"]), true),
+            ]
+        );
+        assert_eq!(reconnects, vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_dropped_after_max_send_attempts() {
+        let (sender, receiver) = flume::unbounded();
+        sender.send(metric("a")).unwrap();
+        let sink = FlakySink::failing(MAX_SEND_ATTEMPTS);
+        let producer = sender.clone();
+        tokio::spawn(async move {
+            // after the last attempt of a, each one backs off for 10ms
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            producer.send(metric("b")).unwrap();
+        });
+
+        let (batches, reconnects) = sink
+            .drive_for(Duration::from_millis(400), settings(None), receiver)
+            .await;
+        let mut expected = vec![(names(&["a"]), false); MAX_SEND_ATTEMPTS as usize];
+        expected.push((names(&["b"]), true));
+        assert_eq!(written(&batches), expected);
+        assert_eq!(reconnects.len(), MAX_SEND_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn max_rate_writes_a_batch_per_slot() {
+        let (sender, receiver) = flume::unbounded();
+        for i in 0..35 {
+            sender.send(metric(&i.to_string())).unwrap();
+        }
+        // 10 metrics per slot of 100ms
+        let settings = DriveSettings {
+            max_rate: Some(100),
+            ..settings(None)
+        };
+
+        let (batches, reconnects) = FlakySink::failing(0)
+            .drive_for(Duration::from_millis(500), settings, receiver)
+            .await;
+        let sizes: Vec<usize> = batches.iter().map(|(_, names, _)| names.len()).collect();
+        assert_eq!(sizes, vec![10, 10, 10, 5]);
+        for slots in batches.windows(2) {
+            assert!(slots[1].0 - slots[0].0 >= Duration::from_millis(90));
+        }
+        assert!(reconnects.is_empty());
+    }
+}
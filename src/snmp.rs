@@ -1,16 +1,338 @@
-use anyhow::{format_err, Error};
-use log::trace;
-use std::time::SystemTime;
+use anyhow::{bail, format_err, Error};
+use log::{debug, trace, warn};
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant, SystemTime};
 
-use msnmp::msg_factory;
 use msnmp::request::get_var_binds;
-use msnmp::session::{Session, Step};
-use msnmp::Client;
-use snmp_mp::{ObjectIdent, PduType, VarBind, VarValue};
-use snmp_usm::{Digest, PrivKey};
+use msnmp::session::Step;
+use snmp_mp::{
+    MsgProcessingError, ObjectIdent, PduErrorStatus, PduType, ScopedPduData, SnmpMsg, VarBind,
+    VarValue,
+};
+use snmp_usm::{Digest, PrivKey, SecurityError};
 
-pub async fn snmp_bulkwalk<D, P, S>(
+use crate::client::{create_bulk_request_msg, create_request_msg, Client, Session};
+use crate::config::WalkMode;
+
+/// SNMPv2-MIB::sysUpTime.0
+pub const SYS_UPTIME_OID: [u64; 9] = [1, 3, 6, 1, 2, 1, 1, 3, 0];
+
+/// SNMP-USER-BASED-SM-MIB::usmStats, reported by agents which reject a request
+const USM_STATS_OID: [u64; 9] = [1, 3, 6, 1, 6, 3, 15, 1, 1];
+
+#[derive(Debug)]
+pub struct SnmpAuthError(&'static str);
+
+impl fmt::Display for SnmpAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "authentication failed, agent reported {}", self.0)
+    }
+}
+
+impl std::error::Error for SnmpAuthError {}
+
+/// The agent's engine boots or time moved outside of the window of the session
+#[derive(Debug)]
+pub struct SnmpTimeWindowError;
+
+impl fmt::Display for SnmpTimeWindowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "agent reported usmStatsNotInTimeWindows")
+    }
+}
+
+impl std::error::Error for SnmpTimeWindowError {}
+
+/// The agent reported the engine time of the session outside of its window, or answered with one
+/// outside of the window of the session, e.g. after a reboot. An authenticated report fails the
+/// time check of the response before its counter is read.
+pub fn is_time_window_error(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<SnmpTimeWindowError>().is_some()
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|io_error| io_error.get_ref())
+                .and_then(|inner| inner.downcast_ref::<SecurityError>())
+                == Some(&SecurityError::NotInTimeWindow)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SnmpErrorClass {
+    Auth,
+    Timeout,
+    Other,
+}
+
+impl fmt::Display for SnmpErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnmpErrorClass::Auth => write!(f, "auth"),
+            SnmpErrorClass::Timeout => write!(f, "timeout"),
+            SnmpErrorClass::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Classify an error by walking its source chain. The message processing and security errors
+/// of the SNMP libraries arrive wrapped in an `io::Error` and are looked up by its inner error.
+pub fn classify_error(error: &Error) -> SnmpErrorClass {
+    for cause in error.chain() {
+        if cause.downcast_ref::<SnmpAuthError>().is_some() {
+            return SnmpErrorClass::Auth;
+        }
+        if cause
+            .downcast_ref::<tokio::time::error::Elapsed>()
+            .is_some()
+        {
+            return SnmpErrorClass::Timeout;
+        }
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            if matches!(io_error.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+                return SnmpErrorClass::Timeout;
+            }
+            if io_error.get_ref().is_some_and(is_auth_error) {
+                return SnmpErrorClass::Auth;
+            }
+        }
+    }
+
+    SnmpErrorClass::Other
+}
+
+/// A response which did not authenticate or decrypt with the keys of the session
+fn is_auth_error(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<SecurityError>(),
+        Some(SecurityError::WrongAuthParams | SecurityError::DecryptError)
+    ) || error.downcast_ref::<MsgProcessingError>() == Some(&MsgProcessingError::DecryptError)
+}
+
+// agents answer requests with bad credentials with a report pdu carrying a usmStats counter
+fn check_report(var_binds: &[VarBind]) -> Result<(), Error> {
+    for var_bind in var_binds {
+        let components = var_bind.name().components();
+        if components.starts_with(&USM_STATS_OID) {
+            let counter = match components.get(USM_STATS_OID.len()) {
+                Some(1) => "usmStatsUnsupportedSecLevels",
+                Some(2) => return Err(SnmpTimeWindowError.into()),
+                Some(3) => "usmStatsUnknownUserNames",
+                Some(5) => "usmStatsWrongDigests",
+                Some(6) => "usmStatsDecryptionErrors",
+                _ => continue,
+            };
+            return Err(SnmpAuthError(counter).into());
+        }
+    }
+    Ok(())
+}
+
+/// Rows requested per GETBULK unless limited by `max_pdu_size`
+const DEFAULT_MAX_REPETITIONS: u32 = 10;
+/// Rough encoded size of a var bind, used to derive the rows per GETBULK from `max_pdu_size`
+const ESTIMATED_VAR_BIND_SIZE: u32 = 100;
+/// Successful cycles after which a reduced number of rows per GETBULK is doubled again
+const REPETITIONS_RECOVERY_CYCLES: u32 = 3;
+
+/// Rows requested per GETBULK. The walk halves the rows and retries the request if the agent
+/// answers tooBig. A response larger than `max_pdu_size` is kept, but the following requests
+/// ask for fewer rows. A timeout fails the walk like any other request, it does not tell the
+/// response was too large.
+#[derive(Debug)]
+pub struct BulkRepetitions {
+    initial: u32,
+    current: u32,
+    successful_cycles: u32,
+    max_pdu_size: Option<u32>,
+}
+
+impl BulkRepetitions {
+    pub fn new(max_pdu_size: Option<u32>) -> Self {
+        let initial = match max_pdu_size {
+            Some(max_pdu_size) => {
+                (max_pdu_size / ESTIMATED_VAR_BIND_SIZE).clamp(1, DEFAULT_MAX_REPETITIONS)
+            }
+            None => DEFAULT_MAX_REPETITIONS,
+        };
+        BulkRepetitions {
+            initial,
+            current: initial,
+            successful_cycles: 0,
+            max_pdu_size,
+        }
+    }
+
+    /// Whether a response of `len` bytes exceeds `max_pdu_size`
+    fn is_oversized(&self, len: usize) -> bool {
+        self.max_pdu_size
+            .is_some_and(|max_pdu_size| len > max_pdu_size as usize)
+    }
+
+    /// Halve the rows per request, false if a single row is requested already
+    fn reduce(&mut self) -> bool {
+        self.successful_cycles = 0;
+        if self.current == 1 {
+            return false;
+        }
+        self.current /= 2;
+        true
+    }
+
+    /// Rows requested per GETBULK before any timeout
+    pub fn initial(&self) -> u32 {
+        self.initial
+    }
+
+    /// Move back towards the initial rows per request after a few cycles without reductions
+    pub fn cycle_succeeded(&mut self) {
+        if self.current == self.initial {
+            return;
+        }
+        self.successful_cycles += 1;
+        if self.successful_cycles >= REPETITIONS_RECOVERY_CYCLES {
+            self.current = (self.current * 2).min(self.initial);
+            self.successful_cycles = 0;
+            debug!("snmp_walk: increased max_repetitions to {}", self.current);
+        }
+    }
+}
+
+fn error_status(msg: &SnmpMsg) -> Option<PduErrorStatus> {
+    Some(msg.scoped_pdu_data.plaintext()?.error_status())
+}
+
+/// A GetBulkRequest carries max-repetitions in the error-index field (RFC 3416)
+fn set_max_repetitions(msg: &mut SnmpMsg, max_repetitions: u32) {
+    if let ScopedPduData::Plaintext(scoped_pdu) = &mut msg.scoped_pdu_data {
+        scoped_pdu.set_error_index(max_repetitions);
+    }
+}
+
+tokio::task_local! {
+    /// SNMPv3 context name of the requests sent within `with_context_name`
+    static CONTEXT_NAME: Vec<u8>;
+}
+
+/// Run `requests` with every request sent in the context `context_name`, the default context
+/// of the engine is used outside of it
+pub async fn with_context_name<F: Future>(context_name: Vec<u8>, requests: F) -> F::Output {
+    CONTEXT_NAME.scope(context_name, requests).await
+}
+
+tokio::task_local! {
+    /// Timestamp of every value received within `with_cycle_timestamp`, set by the first
+    /// response
+    static CYCLE_TIMESTAMP: Cell<Option<SystemTime>>;
+}
+
+/// Run `requests` with every value timestamped alike if `enabled`: at the midpoint of the first
+/// request and its response, when the agent most likely read its values, instead of the time
+/// each response was received
+pub async fn with_cycle_timestamp<F: Future>(enabled: bool, requests: F) -> F::Output {
+    if enabled {
+        CYCLE_TIMESTAMP.scope(Cell::new(None), requests).await
+    } else {
+        requests.await
+    }
+}
+
+/// Timestamp of a value received now
+pub fn received_at() -> SystemTime {
+    CYCLE_TIMESTAMP
+        .try_with(Cell::get)
+        .ok()
+        .flatten()
+        .unwrap_or_else(SystemTime::now)
+}
+
+/// Anchor the timestamp of the cycle to the first exchange, sent at `sent` and answered after
+/// `round_trip`
+fn anchor_cycle_timestamp(sent: SystemTime, round_trip: Duration) {
+    let _ = CYCLE_TIMESTAMP.try_with(|timestamp| {
+        if timestamp.get().is_none() {
+            trace!(
+                "snmp: round trip of the first request took {:?}",
+                round_trip
+            );
+            timestamp.set(Some(sent + round_trip / 2));
+        }
+    });
+}
+
+tokio::task_local! {
+    /// Pace of the requests sent within `with_max_pps`
+    static PACING: Cell<Pacing>;
+}
+
+#[derive(Clone, Copy)]
+struct Pacing {
+    gap: Duration,
+    next_request: Instant,
+    /// time requests have been held back so far
+    delayed: Duration,
+}
+
+/// Run `requests` sending at most `max_pps` requests per second, for agents which fail under
+/// load. Returns their output and how long requests have been held back.
+pub async fn with_max_pps<F: Future>(max_pps: Option<u32>, requests: F) -> (F::Output, Duration) {
+    let max_pps = match max_pps {
+        Some(max_pps) => max_pps,
+        None => return (requests.await, Duration::ZERO),
+    };
+    let pacing = Cell::new(Pacing {
+        gap: Duration::from_secs(1) / max_pps,
+        next_request: Instant::now(),
+        delayed: Duration::ZERO,
+    });
+    PACING
+        .scope(pacing, async {
+            let output = requests.await;
+            (output, PACING.with(|pacing| pacing.get().delayed))
+        })
+        .await
+}
+
+/// Wait until the next request may be sent within `with_max_pps`
+async fn pace() {
+    let delay = PACING
+        .try_with(|pacing| {
+            let mut state = pacing.get();
+            let now = Instant::now();
+            let at = state.next_request.max(now);
+            state.next_request = at + state.gap;
+            state.delayed += at - now;
+            pacing.set(state);
+            at - now
+        })
+        .unwrap_or_default();
+    if !delay.is_zero() {
+        trace!("snmp: delaying the request for {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn set_context_name(msg: &mut SnmpMsg) {
+    let _ = CONTEXT_NAME.try_with(|context_name| {
+        if let ScopedPduData::Plaintext(scoped_pdu) = &mut msg.scoped_pdu_data {
+            scoped_pdu.set_context_name(context_name);
+        }
+    });
+}
+
+/// Walk the subtree of `oid` with the configured request type, starting after the row
+/// `start_index` if it is not empty. GETNEXT requests one var bind at a time, for agents which
+/// do not handle GETBULK. The walk ends after `max_rows` rows, so an agent which never ends
+/// the column can not stall the cycle.
+pub async fn snmp_walk<D, P, S>(
     oid: Vec<VarBind>,
+    start_index: &[u64],
+    max_rows: usize,
+    walk_mode: &WalkMode,
+    repetitions: &mut BulkRepetitions,
     client: &mut Client,
     session: &mut Session<'_, D, P, S>,
 ) -> Result<Vec<(SystemTime, VarBind)>, Error>
@@ -23,54 +345,254 @@ where
 
     let mut result: Vec<(SystemTime, VarBind)> = vec![];
 
+    // the walk still ends at the end of the column, wherever it starts
     let end_oid = &msnmp::request::next_sibling(request_var_binds[0].name());
+    if !start_index.is_empty() {
+        let start_oid = [request_var_binds[0].name().components(), start_index].concat();
+        trace!("snmp_walk: starting at {:?}", start_oid);
+        request_var_binds = vec![vec_to_var_binds(start_oid)];
+    }
     loop {
-        let mut get_next_request =
-            msg_factory::create_bulk_request_msg(request_var_binds.clone(), session);
-
-        let get_next_response = client.send_request(&mut get_next_request, session).await?;
+        let max_repetitions = repetitions.current;
+        let get_next_response = send_request(
+            |session| match walk_mode {
+                WalkMode::Bulk => {
+                    let mut msg = create_bulk_request_msg(request_var_binds.clone(), session);
+                    set_max_repetitions(&mut msg, max_repetitions);
+                    msg
+                }
+                WalkMode::GetNext => {
+                    create_request_msg(PduType::GetNextRequest, request_var_binds.clone(), session)
+                }
+            },
+            client,
+            session,
+        )
+        .await?;
+        if walk_mode == &WalkMode::Bulk {
+            if error_status(&get_next_response) == Some(PduErrorStatus::TooBig) {
+                if !repetitions.reduce() {
+                    bail!("agent answered tooBig to a GETBULK of a single row");
+                }
+                debug!(
+                    "snmp_walk: tooBig with max_repetitions {}, retrying with {}",
+                    max_repetitions, repetitions.current
+                );
+                continue;
+            }
+            if repetitions.is_oversized(client.response_len()) && repetitions.reduce() {
+                debug!(
+                    "snmp_walk: response of {} bytes with max_repetitions {} exceeds max_pdu_size, requesting {} from now on",
+                    client.response_len(),
+                    max_repetitions,
+                    repetitions.current
+                );
+            }
+        }
 
         match get_var_binds(&get_next_response) {
             Some(binds) => {
+                check_report(binds)?;
                 for var_bind in binds {
                     if var_bind.name() >= end_oid || var_bind.value() == &VarValue::EndOfMibView {
                         return Ok(result);
                     }
+                    if is_exception(var_bind.value()) {
+                        trace!("snmp_walk: skipping exception for {}", var_bind.name());
+                        continue;
+                    }
 
-                    result.push((SystemTime::now(), var_bind.clone()));
+                    result.push((received_at(), var_bind.clone()));
+                    if result.len() >= max_rows {
+                        warn!(
+                            "snmp_walk: stopping the walk of {} after {} rows, keeping them; raise max_rows if the table is that large",
+                            end_oid, max_rows
+                        );
+                        return Ok(result);
+                    }
                 }
-                request_var_binds = vec![VarBind::new(binds.last().unwrap().name().clone())];
+                let last_name = match binds.last() {
+                    Some(last) => last.name().clone(),
+                    None => return Ok(result),
+                };
+                // broken agents may answer with an oid which does not follow the requested one
+                if &last_name <= request_var_binds[0].name() {
+                    return Err(format_err!(
+                        "agent returned {} which does not follow the requested {}",
+                        last_name,
+                        request_var_binds[0].name()
+                    ));
+                }
+                request_var_binds = vec![VarBind::new(last_name)];
             }
             None => return Ok(result),
         }
     }
 }
 
+/// Response to a GetRequest
+#[derive(Debug, Default)]
+pub struct GetResponse {
+    pub values: Vec<(SystemTime, VarBind)>,
+    /// var binds the agent answered with an exception instead of a value
+    pub missing: Vec<VarBind>,
+}
+
+/// SNMP type of a numeric value, for outputs which can carry it along with the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Integer,
+    Counter32,
+    Counter64,
+    /// Unsigned32 as well, both share their BER tag
+    Gauge32,
+    TimeTicks,
+    Opaque,
+    /// A number sent as text, only with `parse_string_as_number`
+    OctetString,
+}
+
+impl ValueType {
+    pub fn of(value: &VarValue) -> Option<ValueType> {
+        match value {
+            VarValue::Int(_) => Some(ValueType::Integer),
+            VarValue::Counter(_) => Some(ValueType::Counter32),
+            VarValue::BigCounter(_) => Some(ValueType::Counter64),
+            VarValue::UnsignedInt(_) => Some(ValueType::Gauge32),
+            VarValue::TimeTicks(_) => Some(ValueType::TimeTicks),
+            VarValue::Opaque(_) => Some(ValueType::Opaque),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::Integer => "integer",
+            ValueType::Counter32 => "counter32",
+            ValueType::Counter64 => "counter64",
+            ValueType::Gauge32 => "gauge32",
+            ValueType::TimeTicks => "timeticks",
+            ValueType::Opaque => "opaque",
+            ValueType::OctetString => "octetstring",
+        }
+    }
+
+    /// Counter32 and Counter64 only increase and wrap, consumers derive rates from them. Every
+    /// other type, Gauge32 included, is a current value.
+    pub fn is_counter(&self) -> bool {
+        matches!(self, ValueType::Counter32 | ValueType::Counter64)
+    }
+}
+
+/// noSuchObject, noSuchInstance and endOfMibView: the agent does not have the oid, as opposed
+/// to a value which can not be decoded
+pub fn is_exception(value: &VarValue) -> bool {
+    matches!(
+        value,
+        VarValue::NoSuchObject | VarValue::NoSuchInstance | VarValue::EndOfMibView
+    )
+}
+
 pub async fn snmp_get<D, P, S>(
     request_var_binds: Vec<VarBind>,
     client: &mut Client,
     session: &mut Session<'_, D, P, S>,
-) -> Result<Vec<(SystemTime, VarBind)>, Error>
+) -> Result<GetResponse, Error>
 where
     D: Digest,
     P: PrivKey<Salt = S>,
     S: Step + Copy,
 {
-    let mut get_request =
-        msg_factory::create_request_msg(PduType::GetRequest, request_var_binds, session);
-
-    let mut result: Vec<(SystemTime, VarBind)> = vec![];
+    let mut result = GetResponse::default();
 
-    let response = client.send_request(&mut get_request, session).await?;
+    let response = send_request(
+        |session| create_request_msg(PduType::GetRequest, request_var_binds.clone(), session),
+        client,
+        session,
+    )
+    .await?;
     if let Some(var_binds) = get_var_binds(&response) {
+        check_report(var_binds)?;
         for var_bind in var_binds {
-            result.push((SystemTime::now(), var_bind.clone()));
+            if is_exception(var_bind.value()) {
+                result.missing.push(var_bind.clone());
+            } else {
+                result.values.push((received_at(), var_bind.clone()));
+            }
         }
     }
 
     Ok(result)
 }
 
+tokio::task_local! {
+    /// Requests sent within `with_request_audit`
+    static AUDITED_REQUESTS: RefCell<Vec<AuditedRequest>>;
+}
+
+/// A request which passed `check_read_only` and was sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditedRequest {
+    pub pdu_type: PduType,
+    pub oids: Vec<String>,
+}
+
+/// Run `requests` and return the requests they sent along with their output
+pub async fn with_request_audit<F: Future>(requests: F) -> (F::Output, Vec<AuditedRequest>) {
+    AUDITED_REQUESTS
+        .scope(RefCell::new(vec![]), async {
+            let output = requests.await;
+            (output, AUDITED_REQUESTS.with(RefCell::take))
+        })
+        .await
+}
+
+/// The collector only reads from agents, any other request is refused before it is sent
+fn check_read_only(msg: &SnmpMsg) -> Result<(), Error> {
+    let pdu_type = match &msg.scoped_pdu_data {
+        ScopedPduData::Plaintext(scoped_pdu) => scoped_pdu.pdu_type(),
+        _ => bail!("refusing to send an encrypted request, its type can not be checked"),
+    };
+    match pdu_type {
+        PduType::GetRequest | PduType::GetNextRequest | PduType::GetBulkRequest => {}
+        _ => bail!(
+            "refusing to send a {:?}, only read requests are allowed",
+            pdu_type
+        ),
+    }
+
+    let _ = AUDITED_REQUESTS.try_with(|audited| {
+        let oids = get_var_binds(msg)
+            .unwrap_or_default()
+            .iter()
+            .map(|var_bind| var_bind.name().to_string())
+            .collect();
+        audited.borrow_mut().push(AuditedRequest { pdu_type, oids });
+    });
+    Ok(())
+}
+
+/// Send the message built by `create_request` and return its response
+async fn send_request<D, P, S>(
+    create_request: impl FnOnce(&mut Session<'_, D, P, S>) -> SnmpMsg,
+    client: &mut Client,
+    session: &mut Session<'_, D, P, S>,
+) -> Result<SnmpMsg, Error>
+where
+    D: Digest,
+    P: PrivKey<Salt = S>,
+    S: Step + Copy,
+{
+    pace().await;
+    let mut request = create_request(session);
+    set_context_name(&mut request);
+    check_read_only(&request)?;
+    let (sent, started) = (SystemTime::now(), Instant::now());
+    let response = client.send_request(&mut request, session).await?;
+    anchor_cycle_timestamp(sent, started.elapsed());
+    Ok(response)
+}
+
 pub fn vec_to_var_binds(v: Vec<u64>) -> VarBind {
     VarBind::new(ObjectIdent::new(v))
 }
@@ -88,11 +610,61 @@ fn find_module<'a>(
         .nth(0)
 }
 
+/// Numeric OID of `MODULE::field`, the field may be followed by a dotted numeric suffix which is
+/// appended as is, e.g. `IF-MIB::ifXTable.1.8` or a column and index like `IF-MIB::ifName.3`
 pub fn build_snmp_mib_tree(oid: &str, mibs: &[mib_parser::MibInfo]) -> Result<Vec<u64>, Error> {
+    build_snmp_mib_tree_traced(oid, mibs, &mut vec![])
+}
+
+/// Field of `MODULE::field` as used in metric keys, a numeric suffix is joined with `_` so it
+/// does not add levels to the key, e.g. `ifXTable_1_8`
+pub fn field_name(oid: &str) -> String {
+    oid.split_once("::")
+        .map_or(oid, |(_, field)| field)
+        .replace('.', "_")
+}
+
+/// Split `field.1.2` into the field and its numeric suffix
+fn split_numeric_suffix(field: &str) -> Result<(&str, Vec<u64>), Error> {
+    let (field, suffix) = match field.split_once('.') {
+        Some(split) => split,
+        None => return Ok((field, vec![])),
+    };
+    let suffix = suffix
+        .split('.')
+        .map(|component| component.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| {
+            format_err!(
+                "build_snmp_mib_tree: expected numbers after {}, got '{}'",
+                field,
+                suffix
+            )
+        })?;
+    Ok((field, suffix))
+}
+
+/// `build_snmp_mib_tree`, recording `oid` and every import followed to resolve it
+pub fn build_snmp_mib_tree_traced(
+    oid: &str,
+    mibs: &[mib_parser::MibInfo],
+    import_chain: &mut Vec<String>,
+) -> Result<Vec<u64>, Error> {
     let mut tree_oid: Vec<u64> = vec![];
+    import_chain.push(oid.to_string());
 
     let oid_module = oid.split("::").nth(0).unwrap().to_string();
-    let oid_field = oid.split("::").nth(1).unwrap().to_string();
+    let (oid_field, suffix) = split_numeric_suffix(oid.split("::").nth(1).unwrap())?;
+    if !suffix.is_empty() {
+        let mut full_oid = build_snmp_mib_tree_traced(
+            &format!("{}::{}", oid_module, oid_field),
+            mibs,
+            import_chain,
+        )?;
+        full_oid.extend(suffix);
+        return Ok(full_oid);
+    }
+    let oid_field = oid_field.to_string();
 
     let module = find_module(&oid_module, mibs);
 
@@ -135,8 +707,11 @@ pub fn build_snmp_mib_tree(oid: &str, mibs: &[mib_parser::MibInfo]) -> Result<Ve
 
             oid_field = assignment_parent_name.to_string();
         } else if let Some(import) = module.imports.iter().filter(|v| v.name == oid_field).nth(0) {
-            let mut upper_oid =
-                build_snmp_mib_tree(&format!("{}::{}", import.from, import.name), mibs)?;
+            let mut upper_oid = build_snmp_mib_tree_traced(
+                &format!("{}::{}", import.from, import.name),
+                mibs,
+                import_chain,
+            )?;
             upper_oid.extend(tree_oid.iter().rev());
             return Ok(upper_oid);
         } else {
@@ -164,13 +739,74 @@ pub fn var_numeric_value_to_string(var_value: &VarValue) -> Option<String> {
         VarValue::Counter(c) => Some(format!("{}", c)),
         VarValue::UnsignedInt(ui) => Some(format!("{}", ui)),
         VarValue::BigCounter(bc) => Some(format!("{}", bc)),
+        VarValue::Opaque(o) => opaque_to_decimal(o),
         _ => None,
     }
 }
 
+/// Decode the Opaque-wrapped Float and Double types of NET-SNMP-TC (draft-perkins-opaque), the
+/// only Opaque contents which are commonly used for measurements. Anything else is logged and
+/// skipped, vendors put all kinds of data into Opaque values.
+pub fn opaque_to_decimal(opaque: &[u8]) -> Option<String> {
+    // extension tag 0x9f, then 0x78 (Float) or 0x79 (Double), length and big endian value.
+    // Floats are formatted as f32 so they do not gain digits from the conversion to f64.
+    let (value, is_finite) = match opaque {
+        [0x9f, 0x78, 4, value @ ..] if value.len() == 4 => {
+            let value = f32::from_be_bytes(value.try_into().unwrap());
+            (format!("{}", value), value.is_finite())
+        }
+        [0x9f, 0x79, 8, value @ ..] if value.len() == 8 => {
+            let value = f64::from_be_bytes(value.try_into().unwrap());
+            (format!("{}", value), value.is_finite())
+        }
+        _ => {
+            debug!("snmp: can not decode opaque value {:02x?}", opaque);
+            return None;
+        }
+    };
+    if !is_finite {
+        debug!("snmp: skipping non finite opaque value {}", value);
+        return None;
+    }
+    Some(value)
+}
+
+/// The number an OCTET STRING holds as text, e.g. "42.5" or " 17\0". None for anything but a
+/// finite integer or decimal number.
+pub fn string_to_number(s: &[u8]) -> Option<String> {
+    let s = std::str::from_utf8(s)
+        .ok()?
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    if let Ok(value) = s.parse::<i128>() {
+        return Some(format!("{}", value));
+    }
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() => Some(format!("{}", value)),
+        _ => None,
+    }
+}
+
+/// Whether an OCTET STRING can be used as is in a metric key
+pub fn is_printable(s: &[u8]) -> bool {
+    match std::str::from_utf8(s) {
+        Ok(s) => !s.is_empty() && !s.chars().any(char::is_control),
+        Err(_) => false,
+    }
+}
+
+/// Numeric value of `v`, emitted unchanged whatever the SNMP type:
+///
+/// - Counter32 (`Counter`) and Counter64 (`BigCounter`) are counters, rates are left to the
+///   consumers of the outputs
+/// - Gauge32 and Unsigned32 share their BER tag, snmp_mp decodes both as `UnsignedInt`. They
+///   are gauges and must not be treated as counters.
+/// - INTEGER (`Int`) and TimeTicks are emitted as is
+/// - Opaque Float and Double are handled by `var_bind_to_metric_value`, every other type is
+///   not numeric
 pub fn var_bind_to_i128(v: VarBind) -> Option<i128> {
     match v.value() {
         snmp_mp::VarValue::Counter(value) => Some(*value as i128),
+        // Gauge32 / Unsigned32
         snmp_mp::VarValue::UnsignedInt(value) => Some(*value as i128),
         snmp_mp::VarValue::BigCounter(value) => Some((*value).into()),
         snmp_mp::VarValue::Int(value) => Some((*value).into()),
@@ -178,3 +814,267 @@ pub fn var_bind_to_i128(v: VarBind) -> Option<i128> {
         _ => None,
     }
 }
+
+/// The value of `v` as sent to the outputs: integers as is, floats in decimal notation. Integers
+/// never pass through a float, so Counter64 values keep all 64 bits.
+pub fn var_bind_to_metric_value(v: VarBind) -> Option<String> {
+    match v.value() {
+        VarValue::Opaque(opaque) => opaque_to_decimal(opaque),
+        _ => var_bind_to_i128(v).map(|value| format!("{}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::fake_agent::{self, response_pdu, Answer};
+    use snmp_mp::ScopedPdu;
+    use std::sync::{Arc, Mutex};
+
+    fn value(value: VarValue) -> VarBind {
+        VarBind::with_value(ObjectIdent::from_slice(&SYS_UPTIME_OID), value)
+    }
+
+    /// A column of a private table, walked by the tests with a fake agent
+    const COLUMN: [u64; 9] = [1, 3, 6, 1, 4, 1, 99999, 1, 1];
+
+    /// The `max_repetitions` rows of a column with `rows` rows following `oid`, then the rows of
+    /// the next column. Every value is `value`.
+    fn rows_after(oid: &[u64], rows: u64, max_repetitions: u32, value: &VarValue) -> Vec<VarBind> {
+        let next_column = [&COLUMN[..COLUMN.len() - 1], &[COLUMN[COLUMN.len() - 1] + 1]].concat();
+        (1..=rows)
+            .map(|row| [&COLUMN[..], &[row]].concat())
+            .chain((1..).map(|row| [&next_column[..], &[row]].concat()))
+            .filter(|row| row.as_slice() > oid)
+            .take(max_repetitions as usize)
+            .map(|row| VarBind::with_value(ObjectIdent::new(row), value.clone()))
+            .collect()
+    }
+
+    /// Walk up to `max_rows` rows of `COLUMN` in bulk mode against an agent answering with
+    /// `answer`, the max-repetitions of every request are recorded
+    async fn walk_with(
+        max_rows: usize,
+        repetitions: &mut BulkRepetitions,
+        mut answer: impl FnMut(&ScopedPdu) -> Option<Answer>,
+    ) -> (Result<Vec<(SystemTime, VarBind)>, Error>, Vec<u32>) {
+        let agent = fake_agent::bind().await;
+        let (mut client, mut session) = fake_agent::connect(&agent).await;
+        let requested = Arc::new(Mutex::new(vec![]));
+        let recorded = requested.clone();
+        let walk = snmp_walk(
+            vec![VarBind::new(ObjectIdent::from_slice(&COLUMN))],
+            &[],
+            max_rows,
+            &WalkMode::Bulk,
+            repetitions,
+            &mut client,
+            &mut session,
+        );
+        let serve = fake_agent::serve(&agent, |request| {
+            recorded.lock().unwrap().push(request.error_index());
+            answer(request)
+        });
+        let result = tokio::select! {
+            result = walk => result,
+            _ = serve => unreachable!(),
+        };
+        let requested = requested.lock().unwrap().clone();
+        (result, requested)
+    }
+
+    #[tokio::test]
+    async fn bulk_walk_halves_the_rows_on_too_big() {
+        let mut repetitions = BulkRepetitions::new(None);
+        let (walked, requested) = walk_with(1000, &mut repetitions, |request| {
+            let mut response = response_pdu(request, vec![]);
+            if request.error_index() > 2 {
+                response.set_error_status(PduErrorStatus::TooBig);
+            } else {
+                let oid = request.var_binds()[0].name().components();
+                response.set_var_binds(rows_after(
+                    oid,
+                    3,
+                    request.error_index(),
+                    &VarValue::Int(1),
+                ));
+            }
+            Some(response.into())
+        })
+        .await;
+
+        assert_eq!(walked.unwrap().len(), 3);
+        assert_eq!(requested, vec![10, 5, 2, 2]);
+        assert_eq!(repetitions.current, 2);
+    }
+
+    #[tokio::test]
+    async fn bulk_walk_fails_on_a_timeout() {
+        let mut repetitions = BulkRepetitions::new(None);
+        let (walked, requested) = walk_with(1000, &mut repetitions, |_| None).await;
+
+        assert_eq!(
+            classify_error(&walked.unwrap_err()),
+            SnmpErrorClass::Timeout
+        );
+        // not retried with fewer rows, a lost response does not tell it was too large
+        assert_eq!(requested, vec![10]);
+        assert_eq!(repetitions.current, 10);
+    }
+
+    #[tokio::test]
+    async fn bulk_walk_requests_fewer_rows_after_an_oversized_response() {
+        // three rows of 100 bytes per request
+        let mut repetitions = BulkRepetitions::new(Some(300));
+        let value = VarValue::String(vec![b'x'; 200]);
+        let (walked, requested) = walk_with(1000, &mut repetitions, |request| {
+            let oid = request.var_binds()[0].name().components();
+            let var_binds = rows_after(oid, 4, request.error_index(), &value);
+            Some(response_pdu(request, var_binds).into())
+        })
+        .await;
+
+        // the oversized response is kept
+        assert_eq!(walked.unwrap().len(), 4);
+        assert_eq!(requested, vec![3, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn a_walk_ends_after_max_rows_of_a_column_which_never_ends() {
+        let mut repetitions = BulkRepetitions::new(None);
+        let (walked, requested) = walk_with(25, &mut repetitions, |request| {
+            // neither the end of the column nor EndOfMibView
+            let oid = request.var_binds()[0].name().components();
+            let var_binds = rows_after(oid, u64::MAX, request.error_index(), &VarValue::Int(1));
+            Some(response_pdu(request, var_binds).into())
+        })
+        .await;
+
+        let walked = walked.unwrap();
+        assert_eq!(walked.len(), 25);
+        assert_eq!(
+            walked.last().unwrap().1.name().components(),
+            [&COLUMN[..], &[25]].concat()
+        );
+        assert_eq!(requested, vec![10, 10, 10]);
+    }
+
+    #[test]
+    fn counter64_values_are_formatted_exactly() {
+        assert_eq!(
+            var_bind_to_metric_value(value(VarValue::BigCounter(u64::MAX))),
+            Some("18446744073709551615".to_string())
+        );
+        assert_eq!(
+            var_bind_to_metric_value(value(VarValue::BigCounter(u64::MAX - 1))),
+            Some("18446744073709551614".to_string())
+        );
+        assert_eq!(
+            var_bind_to_metric_value(value(VarValue::Counter(u32::MAX))),
+            Some("4294967295".to_string())
+        );
+    }
+
+    #[test]
+    fn gauges_are_no_counters() {
+        // Gauge32 and Unsigned32 are decoded as UnsignedInt
+        let gauge = ValueType::of(&VarValue::UnsignedInt(u32::MAX)).unwrap();
+        assert_eq!(gauge, ValueType::Gauge32);
+        assert!(!gauge.is_counter());
+        assert_eq!(
+            var_bind_to_metric_value(value(VarValue::UnsignedInt(u32::MAX))),
+            Some("4294967295".to_string())
+        );
+
+        for counter in [VarValue::Counter(1), VarValue::BigCounter(1)] {
+            assert!(ValueType::of(&counter).unwrap().is_counter());
+        }
+        for current in [VarValue::Int(-1), VarValue::TimeTicks(1)] {
+            assert!(!ValueType::of(&current).unwrap().is_counter());
+        }
+        assert!(!ValueType::OctetString.is_counter());
+        assert_eq!(ValueType::of(&VarValue::String(b"1".to_vec())), None);
+    }
+
+    #[test]
+    fn errors_are_classified_by_type() {
+        use anyhow::Context;
+        use std::io;
+
+        let wrong_key: Result<(), io::Error> = Err(SecurityError::WrongAuthParams.into());
+        let error = wrong_key.context("failed to collect").unwrap_err();
+        assert_eq!(classify_error(&error), SnmpErrorClass::Auth);
+        let undecryptable = Error::from(io::Error::from(MsgProcessingError::DecryptError));
+        assert_eq!(classify_error(&undecryptable), SnmpErrorClass::Auth);
+        let unknown_user = Error::from(SnmpAuthError("usmStatsUnknownUserNames"));
+        assert_eq!(classify_error(&unknown_user), SnmpErrorClass::Auth);
+
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "no response within 3s");
+        assert_eq!(classify_error(&timed_out.into()), SnmpErrorClass::Timeout);
+
+        // only the type counts, not the description
+        let too_big = Error::from(io::Error::from(MsgProcessingError::TooBig));
+        assert_eq!(classify_error(&too_big), SnmpErrorClass::Other);
+        let described = format_err!("authentication timeout of device 'sw1' in the config");
+        assert_eq!(classify_error(&described), SnmpErrorClass::Other);
+        let time_window = Error::from(io::Error::from(SecurityError::NotInTimeWindow));
+        assert_eq!(classify_error(&time_window), SnmpErrorClass::Other);
+    }
+
+    fn set_request<D, P, S>(session: &mut Session<'_, D, P, S>) -> SnmpMsg {
+        create_request_msg(PduType::SetRequest, vec![value(VarValue::Int(1))], session)
+    }
+
+    fn get_request<D, P, S>(session: &mut Session<'_, D, P, S>) -> SnmpMsg {
+        create_request_msg(
+            PduType::GetRequest,
+            vec![value(VarValue::Unspecified)],
+            session,
+        )
+    }
+
+    #[tokio::test]
+    async fn writes_are_refused_before_they_are_sent() {
+        let agent = fake_agent::bind().await;
+        let (mut client, mut session) = fake_agent::connect(&agent).await;
+
+        let (result, audited) =
+            with_request_audit(send_request(set_request, &mut client, &mut session)).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "refusing to send a SetRequest, only read requests are allowed"
+        );
+        assert!(audited.is_empty());
+
+        // nothing reached the agent
+        let mut buf = [0; 16];
+        let received = tokio::time::timeout(Duration::from_millis(100), agent.recv(&mut buf)).await;
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn sent_reads_are_audited_with_their_oids() {
+        let agent = fake_agent::bind().await;
+        let (mut client, mut session) = fake_agent::connect(&agent).await;
+
+        let requests = with_request_audit(send_request(get_request, &mut client, &mut session));
+        let (result, audited) = tokio::select! {
+            audit = requests => audit,
+            _ = fake_agent::serve(&agent, |request| {
+                Some(response_pdu(request, vec![value(VarValue::TimeTicks(1))]).into())
+            }) => unreachable!(),
+        };
+        result.unwrap();
+        assert_eq!(
+            audited,
+            vec![AuditedRequest {
+                pdu_type: PduType::GetRequest,
+                oids: vec![ObjectIdent::from_slice(&SYS_UPTIME_OID).to_string()],
+            }]
+        );
+
+        // outside of the audit nothing is recorded
+        let (_, audited) = with_request_audit(async {}).await;
+        assert!(audited.is_empty());
+    }
+}
@@ -5,7 +5,11 @@ use snmp_mp::VarBind;
 #[derive(Debug)]
 pub struct SnmpStatResult {
     pub device: String,
+    /// name of the endpoint of a device with `snmp.endpoints`
+    pub endpoint: Option<String>,
     pub timestamp: SystemTime,
     pub key: VarBind,
     pub value: VarBind,
+    /// instance sub-identifiers following the column oid, shared by `key` and `value`
+    pub index: Vec<u64>,
 }
@@ -0,0 +1,144 @@
+use std::env;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{debug, info, warn};
+
+use crate::collector::CYCLES_COMPLETED;
+use crate::config::Config;
+use crate::watchdog::DeviceProgress;
+
+/// Intervals without progress after which a device counts as stuck, unless
+/// `watchdog_intervals` is set
+const DEFAULT_STALL_INTERVALS: f64 = 10.0;
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Tell systemd the collector is ready (`READY=1`, for `Type=notify`) once the first cycle of
+/// any device completed, then ping its watchdog (`WATCHDOG=1`, for `WatchdogSec`) as long as
+/// some device makes progress. Does nothing unless started by systemd with `NOTIFY_SOCKET`.
+/// Until a cycle completes the unit is starting, systemd fails it after `TimeoutStartSec`.
+///
+/// `devices` is read on every check, the watchdog replaces the progress of a restarted task.
+pub async fn notify(config: Arc<Config>, devices: DeviceProgress) -> Result<(), Error> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let notifier = Notifier::new(&path)?;
+    let ping_period = watchdog_period();
+    let intervals = config
+        .main
+        .watchdog_intervals
+        .unwrap_or(DEFAULT_STALL_INTERVALS);
+    match ping_period {
+        Some(ping_period) => info!(
+            "systemd({}): pinging the watchdog every {:?} while devices make progress",
+            path, ping_period
+        ),
+        None => info!("systemd({}): no watchdog, only notifying readiness", path),
+    }
+
+    let mut check = tokio::time::interval(
+        ping_period.map_or(CHECK_PERIOD, |ping_period| ping_period.min(CHECK_PERIOD)),
+    );
+    let mut ready = false;
+    let mut healthy = true;
+    loop {
+        check.tick().await;
+        if !ready && CYCLES_COMPLETED.load(Ordering::Relaxed) > 0 {
+            notifier.send("READY=1")?;
+            info!(
+                "systemd({}): first cycle completed, notified readiness",
+                path
+            );
+            ready = true;
+        }
+        if ping_period.is_none() {
+            if ready {
+                return Ok(());
+            }
+            continue;
+        }
+
+        // a collector stuck as a whole is left to the watchdog of systemd
+        let progressing = is_progressing(&config, &devices, intervals);
+        if progressing != healthy {
+            if progressing {
+                info!("systemd({}): devices make progress again, pinging", path);
+            } else {
+                warn!(
+                    "systemd({}): no device made progress for {} intervals, no longer pinging the watchdog",
+                    path, intervals
+                );
+            }
+            healthy = progressing;
+        }
+        if healthy {
+            debug!("systemd({}): pinging the watchdog", path);
+            notifier.send("WATCHDOG=1")?;
+        }
+    }
+}
+
+/// Half of `WATCHDOG_USEC` as recommended by sd_watchdog_enabled(3), none without a watchdog
+/// or if it is meant for another process
+fn watchdog_period() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec) / 2).filter(|period| !period.is_zero())
+}
+
+/// Devices which have not started yet count as progressing, as does a collector without devices
+fn is_progressing(config: &Config, devices: &DeviceProgress, intervals: f64) -> bool {
+    let devices = devices.read().unwrap();
+    devices.is_empty()
+        || devices.iter().any(|(device_name, progress)| {
+            let device = config.devices.get(device_name).unwrap();
+            let limit = Duration::from_secs(device.interval.into()).mul_f64(intervals);
+            progress
+                .stalled_for()
+                .is_none_or(|stalled_for| stalled_for <= limit)
+        })
+}
+
+struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+    /// `path` is a file system path, or an abstract socket name starting with `@`
+    fn new(path: &str) -> Result<Notifier, Error> {
+        let address = match path.strip_prefix('@') {
+            Some(name) => abstract_address(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            address,
+        })
+    }
+
+    fn send(&self, state: &str) -> Result<(), Error> {
+        self.socket.send_to_addr(state.as_bytes(), &self.address)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_address(name: &str) -> Result<SocketAddr, Error> {
+    use std::os::linux::net::SocketAddrExt;
+    Ok(SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_address(name: &str) -> Result<SocketAddr, Error> {
+    anyhow::bail!("abstract socket @{} is only supported on Linux", name)
+}
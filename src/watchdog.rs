@@ -0,0 +1,347 @@
+use flume::{Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::collector::{CycleSummary, Progress};
+use crate::config::Config;
+use crate::output::{device_self_metric, self_metric, CarbonMetricValue};
+
+const CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// The progress of the current task of every device, shared with the systemd notifier. A
+/// replacement task gets a new one, the replaced task can not count as progress of the device.
+pub type DeviceProgress = Arc<RwLock<HashMap<String, Progress>>>;
+
+/// The collection task of a device and what is needed to replace it
+pub struct DeviceTask {
+    pub device_name: String,
+    pub handle: JoinHandle<CycleSummary>,
+    pub progress: Progress,
+    pub trigger: Receiver<()>,
+}
+
+/// Restart counts and the replaced tasks which have not stopped yet
+#[derive(Default)]
+struct Restarts {
+    total: u64,
+    per_device: HashMap<String, u64>,
+    /// aborted tasks of the devices, with whether they have been reported as blocked
+    replaced: Vec<(String, JoinHandle<CycleSummary>, bool)>,
+}
+
+/// Restart the task of every device which made no progress for `intervals` of its interval,
+/// e.g. because it waits on a socket which never times out. `spawn` starts a new task for the
+/// device with the given trigger and progress.
+///
+/// An aborted task stops at its next await. A task blocked in a synchronous call, e.g. a
+/// blocking DNS lookup, keeps the runtime thread it runs on until the call returns. Such tasks
+/// are logged and counted in `watchdog_blocked_tasks`, they are left to systemd's watchdog if
+/// they block every thread.
+pub async fn watch<F>(
+    config: Arc<Config>,
+    mut tasks: Vec<DeviceTask>,
+    intervals: f64,
+    spawn: F,
+    progress: DeviceProgress,
+    metric_channel: Sender<CarbonMetricValue>,
+) -> Result<(), Error>
+where
+    F: Fn(&str, Receiver<()>, Progress) -> Result<JoinHandle<CycleSummary>, Error>,
+{
+    info!(
+        "watchdog: watching {} devices, restarting them after {} intervals without progress",
+        tasks.len(),
+        intervals
+    );
+    let mut restarts = Restarts::default();
+    loop {
+        tokio::time::sleep(CHECK_PERIOD).await;
+        check(
+            &config,
+            &mut tasks,
+            intervals,
+            &spawn,
+            &progress,
+            &mut restarts,
+            &metric_channel,
+        )?;
+    }
+}
+
+fn check<F>(
+    config: &Config,
+    tasks: &mut [DeviceTask],
+    intervals: f64,
+    spawn: &F,
+    progress: &DeviceProgress,
+    restarts: &mut Restarts,
+    metric_channel: &Sender<CarbonMetricValue>,
+) -> Result<(), Error>
+where
+    F: Fn(&str, Receiver<()>, Progress) -> Result<JoinHandle<CycleSummary>, Error>,
+{
+    let blocked_before = restarts.replaced.len();
+    restarts.replaced.retain(|(device_name, handle, _)| {
+        let finished = handle.is_finished();
+        if finished {
+            info!("watchdog({}): the replaced task stopped", device_name);
+        }
+        !finished
+    });
+    for (device_name, _, reported) in restarts
+        .replaced
+        .iter_mut()
+        .filter(|(_, _, reported)| !*reported)
+    {
+        warn!(
+            "watchdog({}): the replaced task did not stop, it is blocked in a synchronous call and holds a runtime thread until the call returns",
+            device_name
+        );
+        *reported = true;
+    }
+    // tasks replaced at this check are looked at in the next one
+    if blocked_before > 0 {
+        metric_channel
+            .send(self_metric(
+                "watchdog_blocked_tasks",
+                restarts.replaced.len(),
+            ))
+            .unwrap();
+    }
+
+    for task in tasks.iter_mut() {
+        let device = config.devices.get(&task.device_name).unwrap();
+        let limit = Duration::from_secs(device.interval.into()).mul_f64(intervals);
+        let stalled_for = match task.progress.stalled_for() {
+            Some(stalled_for) if stalled_for > limit => stalled_for,
+            _ => continue,
+        };
+
+        warn!(
+            "watchdog({}): no progress for {:?}, more than {} intervals; restarting its task",
+            task.device_name, stalled_for, intervals
+        );
+        task.handle.abort();
+        // the replaced task may still touch its progress until it reaches an await
+        task.progress = Progress::default();
+        progress
+            .write()
+            .unwrap()
+            .insert(task.device_name.clone(), task.progress.clone());
+        let replaced = std::mem::replace(
+            &mut task.handle,
+            spawn(
+                &task.device_name,
+                task.trigger.clone(),
+                task.progress.clone(),
+            )?,
+        );
+        restarts
+            .replaced
+            .push((task.device_name.clone(), replaced, false));
+
+        restarts.total += 1;
+        let device_restarts = restarts
+            .per_device
+            .entry(task.device_name.clone())
+            .or_insert(0);
+        *device_restarts += 1;
+        metric_channel
+            .send(device_self_metric(
+                &task.device_name,
+                "watchdog_restarts",
+                *device_restarts,
+            ))
+            .unwrap();
+        metric_channel
+            .send(self_metric("watchdog_restarts", restarts.total))
+            .unwrap();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/example/simple-example.yaml");
+    const DEVICE: &str = "example-device001";
+    /// the device of the example is polled every 20s, 0.005 intervals are 100ms
+    const INTERVALS: f64 = 0.005;
+
+    fn device_task(handle: JoinHandle<CycleSummary>, progress: Progress) -> DeviceTask {
+        DeviceTask {
+            device_name: DEVICE.to_string(),
+            handle,
+            progress,
+            trigger: flume::bounded(1).1,
+        }
+    }
+
+    /// A task which touches `progress` until it is aborted
+    fn touching(progress: Progress) -> JoinHandle<CycleSummary> {
+        tokio::spawn(async move {
+            loop {
+                progress.touch();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+    }
+
+    fn metric_names(metrics: &Receiver<CarbonMetricValue>) -> Vec<String> {
+        metrics.drain().map(|metricval| metricval.metric).collect()
+    }
+
+    fn self_metric_name(metric_name: &str) -> String {
+        self_metric(metric_name, 0).metric
+    }
+
+    #[tokio::test]
+    async fn a_stalled_task_is_replaced_with_its_own_progress() {
+        let config = config::from_file(EXAMPLE, None).unwrap();
+        let stalled = Progress::default();
+        stalled.touch();
+        // the stalled task still touches its progress until it is aborted
+        let old_progress = stalled.clone();
+        let mut tasks = vec![device_task(
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                old_progress.touch();
+                CycleSummary::default()
+            }),
+            stalled.clone(),
+        )];
+        let progress: DeviceProgress = Arc::new(RwLock::new(
+            vec![(DEVICE.to_string(), stalled.clone())]
+                .into_iter()
+                .collect(),
+        ));
+        let spawned = Arc::new(RwLock::new(vec![]));
+        let spawn = |_: &str, _, progress: Progress| {
+            spawned.write().unwrap().push(progress.clone());
+            Ok(touching(progress))
+        };
+        let (metric_channel, metrics) = flume::unbounded();
+        let mut restarts = Restarts::default();
+
+        // within the limit
+        check(
+            &config,
+            &mut tasks,
+            INTERVALS,
+            &spawn,
+            &progress,
+            &mut restarts,
+            &metric_channel,
+        )
+        .unwrap();
+        assert!(spawned.read().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        check(
+            &config,
+            &mut tasks,
+            INTERVALS,
+            &spawn,
+            &progress,
+            &mut restarts,
+            &metric_channel,
+        )
+        .unwrap();
+        assert_eq!(spawned.read().unwrap().len(), 1);
+        assert_eq!(
+            metric_names(&metrics),
+            vec![
+                device_self_metric(DEVICE, "watchdog_restarts", 0).metric,
+                self_metric_name("watchdog_restarts")
+            ]
+        );
+
+        // the replacement is shared with systemd, the stalled progress is left alone
+        let replacement = spawned.read().unwrap()[0].clone();
+        assert!(stalled.stalled_for().unwrap() >= Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(replacement.stalled_for().unwrap() < Duration::from_millis(100));
+        assert!(
+            progress.read().unwrap()[DEVICE].stalled_for().unwrap() < Duration::from_millis(100)
+        );
+
+        // the aborted task stopped at its await, the replacement makes progress
+        check(
+            &config,
+            &mut tasks,
+            INTERVALS,
+            &spawn,
+            &progress,
+            &mut restarts,
+            &metric_channel,
+        )
+        .unwrap();
+        assert_eq!(spawned.read().unwrap().len(), 1);
+        assert!(restarts.replaced.is_empty());
+        assert_eq!(
+            metric_names(&metrics),
+            vec![self_metric_name("watchdog_blocked_tasks")]
+        );
+        tasks[0].handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_task_blocked_in_a_synchronous_call_is_reported() {
+        let config = config::from_file(EXAMPLE, None).unwrap();
+        let stalled = Progress::default();
+        stalled.touch();
+        let mut tasks = vec![device_task(
+            tokio::spawn(async {
+                std::thread::sleep(Duration::from_millis(600));
+                CycleSummary::default()
+            }),
+            stalled,
+        )];
+        let progress: DeviceProgress = Arc::default();
+        let spawn = |_: &str, _, progress: Progress| Ok(touching(progress));
+        let (metric_channel, metrics) = flume::unbounded();
+        let mut restarts = Restarts::default();
+        let check_now = |tasks: &mut Vec<DeviceTask>, restarts: &mut Restarts| {
+            check(
+                &config,
+                tasks,
+                INTERVALS,
+                &spawn,
+                &progress,
+                restarts,
+                &metric_channel,
+            )
+            .unwrap()
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        check_now(&mut tasks, &mut restarts);
+        assert_eq!(restarts.total, 1);
+        assert_eq!(metric_names(&metrics).len(), 2);
+
+        // abort can not interrupt the sleep
+        check_now(&mut tasks, &mut restarts);
+        assert_eq!(restarts.replaced.len(), 1);
+        assert!(restarts.replaced[0].2);
+        let blocked: Vec<String> = metrics
+            .drain()
+            .filter(|metricval| metricval.metric == self_metric_name("watchdog_blocked_tasks"))
+            .map(|metricval| metricval.value)
+            .collect();
+        assert_eq!(blocked, vec!["1"]);
+
+        // once the call returned the task stops
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        check_now(&mut tasks, &mut restarts);
+        assert!(restarts.replaced.is_empty());
+        assert_eq!(restarts.total, 1);
+        tasks[0].handle.abort();
+    }
+}